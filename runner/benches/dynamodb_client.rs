@@ -0,0 +1,40 @@
+//! Compares what building a DynamoDB client for every request would cost
+//! with cloning the one `main` builds at startup, which is what each request
+//! does now.
+//!
+//! Building one means loading the AWS config from the environment, as
+//! `DynamoDBDatastore::from_env` does, and then the client itself. Nothing
+//! here talks to AWS: credentials are only fetched when a request is sent.
+//! The bench sets a region and static credentials so that loading the
+//! config doesn't go looking for instance metadata. Run it with
+//! `cargo bench --bench dynamodb_client`.
+
+mod timing;
+
+use aws_config::BehaviorVersion;
+use timing::bench;
+
+const ITERATIONS: usize = 1_000;
+
+async fn build() -> aws_sdk_dynamodb::Client {
+    let config = aws_config::load_defaults(BehaviorVersion::latest()).await;
+    aws_sdk_dynamodb::Client::from_conf(aws_sdk_dynamodb::config::Builder::from(&config).build())
+}
+
+fn main() {
+    std::env::set_var("AWS_REGION", "us-east-1");
+    std::env::set_var("AWS_ACCESS_KEY_ID", "bench");
+    std::env::set_var("AWS_SECRET_ACCESS_KEY", "bench");
+    std::env::set_var("AWS_EC2_METADATA_DISABLED", "true");
+    let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+    runtime.block_on(async {
+	bench("build", ITERATIONS, || async {
+	    std::hint::black_box(build().await);
+	}).await;
+
+	let client = build().await;
+	bench("clone", ITERATIONS, || async {
+	    std::hint::black_box(client.clone());
+	}).await;
+    });
+}
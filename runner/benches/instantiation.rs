@@ -0,0 +1,112 @@
+//! Compares instantiating the guest from the pooling allocator, as the runner
+//! does, with instantiating it in freshly mapped memory, as it used to, and
+//! with compiling it first, as it did before it cached the module.
+//!
+//! Each iteration does what a request does before it gets to the guest's
+//! entrypoint: makes a new `Store`, instantiates the guest in it and calls
+//! `wasmtest_abi_version`. Host functions are stubbed out, since nothing
+//! here calls them. Build the guest first, with `cargo build --release` in
+//! `wasmtest`, then run `cargo bench`. `WASMTEST_MODULE_PATH` picks another
+//! guest, as it does for the runner.
+
+mod timing;
+
+use timing::bench;
+use wasmtime::{Config, Engine, InstanceAllocationStrategy, InstancePre, Linker, Module, PoolingAllocationConfig, Store};
+
+/// How many timed instantiations each case reports on. Compiling takes
+/// long enough that it gets fewer.
+const ITERATIONS: usize = 1_000;
+const COMPILE_ITERATIONS: usize = 100;
+
+/// The runner's defaults for the pool: `POOL_SIZE` slots, each with room for
+/// `DEFAULT_MEMORY_LIMIT` bytes of memory and `DEFAULT_TABLE_LIMIT` table
+/// elements.
+const POOL_SIZE: u32 = 16;
+const MEMORY_PAGES: u64 = (64 << 20) / (64 << 10);
+const TABLE_ELEMENTS: u32 = 10_000;
+
+/// An engine configured like the runner's, with or without the pool.
+fn runner_engine(pooled: bool) -> Engine {
+    let mut config = Config::new();
+    config.async_support(true);
+    config.consume_fuel(true);
+    config.epoch_interruption(true);
+    if pooled {
+	let mut pool = PoolingAllocationConfig::default();
+	pool.total_core_instances(POOL_SIZE)
+	    .total_memories(POOL_SIZE)
+	    .total_tables(POOL_SIZE)
+	    .total_stacks(POOL_SIZE)
+	    .memory_pages(MEMORY_PAGES)
+	    .table_elements(TABLE_ELEMENTS);
+	config.allocation_strategy(InstanceAllocationStrategy::Pooling(pool));
+    }
+    Engine::new(&config).unwrap()
+}
+
+fn guest() -> Vec<u8> {
+    let path = std::env::var("WASMTEST_MODULE_PATH")
+	.unwrap_or_else(|_| "../wasmtest/target/wasm32-unknown-unknown/release/wasmtest.wasm".into());
+    std::fs::read(&path).unwrap_or_else(|e| panic!("couldn't read guest {}: {}; build it first", path, e))
+}
+
+/// How a case gets an instance of the guest.
+enum Instantiate<'a> {
+    /// Compiles the module from its wasm, then goes on like `OnDemand`.
+    Compile(&'a [u8]),
+    /// Links the module's imports and instantiates it.
+    OnDemand(&'a Module),
+    /// Instantiates from a module linked ahead of time.
+    Pre(&'a InstancePre<()>),
+}
+
+/// Makes a store and instantiates the guest in it, as `function_handler`
+/// does.
+async fn request(engine: &Engine, how: &Instantiate<'_>) {
+    let mut store = Store::new(engine, ());
+    store.set_fuel(u64::MAX).unwrap();
+    store.set_epoch_deadline(u64::MAX);
+    let instance = match how {
+	Instantiate::Compile(wasm) => {
+	    let module = Module::new(engine, wasm).unwrap();
+	    let mut linker = Linker::new(engine);
+	    linker.define_unknown_imports_as_traps(&module).unwrap();
+	    linker.instantiate_async(&mut store, &module).await
+	}
+	Instantiate::OnDemand(module) => {
+	    let mut linker = Linker::new(engine);
+	    linker.define_unknown_imports_as_traps(module).unwrap();
+	    linker.instantiate_async(&mut store, module).await
+	}
+	Instantiate::Pre(instance_pre) => instance_pre.instantiate_async(&mut store).await,
+    };
+    let instance = instance.unwrap();
+    let version = instance.get_typed_func::<(), u32>(&mut store, "wasmtest_abi_version").unwrap();
+    version.call_async(&mut store, ()).await.unwrap();
+}
+
+fn main() {
+    let wasm = guest();
+    let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+    runtime.block_on(async {
+	// Before the module was cached: compile it for every request too.
+	let engine = runner_engine(false);
+	let how = Instantiate::Compile(&wasm);
+	bench("compile", COMPILE_ITERATIONS, || request(&engine, &how)).await;
+
+	// Before the pool: link and instantiate in fresh memory every time.
+	let module = Module::new(&engine, &wasm).unwrap();
+	let how = Instantiate::OnDemand(&module);
+	bench("on-demand", ITERATIONS, || request(&engine, &how)).await;
+
+	// The runner: link once, instantiate into a pool slot.
+	let engine = runner_engine(true);
+	let module = Module::new(&engine, &wasm).unwrap();
+	let mut linker = Linker::new(&engine);
+	linker.define_unknown_imports_as_traps(&module).unwrap();
+	let instance_pre = linker.instantiate_pre(&module).unwrap();
+	let how = Instantiate::Pre(&instance_pre);
+	bench("pooled", ITERATIONS, || request(&engine, &how)).await;
+    });
+}
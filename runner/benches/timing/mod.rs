@@ -0,0 +1,26 @@
+//! The timing loop the benchmarks share. They run without a harness, since
+//! the runner has no benchmarking library to lean on.
+
+use std::future::Future;
+use std::time::{Duration, Instant};
+
+/// How many times each case runs before it's timed.
+const WARMUP: usize = 10;
+
+/// Runs `iteration` `WARMUP` times, then prints how long each of
+/// `iterations` more took.
+pub async fn bench<F: Future<Output = ()>>(name: &str, iterations: usize, mut iteration: impl FnMut() -> F) {
+    for _ in 0..WARMUP {
+	iteration().await;
+    }
+    let mut times: Vec<Duration> = Vec::with_capacity(iterations);
+    for _ in 0..iterations {
+	let start = Instant::now();
+	iteration().await;
+	times.push(start.elapsed());
+    }
+    times.sort();
+    let mean = times.iter().sum::<Duration>() / iterations as u32;
+    let percentile = |p: usize| times[(iterations * p / 100).min(iterations - 1)];
+    println!("{:<12} mean {:>10.1?}  p50 {:>10.1?}  p99 {:>10.1?}", name, mean, percentile(50), percentile(99));
+}
@@ -0,0 +1,229 @@
+//! A `Datastore` that keeps an audit trail of what guests do to their
+//! storage.
+//!
+//! Every operation is logged through `tracing`, under the `audit` target,
+//! with the operation, the keys it touched, when it happened and whether it
+//! succeeded. Values are never recorded. Keys are recorded as the hex of
+//! their SHA-256 hash unless `KeyMode::Plain` asks for them in hex as-is;
+//! the hash still lets a trail be searched for a known key without giving
+//! keys away to whoever reads the logs.
+//!
+//! Records can also be appended, one JSON object per line, to a file that's
+//! only ever opened for appending. A record that can't be written there is
+//! logged as a warning; the operation it describes has already happened.
+
+use std::fs::File;
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use lambda_http::{tracing, Error};
+use sha2::{Digest, Sha256};
+
+use crate::{env_opt, env_or, to_hex, Datastore, DatastoreError, Entries, Values};
+
+/// How keys appear in audit records.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KeyMode {
+    /// The hex of the key's SHA-256 hash.
+    Hashed,
+    /// The key itself, in hex.
+    Plain,
+}
+
+/// Records every operation on `backend`.
+#[derive(Clone, Debug)]
+pub struct AuditingDatastore<B> {
+    backend: B,
+    keys: KeyMode,
+    /// Where records are appended, besides the logs. Clones share it.
+    trail: Option<Arc<Mutex<File>>>,
+}
+
+impl<B: Datastore> AuditingDatastore<B> {
+    pub fn new(backend: B, keys: KeyMode, trail: Option<File>) -> Self {
+	AuditingDatastore { backend, keys, trail: trail.map(|file| Arc::new(Mutex::new(file))) }
+    }
+
+    /// Records keys as `WASMTEST_AUDIT_KEYS` says, `hashed` or `plain`, and
+    /// appends records to the file at `WASMTEST_AUDIT_PATH` if that's set.
+    pub fn from_env(backend: B) -> Result<Self, Error> {
+	let keys = match env_or("WASMTEST_AUDIT_KEYS", "hashed".to_string())?.as_str() {
+	    "hashed" => KeyMode::Hashed,
+	    "plain" => KeyMode::Plain,
+	    other => return Err(format!("unknown WASMTEST_AUDIT_KEYS {:?}; expected hashed or plain", other).into()),
+	};
+	let trail = env_opt::<String>("WASMTEST_AUDIT_PATH")?
+	    .map(|path| {
+		File::options().append(true).create(true).open(&path)
+		    .map_err(|e| format!("couldn't open audit trail {}: {}", path, e))
+	    })
+	    .transpose()?;
+	Ok(Self::new(backend, keys, trail))
+    }
+
+    /// How `key` appears in records.
+    fn show(&self, key: &[u8]) -> String {
+	match self.keys {
+	    KeyMode::Hashed => to_hex(&Sha256::digest(key)),
+	    KeyMode::Plain => to_hex(key),
+	}
+    }
+
+    /// Records that `op` on `keys` finished with `result`.
+    fn record<'a, T>(&self, op: &str, keys: impl IntoIterator<Item = &'a [u8]>, result: &Result<T, DatastoreError>) {
+	let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64;
+	let keys: Vec<_> = keys.into_iter().map(|key| self.show(key)).collect();
+	let outcome = if result.is_ok() { "ok" } else { "error" };
+	tracing::info!(target: "audit", backend = self.backend.name(), op, ?keys, timestamp, outcome, "datastore operation");
+	let Some(trail) = &self.trail else {
+	    return;
+	};
+	let record = serde_json::json!({
+	    "timestamp": timestamp,
+	    "backend": self.backend.name(),
+	    "op": op,
+	    "keys": keys,
+	    "outcome": outcome,
+	});
+	// Write the whole line at once so records from concurrent requests
+	// don't interleave.
+	if let Err(e) = trail.lock().unwrap().write_all(format!("{}\n", record).as_bytes()) {
+	    tracing::warn!(error = %e, op, "couldn't write audit record");
+	}
+    }
+}
+
+/// Awaits `$call` and records it as a `$op` on `$keys`.
+macro_rules! audited {
+    ($self:ident, $op:literal, $keys:expr, $call:expr) => {{
+	let result = $call.await;
+	$self.record($op, $keys, &result);
+	result
+    }};
+}
+
+impl<B: Datastore> Datastore for AuditingDatastore<B> {
+    const NAME: &'static str = B::NAME;
+
+    fn name(&self) -> &'static str {
+	self.backend.name()
+    }
+
+    async fn put_item(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<(), DatastoreError> {
+	audited!(self, "put_item", [&key[..]], self.backend.put_item(key.clone(), value))
+    }
+
+    async fn put_item_with_ttl(&mut self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<(), DatastoreError> {
+	audited!(self, "put_item_with_ttl", [&key[..]], self.backend.put_item_with_ttl(key.clone(), value, ttl))
+    }
+
+    async fn get_item(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>, DatastoreError> {
+	audited!(self, "get_item", [key], self.backend.get_item(key))
+    }
+
+    async fn batch_get_items(&mut self, keys: &[Vec<u8>]) -> Result<Values, DatastoreError> {
+	audited!(self, "batch_get_items", keys.iter().map(Vec::as_slice), self.backend.batch_get_items(keys))
+    }
+
+    async fn delete_item(&mut self, key: &[u8]) -> Result<(), DatastoreError> {
+	audited!(self, "delete_item", [key], self.backend.delete_item(key))
+    }
+
+    async fn exists(&mut self, key: &[u8]) -> Result<bool, DatastoreError> {
+	audited!(self, "exists", [key], self.backend.exists(key))
+    }
+
+    async fn compare_and_swap(&mut self, key: Vec<u8>, expected: Option<&[u8]>, new: Vec<u8>) -> Result<bool, DatastoreError> {
+	audited!(self, "compare_and_swap", [&key[..]], self.backend.compare_and_swap(key.clone(), expected, new))
+    }
+
+    async fn append(&mut self, key: Vec<u8>, suffix: Vec<u8>) -> Result<(), DatastoreError> {
+	audited!(self, "append", [&key[..]], self.backend.append(key.clone(), suffix))
+    }
+
+    async fn delete_if_equals(&mut self, key: &[u8], expected: &[u8]) -> Result<bool, DatastoreError> {
+	audited!(self, "delete_if_equals", [key], self.backend.delete_if_equals(key, expected))
+    }
+
+    async fn swap(&mut self, key: Vec<u8>, new: Vec<u8>) -> Result<Option<Vec<u8>>, DatastoreError> {
+	audited!(self, "swap", [&key[..]], self.backend.swap(key.clone(), new))
+    }
+
+    async fn increment(&mut self, key: Vec<u8>, delta: i64) -> Result<Option<i64>, DatastoreError> {
+	audited!(self, "increment", [&key[..]], self.backend.increment(key.clone(), delta))
+    }
+
+    async fn put_items(&mut self, pairs: Vec<(Vec<u8>, Vec<u8>)>) -> Result<(), DatastoreError> {
+	let keys: Vec<_> = pairs.iter().map(|(key, _)| key.clone()).collect();
+	audited!(self, "put_items", keys.iter().map(Vec::as_slice), self.backend.put_items(pairs))
+    }
+
+    async fn scan_prefix(&mut self, prefix: &[u8]) -> Result<Entries, DatastoreError> {
+	// The prefix is recorded the way a key would be.
+	audited!(self, "scan_prefix", [prefix], self.backend.scan_prefix(prefix))
+    }
+
+    async fn count(&mut self) -> Result<u64, DatastoreError> {
+	audited!(self, "count", [], self.backend.count())
+    }
+}
+
+#[cfg(all(test, feature = "test-util"))]
+mod tests {
+    use serde_json::{json, Value};
+
+    use super::*;
+    use crate::mock_datastore::MockDatastore;
+
+    /// Runs `ops` against an audited `MockDatastore` and returns the records
+    /// it appended to its trail.
+    async fn trail_of(keys: KeyMode, ops: impl AsyncFnOnce(&mut AuditingDatastore<MockDatastore>, &MockDatastore)) -> Vec<Value> {
+	let path = std::env::temp_dir().join(format!("wasmtest-audit-{:016x}", rand::random::<u64>()));
+	let file = File::options().append(true).create(true).open(&path).unwrap();
+	let backend = MockDatastore::default();
+	let mut datastore = AuditingDatastore::new(backend.clone(), keys, Some(file));
+
+	ops(&mut datastore, &backend).await;
+
+	let trail = std::fs::read_to_string(&path).unwrap();
+	std::fs::remove_file(&path).unwrap();
+	trail.lines().map(|line| serde_json::from_str(line).unwrap()).collect()
+    }
+
+    #[tokio::test]
+    async fn records_each_operation() {
+	let records = trail_of(KeyMode::Plain, async |datastore, backend| {
+	    datastore.put_item(b"foo".to_vec(), b"secret".to_vec()).await.unwrap();
+	    datastore.get_item(b"foo").await.unwrap();
+	    backend.fail_next(1);
+	    datastore.delete_item(b"bar").await.unwrap_err();
+	    datastore.put_items(vec![(b"a".to_vec(), b"1".to_vec()), (b"b".to_vec(), b"2".to_vec())]).await.unwrap();
+	    datastore.count().await.unwrap();
+	}).await;
+
+	let summary: Vec<_> = records.iter().map(|record| (&record["op"], &record["keys"], &record["outcome"])).collect();
+	assert_eq!(summary, [
+	    (&json!("put_item"), &json!(["666f6f"]), &json!("ok")),
+	    (&json!("get_item"), &json!(["666f6f"]), &json!("ok")),
+	    (&json!("delete_item"), &json!(["626172"]), &json!("error")),
+	    (&json!("put_items"), &json!(["61", "62"]), &json!("ok")),
+	    (&json!("count"), &json!([]), &json!("ok")),
+	]);
+	for record in &records {
+	    assert_eq!(record["backend"], "mock");
+	    assert!(record["timestamp"].as_u64().unwrap() > 0);
+	    assert!(!record.to_string().contains(&to_hex(b"secret")));
+	}
+    }
+
+    #[tokio::test]
+    async fn hashes_keys_by_default() {
+	let records = trail_of(KeyMode::Hashed, async |datastore, _| {
+	    datastore.get_item(b"foo").await.unwrap();
+	}).await;
+
+	assert_eq!(records[0]["keys"], json!([to_hex(&Sha256::digest(b"foo"))]));
+	assert_ne!(records[0]["keys"], json!(["666f6f"]));
+    }
+}
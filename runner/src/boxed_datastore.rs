@@ -0,0 +1,221 @@
+//! A `Datastore` whose backend is only known at runtime.
+//!
+//! Every wrapper is generic over the datastore it wraps, so stacking them as
+//! static types compiles the runner once for every combination of backend and
+//! wrappers the environment could pick. Boxing each layer instead compiles
+//! every wrapper, and the runtime with its host functions, just once, over
+//! `BoxedDatastore`. That costs an allocation and a dynamic call per layer
+//! per operation, which is nothing next to a round trip to a backend.
+
+use std::future::Future;
+use std::pin::Pin;
+use std::time::Duration;
+
+use crate::{Datastore, DatastoreError, Entries, Values};
+
+type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = Result<T, DatastoreError>> + Send + 'a>>;
+
+/// `Datastore` with its futures boxed, so it can be a trait object.
+trait DynDatastore: Send {
+    fn name(&self) -> &'static str;
+    fn clone_box(&self) -> Box<dyn DynDatastore>;
+    fn put_item(&mut self, key: Vec<u8>, value: Vec<u8>) -> BoxFuture<'_, ()>;
+    fn put_item_with_ttl(&mut self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> BoxFuture<'_, ()>;
+    fn get_item<'a>(&'a mut self, key: &'a [u8]) -> BoxFuture<'a, Option<Vec<u8>>>;
+    fn batch_get_items<'a>(&'a mut self, keys: &'a [Vec<u8>]) -> BoxFuture<'a, Values>;
+    fn delete_item<'a>(&'a mut self, key: &'a [u8]) -> BoxFuture<'a, ()>;
+    fn exists<'a>(&'a mut self, key: &'a [u8]) -> BoxFuture<'a, bool>;
+    fn compare_and_swap<'a>(&'a mut self, key: Vec<u8>, expected: Option<&'a [u8]>, new: Vec<u8>) -> BoxFuture<'a, bool>;
+    fn append(&mut self, key: Vec<u8>, suffix: Vec<u8>) -> BoxFuture<'_, ()>;
+    fn delete_if_equals<'a>(&'a mut self, key: &'a [u8], expected: &'a [u8]) -> BoxFuture<'a, bool>;
+    fn swap(&mut self, key: Vec<u8>, new: Vec<u8>) -> BoxFuture<'_, Option<Vec<u8>>>;
+    fn increment(&mut self, key: Vec<u8>, delta: i64) -> BoxFuture<'_, Option<i64>>;
+    fn put_items(&mut self, pairs: Vec<(Vec<u8>, Vec<u8>)>) -> BoxFuture<'_, ()>;
+    fn scan_prefix<'a>(&'a mut self, prefix: &'a [u8]) -> BoxFuture<'a, Entries>;
+    fn count(&mut self) -> BoxFuture<'_, u64>;
+}
+
+impl<D: Datastore + Clone + 'static> DynDatastore for D {
+    fn name(&self) -> &'static str {
+	Datastore::name(self)
+    }
+
+    fn clone_box(&self) -> Box<dyn DynDatastore> {
+	Box::new(self.clone())
+    }
+
+    fn put_item(&mut self, key: Vec<u8>, value: Vec<u8>) -> BoxFuture<'_, ()> {
+	Box::pin(Datastore::put_item(self, key, value))
+    }
+
+    fn put_item_with_ttl(&mut self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> BoxFuture<'_, ()> {
+	Box::pin(Datastore::put_item_with_ttl(self, key, value, ttl))
+    }
+
+    fn get_item<'a>(&'a mut self, key: &'a [u8]) -> BoxFuture<'a, Option<Vec<u8>>> {
+	Box::pin(Datastore::get_item(self, key))
+    }
+
+    fn batch_get_items<'a>(&'a mut self, keys: &'a [Vec<u8>]) -> BoxFuture<'a, Values> {
+	Box::pin(Datastore::batch_get_items(self, keys))
+    }
+
+    fn delete_item<'a>(&'a mut self, key: &'a [u8]) -> BoxFuture<'a, ()> {
+	Box::pin(Datastore::delete_item(self, key))
+    }
+
+    fn exists<'a>(&'a mut self, key: &'a [u8]) -> BoxFuture<'a, bool> {
+	Box::pin(Datastore::exists(self, key))
+    }
+
+    fn compare_and_swap<'a>(&'a mut self, key: Vec<u8>, expected: Option<&'a [u8]>, new: Vec<u8>) -> BoxFuture<'a, bool> {
+	Box::pin(Datastore::compare_and_swap(self, key, expected, new))
+    }
+
+    fn append(&mut self, key: Vec<u8>, suffix: Vec<u8>) -> BoxFuture<'_, ()> {
+	Box::pin(Datastore::append(self, key, suffix))
+    }
+
+    fn delete_if_equals<'a>(&'a mut self, key: &'a [u8], expected: &'a [u8]) -> BoxFuture<'a, bool> {
+	Box::pin(Datastore::delete_if_equals(self, key, expected))
+    }
+
+    fn swap(&mut self, key: Vec<u8>, new: Vec<u8>) -> BoxFuture<'_, Option<Vec<u8>>> {
+	Box::pin(Datastore::swap(self, key, new))
+    }
+
+    fn increment(&mut self, key: Vec<u8>, delta: i64) -> BoxFuture<'_, Option<i64>> {
+	Box::pin(Datastore::increment(self, key, delta))
+    }
+
+    fn put_items(&mut self, pairs: Vec<(Vec<u8>, Vec<u8>)>) -> BoxFuture<'_, ()> {
+	Box::pin(Datastore::put_items(self, pairs))
+    }
+
+    fn scan_prefix<'a>(&'a mut self, prefix: &'a [u8]) -> BoxFuture<'a, Entries> {
+	Box::pin(Datastore::scan_prefix(self, prefix))
+    }
+
+    fn count(&mut self) -> BoxFuture<'_, u64> {
+	Box::pin(Datastore::count(self))
+    }
+}
+
+/// Any datastore, wrappers and all, behind one type. Clones clone the
+/// datastore inside, so they share whatever its clones share.
+pub struct BoxedDatastore(Box<dyn DynDatastore>);
+
+impl BoxedDatastore {
+    pub fn new<D: Datastore + Clone + 'static>(datastore: D) -> Self {
+	BoxedDatastore(Box::new(datastore))
+    }
+}
+
+impl Clone for BoxedDatastore {
+    fn clone(&self) -> Self {
+	BoxedDatastore(self.0.clone_box())
+    }
+}
+
+impl Datastore for BoxedDatastore {
+    const NAME: &'static str = "boxed";
+
+    fn name(&self) -> &'static str {
+	self.0.name()
+    }
+
+    async fn put_item(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<(), DatastoreError> {
+	self.0.put_item(key, value).await
+    }
+
+    async fn put_item_with_ttl(&mut self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<(), DatastoreError> {
+	self.0.put_item_with_ttl(key, value, ttl).await
+    }
+
+    async fn get_item(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>, DatastoreError> {
+	self.0.get_item(key).await
+    }
+
+    async fn batch_get_items(&mut self, keys: &[Vec<u8>]) -> Result<Values, DatastoreError> {
+	self.0.batch_get_items(keys).await
+    }
+
+    async fn delete_item(&mut self, key: &[u8]) -> Result<(), DatastoreError> {
+	self.0.delete_item(key).await
+    }
+
+    async fn exists(&mut self, key: &[u8]) -> Result<bool, DatastoreError> {
+	self.0.exists(key).await
+    }
+
+    async fn compare_and_swap(&mut self, key: Vec<u8>, expected: Option<&[u8]>, new: Vec<u8>) -> Result<bool, DatastoreError> {
+	self.0.compare_and_swap(key, expected, new).await
+    }
+
+    async fn append(&mut self, key: Vec<u8>, suffix: Vec<u8>) -> Result<(), DatastoreError> {
+	self.0.append(key, suffix).await
+    }
+
+    async fn delete_if_equals(&mut self, key: &[u8], expected: &[u8]) -> Result<bool, DatastoreError> {
+	self.0.delete_if_equals(key, expected).await
+    }
+
+    async fn swap(&mut self, key: Vec<u8>, new: Vec<u8>) -> Result<Option<Vec<u8>>, DatastoreError> {
+	self.0.swap(key, new).await
+    }
+
+    async fn increment(&mut self, key: Vec<u8>, delta: i64) -> Result<Option<i64>, DatastoreError> {
+	self.0.increment(key, delta).await
+    }
+
+    async fn put_items(&mut self, pairs: Vec<(Vec<u8>, Vec<u8>)>) -> Result<(), DatastoreError> {
+	self.0.put_items(pairs).await
+    }
+
+    async fn scan_prefix(&mut self, prefix: &[u8]) -> Result<Entries, DatastoreError> {
+	self.0.scan_prefix(prefix).await
+    }
+
+    async fn count(&mut self) -> Result<u64, DatastoreError> {
+	self.0.count().await
+    }
+}
+
+#[cfg(all(test, feature = "test-util"))]
+mod tests {
+    // Not `super::*`, whose `DynDatastore` would shadow `Datastore`'s methods.
+    use super::BoxedDatastore;
+    use crate::file_datastore::FileDatastore;
+    use crate::test_runner::TestRunner;
+    use crate::{Datastore, MemoryDatastore};
+
+    #[tokio::test]
+    async fn guest_runs_against_memory() {
+	let mut memory = MemoryDatastore::default();
+	memory.items.insert(b"foo".to_vec(), b"bar".to_vec());
+	let runner = TestRunner::new(BoxedDatastore::new(memory)).unwrap();
+
+	let response = runner.call(b"hello").await.unwrap();
+
+	assert_eq!(response.status, 200);
+	assert_eq!(response.body, b"bar");
+	assert_eq!(runner.runtime().datastore.name(), "memory");
+    }
+
+    #[tokio::test]
+    async fn guest_runs_against_files() {
+	let root = std::env::temp_dir().join(format!("wasmtest-boxed-{:016x}", rand::random::<u64>()));
+	tokio::fs::create_dir_all(&root).await.unwrap();
+	let mut file = FileDatastore::new(root.clone());
+	file.put_item(b"foo".to_vec(), b"bar".to_vec()).await.unwrap();
+	let runner = TestRunner::new(BoxedDatastore::new(file.clone())).unwrap();
+
+	let response = runner.call(b"hello").await.unwrap();
+
+	assert_eq!(response.status, 200);
+	assert_eq!(response.body, b"bar");
+	assert_eq!(file.get_item(b"hello").await.unwrap().as_deref(), Some(&b"world"[..]));
+	assert_eq!(file.get_item(b"world").await.unwrap().as_deref(), Some(&b"bar"[..]));
+	tokio::fs::remove_dir_all(&root).await.unwrap();
+    }
+}
@@ -0,0 +1,191 @@
+//! A `Datastore` that layers a fast cache over a slower backend.
+//!
+//! Reads try the cache first and fall back to the backend, copying what they
+//! find into the cache. Writes go to the backend and then the cache, and fail
+//! if either does, so once a write through this store returns, later reads
+//! through any store sharing the cache see it. Filling the cache after a
+//! miss is best-effort, though: a read racing a write can put the old value
+//! back, and writes that bypass the cache aren't seen until the cached copy
+//! expires. Every cached entry expires after `ttl`, which bounds how stale a
+//! read can be. A cache that fails a read is passed over for the backend,
+//! since the backend has the value anyway.
+
+use std::time::Duration;
+
+use lambda_http::tracing;
+
+use crate::{Datastore, DatastoreError, Entries, Values};
+
+/// How long cached entries live if `WASMTEST_CACHE_TTL_MS` doesn't say.
+pub const DEFAULT_TTL_MS: u64 = 60_000;
+
+/// Caches `backend`'s entries in `cache`.
+#[derive(Clone, Debug)]
+pub struct CachingDatastore<C, B> {
+    cache: C,
+    backend: B,
+    ttl: Duration,
+}
+
+impl<C: Datastore, B: Datastore> CachingDatastore<C, B> {
+    pub fn new(cache: C, backend: B, ttl: Duration) -> Self {
+	CachingDatastore { cache, backend, ttl }
+    }
+
+    /// Caches `value` for `key` for at most `ttl`.
+    async fn cache(&mut self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<(), DatastoreError> {
+	self.cache.put_item_with_ttl(key, value, ttl.min(self.ttl)).await
+    }
+}
+
+impl<C: Datastore, B: Datastore> Datastore for CachingDatastore<C, B> {
+    const NAME: &'static str = B::NAME;
+
+    fn name(&self) -> &'static str {
+	self.backend.name()
+    }
+
+    async fn put_item(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<(), DatastoreError> {
+	self.backend.put_item(key.clone(), value.clone()).await?;
+	self.cache(key, value, self.ttl).await
+    }
+
+    async fn put_item_with_ttl(&mut self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<(), DatastoreError> {
+	self.backend.put_item_with_ttl(key.clone(), value.clone(), ttl).await?;
+	self.cache(key, value, ttl).await
+    }
+
+    async fn get_item(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>, DatastoreError> {
+	match self.cache.get_item(key).await {
+	    Ok(Some(value)) => return Ok(Some(value)),
+	    Ok(None) => {}
+	    Err(e) => tracing::warn!(cache = self.cache.name(), error = %e, "cache read failed; reading the backend"),
+	}
+	let value = self.backend.get_item(key).await?;
+	if let Some(value) = &value {
+	    // The read already succeeded, so a cache that can't take the
+	    // value shouldn't fail it.
+	    let _ = self.cache(key.to_vec(), value.clone(), self.ttl).await;
+	}
+	Ok(value)
+    }
+
+    async fn batch_get_items(&mut self, keys: &[Vec<u8>]) -> Result<Values, DatastoreError> {
+	let mut values = match self.cache.batch_get_items(keys).await {
+	    Ok(values) => values,
+	    Err(e) => {
+		tracing::warn!(cache = self.cache.name(), error = %e, "cache read failed; reading the backend");
+		vec![None; keys.len()]
+	    }
+	};
+	let (misses, positions): (Vec<_>, Vec<_>) = keys.iter().zip(&values).enumerate()
+	    .filter(|(_, (_, value))| value.is_none())
+	    .map(|(i, (key, _))| (key.clone(), i))
+	    .unzip();
+	if misses.is_empty() {
+	    return Ok(values);
+	}
+	let found = self.backend.batch_get_items(&misses).await?;
+	for ((key, i), value) in misses.into_iter().zip(positions).zip(found) {
+	    if let Some(value) = &value {
+		let _ = self.cache(key, value.clone(), self.ttl).await;
+	    }
+	    values[i] = value;
+	}
+	Ok(values)
+    }
+
+    async fn delete_item(&mut self, key: &[u8]) -> Result<(), DatastoreError> {
+	self.backend.delete_item(key).await?;
+	self.cache.delete_item(key).await
+    }
+
+    async fn compare_and_swap(&mut self, key: Vec<u8>, expected: Option<&[u8]>, new: Vec<u8>) -> Result<bool, DatastoreError> {
+	// Only the backend can say whether the swap should happen. If it
+	// didn't, the cached value may be what was out of date, so drop it.
+	if self.backend.compare_and_swap(key.clone(), expected, new.clone()).await? {
+	    self.cache(key, new, self.ttl).await?;
+	    Ok(true)
+	} else {
+	    self.cache.delete_item(&key).await?;
+	    Ok(false)
+	}
+    }
+
+    async fn append(&mut self, key: Vec<u8>, suffix: Vec<u8>) -> Result<(), DatastoreError> {
+	// Only the backend has the whole value to append to, so drop the
+	// cached copy rather than work out the new one.
+	self.backend.append(key.clone(), suffix).await?;
+	self.cache.delete_item(&key).await
+    }
+
+    async fn delete_if_equals(&mut self, key: &[u8], expected: &[u8]) -> Result<bool, DatastoreError> {
+	// Whether or not the backend deleted it, the cached copy is either
+	// gone or possibly stale, so drop it.
+	let deleted = self.backend.delete_if_equals(key, expected).await?;
+	self.cache.delete_item(key).await?;
+	Ok(deleted)
+    }
+
+    async fn swap(&mut self, key: Vec<u8>, new: Vec<u8>) -> Result<Option<Vec<u8>>, DatastoreError> {
+	// The old value has to come from the backend; the cache's may be stale.
+	let old = self.backend.swap(key.clone(), new.clone()).await?;
+	self.cache(key, new, self.ttl).await?;
+	Ok(old)
+    }
+
+    async fn increment(&mut self, key: Vec<u8>, delta: i64) -> Result<Option<i64>, DatastoreError> {
+	// The cache may still hold a TTL'd counter's value after the backend
+	// has expired it, so drop the cached copy rather than overwrite it
+	// with one that would outlive the backend's.
+	let total = self.backend.increment(key.clone(), delta).await?;
+	self.cache.delete_item(&key).await?;
+	Ok(total)
+    }
+
+    async fn put_items(&mut self, pairs: Vec<(Vec<u8>, Vec<u8>)>) -> Result<(), DatastoreError> {
+	self.backend.put_items(pairs.clone()).await?;
+	for (key, value) in pairs {
+	    self.cache(key, value, self.ttl).await?;
+	}
+	Ok(())
+    }
+
+    async fn scan_prefix(&mut self, prefix: &[u8]) -> Result<Entries, DatastoreError> {
+	// The cache can't know whether it holds every key with the prefix, so
+	// scans always go to the backend.
+	self.backend.scan_prefix(prefix).await
+    }
+
+    async fn count(&mut self) -> Result<u64, DatastoreError> {
+	self.backend.count().await
+    }
+}
+
+#[cfg(all(test, feature = "test-util"))]
+mod tests {
+    use super::*;
+    use crate::mock_datastore::MockDatastore;
+    use crate::MemoryDatastore;
+
+    #[tokio::test]
+    async fn reads_pass_over_a_failing_cache() {
+	let cache = MockDatastore::default();
+	let mut backend = MemoryDatastore::default();
+	backend.put_item(b"foo".to_vec(), b"bar".to_vec()).await.unwrap();
+	let mut datastore = CachingDatastore::new(cache.clone(), backend, Duration::from_secs(60));
+
+	cache.fail_next(1);
+	assert_eq!(datastore.get_item(b"foo").await.unwrap().as_deref(), Some(&b"bar"[..]));
+	cache.fail_next(1);
+	let values = datastore.batch_get_items(&[b"foo".to_vec(), b"missing".to_vec()]).await.unwrap();
+	assert_eq!(values, [Some(b"bar".to_vec()), None]);
+	assert_eq!(cache.item(b"foo").await.as_deref(), Some(&b"bar"[..]));
+    }
+
+    #[tokio::test]
+    async fn is_named_for_its_backend() {
+	let datastore = CachingDatastore::new(MockDatastore::default(), MemoryDatastore::default(), Duration::from_secs(60));
+	assert_eq!(datastore.name(), "memory");
+    }
+}
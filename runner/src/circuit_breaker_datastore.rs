@@ -0,0 +1,250 @@
+//! A `Datastore` that stops calling a backend that keeps failing, so a
+//! struggling backend isn't kept down by every request piling onto it.
+//!
+//! The circuit starts closed, passing calls through. Once `threshold` calls
+//! in a row have failed it opens, and for the next `cooldown` every call
+//! fails straight away with a backend error. After that it's half-open: one
+//! call goes through as a probe while the rest keep failing fast. The circuit
+//! closes again if the probe succeeds and opens for another `cooldown` if it
+//! fails. A probe that never finishes, because the request making it was cut
+//! off, is given up on after a `cooldown` and another one let through.
+//!
+//! The state is shared by clones, so it covers every request a container
+//! serves, and is reported to the local server's `/metrics` page as the
+//! `wasmtest_datastore_circuit_state` gauge: 0 closed, 1 half-open, 2 open.
+
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use lambda_http::{tracing, Error};
+use tokio::time::Instant;
+
+use crate::{env_or, Datastore, DatastoreError, Entries, Values};
+
+/// How many calls in a row have to fail to open the circuit if
+/// `WASMTEST_CIRCUIT_FAILURES` doesn't say.
+const DEFAULT_THRESHOLD: u32 = 5;
+
+/// How long the circuit stays open if `WASMTEST_CIRCUIT_COOLDOWN_MS` doesn't
+/// say.
+const DEFAULT_COOLDOWN_MS: u64 = 30_000;
+
+/// Fails calls fast while `backend` is failing.
+#[derive(Clone, Debug)]
+pub struct CircuitBreakerDatastore<B> {
+    backend: B,
+    state: Arc<Mutex<State>>,
+    /// How many calls in a row have to fail to open the circuit.
+    threshold: u32,
+    cooldown: Duration,
+}
+
+#[derive(Debug)]
+enum State {
+    Closed { failures: u32 },
+    Open { until: Instant },
+    /// A probe is in flight, and is given up on at `until`.
+    HalfOpen { until: Instant },
+}
+
+impl State {
+    /// The value of the `wasmtest_datastore_circuit_state` gauge.
+    fn gauge(&self) -> f64 {
+	match self {
+	    State::Closed { .. } => 0.0,
+	    State::HalfOpen { .. } => 1.0,
+	    State::Open { .. } => 2.0,
+	}
+    }
+}
+
+impl<B: Datastore> CircuitBreakerDatastore<B> {
+    pub fn new(backend: B, threshold: u32, cooldown: Duration) -> Self {
+	let state = Arc::new(Mutex::new(State::Closed { failures: 0 }));
+	CircuitBreakerDatastore { backend, state, threshold: threshold.max(1), cooldown }
+    }
+
+    /// Opens after `WASMTEST_CIRCUIT_FAILURES` failures in a row, for
+    /// `WASMTEST_CIRCUIT_COOLDOWN_MS` milliseconds.
+    pub fn from_env(backend: B) -> Result<Self, Error> {
+	let threshold = env_or("WASMTEST_CIRCUIT_FAILURES", DEFAULT_THRESHOLD)?;
+	let cooldown = Duration::from_millis(env_or("WASMTEST_CIRCUIT_COOLDOWN_MS", DEFAULT_COOLDOWN_MS)?);
+	Ok(Self::new(backend, threshold, cooldown))
+    }
+
+    /// Whether a call may go to the backend now. The lock is never held
+    /// across an await, so this can't deadlock.
+    fn admit(&self) -> Result<(), DatastoreError> {
+	let now = Instant::now();
+	let mut state = self.state.lock().unwrap();
+	match *state {
+	    State::Closed { .. } => return Ok(()),
+	    State::Open { until } | State::HalfOpen { until } if now < until => {
+		return Err(DatastoreError::Backend(format!("{} is failing; not calling it for now", self.backend.name())));
+	    }
+	    _ => {}
+	}
+	tracing::info!(backend = self.backend.name(), "probing datastore");
+	self.set(&mut state, State::HalfOpen { until: now + self.cooldown });
+	Ok(())
+    }
+
+    /// Records how an admitted call went.
+    fn record(&self, ok: bool) {
+	let mut state = self.state.lock().unwrap();
+	let next = match (&*state, ok) {
+	    (State::Closed { .. }, true) => State::Closed { failures: 0 },
+	    (State::HalfOpen { .. }, true) => {
+		tracing::info!(backend = self.backend.name(), "datastore recovered; closing circuit");
+		State::Closed { failures: 0 }
+	    }
+	    (State::Closed { failures }, false) if failures + 1 < self.threshold => State::Closed { failures: failures + 1 },
+	    (State::Closed { .. } | State::HalfOpen { .. }, false) => {
+		tracing::warn!(backend = self.backend.name(), cooldown = ?self.cooldown, "datastore keeps failing; opening circuit");
+		State::Open { until: Instant::now() + self.cooldown }
+	    }
+	    // A call admitted before the circuit opened doesn't change it.
+	    (State::Open { .. }, _) => return,
+	};
+	self.set(&mut state, next);
+    }
+
+    fn set(&self, state: &mut State, next: State) {
+	::metrics::gauge!("wasmtest_datastore_circuit_state", "backend" => self.backend.name()).set(next.gauge());
+	*state = next;
+    }
+}
+
+/// Awaits `$call` if the circuit lets it through, and records how it went.
+macro_rules! guarded {
+    ($self:ident, $call:expr) => {{
+	$self.admit()?;
+	let result = $call.await;
+	$self.record(result.is_ok());
+	result
+    }};
+}
+
+impl<B: Datastore> Datastore for CircuitBreakerDatastore<B> {
+    const NAME: &'static str = B::NAME;
+
+    fn name(&self) -> &'static str {
+	self.backend.name()
+    }
+
+    async fn put_item(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<(), DatastoreError> {
+	guarded!(self, self.backend.put_item(key, value))
+    }
+
+    async fn put_item_with_ttl(&mut self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<(), DatastoreError> {
+	guarded!(self, self.backend.put_item_with_ttl(key, value, ttl))
+    }
+
+    async fn get_item(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>, DatastoreError> {
+	guarded!(self, self.backend.get_item(key))
+    }
+
+    async fn batch_get_items(&mut self, keys: &[Vec<u8>]) -> Result<Values, DatastoreError> {
+	guarded!(self, self.backend.batch_get_items(keys))
+    }
+
+    async fn delete_item(&mut self, key: &[u8]) -> Result<(), DatastoreError> {
+	guarded!(self, self.backend.delete_item(key))
+    }
+
+    async fn exists(&mut self, key: &[u8]) -> Result<bool, DatastoreError> {
+	guarded!(self, self.backend.exists(key))
+    }
+
+    async fn compare_and_swap(&mut self, key: Vec<u8>, expected: Option<&[u8]>, new: Vec<u8>) -> Result<bool, DatastoreError> {
+	guarded!(self, self.backend.compare_and_swap(key, expected, new))
+    }
+
+    async fn append(&mut self, key: Vec<u8>, suffix: Vec<u8>) -> Result<(), DatastoreError> {
+	guarded!(self, self.backend.append(key, suffix))
+    }
+
+    async fn delete_if_equals(&mut self, key: &[u8], expected: &[u8]) -> Result<bool, DatastoreError> {
+	guarded!(self, self.backend.delete_if_equals(key, expected))
+    }
+
+    async fn swap(&mut self, key: Vec<u8>, new: Vec<u8>) -> Result<Option<Vec<u8>>, DatastoreError> {
+	guarded!(self, self.backend.swap(key, new))
+    }
+
+    async fn increment(&mut self, key: Vec<u8>, delta: i64) -> Result<Option<i64>, DatastoreError> {
+	guarded!(self, self.backend.increment(key, delta))
+    }
+
+    async fn put_items(&mut self, pairs: Vec<(Vec<u8>, Vec<u8>)>) -> Result<(), DatastoreError> {
+	guarded!(self, self.backend.put_items(pairs))
+    }
+
+    async fn scan_prefix(&mut self, prefix: &[u8]) -> Result<Entries, DatastoreError> {
+	guarded!(self, self.backend.scan_prefix(prefix))
+    }
+
+    async fn count(&mut self) -> Result<u64, DatastoreError> {
+	guarded!(self, self.backend.count())
+    }
+}
+
+#[cfg(all(test, feature = "test-util"))]
+mod tests {
+    use super::*;
+    use crate::mock_datastore::MockDatastore;
+
+    const COOLDOWN: Duration = Duration::from_secs(30);
+
+    #[tokio::test(start_paused = true)]
+    async fn trips_fails_fast_probes_and_resets() {
+	let backend = MockDatastore::default();
+	let mut datastore = CircuitBreakerDatastore::new(backend.clone(), 2, COOLDOWN);
+	let shared = datastore.state.clone();
+	let state = || shared.lock().unwrap().gauge();
+
+	backend.fail_next(2);
+	assert!(matches!(datastore.get_item(b"foo").await, Err(DatastoreError::Throttled)));
+	assert_eq!(state(), 0.0);
+	assert!(matches!(datastore.get_item(b"foo").await, Err(DatastoreError::Throttled)));
+	assert_eq!(state(), 2.0);
+
+	// Open: fails without calling the backend.
+	backend.clear_ops();
+	assert!(matches!(datastore.get_item(b"foo").await, Err(DatastoreError::Backend(_))));
+	tokio::time::advance(COOLDOWN - Duration::from_millis(1)).await;
+	assert!(matches!(datastore.get_item(b"foo").await, Err(DatastoreError::Backend(_))));
+	assert!(backend.ops().is_empty());
+
+	// A failed probe opens it for another cooldown.
+	tokio::time::advance(Duration::from_millis(1)).await;
+	backend.fail_next(1);
+	assert!(matches!(datastore.get_item(b"foo").await, Err(DatastoreError::Throttled)));
+	assert_eq!(state(), 2.0);
+	assert!(matches!(datastore.get_item(b"foo").await, Err(DatastoreError::Backend(_))));
+	assert_eq!(backend.ops().len(), 1);
+
+	// A successful one closes it.
+	tokio::time::advance(COOLDOWN).await;
+	assert_eq!(datastore.get_item(b"foo").await.unwrap(), None);
+	assert_eq!(state(), 0.0);
+	datastore.get_item(b"foo").await.unwrap();
+	assert_eq!(backend.ops().len(), 3);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn lets_one_probe_through_at_a_time() {
+	let backend = MockDatastore::default();
+	let datastore = CircuitBreakerDatastore::new(backend, 1, COOLDOWN);
+	datastore.record(false);
+	tokio::time::advance(COOLDOWN).await;
+
+	assert!(datastore.admit().is_ok());
+	assert_eq!(datastore.state.lock().unwrap().gauge(), 1.0);
+	assert!(datastore.admit().is_err());
+
+	// Until the probe is given up on.
+	tokio::time::advance(COOLDOWN).await;
+	assert!(datastore.admit().is_ok());
+    }
+}
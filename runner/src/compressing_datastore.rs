@@ -0,0 +1,214 @@
+//! A `Datastore` that compresses values before they reach the backend.
+//!
+//! Values of at least `min_len` bytes are stored as `MAGIC` followed by a
+//! zstd frame, if that's smaller than the value itself; everything else is
+//! stored as-is. Reads decompress anything that starts with `MAGIC` and holds
+//! a valid frame and return everything else untouched, so values written
+//! before compression was turned on still read back. The one value that
+//! can't be stored as-is is one that itself starts with `MAGIC`, which is
+//! always compressed so it can't be mistaken for a frame.
+//!
+//! Counters are left alone: `increment` goes straight to the backend, which
+//! only deals in 8-byte values that are never worth compressing.
+
+use std::time::Duration;
+
+use lambda_http::Error;
+
+use crate::{env_or, Datastore, DatastoreError, Entries, Values};
+
+/// Marks a compressed value. `0xff` never appears in UTF-8 text, so no JSON
+/// or other text value starts with it.
+const MAGIC: &[u8] = b"\xffwtz";
+
+/// The smallest value to try compressing if `WASMTEST_COMPRESS_MIN_BYTES`
+/// doesn't say. Shorter values rarely shrink by more than the header costs.
+const DEFAULT_MIN_LEN: usize = 256;
+
+/// The zstd level values are compressed at, which favors speed.
+const LEVEL: i32 = 3;
+
+/// Compresses the values `backend` stores.
+#[derive(Clone, Debug)]
+pub struct CompressingDatastore<B> {
+    backend: B,
+    /// Values shorter than this are stored uncompressed.
+    min_len: usize,
+}
+
+impl<B: Datastore> CompressingDatastore<B> {
+    pub fn new(backend: B, min_len: usize) -> Self {
+	CompressingDatastore { backend, min_len }
+    }
+
+    /// Compresses values of at least `WASMTEST_COMPRESS_MIN_BYTES` bytes.
+    pub fn from_env(backend: B) -> Result<Self, Error> {
+	Ok(Self::new(backend, env_or("WASMTEST_COMPRESS_MIN_BYTES", DEFAULT_MIN_LEN)?))
+    }
+
+    /// The form `value` is stored in.
+    fn pack(&self, value: Vec<u8>) -> Result<Vec<u8>, DatastoreError> {
+	let ambiguous = value.starts_with(MAGIC);
+	if value.len() < self.min_len && !ambiguous {
+	    return Ok(value);
+	}
+	let frame = zstd::bulk::compress(&value, LEVEL)
+	    .map_err(|e| DatastoreError::Backend(format!("couldn't compress value: {}", e)))?;
+	if MAGIC.len() + frame.len() >= value.len() && !ambiguous {
+	    return Ok(value);
+	}
+	Ok([MAGIC, &frame].concat())
+    }
+}
+
+/// The value `stored` holds.
+fn unpack(stored: Vec<u8>) -> Vec<u8> {
+    match stored.strip_prefix(MAGIC).map(zstd::decode_all) {
+	Some(Ok(value)) => value,
+	_ => stored,
+    }
+}
+
+impl<B: Datastore> Datastore for CompressingDatastore<B> {
+    const NAME: &'static str = B::NAME;
+
+    fn name(&self) -> &'static str {
+	self.backend.name()
+    }
+
+    async fn put_item(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<(), DatastoreError> {
+	let packed = self.pack(value)?;
+	self.backend.put_item(key, packed).await
+    }
+
+    async fn put_item_with_ttl(&mut self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<(), DatastoreError> {
+	let packed = self.pack(value)?;
+	self.backend.put_item_with_ttl(key, packed, ttl).await
+    }
+
+    async fn get_item(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>, DatastoreError> {
+	Ok(self.backend.get_item(key).await?.map(unpack))
+    }
+
+    async fn batch_get_items(&mut self, keys: &[Vec<u8>]) -> Result<Values, DatastoreError> {
+	let values = self.backend.batch_get_items(keys).await?;
+	Ok(values.into_iter().map(|value| value.map(unpack)).collect())
+    }
+
+    async fn delete_item(&mut self, key: &[u8]) -> Result<(), DatastoreError> {
+	self.backend.delete_item(key).await
+    }
+
+    async fn exists(&mut self, key: &[u8]) -> Result<bool, DatastoreError> {
+	self.backend.exists(key).await
+    }
+
+    async fn compare_and_swap(&mut self, key: Vec<u8>, expected: Option<&[u8]>, new: Vec<u8>) -> Result<bool, DatastoreError> {
+	// The same value can be stored compressed or not, so compare what the
+	// stored bytes hold and have the backend swap on those exact bytes.
+	let current = self.backend.get_item(&key).await?;
+	if current.clone().map(unpack).as_deref() != expected {
+	    return Ok(false);
+	}
+	let packed = self.pack(new)?;
+	self.backend.compare_and_swap(key, current.as_deref(), packed).await
+    }
+
+    async fn delete_if_equals(&mut self, key: &[u8], expected: &[u8]) -> Result<bool, DatastoreError> {
+	// As with `compare_and_swap`, compare what's stored and have the
+	// backend delete on those exact bytes.
+	let Some(current) = self.backend.get_item(key).await? else {
+	    return Ok(false);
+	};
+	if unpack(current.clone()) != expected {
+	    return Ok(false);
+	}
+	self.backend.delete_if_equals(key, &current).await
+    }
+
+    async fn swap(&mut self, key: Vec<u8>, new: Vec<u8>) -> Result<Option<Vec<u8>>, DatastoreError> {
+	let packed = self.pack(new)?;
+	Ok(self.backend.swap(key, packed).await?.map(unpack))
+    }
+
+    async fn increment(&mut self, key: Vec<u8>, delta: i64) -> Result<Option<i64>, DatastoreError> {
+	self.backend.increment(key, delta).await
+    }
+
+    async fn put_items(&mut self, pairs: Vec<(Vec<u8>, Vec<u8>)>) -> Result<(), DatastoreError> {
+	let pairs = pairs.into_iter()
+	    .map(|(key, value)| Ok((key, self.pack(value)?)))
+	    .collect::<Result<_, DatastoreError>>()?;
+	self.backend.put_items(pairs).await
+    }
+
+    async fn scan_prefix(&mut self, prefix: &[u8]) -> Result<Entries, DatastoreError> {
+	let entries = self.backend.scan_prefix(prefix).await?;
+	Ok(entries.into_iter().map(|(key, value)| (key, unpack(value))).collect())
+    }
+
+    async fn count(&mut self) -> Result<u64, DatastoreError> {
+	self.backend.count().await
+    }
+}
+
+#[cfg(all(test, feature = "test-util"))]
+mod tests {
+    use super::*;
+    use crate::mock_datastore::MockDatastore;
+
+    fn compressing(backend: &MockDatastore) -> CompressingDatastore<MockDatastore> {
+	CompressingDatastore::new(backend.clone(), DEFAULT_MIN_LEN)
+    }
+
+    #[tokio::test]
+    async fn compresses_a_compressible_value() {
+	let backend = MockDatastore::default();
+	let mut datastore = compressing(&backend);
+	let value = b"all work and no play ".repeat(100);
+
+	datastore.put_item(b"key".to_vec(), value.clone()).await.unwrap();
+
+	let stored = backend.item(b"key").await.unwrap();
+	assert!(stored.starts_with(MAGIC));
+	assert!(stored.len() < value.len());
+	assert_eq!(datastore.get_item(b"key").await.unwrap(), Some(value));
+    }
+
+    #[tokio::test]
+    async fn stores_an_incompressible_value_as_is() {
+	let backend = MockDatastore::default();
+	let mut datastore = compressing(&backend);
+	let value: Vec<u8> = (0..4096).map(|_| rand::random()).collect();
+
+	datastore.put_item(b"key".to_vec(), value.clone()).await.unwrap();
+
+	assert_eq!(backend.item(b"key").await, Some(value.clone()));
+	assert_eq!(datastore.get_item(b"key").await.unwrap(), Some(value));
+    }
+
+    #[tokio::test]
+    async fn stores_a_short_value_as_is() {
+	let backend = MockDatastore::default();
+	let mut datastore = compressing(&backend);
+
+	datastore.put_item(b"key".to_vec(), b"short".to_vec()).await.unwrap();
+
+	assert_eq!(backend.item(b"key").await.as_deref(), Some(&b"short"[..]));
+	assert_eq!(datastore.get_item(b"key").await.unwrap(), Some(b"short".to_vec()));
+    }
+
+    #[tokio::test]
+    async fn compresses_a_value_that_looks_compressed() {
+	let backend = MockDatastore::default();
+	let mut datastore = compressing(&backend);
+	let value = [MAGIC, b"not a frame"].concat();
+
+	datastore.put_item(b"key".to_vec(), value.clone()).await.unwrap();
+
+	let stored = backend.item(b"key").await.unwrap();
+	assert!(stored.starts_with(MAGIC));
+	assert_ne!(stored, value);
+	assert_eq!(datastore.get_item(b"key").await.unwrap(), Some(value));
+    }
+}
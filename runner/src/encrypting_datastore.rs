@@ -0,0 +1,192 @@
+//! A `Datastore` that encrypts values before they reach the backend.
+//!
+//! Values are sealed with AES-256-GCM: each stored value is a fresh random
+//! 12-byte nonce followed by the ciphertext, with the key as associated data
+//! so a value copied to another key won't decrypt there. Keys themselves are
+//! stored as-is, so lookups and `scan_prefix` work as before. A value that
+//! doesn't decrypt, because it was tampered with, written under another
+//! encryption key or written before encryption was turned on, fails the read
+//! with a backend error.
+//!
+//! Because every write picks a new nonce, the backend can't compare or add
+//! to values itself: `compare_and_swap` and `delete_if_equals` compare
+//! decrypted values and then act on the exact bytes they read, and
+//! `increment` is a loop of `compare_and_swap`s. Counters incremented
+//! through this store don't keep their TTLs.
+
+use std::time::Duration;
+
+use aes_gcm::aead::{Aead, KeyInit, Payload};
+use aes_gcm::{Aes256Gcm, Nonce};
+use base64::{prelude::BASE64_STANDARD, Engine as _};
+use lambda_http::Error;
+
+use crate::{env_opt, Datastore, DatastoreError, Entries, Values};
+
+/// How many bytes of nonce come before each ciphertext.
+const NONCE_LEN: usize = 12;
+
+/// How many times `increment` retries when another writer keeps beating it
+/// to the counter.
+const MAX_INCREMENT_ATTEMPTS: usize = 10;
+
+/// Encrypts the values `backend` stores.
+#[derive(Clone)]
+pub struct EncryptingDatastore<B> {
+    backend: B,
+    cipher: Aes256Gcm,
+}
+
+impl<B: Datastore> EncryptingDatastore<B> {
+    pub fn new(backend: B, cipher: Aes256Gcm) -> Self {
+	EncryptingDatastore { backend, cipher }
+    }
+
+    fn seal(&self, key: &[u8], value: &[u8]) -> Result<Vec<u8>, DatastoreError> {
+	let nonce: [u8; NONCE_LEN] = rand::random();
+	let ciphertext = self.cipher.encrypt(Nonce::from_slice(&nonce), Payload { msg: value, aad: key })
+	    .map_err(|_| DatastoreError::Backend("couldn't encrypt value".into()))?;
+	Ok([&nonce[..], &ciphertext].concat())
+    }
+
+    fn open(&self, key: &[u8], sealed: &[u8]) -> Result<Vec<u8>, DatastoreError> {
+	let failed = || DatastoreError::Backend(format!("couldn't decrypt the value of a {}-byte key", key.len()));
+	if sealed.len() < NONCE_LEN {
+	    return Err(failed());
+	}
+	let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+	self.cipher.decrypt(Nonce::from_slice(nonce), Payload { msg: ciphertext, aad: key })
+	    .map_err(|_| failed())
+    }
+}
+
+/// Reads the 32-byte encryption key from `WASMTEST_ENCRYPTION_KEY`, in
+/// base64, or returns `None` if it isn't set.
+pub fn cipher_from_env() -> Result<Option<Aes256Gcm>, Error> {
+    let Some(key) = env_opt::<String>("WASMTEST_ENCRYPTION_KEY")? else {
+	return Ok(None);
+    };
+    let key = BASE64_STANDARD.decode(key.trim())
+	.map_err(|e| format!("WASMTEST_ENCRYPTION_KEY isn't valid base64: {}", e))?;
+    let cipher = Aes256Gcm::new_from_slice(&key)
+	.map_err(|_| format!("WASMTEST_ENCRYPTION_KEY must be 32 bytes, not {}", key.len()))?;
+    Ok(Some(cipher))
+}
+
+impl<B: Datastore> Datastore for EncryptingDatastore<B> {
+    const NAME: &'static str = B::NAME;
+
+    fn name(&self) -> &'static str {
+	self.backend.name()
+    }
+
+    async fn put_item(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<(), DatastoreError> {
+	let sealed = self.seal(&key, &value)?;
+	self.backend.put_item(key, sealed).await
+    }
+
+    async fn put_item_with_ttl(&mut self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<(), DatastoreError> {
+	let sealed = self.seal(&key, &value)?;
+	self.backend.put_item_with_ttl(key, sealed, ttl).await
+    }
+
+    async fn get_item(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>, DatastoreError> {
+	match self.backend.get_item(key).await? {
+	    Some(sealed) => Ok(Some(self.open(key, &sealed)?)),
+	    None => Ok(None),
+	}
+    }
+
+    async fn batch_get_items(&mut self, keys: &[Vec<u8>]) -> Result<Values, DatastoreError> {
+	let values = self.backend.batch_get_items(keys).await?;
+	keys.iter().zip(values)
+	    .map(|(key, sealed)| sealed.map(|sealed| self.open(key, &sealed)).transpose())
+	    .collect()
+    }
+
+    async fn delete_item(&mut self, key: &[u8]) -> Result<(), DatastoreError> {
+	self.backend.delete_item(key).await
+    }
+
+    async fn exists(&mut self, key: &[u8]) -> Result<bool, DatastoreError> {
+	self.backend.exists(key).await
+    }
+
+    async fn compare_and_swap(&mut self, key: Vec<u8>, expected: Option<&[u8]>, new: Vec<u8>) -> Result<bool, DatastoreError> {
+	// The backend only sees ciphertexts, so check the plaintext here and
+	// have the backend swap only if the ciphertext is still the one we
+	// checked.
+	let current = self.backend.get_item(&key).await?;
+	let plaintext = current.as_deref().map(|sealed| self.open(&key, sealed)).transpose()?;
+	if plaintext.as_deref() != expected {
+	    return Ok(false);
+	}
+	let sealed = self.seal(&key, &new)?;
+	self.backend.compare_and_swap(key, current.as_deref(), sealed).await
+    }
+
+    async fn delete_if_equals(&mut self, key: &[u8], expected: &[u8]) -> Result<bool, DatastoreError> {
+	// As with `compare_and_swap`, check the plaintext and have the backend
+	// delete only if the ciphertext is still the one we checked.
+	let Some(sealed) = self.backend.get_item(key).await? else {
+	    return Ok(false);
+	};
+	if self.open(key, &sealed)? != expected {
+	    return Ok(false);
+	}
+	self.backend.delete_if_equals(key, &sealed).await
+    }
+
+    async fn swap(&mut self, key: Vec<u8>, new: Vec<u8>) -> Result<Option<Vec<u8>>, DatastoreError> {
+	// Unlike `compare_and_swap`, this never looks at the old value before
+	// writing, so the backend can do it in one step.
+	let sealed = self.seal(&key, &new)?;
+	match self.backend.swap(key.clone(), sealed).await? {
+	    Some(old) => Ok(Some(self.open(&key, &old)?)),
+	    None => Ok(None),
+	}
+    }
+
+    async fn increment(&mut self, key: Vec<u8>, delta: i64) -> Result<Option<i64>, DatastoreError> {
+	for _ in 0..MAX_INCREMENT_ATTEMPTS {
+	    let current = self.backend.get_item(&key).await?;
+	    let value = match &current {
+		Some(sealed) => self.open(&key, sealed)?,
+		None => 0i64.to_le_bytes().to_vec(),
+	    };
+	    let Ok(value) = value.as_slice().try_into() else {
+		return Ok(None);
+	    };
+	    let total = i64::from_le_bytes(value).wrapping_add(delta);
+	    let sealed = self.seal(&key, &total.to_le_bytes())?;
+	    if self.backend.compare_and_swap(key.clone(), current.as_deref(), sealed).await? {
+		return Ok(Some(total));
+	    }
+	}
+	Err(DatastoreError::Throttled)
+    }
+
+    async fn put_items(&mut self, pairs: Vec<(Vec<u8>, Vec<u8>)>) -> Result<(), DatastoreError> {
+	let pairs = pairs.into_iter()
+	    .map(|(key, value)| {
+		let sealed = self.seal(&key, &value)?;
+		Ok((key, sealed))
+	    })
+	    .collect::<Result<_, DatastoreError>>()?;
+	self.backend.put_items(pairs).await
+    }
+
+    async fn scan_prefix(&mut self, prefix: &[u8]) -> Result<Entries, DatastoreError> {
+	let entries = self.backend.scan_prefix(prefix).await?;
+	entries.into_iter()
+	    .map(|(key, sealed)| {
+		let value = self.open(&key, &sealed)?;
+		Ok((key, value))
+	    })
+	    .collect()
+    }
+
+    async fn count(&mut self) -> Result<u64, DatastoreError> {
+	self.backend.count().await
+    }
+}
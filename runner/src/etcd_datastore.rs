@@ -0,0 +1,185 @@
+//! A `Datastore` backed by etcd, for guests that coordinate with each other,
+//! such as electing a leader or holding a lock.
+//!
+//! Only built with the `etcd` feature, since the client's gRPC bindings need
+//! `protoc` at build time.
+//!
+//! Keys and values are stored as-is: etcd keys and values are both
+//! arbitrary bytes. TTLs are leases, which etcd grants in whole seconds and
+//! revokes, deleting the key, once they run out.
+//!
+//! etcd is the only backend here that's linearizable across runners. Every
+//! read goes through the Raft leader, so it sees every write acknowledged
+//! before it started, from any container, and `compare_and_swap` and
+//! `delete_if_equals` are single transactions checked against the latest
+//! value. DynamoDB reads are eventually consistent and may briefly miss a
+//! write. Redis is only as consistent as its replication, which is
+//! asynchronous. S3 reads see earlier writes but its conditional writes
+//! compare ETags, not values. Memory, file and SQLite stores are local to
+//! one container and can't coordinate across containers at all.
+
+use std::time::Duration;
+
+use etcd_client::{Client, Compare, CompareOp, GetOptions, PutOptions, Txn, TxnOp};
+use lambda_http::Error;
+
+use crate::{env_opt, Datastore, DatastoreError, Entries, MAX_CAS_ATTEMPTS, MAX_SCAN_ENTRIES};
+
+/// Stores each key as an etcd key. Cloning is cheap and shares the client's
+/// connections.
+#[derive(Clone)]
+pub struct EtcdDatastore {
+    client: Client,
+}
+
+impl EtcdDatastore {
+    /// Connects to the comma-separated endpoints in `WASMTEST_ETCD_ENDPOINTS`,
+    /// e.g. `http://etcd-0:2379,http://etcd-1:2379`. The client balances
+    /// requests across them and fails over if one goes down.
+    pub async fn from_env() -> Result<Self, Error> {
+	let endpoints = env_opt::<String>("WASMTEST_ETCD_ENDPOINTS")?
+	    .ok_or("WASMTEST_ETCD_ENDPOINTS must be set to use the etcd datastore")?;
+	let endpoints: Vec<&str> = endpoints.split(',').map(str::trim).filter(|e| !e.is_empty()).collect();
+	let client = Client::connect(&endpoints, None).await
+	    .map_err(|e| format!("couldn't connect to etcd at {}: {}", endpoints.join(","), e))?;
+	Ok(EtcdDatastore { client })
+    }
+
+    /// Rewrites `key` as `update` says, keeping its lease, until nothing
+    /// else writes it in between. `update` returns `None` to leave the key
+    /// alone.
+    async fn update<T>(
+	&mut self,
+	key: Vec<u8>,
+	mut update: impl FnMut(Option<&[u8]>) -> Option<(Vec<u8>, T)> + Send,
+    ) -> Result<Option<T>, DatastoreError> {
+	for _ in 0..MAX_CAS_ATTEMPTS {
+	    let current = self.client.get(key.clone(), None).await.map_err(DatastoreError::from_etcd)?
+		.take_kvs().into_iter().next();
+	    let Some((new, result)) = update(current.as_ref().map(|kv| kv.value())) else {
+		return Ok(None);
+	    };
+	    // A key that's absent has a modification revision of 0, so this
+	    // also checks that nobody created it in the meantime.
+	    let mod_revision = current.as_ref().map_or(0, |kv| kv.mod_revision());
+	    let lease = current.as_ref().map_or(0, |kv| kv.lease());
+	    let txn = Txn::new()
+		.when([Compare::mod_revision(key.clone(), CompareOp::Equal, mod_revision)])
+		.and_then([TxnOp::put(key.clone(), new, Some(PutOptions::new().with_lease(lease)))]);
+	    if self.client.txn(txn).await.map_err(DatastoreError::from_etcd)?.succeeded() {
+		return Ok(Some(result));
+	    }
+	}
+	Err(DatastoreError::Throttled)
+    }
+}
+
+impl DatastoreError {
+    fn from_etcd(e: etcd_client::Error) -> Self {
+	use etcd_client::Error;
+	use tonic::Code;
+	match &e {
+	    Error::GRpcStatus(status) => match status.code() {
+		Code::DeadlineExceeded => DatastoreError::Timeout,
+		// etcd answers `Unavailable` while the cluster has no leader.
+		Code::ResourceExhausted | Code::Unavailable => DatastoreError::Throttled,
+		_ => DatastoreError::Backend(e.to_string()),
+	    },
+	    _ => DatastoreError::Backend(e.to_string()),
+	}
+    }
+}
+
+impl Datastore for EtcdDatastore {
+    const NAME: &'static str = "etcd";
+
+    async fn put_item(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<(), DatastoreError> {
+	// A put without a lease detaches the key from any it had, clearing
+	// its TTL.
+	self.client.put(key, value, None).await.map_err(DatastoreError::from_etcd)?;
+	Ok(())
+    }
+
+    async fn put_item_with_ttl(&mut self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<(), DatastoreError> {
+	// Leases last a whole number of seconds, so round up rather than
+	// expire the key early. etcd also stretches very short leases to its
+	// minimum TTL, a couple of seconds by default.
+	let secs = ttl.as_millis().div_ceil(1000).max(1);
+	let lease = self.client.lease_grant(secs.try_into().unwrap_or(i64::MAX), None).await
+	    .map_err(DatastoreError::from_etcd)?;
+	self.client.put(key, value, Some(PutOptions::new().with_lease(lease.id()))).await
+	    .map_err(DatastoreError::from_etcd)?;
+	Ok(())
+    }
+
+    async fn get_item(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>, DatastoreError> {
+	let mut response = self.client.get(key, None).await.map_err(DatastoreError::from_etcd)?;
+	Ok(response.take_kvs().into_iter().next().map(|kv| kv.into_key_value().1))
+    }
+
+    async fn delete_item(&mut self, key: &[u8]) -> Result<(), DatastoreError> {
+	self.client.delete(key, None).await.map_err(DatastoreError::from_etcd)?;
+	Ok(())
+    }
+
+    async fn exists(&mut self, key: &[u8]) -> Result<bool, DatastoreError> {
+	let response = self.client.get(key, Some(GetOptions::new().with_count_only())).await
+	    .map_err(DatastoreError::from_etcd)?;
+	Ok(response.count() > 0)
+    }
+
+    async fn compare_and_swap(&mut self, key: Vec<u8>, expected: Option<&[u8]>, new: Vec<u8>) -> Result<bool, DatastoreError> {
+	// etcd fails a value comparison against a missing key, and reports a
+	// missing key's creation revision as 0.
+	let compare = match expected {
+	    Some(expected) => Compare::value(key.clone(), CompareOp::Equal, expected),
+	    None => Compare::create_revision(key.clone(), CompareOp::Equal, 0),
+	};
+	let txn = Txn::new().when([compare]).and_then([TxnOp::put(key, new, None)]);
+	Ok(self.client.txn(txn).await.map_err(DatastoreError::from_etcd)?.succeeded())
+    }
+
+    async fn delete_if_equals(&mut self, key: &[u8], expected: &[u8]) -> Result<bool, DatastoreError> {
+	let txn = Txn::new()
+	    .when([Compare::value(key, CompareOp::Equal, expected)])
+	    .and_then([TxnOp::delete(key, None)]);
+	Ok(self.client.txn(txn).await.map_err(DatastoreError::from_etcd)?.succeeded())
+    }
+
+    async fn swap(&mut self, key: Vec<u8>, new: Vec<u8>) -> Result<Option<Vec<u8>>, DatastoreError> {
+	let mut response = self.client.put(key, new, Some(PutOptions::new().with_prev_key())).await
+	    .map_err(DatastoreError::from_etcd)?;
+	Ok(response.take_prev_key().map(|kv| kv.into_key_value().1))
+    }
+
+    async fn append(&mut self, key: Vec<u8>, suffix: Vec<u8>) -> Result<(), DatastoreError> {
+	self.update(key, |current| Some(([current.unwrap_or_default(), &suffix].concat(), ()))).await?;
+	Ok(())
+    }
+
+    async fn increment(&mut self, key: Vec<u8>, delta: i64) -> Result<Option<i64>, DatastoreError> {
+	self.update(key, |current| {
+	    let current = match current {
+		Some(value) => i64::from_le_bytes(value.try_into().ok()?),
+		None => 0,
+	    };
+	    let total = current.wrapping_add(delta);
+	    Some((total.to_le_bytes().to_vec(), total))
+	}).await
+    }
+
+    async fn scan_prefix(&mut self, prefix: &[u8]) -> Result<Entries, DatastoreError> {
+	// etcd returns keys in byte order, so the limit keeps the first ones.
+	let options = GetOptions::new().with_prefix().with_limit(MAX_SCAN_ENTRIES as i64);
+	let mut response = self.client.get(prefix, Some(options)).await.map_err(DatastoreError::from_etcd)?;
+	Ok(response.take_kvs().into_iter().map(|kv| kv.into_key_value()).collect())
+    }
+
+    /// Counts every key in the cluster, including any that weren't written
+    /// through this store.
+    async fn count(&mut self) -> Result<u64, DatastoreError> {
+	let options = GetOptions::new().with_all_keys().with_count_only();
+	let response = self.client.get(Vec::new(), Some(options)).await.map_err(DatastoreError::from_etcd)?;
+	Ok(response.count().try_into().unwrap_or_default())
+    }
+}
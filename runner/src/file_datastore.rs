@@ -0,0 +1,246 @@
+//! A `Datastore` that keeps each key in a file, for local development
+//! without any cloud services.
+//!
+//! Files live directly under the root directory, named `k` followed by the
+//! key in hex so any key makes a safe file name. Each holds the time the key
+//! expires, as little-endian milliseconds since the epoch (or zero if it
+//! never does), followed by the value. Writes go to a temporary file that's
+//! renamed into place, so readers never see half a value.
+//!
+//! Writers in one process are serialized, which is what makes
+//! `compare_and_swap`, `delete_if_equals`, `append` and `increment` atomic;
+//! several processes sharing a root can race each other.
+
+use std::io::ErrorKind;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use lambda_http::Error;
+use tokio::sync::Mutex;
+
+use crate::{env_or, from_hex, to_hex, Datastore, DatastoreError, MAX_SCAN_ENTRIES};
+
+/// Where the files go if `WASMTEST_FILE_ROOT` doesn't say.
+const DEFAULT_ROOT: &str = "wasmtest-data";
+
+/// Stores each key as a file under `root`.
+#[derive(Clone, Debug)]
+pub struct FileDatastore {
+    root: PathBuf,
+    /// Held by every write, and shared by every clone.
+    lock: Arc<Mutex<()>>,
+}
+
+/// A value as it was read from its file.
+struct Entry {
+    value: Vec<u8>,
+    expires_at: Option<u64>,
+}
+
+impl Entry {
+    fn is_live(&self) -> bool {
+	self.expires_at.is_none_or(|expires_at| expires_at > now_millis())
+    }
+}
+
+impl FileDatastore {
+    pub fn new(root: PathBuf) -> Self {
+	FileDatastore { root, lock: Arc::default() }
+    }
+
+    /// Stores files under `WASMTEST_FILE_ROOT`, creating it if need be.
+    pub async fn from_env() -> Result<Self, Error> {
+	let root = PathBuf::from(env_or("WASMTEST_FILE_ROOT", DEFAULT_ROOT.to_string())?);
+	tokio::fs::create_dir_all(&root).await
+	    .map_err(|e| format!("couldn't create {}: {}", root.display(), e))?;
+	Ok(Self::new(root))
+    }
+
+    fn file_name(key: &[u8]) -> String {
+	format!("k{}", to_hex(key))
+    }
+
+    async fn read(&self, key: &[u8]) -> Result<Option<Entry>, DatastoreError> {
+	let path = self.root.join(Self::file_name(key));
+	let contents = match tokio::fs::read(&path).await {
+	    Ok(contents) => contents,
+	    Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+	    Err(e) => return Err(DatastoreError::from_io(e)),
+	};
+	let Some((expires_at, value)) = contents.split_first_chunk::<8>() else {
+	    return Err(DatastoreError::Backend(format!("{} is truncated", path.display())));
+	};
+	let expires_at = Some(u64::from_le_bytes(*expires_at)).filter(|&expires_at| expires_at != 0);
+	Ok(Some(Entry { value: value.to_vec(), expires_at }))
+    }
+
+    /// Reads `key`, treating it as absent if it's expired.
+    async fn read_live(&self, key: &[u8]) -> Result<Option<Vec<u8>>, DatastoreError> {
+	Ok(self.read(key).await?.filter(Entry::is_live).map(|entry| entry.value))
+    }
+
+    /// Replaces `key`'s file. Callers must hold `lock`.
+    async fn write(&self, key: &[u8], value: &[u8], expires_at: Option<u64>) -> Result<(), DatastoreError> {
+	let name = Self::file_name(key);
+	let mut contents = Vec::with_capacity(8 + value.len());
+	contents.extend_from_slice(&expires_at.unwrap_or(0).to_le_bytes());
+	contents.extend_from_slice(value);
+	let temp = self.root.join(format!(".{}.{:016x}", name, rand::random::<u64>()));
+	tokio::fs::write(&temp, contents).await.map_err(DatastoreError::from_io)?;
+	tokio::fs::rename(&temp, self.root.join(name)).await.map_err(DatastoreError::from_io)
+    }
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64
+}
+
+impl DatastoreError {
+    fn from_io(e: std::io::Error) -> Self {
+	match e.kind() {
+	    ErrorKind::TimedOut => DatastoreError::Timeout,
+	    _ => DatastoreError::Backend(e.to_string()),
+	}
+    }
+}
+
+impl Datastore for FileDatastore {
+    const NAME: &'static str = "file";
+
+    async fn put_item(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<(), DatastoreError> {
+	let _lock = self.lock.lock().await;
+	self.write(&key, &value, None).await
+    }
+
+    async fn put_item_with_ttl(&mut self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<(), DatastoreError> {
+	let _lock = self.lock.lock().await;
+	self.write(&key, &value, Some(now_millis() + ttl.as_millis() as u64)).await
+    }
+
+    async fn get_item(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>, DatastoreError> {
+	self.read_live(key).await
+    }
+
+    async fn delete_item(&mut self, key: &[u8]) -> Result<(), DatastoreError> {
+	let _lock = self.lock.lock().await;
+	match tokio::fs::remove_file(self.root.join(Self::file_name(key))).await {
+	    Err(e) if e.kind() != ErrorKind::NotFound => Err(DatastoreError::from_io(e)),
+	    _ => Ok(()),
+	}
+    }
+
+    async fn compare_and_swap(&mut self, key: Vec<u8>, expected: Option<&[u8]>, new: Vec<u8>) -> Result<bool, DatastoreError> {
+	let _lock = self.lock.lock().await;
+	if self.read_live(&key).await?.as_deref() != expected {
+	    return Ok(false);
+	}
+	self.write(&key, &new, None).await?;
+	Ok(true)
+    }
+
+    async fn append(&mut self, key: Vec<u8>, suffix: Vec<u8>) -> Result<(), DatastoreError> {
+	let _lock = self.lock.lock().await;
+	let (mut value, expires_at) = match self.read(&key).await?.filter(Entry::is_live) {
+	    Some(entry) => (entry.value, entry.expires_at),
+	    None => (Vec::new(), None),
+	};
+	value.extend(suffix);
+	self.write(&key, &value, expires_at).await
+    }
+
+    async fn delete_if_equals(&mut self, key: &[u8], expected: &[u8]) -> Result<bool, DatastoreError> {
+	let _lock = self.lock.lock().await;
+	if self.read_live(key).await?.as_deref() != Some(expected) {
+	    return Ok(false);
+	}
+	match tokio::fs::remove_file(self.root.join(Self::file_name(key))).await {
+	    Ok(()) => Ok(true),
+	    Err(e) if e.kind() == ErrorKind::NotFound => Ok(false),
+	    Err(e) => Err(DatastoreError::from_io(e)),
+	}
+    }
+
+    async fn increment(&mut self, key: Vec<u8>, delta: i64) -> Result<Option<i64>, DatastoreError> {
+	let _lock = self.lock.lock().await;
+	let (value, expires_at) = match self.read(&key).await?.filter(Entry::is_live) {
+	    Some(entry) => (entry.value, entry.expires_at),
+	    None => (0i64.to_le_bytes().to_vec(), None),
+	};
+	let Ok(current) = value.as_slice().try_into() else {
+	    return Ok(None);
+	};
+	let total = i64::from_le_bytes(current).wrapping_add(delta);
+	self.write(&key, &total.to_le_bytes(), expires_at).await?;
+	Ok(Some(total))
+    }
+
+    async fn scan_prefix(&mut self, prefix: &[u8]) -> Result<Vec<(Vec<u8>, Vec<u8>)>, DatastoreError> {
+	let prefix = Self::file_name(prefix);
+	let mut keys = Vec::new();
+	let mut dir = tokio::fs::read_dir(&self.root).await.map_err(DatastoreError::from_io)?;
+	while let Some(file) = dir.next_entry().await.map_err(DatastoreError::from_io)? {
+	    // Skips temporary files, which start with a dot.
+	    let name = file.file_name();
+	    let Some(key) = name.to_str().filter(|name| name.starts_with(&prefix)).and_then(|name| from_hex(&name[1..])) else {
+		continue;
+	    };
+	    keys.push(key);
+	}
+	keys.sort();
+
+	let mut entries = Vec::new();
+	for key in keys {
+	    if let Some(value) = self.read_live(&key).await? {
+		entries.push((key, value));
+		if entries.len() == MAX_SCAN_ENTRIES {
+		    break;
+		}
+	    }
+	}
+	Ok(entries)
+    }
+
+    async fn count(&mut self) -> Result<u64, DatastoreError> {
+	// Reading every file is slow, but it's the only way to skip the ones
+	// that have expired.
+	let mut count = 0;
+	let mut dir = tokio::fs::read_dir(&self.root).await.map_err(DatastoreError::from_io)?;
+	while let Some(file) = dir.next_entry().await.map_err(DatastoreError::from_io)? {
+	    let name = file.file_name();
+	    let Some(key) = name.to_str().and_then(|name| name.strip_prefix('k')).and_then(from_hex) else {
+		continue;
+	    };
+	    if self.read(&key).await?.is_some_and(|entry| entry.is_live()) {
+		count += 1;
+	    }
+	}
+	Ok(count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn keeps_binary_keys_apart() {
+	let root = std::env::temp_dir().join(format!("wasmtest-file-{:016x}", rand::random::<u64>()));
+	tokio::fs::create_dir_all(&root).await.unwrap();
+	let mut file = FileDatastore::new(root.clone());
+
+	// Both are `a\u{fffd}b` to a lossy UTF-8 decoding.
+	file.put_item(b"a\xffb".to_vec(), b"ff".to_vec()).await.unwrap();
+	file.put_item(b"a\xfeb".to_vec(), b"fe".to_vec()).await.unwrap();
+	file.put_item(b"\x00\xff\x80".to_vec(), b"binary".to_vec()).await.unwrap();
+
+	assert_eq!(file.get_item(b"a\xffb").await.unwrap(), Some(b"ff".to_vec()));
+	assert_eq!(file.get_item(b"a\xfeb").await.unwrap(), Some(b"fe".to_vec()));
+	assert_eq!(file.get_item(b"\x00\xff\x80").await.unwrap(), Some(b"binary".to_vec()));
+	assert_eq!(file.scan_prefix(b"a").await.unwrap(), [
+	    (b"a\xfeb".to_vec(), b"fe".to_vec()),
+	    (b"a\xffb".to_vec(), b"ff".to_vec()),
+	]);
+	tokio::fs::remove_dir_all(&root).await.unwrap();
+    }
+}
@@ -0,0 +1,179 @@
+//! Outbound HTTP for guests, through the `http_fetch` import.
+//!
+//! Requests and responses cross the guest boundary as single buffers. A
+//! request is its method, URL, headers and body: the method, URL and body as
+//! little-endian u32 lengths followed by their bytes, and the headers in the
+//! same count-prefixed encoding `encode_pairs` uses. A response is a u32
+//! status code followed by its headers and body encoded the same way. Guests
+//! can return a response in that encoding from `entry`, too.
+
+use std::time::Duration;
+
+use lambda_http::Error;
+
+use crate::{decode_pairs_from, encode_pairs, env_opt, env_or, take, take_field};
+
+/// How long a fetch may take if `WASMTEST_HTTP_TIMEOUT_MS` doesn't say.
+const DEFAULT_TIMEOUT_MS: u64 = 5_000;
+
+/// A request a guest asked us to make.
+#[derive(Debug)]
+pub struct HttpRequest {
+    pub method: String,
+    pub url: String,
+    pub headers: Vec<(Vec<u8>, Vec<u8>)>,
+    pub body: Vec<u8>,
+}
+
+/// What we got back, to hand to the guest.
+#[derive(Debug)]
+pub struct HttpResponse {
+    pub status: u16,
+    pub headers: Vec<(Vec<u8>, Vec<u8>)>,
+    pub body: Vec<u8>,
+}
+
+/// Decodes a guest's request buffer, returning `None` if it's malformed.
+pub fn decode_request(mut buf: &[u8]) -> Option<HttpRequest> {
+    let method = String::from_utf8(take_field(&mut buf)?.to_vec()).ok()?;
+    let url = String::from_utf8(take_field(&mut buf)?.to_vec()).ok()?;
+    let headers = decode_pairs_from(&mut buf)?;
+    let body = take_field(&mut buf)?.to_vec();
+    Some(HttpRequest { method, url, headers, body })
+}
+
+/// Encodes a response the way the guest's `http::fetch` expects.
+pub fn encode_response(response: &HttpResponse) -> Vec<u8> {
+    let mut buf = Vec::new();
+    buf.extend_from_slice(&(response.status as u32).to_le_bytes());
+    buf.extend_from_slice(&encode_pairs(&response.headers));
+    buf.extend_from_slice(&(response.body.len() as u32).to_le_bytes());
+    buf.extend_from_slice(&response.body);
+    buf
+}
+
+/// Decodes a buffer in the encoding `encode_response` produces, returning
+/// `None` if it's malformed. Guests use the same encoding for the responses
+/// they return from `entry`.
+pub fn decode_response(mut buf: &[u8]) -> Option<HttpResponse> {
+    let status = u32::from_le_bytes(take(&mut buf, 4)?.try_into().ok()?);
+    let status = u16::try_from(status).ok()?;
+    let headers = decode_pairs_from(&mut buf)?;
+    let body = take_field(&mut buf)?.to_vec();
+    Some(HttpResponse { status, headers, body })
+}
+
+/// Why a fetch failed. As with `DatastoreError`, each variant has a fixed
+/// status code that `http_fetch` hands back to the guest.
+#[derive(Debug)]
+pub enum FetchError {
+    /// The request didn't parse, e.g. an unknown method or a bad URL.
+    Invalid(String),
+    /// The URL's host isn't on the allowlist.
+    Forbidden(String),
+    /// The server didn't respond in time.
+    Timeout,
+    /// Any other failure, such as a connection or TLS error.
+    Failed(String),
+}
+
+impl FetchError {
+    pub fn status(&self) -> u32 {
+	match self {
+	    FetchError::Invalid(_) | FetchError::Failed(_) => 3,
+	    FetchError::Timeout => 5,
+	    FetchError::Forbidden(_) => 6,
+	}
+    }
+}
+
+impl std::fmt::Display for FetchError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+	match self {
+	    FetchError::Invalid(e) => write!(f, "invalid request: {}", e),
+	    FetchError::Forbidden(host) => write!(f, "{} isn't on the allowlist", host),
+	    FetchError::Timeout => write!(f, "timed out"),
+	    FetchError::Failed(e) => write!(f, "{}", e),
+	}
+    }
+}
+
+/// Makes guests' requests, as long as they're to a host on the allowlist.
+#[derive(Clone, Debug)]
+pub struct HttpFetcher {
+    client: reqwest::Client,
+    allowlist: Vec<String>,
+}
+
+impl HttpFetcher {
+    /// Builds a fetcher allowed to reach the comma-separated domains in
+    /// `WASMTEST_HTTP_ALLOWLIST`, and nothing at all if that isn't set. Each
+    /// request times out after `WASMTEST_HTTP_TIMEOUT_MS`.
+    pub fn from_env() -> Result<Self, Error> {
+	let allowlist: Vec<String> = env_opt::<String>("WASMTEST_HTTP_ALLOWLIST")?
+	    .map(|domains| domains.split(',').map(|d| d.trim().to_ascii_lowercase()).filter(|d| !d.is_empty()).collect())
+	    .unwrap_or_default();
+	let timeout = Duration::from_millis(env_or("WASMTEST_HTTP_TIMEOUT_MS", DEFAULT_TIMEOUT_MS)?);
+
+	// Redirects are checked against the allowlist too, so an allowed host
+	// can't bounce a guest somewhere it isn't allowed to go.
+	let redirects = allowlist.clone();
+	let client = reqwest::Client::builder()
+	    .timeout(timeout)
+	    .redirect(reqwest::redirect::Policy::custom(move |attempt| {
+		if attempt.previous().len() >= 10 {
+		    attempt.error("too many redirects")
+		} else if allowed(&redirects, attempt.url()) {
+		    attempt.follow()
+		} else {
+		    attempt.stop()
+		}
+	    }))
+	    .build()?;
+	Ok(HttpFetcher { client, allowlist })
+    }
+
+    pub async fn fetch(&self, request: HttpRequest) -> Result<HttpResponse, FetchError> {
+	let url = reqwest::Url::parse(&request.url).map_err(|e| FetchError::Invalid(e.to_string()))?;
+	if !allowed(&self.allowlist, &url) {
+	    return Err(FetchError::Forbidden(url.host_str().unwrap_or_default().into()));
+	}
+	let method = reqwest::Method::from_bytes(request.method.as_bytes())
+	    .map_err(|e| FetchError::Invalid(e.to_string()))?;
+
+	let mut builder = self.client.request(method, url).body(request.body);
+	for (name, value) in request.headers {
+	    builder = builder.header(name, value);
+	}
+	let response = builder.send().await.map_err(fetch_error)?;
+
+	let status = response.status().as_u16();
+	let headers = response.headers().iter()
+	    .map(|(name, value)| (name.as_str().as_bytes().to_vec(), value.as_bytes().to_vec()))
+	    .collect();
+	let body = response.bytes().await.map_err(fetch_error)?.to_vec();
+	Ok(HttpResponse { status, headers, body })
+    }
+}
+
+/// Whether `url` is on `allowlist`, either exactly or as a subdomain of
+/// an entry.
+fn allowed(allowlist: &[String], url: &reqwest::Url) -> bool {
+    let Some(host) = url.host_str() else {
+	return false;
+    };
+    let host = host.to_ascii_lowercase();
+    allowlist.iter().any(|domain| {
+	host == *domain || host.strip_suffix(domain.as_str()).is_some_and(|sub| sub.ends_with('.'))
+    })
+}
+
+fn fetch_error(e: reqwest::Error) -> FetchError {
+    if e.is_timeout() {
+	FetchError::Timeout
+    } else if e.is_builder() {
+	FetchError::Invalid(e.to_string())
+    } else {
+	FetchError::Failed(e.to_string())
+    }
+}
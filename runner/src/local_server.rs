@@ -0,0 +1,132 @@
+//! Serves guests over plain HTTP instead of through the Lambda runtime, for
+//! developing and testing without the Lambda emulator.
+//!
+//! Setting `WASMTEST_LOCAL_PORT` switches the runner into this mode; leaving
+//! it unset keeps the Lambda mode. Either way requests go through the same
+//! `function_handler`, so guests see no difference besides the request ID,
+//! which there is no Lambda context to take from.
+//!
+//! Two paths never reach the guest, for operators:
+//!
+//! * `/healthz` answers 200 once the module has loaded and the datastore
+//!   answers a read of `HEALTH_PROBE_KEY`, and 503 if the read fails.
+//! * `/metrics` reports these in Prometheus's text format:
+//!   * `wasmtest_invocations_total`, labelled by `status`: requests handled,
+//!     with the status each was answered with.
+//!   * `wasmtest_invocation_duration_seconds`, labelled by `status`: a
+//!     histogram of how long each request took.
+//!   * `wasmtest_datastore_calls_total`, labelled by `backend`, `op` and
+//!     `outcome` (`ok` or `error`): calls made to the datastore backend,
+//!     counting each retry (see `MeteredDatastore`).
+//!   * `wasmtest_datastore_call_duration_seconds`, labelled by `backend` and
+//!     `op`: a histogram of how long each of those calls took.
+//!   * `wasmtest_datastore_circuit_state`, labelled by `backend`: 0 while
+//!     calls go through to the backend, 1 while one probes it and 2 while
+//!     they fail fast (see `CircuitBreakerDatastore`).
+
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::time::Instant;
+
+use http_body_util::BodyExt;
+use hyper::body::Incoming;
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper_util::rt::TokioIo;
+use lambda_http::http::{header::CONTENT_TYPE, StatusCode};
+use lambda_http::{tracing, Body, Error, Request, Response};
+use metrics_exporter_prometheus::{Matcher, PrometheusBuilder, PrometheusHandle};
+use tokio::net::TcpListener;
+
+use crate::{error_response, function_handler, Datastore, Runtime};
+
+/// The key `/healthz` reads to check the datastore is reachable. It doesn't
+/// matter whether it exists.
+const HEALTH_PROBE_KEY: &[u8] = b"__wasmtest_healthz";
+
+/// The upper bounds, in seconds, of `wasmtest_invocation_duration_seconds`'s
+/// and `wasmtest_datastore_call_duration_seconds`'s buckets.
+const DURATION_BUCKETS: &[f64] = &[0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+/// Serves requests on `localhost:port` until the process is killed.
+/// Connections are handled concurrently, each on its own task.
+pub async fn serve<D: Datastore + Clone + 'static>(runtime: Runtime<D>, port: u16) -> Result<(), Error> {
+    let metrics = PrometheusBuilder::new()
+	.set_buckets_for_metric(Matcher::Full("wasmtest_invocation_duration_seconds".into()), DURATION_BUCKETS)?
+	.set_buckets_for_metric(Matcher::Full("wasmtest_datastore_call_duration_seconds".into()), DURATION_BUCKETS)?
+	.install_recorder()
+	.map_err(|e| format!("couldn't install the metrics recorder: {}", e))?;
+    let addr = SocketAddr::from(([127, 0, 0, 1], port));
+    let listener = TcpListener::bind(addr).await
+	.map_err(|e| format!("couldn't listen on {}: {}", addr, e))?;
+    tracing::info!(%addr, "serving requests locally");
+    loop {
+	let (stream, peer) = listener.accept().await?;
+	let runtime = runtime.clone();
+	let metrics = metrics.clone();
+	tokio::spawn(async move {
+	    let service = service_fn(move |request| handle(runtime.clone(), metrics.clone(), request));
+	    if let Err(e) = http1::Builder::new().serve_connection(TokioIo::new(stream), service).await {
+		tracing::debug!(%peer, error = %e, "connection failed");
+	    }
+	});
+    }
+}
+
+/// Answers `/healthz` and `/metrics` ourselves, and otherwise buffers
+/// `request`'s body into the form Lambda hands us and runs it through
+/// `function_handler`.
+async fn handle<D: Datastore + Clone + 'static>(runtime: Runtime<D>, metrics: PrometheusHandle, request: hyper::Request<Incoming>) -> Result<Response<Body>, Infallible> {
+    match request.uri().path() {
+	"/healthz" => return Ok(healthz(runtime).await),
+	"/metrics" => return Ok(text(200, "text/plain; version=0.0.4", metrics.render())),
+	_ => {}
+    }
+    let started = Instant::now();
+    let response = invoke(runtime, request).await;
+    let status = response.status().as_u16().to_string();
+    ::metrics::counter!("wasmtest_invocations_total", "status" => status.clone()).increment(1);
+    ::metrics::histogram!("wasmtest_invocation_duration_seconds", "status" => status).record(started.elapsed());
+    Ok(response)
+}
+
+/// Reports whether the datastore answers a read. Having a `Runtime` at all
+/// means the module loaded.
+async fn healthz<D: Datastore + Clone>(runtime: Runtime<D>) -> Response<Body> {
+    match runtime.datastore.clone().get_item(HEALTH_PROBE_KEY).await {
+	Ok(_) => text(200, "text/plain", format!("ok ({})\n", runtime.datastore.name())),
+	Err(e) => {
+	    tracing::warn!(backend = runtime.datastore.name(), error = %e, "health check failed");
+	    text(503, "text/plain", format!("datastore unreachable: {}\n", e))
+	}
+    }
+}
+
+/// Runs `request` through `function_handler`.
+async fn invoke<D: Datastore + Clone + 'static>(runtime: Runtime<D>, request: hyper::Request<Incoming>) -> Response<Body> {
+    let (parts, body) = request.into_parts();
+    let body = match body.collect().await {
+	Ok(body) => body.to_bytes(),
+	Err(e) => return plain_error(400, format!("couldn't read request body: {}", e)),
+    };
+    let body = if body.is_empty() { Body::Empty } else { Body::from(body.to_vec()) };
+    match function_handler(runtime, Request::from_parts(parts, body)).await {
+	Ok(response) => response,
+	// Lambda answers a failed invocation with a 500, so we do too.
+	Err(e) => {
+	    tracing::error!(error = %e, "invocation failed");
+	    plain_error(500, e.to_string())
+	}
+    }
+}
+
+fn text(status: u16, content_type: &'static str, body: String) -> Response<Body> {
+    let mut response = Response::new(Body::from(body));
+    *response.status_mut() = StatusCode::from_u16(status).expect("status codes here are valid");
+    response.headers_mut().insert(CONTENT_TYPE, content_type.parse().expect("content types here are valid"));
+    response
+}
+
+fn plain_error(status: u16, message: String) -> Response<Body> {
+    error_response(status, message).expect("error responses are always valid")
+}
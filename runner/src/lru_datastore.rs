@@ -0,0 +1,228 @@
+//! An in-process `Datastore` that holds a bounded number of entries,
+//! evicting the least recently used, for caching hot keys in front of a
+//! slower backend with `CachingDatastore`.
+//!
+//! Unlike the Redis cache, this one lives in the runner's own memory, so
+//! hits cost no round trip, but it's local to one container: writes from
+//! other containers aren't seen until the cached copy expires. Its size is
+//! bounded by entry count, not bytes, so big values take up as much memory
+//! as they are big.
+
+use std::num::NonZeroUsize;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use lambda_http::Error;
+use lru::LruCache;
+use tokio::time::Instant;
+
+use crate::caching_datastore::CachingDatastore;
+use crate::{env_or, Datastore, DatastoreError, Entries, MAX_SCAN_ENTRIES};
+
+/// How many entries to hold if `WASMTEST_CACHE_CAPACITY` doesn't say.
+const DEFAULT_CAPACITY: usize = 10_000;
+
+/// Caches `B`'s reads in this process.
+pub type LruCachingDatastore<B> = CachingDatastore<LruDatastore, B>;
+
+/// Holds up to a fixed number of entries. Clones share them.
+#[derive(Clone, Debug)]
+pub struct LruDatastore {
+    entries: Arc<Mutex<LruCache<Vec<u8>, Entry>>>,
+}
+
+#[derive(Debug)]
+struct Entry {
+    value: Vec<u8>,
+    expires_at: Option<Instant>,
+}
+
+impl Entry {
+    fn new(value: Vec<u8>, ttl: Option<Duration>) -> Self {
+	Entry { value, expires_at: ttl.map(|ttl| Instant::now() + ttl) }
+    }
+
+    fn is_live(&self) -> bool {
+	self.expires_at.is_none_or(|expires_at| expires_at > Instant::now())
+    }
+}
+
+impl LruDatastore {
+    pub fn new(capacity: NonZeroUsize) -> Self {
+	LruDatastore { entries: Arc::new(Mutex::new(LruCache::new(capacity))) }
+    }
+
+    /// Holds up to `WASMTEST_CACHE_CAPACITY` entries.
+    pub fn from_env() -> Result<Self, Error> {
+	let capacity = env_or("WASMTEST_CACHE_CAPACITY", DEFAULT_CAPACITY)?;
+	let capacity = NonZeroUsize::new(capacity).ok_or("WASMTEST_CACHE_CAPACITY must be at least 1")?;
+	Ok(Self::new(capacity))
+    }
+
+    /// Runs `f` on the entries. The lock is never held across an await, so
+    /// this can't deadlock.
+    fn with<T>(&self, f: impl FnOnce(&mut LruCache<Vec<u8>, Entry>) -> T) -> T {
+	f(&mut self.entries.lock().unwrap())
+    }
+}
+
+/// The entry at `key`, marked as just used, unless it's absent or expired.
+fn live<'a>(entries: &'a mut LruCache<Vec<u8>, Entry>, key: &[u8]) -> Option<&'a mut Entry> {
+    if entries.peek(key).is_some_and(|entry| !entry.is_live()) {
+	entries.pop(key);
+    }
+    entries.get_mut(key)
+}
+
+impl Datastore for LruDatastore {
+    const NAME: &'static str = "lru";
+
+    async fn put_item(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<(), DatastoreError> {
+	self.with(|entries| entries.put(key, Entry::new(value, None)));
+	Ok(())
+    }
+
+    async fn put_item_with_ttl(&mut self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<(), DatastoreError> {
+	self.with(|entries| entries.put(key, Entry::new(value, Some(ttl))));
+	Ok(())
+    }
+
+    async fn get_item(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>, DatastoreError> {
+	Ok(self.with(|entries| live(entries, key).map(|entry| entry.value.clone())))
+    }
+
+    async fn delete_item(&mut self, key: &[u8]) -> Result<(), DatastoreError> {
+	self.with(|entries| entries.pop(key));
+	Ok(())
+    }
+
+    async fn compare_and_swap(&mut self, key: Vec<u8>, expected: Option<&[u8]>, new: Vec<u8>) -> Result<bool, DatastoreError> {
+	Ok(self.with(|entries| {
+	    if live(entries, &key).map(|entry| entry.value.as_slice()) != expected {
+		return false;
+	    }
+	    entries.put(key, Entry::new(new, None));
+	    true
+	}))
+    }
+
+    async fn delete_if_equals(&mut self, key: &[u8], expected: &[u8]) -> Result<bool, DatastoreError> {
+	Ok(self.with(|entries| {
+	    if live(entries, key).map(|entry| entry.value.as_slice()) != Some(expected) {
+		return false;
+	    }
+	    entries.pop(key);
+	    true
+	}))
+    }
+
+    async fn increment(&mut self, key: Vec<u8>, delta: i64) -> Result<Option<i64>, DatastoreError> {
+	Ok(self.with(|entries| {
+	    let Some(entry) = live(entries, &key) else {
+		entries.put(key, Entry::new(delta.to_le_bytes().to_vec(), None));
+		return Some(delta);
+	    };
+	    // The counter keeps its expiry.
+	    let total = i64::from_le_bytes(entry.value.as_slice().try_into().ok()?).wrapping_add(delta);
+	    entry.value = total.to_le_bytes().to_vec();
+	    Some(total)
+	}))
+    }
+
+    /// Walks every entry without marking any as used, since a scan isn't a
+    /// sign a key is hot.
+    async fn scan_prefix(&mut self, prefix: &[u8]) -> Result<Entries, DatastoreError> {
+	let mut found: Entries = self.with(|entries| {
+	    entries.iter()
+		.filter(|(key, entry)| key.starts_with(prefix) && entry.is_live())
+		.map(|(key, entry)| (key.clone(), entry.value.clone()))
+		.collect()
+	});
+	found.sort();
+	found.truncate(MAX_SCAN_ENTRIES);
+	Ok(found)
+    }
+
+    async fn count(&mut self) -> Result<u64, DatastoreError> {
+	Ok(self.with(|entries| entries.iter().filter(|(_, entry)| entry.is_live()).count() as u64))
+    }
+}
+
+#[cfg(all(test, feature = "test-util"))]
+mod tests {
+    use super::*;
+    use crate::mock_datastore::{MockDatastore, Op};
+
+    const TTL: Duration = Duration::from_secs(60);
+
+    fn cached(backend: &MockDatastore, capacity: usize) -> LruCachingDatastore<MockDatastore> {
+	let cache = LruDatastore::new(NonZeroUsize::new(capacity).unwrap());
+	CachingDatastore::new(cache, backend.clone(), TTL)
+    }
+
+    /// How many times the backend was asked for `key`.
+    fn gets(backend: &MockDatastore, key: &[u8]) -> usize {
+	backend.ops().iter().filter(|op| **op == Op::Get { key: key.to_vec() }).count()
+    }
+
+    #[tokio::test]
+    async fn reads_the_backend_once_until_invalidated() {
+	let backend = MockDatastore::with_items([(b"foo".to_vec(), b"bar".to_vec())]);
+	let mut datastore = cached(&backend, 10);
+
+	for _ in 0..3 {
+	    assert_eq!(datastore.get_item(b"foo").await.unwrap().as_deref(), Some(&b"bar"[..]));
+	}
+	assert_eq!(gets(&backend, b"foo"), 1);
+
+	// Writes through the cache update it rather than drop it.
+	datastore.put_item(b"foo".to_vec(), b"baz".to_vec()).await.unwrap();
+	assert_eq!(datastore.get_item(b"foo").await.unwrap().as_deref(), Some(&b"baz"[..]));
+	assert_eq!(gets(&backend, b"foo"), 1);
+
+	// An append drops the cached copy, so the next read goes to the backend.
+	datastore.append(b"foo".to_vec(), b"!".to_vec()).await.unwrap();
+	assert_eq!(datastore.get_item(b"foo").await.unwrap().as_deref(), Some(&b"baz!"[..]));
+	assert_eq!(datastore.get_item(b"foo").await.unwrap().as_deref(), Some(&b"baz!"[..]));
+	assert_eq!(gets(&backend, b"foo"), 2);
+
+	datastore.delete_item(b"foo").await.unwrap();
+	assert_eq!(datastore.get_item(b"foo").await.unwrap(), None);
+	assert_eq!(gets(&backend, b"foo"), 3);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn rereads_the_backend_once_the_ttl_is_up() {
+	let backend = MockDatastore::with_items([(b"foo".to_vec(), b"bar".to_vec())]);
+	let mut datastore = cached(&backend, 10);
+
+	datastore.get_item(b"foo").await.unwrap();
+	tokio::time::advance(TTL - Duration::from_millis(1)).await;
+	datastore.get_item(b"foo").await.unwrap();
+	assert_eq!(gets(&backend, b"foo"), 1);
+
+	tokio::time::advance(Duration::from_millis(1)).await;
+	datastore.get_item(b"foo").await.unwrap();
+	assert_eq!(gets(&backend, b"foo"), 2);
+    }
+
+    #[tokio::test]
+    async fn evicts_the_least_recently_used() {
+	let backend = MockDatastore::with_items([
+	    (b"a".to_vec(), b"1".to_vec()),
+	    (b"b".to_vec(), b"2".to_vec()),
+	    (b"c".to_vec(), b"3".to_vec()),
+	]);
+	let mut datastore = cached(&backend, 2);
+
+	datastore.get_item(b"a").await.unwrap();
+	datastore.get_item(b"b").await.unwrap();
+	datastore.get_item(b"a").await.unwrap();
+	datastore.get_item(b"c").await.unwrap();
+	datastore.get_item(b"a").await.unwrap();
+	datastore.get_item(b"b").await.unwrap();
+
+	assert_eq!(gets(&backend, b"a"), 1);
+	assert_eq!(gets(&backend, b"b"), 2);
+    }
+}
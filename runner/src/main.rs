@@ -1,158 +1,2635 @@
 use wasmtime::*;
 use std::collections::HashMap;
-use lambda_http::{run, service_fn, tracing, Body, Error, Request, Response};
+use std::future::Future;
+use std::num::NonZeroU8;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use lambda_http::tracing::Instrument;
+use lambda_http::{run, service_fn, tracing, Body, Error, Request, RequestExt, Response};
+use lambda_http::http::{header::CONTENT_TYPE, HeaderMap, HeaderName, HeaderValue};
+use base64::{prelude::{BASE64_STANDARD, BASE64_URL_SAFE_NO_PAD}, Engine as _};
+use rand::{rngs::StdRng, RngCore, SeedableRng};
+use sha2::{Digest, Sha256};
+
+mod auditing_datastore;
+mod boxed_datastore;
+mod caching_datastore;
+mod circuit_breaker_datastore;
+mod compressing_datastore;
+mod encrypting_datastore;
+#[cfg(feature = "etcd")]
+mod etcd_datastore;
+mod file_datastore;
+mod http;
+mod local_server;
+mod lru_datastore;
+mod metered_datastore;
+mod metrics;
+#[cfg(all(test, feature = "test-util"))]
+mod mock_datastore;
+mod offloading_datastore;
+mod rate_limited_datastore;
+mod redis_datastore;
+mod retrying_datastore;
+mod router;
+mod s3_datastore;
+mod sharding_datastore;
+mod sled_datastore;
+mod sqlite_datastore;
+#[cfg(all(test, feature = "test-util"))]
+mod test_runner;
+mod workers_kv_datastore;
+
+use auditing_datastore::AuditingDatastore;
+use boxed_datastore::BoxedDatastore;
+use caching_datastore::CachingDatastore;
+use circuit_breaker_datastore::CircuitBreakerDatastore;
+use compressing_datastore::CompressingDatastore;
+use encrypting_datastore::EncryptingDatastore;
+#[cfg(feature = "etcd")]
+use etcd_datastore::EtcdDatastore;
+use file_datastore::FileDatastore;
+use http::HttpFetcher;
+use lru_datastore::{LruCachingDatastore, LruDatastore};
+use metered_datastore::MeteredDatastore;
+use metrics::Metrics;
+use offloading_datastore::OffloadingDatastore;
+use rate_limited_datastore::RateLimitedDatastore;
+use redis_datastore::RedisDatastore;
+use retrying_datastore::RetryingDatastore;
+use router::{RouteMatch, Router};
+use s3_datastore::S3Datastore;
+use sharding_datastore::ShardingDatastore;
+use sled_datastore::SledDatastore;
+use sqlite_datastore::SqliteDatastore;
+use workers_kv_datastore::WorkersKvDatastore;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use wasmtime_wasi::{WasiCtxBuilder, WasiP1Ctx};
 
 #[tokio::main]
 async fn main() -> Result<(), Error> {
     tracing::init_default_subscriber();
 
-    run(service_fn(function_handler)).await
+    let engine = engine()?;
+
+    // `runner --precompile <out.cwasm>` compiles the guest ahead of time for
+    // `WASMTEST_CWASM_PATH` instead of serving requests.
+    let args: Vec<String> = std::env::args().collect();
+    if let [_, flag, out] = &args[..] {
+	if flag == "--precompile" {
+	    let path = module_path();
+	    let wasm = std::fs::read(&path).map_err(|e| format!("couldn't read guest module {}: {}", path, e))?;
+	    std::fs::write(out, engine.precompile_module(&wasm)?)?;
+	    tracing::info!(module = %path, out = %out, "precompiled module");
+	    return Ok(());
+	}
+    }
+
+    // Compiling is by far the most expensive part of handling a request, so
+    // we do it once here and share the result across every invocation this
+    // container serves. Both `Engine` and `Module` are reference-counted, so
+    // cloning them per request is cheap.
+    let started = Instant::now();
+    let module = load_module(&engine)?;
+    tracing::info!(elapsed = ?started.elapsed(), "loaded module");
+
+    tick_epochs(&engine);
+
+    let datastore = datastore_from_env().await?;
+    handle_requests(Runtime::from_env(engine, module, datastore)?).await
 }
 
-trait Datastore {
-    async fn put_item(&mut self, key: Vec<u8>, value: Vec<u8>);
-    async fn get_item(&mut self, key: &Vec<u8>) -> Option<Vec<u8>>;
+/// Builds the datastore guests' reads and writes go to, from the backend
+/// `WASMTEST_DATASTORE` picks and the wrappers the rest of the environment
+/// turns on. Each backend configures itself from its own variables; see
+/// their `from_env` constructors.
+///
+/// Each layer is boxed as it's added, so this builds any combination
+/// without compiling every one; see `BoxedDatastore`. From the backend out:
+///
+/// * Transient failures are retried, and once the backend keeps failing
+///   anyway calls to it fail fast for a while; see
+///   `RetryingDatastore::from_env` and `CircuitBreakerDatastore::from_env`.
+/// * `WASMTEST_SHARDS` spreads keys over that many shards; see
+///   `ShardingDatastore`.
+/// * `WASMTEST_OFFLOAD=s3` keeps values too big for the backend in S3
+///   instead; see `OffloadingDatastore::from_env`.
+/// * `WASMTEST_CACHE=redis` puts a Redis cache in front of reads, and
+///   `WASMTEST_CACHE=lru` one in the runner's memory that holds up to
+///   `WASMTEST_CACHE_CAPACITY` entries. Either way cached entries live for
+///   `WASMTEST_CACHE_TTL_MS`.
+/// * `WASMTEST_COMPRESSION=zstd` compresses the values guests store; see
+///   `CompressingDatastore::from_env`. Values are compressed before they're
+///   encrypted, since ciphertext doesn't compress, and before they're cached.
+/// * `WASMTEST_ENCRYPTION_KEY` encrypts the values guests store. This wraps
+///   the cache too, so neither it nor the backend sees plaintext.
+/// * `WASMTEST_RATE_LIMIT` limits each key to that many operations a second;
+///   see `RateLimitedDatastore::from_env`. This wraps the layers above, so
+///   cache hits count too and the retries never see its throttling.
+/// * `WASMTEST_AUDIT=true` records every operation guests make; see
+///   `AuditingDatastore::from_env`. This is the outermost layer, so the
+///   trail includes operations the rate limit turned away.
+async fn datastore_from_env() -> Result<BoxedDatastore, Error> {
+    let backend = env_or("WASMTEST_DATASTORE", "memory".to_string())?;
+    tracing::info!(%backend, "selected datastore");
+    let mut datastore = match backend.as_str() {
+	"memory" => resilient(MemoryDatastore::from_env()?)?,
+	"dynamodb" => resilient(DynamoDBDatastore::from_env().await?)?,
+	"redis" => resilient(RedisDatastore::from_env().await?)?,
+	"s3" => resilient(S3Datastore::from_env().await?)?,
+	"file" => resilient(FileDatastore::from_env().await?)?,
+	"sqlite" => resilient(SqliteDatastore::from_env()?)?,
+	"sled" => resilient(SledDatastore::from_env()?)?,
+	"workers-kv" => resilient(WorkersKvDatastore::from_env()?)?,
+	#[cfg(feature = "etcd")]
+	"etcd" => resilient(EtcdDatastore::from_env().await?)?,
+	#[cfg(not(feature = "etcd"))]
+	"etcd" => return Err("this runner was built without etcd support; rebuild it with `--features etcd`".into()),
+	other => return Err(format!("unknown WASMTEST_DATASTORE {:?}; expected memory, dynamodb, redis, s3, file, sqlite, sled, workers-kv or etcd", other).into()),
+    };
+
+    if let Some(shards) = env_opt::<NonZeroU8>("WASMTEST_SHARDS")? {
+	tracing::info!(shards = shards.get(), "sharding keys");
+	datastore = BoxedDatastore::new(ShardingDatastore::new(datastore, shards));
+    }
+
+    match env_opt::<String>("WASMTEST_OFFLOAD")?.as_deref() {
+	None => {}
+	Some("s3") => {
+	    let blob = RetryingDatastore::from_env(MeteredDatastore::new(S3Datastore::from_env().await?))?;
+	    tracing::info!(offload = "s3", "offloading big values");
+	    datastore = BoxedDatastore::new(OffloadingDatastore::from_env(datastore, blob)?);
+	}
+	Some(other) => return Err(format!("unknown WASMTEST_OFFLOAD {:?}; expected s3", other).into()),
+    }
+
+    let ttl = || env_or("WASMTEST_CACHE_TTL_MS", caching_datastore::DEFAULT_TTL_MS).map(Duration::from_millis);
+    match env_opt::<String>("WASMTEST_CACHE")?.as_deref() {
+	None => {}
+	Some("redis") => {
+	    let ttl = ttl()?;
+	    tracing::info!(cache = "redis", ?ttl, "caching datastore reads");
+	    datastore = BoxedDatastore::new(CachingDatastore::new(RedisDatastore::from_env().await?, datastore, ttl));
+	}
+	Some("lru") => {
+	    let ttl = ttl()?;
+	    tracing::info!(cache = "lru", ?ttl, "caching datastore reads");
+	    let cached: LruCachingDatastore<_> = CachingDatastore::new(LruDatastore::from_env()?, datastore, ttl);
+	    datastore = BoxedDatastore::new(cached);
+	}
+	Some(other) => return Err(format!("unknown WASMTEST_CACHE {:?}; expected redis or lru", other).into()),
+    }
+
+    match env_opt::<String>("WASMTEST_COMPRESSION")?.as_deref() {
+	None => {}
+	Some("zstd") => {
+	    tracing::info!(compression = "zstd", "compressing stored values");
+	    datastore = BoxedDatastore::new(CompressingDatastore::from_env(datastore)?);
+	}
+	Some(other) => return Err(format!("unknown WASMTEST_COMPRESSION {:?}; expected zstd", other).into()),
+    }
+
+    if let Some(cipher) = encrypting_datastore::cipher_from_env()? {
+	tracing::info!("encrypting stored values");
+	datastore = BoxedDatastore::new(EncryptingDatastore::new(datastore, cipher));
+    }
+
+    if let Some(rate) = env_opt::<f64>("WASMTEST_RATE_LIMIT")? {
+	tracing::info!(rate, "rate limiting datastore keys");
+	datastore = BoxedDatastore::new(RateLimitedDatastore::from_env(datastore, rate)?);
+    }
+
+    if env_or("WASMTEST_AUDIT", false)? {
+	tracing::info!("auditing datastore operations");
+	datastore = BoxedDatastore::new(AuditingDatastore::from_env(datastore)?);
+    }
+    Ok(datastore)
 }
 
-impl Datastore for HashMap<Vec<u8>, Vec<u8>> {
-    async fn put_item(&mut self, key: Vec<u8>, value: Vec<u8>) {
-	self.insert(key, value);
+/// Wraps `backend` in the layers that deal with it failing: retries for
+/// transient failures, counting each call to the backend itself so every
+/// retry shows up, and a circuit breaker around those.
+fn resilient<D: Datastore + Clone + 'static>(backend: D) -> Result<BoxedDatastore, Error> {
+    let datastore = RetryingDatastore::from_env(MeteredDatastore::new(backend))?;
+    Ok(BoxedDatastore::new(CircuitBreakerDatastore::from_env(datastore)?))
+}
+
+/// Takes requests from the Lambda runtime, or, if `WASMTEST_LOCAL_PORT` is
+/// set, serves them over HTTP on that port; see `local_server`.
+async fn handle_requests<D: Datastore + Clone + 'static>(runtime: Runtime<D>) -> Result<(), Error> {
+    if let Some(port) = env_opt("WASMTEST_LOCAL_PORT")? {
+	return local_server::serve(runtime, port).await;
     }
+    run(service_fn(move |event| function_handler(runtime.clone(), event))).await
+}
 
-    async fn get_item(&mut self, key: &Vec<u8>) -> Option<Vec<u8>> {
-	self.get(key).map(Clone::clone)
+/// Advances `engine`'s epoch every `EPOCH_TICK` until it's dropped. Wasmtime
+/// checks the epoch at function entries and loop headers, so ticking it in
+/// the background lets each store put a wall clock deadline on its guest.
+fn tick_epochs(engine: &Engine) {
+    let engine = engine.weak();
+    std::thread::spawn(move || loop {
+	std::thread::sleep(EPOCH_TICK);
+	match engine.upgrade() {
+	    Some(engine) => engine.increment_epoch(),
+	    None => return,
+	}
+    });
+}
+
+/// Where the compiled guest lives: `WASMTEST_MODULE_PATH` if that's set, and
+/// otherwise its build output in the dev tree, relative to the runner's
+/// working directory.
+fn module_path() -> String {
+    std::env::var("WASMTEST_MODULE_PATH")
+	.unwrap_or_else(|_| "../wasmtest/target/wasm32-unknown-unknown/release/wasmtest.wasm".into())
+}
+
+/// How many instances the engine's pool keeps slots for. Lambda only sends
+/// a container one request at a time, so this only needs to cover the odd
+/// instance that's still being torn down.
+const POOL_SIZE: u32 = 16;
+
+/// Builds the "compilation environment" modules are compiled within. The
+/// runner and `--precompile` share it, since a precompiled module only loads
+/// into an engine configured the same way.
+fn engine() -> Result<Engine, Error> {
+    let mut config = Config::new();
+    config.async_support(true);
+    config.consume_fuel(true);
+    config.epoch_interruption(true);
+    config.wasm_memory64(cfg!(feature = "memory64"));
+
+    // Instances come out of preallocated slots rather than freshly mapped
+    // memory. When an instance is dropped its slot's memory is reset to the
+    // module's initial image, so the next request starts clean without
+    // paying for new mappings. Slots are sized to the same limits the
+    // `StoreLimits` on each store enforce.
+    //
+    // Instances themselves are never reused: every request instantiates the
+    // guest afresh in a new `Store` (see `Runtime::store`), and a slot's
+    // memory is zeroed and its data segments copied back in before the slot
+    // is handed out again. Nothing a request leaves in the guest's heap can
+    // be seen by the next, so there's nothing to clear between calls.
+    let mut pool = PoolingAllocationConfig::default();
+    pool.total_core_instances(POOL_SIZE)
+	.total_memories(POOL_SIZE)
+	.total_tables(POOL_SIZE)
+	.total_stacks(POOL_SIZE)
+	.memory_pages(memory_limit()?.div_ceil(WASM_PAGE_SIZE) as u64)
+	.table_elements(table_limit()?);
+    config.allocation_strategy(InstanceAllocationStrategy::Pooling(pool));
+    Ok(Engine::new(&config)?)
+}
+
+/// How much fuel a guest gets per request if `WASMTEST_FUEL` doesn't say.
+/// Wasmtime charges roughly one unit per wasm instruction.
+const DEFAULT_FUEL: u64 = 1_000_000_000;
+
+/// How often the engine's epoch advances, which is the granularity of guest
+/// timeouts.
+const EPOCH_TICK: Duration = Duration::from_millis(10);
+
+/// How many milliseconds a guest gets per request if `WASMTEST_TIMEOUT_MS`
+/// doesn't say.
+const DEFAULT_TIMEOUT_MS: u64 = 5_000;
+
+/// How large a guest's linear memory may grow, in bytes, if
+/// `WASMTEST_MEMORY_LIMIT` doesn't say.
+const DEFAULT_MEMORY_LIMIT: usize = 64 << 20;
+
+/// How many elements a guest's tables may grow to if `WASMTEST_TABLE_LIMIT`
+/// doesn't say.
+const DEFAULT_TABLE_LIMIT: u32 = 10_000;
+
+/// The size of a wasm memory page, in bytes.
+const WASM_PAGE_SIZE: usize = 64 << 10;
+
+/// The most bytes a guest's linear memory may grow to, from
+/// `WASMTEST_MEMORY_LIMIT` if that's set.
+fn memory_limit() -> Result<usize, Error> {
+    env_or("WASMTEST_MEMORY_LIMIT", DEFAULT_MEMORY_LIMIT)
+}
+
+/// The most elements a guest's tables may grow to, from
+/// `WASMTEST_TABLE_LIMIT` if that's set.
+fn table_limit() -> Result<u32, Error> {
+    env_or("WASMTEST_TABLE_LIMIT", DEFAULT_TABLE_LIMIT)
+}
+
+/// Parses the environment variable `name`, if it's set.
+fn env_opt<T: std::str::FromStr>(name: &str) -> Result<Option<T>, Error>
+where T::Err: std::fmt::Display {
+    match std::env::var(name) {
+	Ok(value) => value.parse().map(Some).map_err(|e| format!("invalid {} {:?}: {}", name, value, e).into()),
+	Err(_) => Ok(None),
     }
 }
 
+/// Parses the environment variable `name`, or returns `default` if it isn't
+/// set.
+fn env_or<T: std::str::FromStr>(name: &str, default: T) -> Result<T, Error>
+where T::Err: std::fmt::Display {
+    Ok(env_opt(name)?.unwrap_or(default))
+}
+
+/// Loads the guest module, from the precompiled artifact at
+/// `WASMTEST_CWASM_PATH` if that's set and otherwise by compiling the one at
+/// `module_path`.
+///
+/// An artifact only loads on the same wasmtime version, with the same
+/// `Config`, as the `--precompile` run that produced it. Wasmtime rejects
+/// anything else, in which case we log why and fall back to compiling.
+fn load_module(engine: &Engine) -> Result<Module> {
+    if let Ok(path) = std::env::var("WASMTEST_CWASM_PATH") {
+	// Safety: deserializing runs the artifact's machine code as-is, so it
+	// must come from `--precompile`. It's deployed alongside the runner
+	// itself, which we trust just as much.
+	match unsafe { Module::deserialize_file(engine, &path) } {
+	    Ok(module) => return Ok(module),
+	    Err(e) => tracing::warn!(path = %path, error = format!("{:#}", e), "couldn't load precompiled module, compiling instead"),
+	}
+    }
+    let path = module_path();
+    Module::from_file(engine, &path).map_err(|e| e.context(format!("couldn't load guest module {}", path)))
+}
+
+/// The version of the guest ABI this runner speaks, which guests report
+/// through their `wasmtest_abi_version` export. The guest crate's
+/// `ABI_VERSION` documents what each version means; we refuse to call guests
+/// that speak any other.
+const ABI_VERSION: u32 = 1;
+
+/// A guest address or length. ABI version 1 makes them as wide as the
+/// guest's pointers, so this is 32 bits, or 64 with the `memory64` feature,
+/// which runs only guests built for 64-bit memories.
+#[cfg(not(feature = "memory64"))]
+type GuestPtr = u32;
+#[cfg(feature = "memory64")]
+type GuestPtr = u64;
+
+/// How many bytes a `GuestPtr` takes up in guest memory.
+const PTR_SIZE: usize = std::mem::size_of::<GuestPtr>();
+
+/// How many bytes a `WasmBytes`, a base address and a length, takes up.
+const WASM_BYTES_SIZE: usize = 2 * PTR_SIZE;
+
+/// Splits a `WasmBytes` the guest stored into its base and length.
+fn split_wasm_bytes(header: &[u8; WASM_BYTES_SIZE]) -> (GuestPtr, GuestPtr) {
+    let base = GuestPtr::from_le_bytes(header[..PTR_SIZE].try_into().unwrap());
+    let len = GuestPtr::from_le_bytes(header[PTR_SIZE..].try_into().unwrap());
+    (base, len)
+}
+
+/// The most entries a single `scan_prefix` call returns. Guests that need
+/// more should scan again with a longer prefix.
+const MAX_SCAN_ENTRIES: usize = 100;
+
+/// How many times the default `Datastore::swap` and `Datastore::append` retry
+/// when other writers keep beating them to the key.
+const MAX_CAS_ATTEMPTS: usize = 10;
+
+/// Why a datastore operation failed. Each variant has a fixed status code
+/// that host functions hand back to the guest.
+#[derive(Debug)]
+enum DatastoreError {
+    /// The backend is rate limiting us; retrying later may succeed.
+    Throttled,
+    /// The backend didn't respond in time.
+    Timeout,
+    /// The table (or other backend resource) we were configured with
+    /// doesn't exist.
+    ResourceNotFound(String),
+    /// Any other backend failure, such as a network or serialization error.
+    Backend(String),
+}
+
+impl DatastoreError {
+    /// The status code the guest's `DatastoreError` decodes this as. 0 is
+    /// reserved for success and 1 for a missing key, as `read_key` returns.
+    fn status(&self) -> u32 {
+	match self {
+	    DatastoreError::Throttled => 2,
+	    DatastoreError::ResourceNotFound(_) | DatastoreError::Backend(_) => 3,
+	    DatastoreError::Timeout => 5,
+	}
+    }
+
+    /// Classifies a DynamoDB SDK error by its error code.
+    fn from_dynamodb<E>(e: aws_sdk_dynamodb::error::SdkError<E>) -> Self
+    where
+	E: aws_sdk_dynamodb::error::ProvideErrorMetadata + std::error::Error + 'static,
+    {
+	use aws_sdk_dynamodb::error::{DisplayErrorContext, ProvideErrorMetadata, SdkError};
+	match &e {
+	    SdkError::TimeoutError(_) => return DatastoreError::Timeout,
+	    SdkError::DispatchFailure(failure) if failure.is_timeout() => return DatastoreError::Timeout,
+	    _ => {}
+	}
+	match e.code() {
+	    Some("ProvisionedThroughputExceededException" | "RequestLimitExceeded" | "ThrottlingException") => DatastoreError::Throttled,
+	    Some("ResourceNotFoundException") => DatastoreError::ResourceNotFound(DisplayErrorContext(&e).to_string()),
+	    _ => DatastoreError::Backend(DisplayErrorContext(&e).to_string()),
+	}
+    }
+}
+
+impl std::fmt::Display for DatastoreError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+	match self {
+	    DatastoreError::Throttled => write!(f, "throttled by the backend"),
+	    DatastoreError::Timeout => write!(f, "timed out waiting for the backend"),
+	    DatastoreError::ResourceNotFound(msg) => write!(f, "backend resource not found: {}", msg),
+	    DatastoreError::Backend(msg) => write!(f, "backend error: {}", msg),
+	}
+    }
+}
+
+impl std::error::Error for DatastoreError {}
+
+/// The status returned when the guest's `alloc` can't reserve room for a
+/// result.
+const STATUS_OUT_OF_MEMORY: u32 = 4;
+
+/// Converts the outcome of a datastore write into the status code returned
+/// to the guest.
+fn status(result: Result<(), DatastoreError>) -> u32 {
+    match result {
+	Ok(()) => 0,
+	Err(e) => {
+	    tracing::warn!(error = %e, "datastore write failed");
+	    e.status()
+	}
+    }
+}
+
+/// Key/value pairs, as `scan_prefix` returns them.
+type Entries = Vec<(Vec<u8>, Vec<u8>)>;
+
+/// The values of a batch of keys, as `batch_get_items` returns them.
+type Values = Vec<Option<Vec<u8>>>;
+
+/// A key/value store guests read and write through the datastore imports.
+///
+/// The futures are `Send` so the host functions that await them can be
+/// registered for any backend.
+trait Datastore: Send {
+    /// Identifies the backend in logs.
+    const NAME: &'static str;
+
+    /// `NAME`, or for wrappers that pass it through, the name of the backend
+    /// they wrap, which `BoxedDatastore` only knows at runtime.
+    fn name(&self) -> &'static str {
+	Self::NAME
+    }
+
+    fn put_item(&mut self, key: Vec<u8>, value: Vec<u8>) -> impl Future<Output = Result<(), DatastoreError>> + Send;
+    fn get_item(&mut self, key: &[u8]) -> impl Future<Output = Result<Option<Vec<u8>>, DatastoreError>> + Send;
+
+    /// Reads every key in `keys`, returning their values in the same order.
+    /// Backends with a bulk read API should override this to avoid a round
+    /// trip per key.
+    fn batch_get_items(&mut self, keys: &[Vec<u8>]) -> impl Future<Output = Result<Values, DatastoreError>> + Send {
+	async move {
+	    let mut values = Vec::with_capacity(keys.len());
+	    for key in keys {
+		values.push(self.get_item(key).await?);
+	    }
+	    Ok(values)
+	}
+    }
+
+    /// Writes `key` so that it reads as absent once `ttl` has passed.
+    fn put_item_with_ttl(&mut self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> impl Future<Output = Result<(), DatastoreError>> + Send;
+
+    fn delete_item(&mut self, key: &[u8]) -> impl Future<Output = Result<(), DatastoreError>> + Send;
+
+    /// Returns whether `key` is present. Backends that can check presence
+    /// without fetching the value should override this.
+    fn exists(&mut self, key: &[u8]) -> impl Future<Output = Result<bool, DatastoreError>> + Send {
+	async move { Ok(self.get_item(key).await?.is_some()) }
+    }
+
+    /// Atomically sets `key` to `new` if its current value is `expected`
+    /// (with `None` meaning the key must be absent). Returns whether the
+    /// swap happened.
+    fn compare_and_swap(&mut self, key: Vec<u8>, expected: Option<&[u8]>, new: Vec<u8>) -> impl Future<Output = Result<bool, DatastoreError>> + Send;
+
+    /// Atomically deletes `key` if its current value is `expected`. Returns
+    /// whether the delete happened, which it doesn't if the key is absent.
+    fn delete_if_equals(&mut self, key: &[u8], expected: &[u8]) -> impl Future<Output = Result<bool, DatastoreError>> + Send;
+
+    /// Atomically sets `key` to `new`, clearing any TTL, and returns the
+    /// value it replaced, or `None` if it was absent. This is a loop of
+    /// `compare_and_swap`s, which fails with `Throttled` if other writers keep
+    /// beating it to the key. Backends that can return the old value from a
+    /// write should override this.
+    fn swap(&mut self, key: Vec<u8>, new: Vec<u8>) -> impl Future<Output = Result<Option<Vec<u8>>, DatastoreError>> + Send {
+	async move {
+	    for _ in 0..MAX_CAS_ATTEMPTS {
+		let current = self.get_item(&key).await?;
+		if self.compare_and_swap(key.clone(), current.as_deref(), new.clone()).await? {
+		    return Ok(current);
+		}
+	    }
+	    Err(DatastoreError::Throttled)
+	}
+    }
+
+    /// Atomically appends `suffix` to the value at `key`, treating a missing
+    /// key as empty. Like `swap`, this is a loop of `compare_and_swap`s by
+    /// default, which clears any TTL. Backends that can append in place
+    /// should override this, and keep the TTL if they can.
+    fn append(&mut self, key: Vec<u8>, suffix: Vec<u8>) -> impl Future<Output = Result<(), DatastoreError>> + Send {
+	async move {
+	    for _ in 0..MAX_CAS_ATTEMPTS {
+		let current = self.get_item(&key).await?;
+		let appended = [current.as_deref().unwrap_or_default(), &suffix].concat();
+		if self.compare_and_swap(key.clone(), current.as_deref(), appended).await? {
+		    return Ok(());
+		}
+	    }
+	    Err(DatastoreError::Throttled)
+	}
+    }
+
+    /// Atomically adds `delta` to the little-endian i64 counter stored at
+    /// `key`, treating a missing key as 0, and returns the new total.
+    /// Returns `None` without writing if the existing value isn't a counter.
+    fn increment(&mut self, key: Vec<u8>, delta: i64) -> impl Future<Output = Result<Option<i64>, DatastoreError>> + Send;
+
+    /// Writes every pair in `pairs`. Backends with a bulk write API should
+    /// override this to avoid a round trip per key.
+    fn put_items(&mut self, pairs: Vec<(Vec<u8>, Vec<u8>)>) -> impl Future<Output = Result<(), DatastoreError>> + Send {
+	async move {
+	    for (key, value) in pairs {
+		self.put_item(key, value).await?;
+	    }
+	    Ok(())
+	}
+    }
+
+    /// Returns the key/value pairs whose key starts with `prefix`, in key
+    /// order, stopping after the first `MAX_SCAN_ENTRIES`.
+    fn scan_prefix(&mut self, prefix: &[u8]) -> impl Future<Output = Result<Entries, DatastoreError>> + Send;
+
+    /// Returns how many keys are stored. Some backends can only estimate
+    /// this, or count keys that have expired but not yet been removed; see
+    /// their implementations.
+    fn count(&mut self) -> impl Future<Output = Result<u64, DatastoreError>> + Send;
+}
+
+/// An in-process datastore backed by a `HashMap`. Every invocation works on
+/// its own clone, so nothing written survives the request.
+#[derive(Clone, Debug, Default)]
+struct MemoryDatastore {
+    items: HashMap<Vec<u8>, Vec<u8>>,
+    /// When each key written with a TTL stops being visible.
+    expiries: HashMap<Vec<u8>, SystemTime>,
+}
+
+impl MemoryDatastore {
+    /// Starts out holding the items in the JSON file at
+    /// `WASMTEST_MEMORY_SEED`, if that's set, and empty otherwise. The file is
+    /// an object mapping each base64-encoded key to its base64-encoded value,
+    /// e.g. `{"Zm9v": "YmFy"}` for `foo` = `bar`.
+    fn from_env() -> Result<Self, Error> {
+	let mut memory = MemoryDatastore::default();
+	let Some(path) = env_opt::<String>("WASMTEST_MEMORY_SEED")? else {
+	    return Ok(memory);
+	};
+	let json = std::fs::read(&path).map_err(|e| format!("couldn't read {}: {}", path, e))?;
+	let seed: HashMap<String, String> = serde_json::from_slice(&json)
+	    .map_err(|e| format!("invalid seed file {}: {}", path, e))?;
+	for (key, value) in seed {
+	    let decode = |text: &str| BASE64_STANDARD.decode(text)
+		.map_err(|e| format!("invalid base64 {:?} in {}: {}", text, path, e));
+	    memory.items.insert(decode(&key)?, decode(&value)?);
+	}
+	tracing::info!(%path, items = memory.items.len(), "seeded memory datastore");
+	Ok(memory)
+    }
+
+    /// Drops `key` if its TTL has passed, so every operation sees expired
+    /// keys as absent.
+    fn expire(&mut self, key: &[u8]) {
+	if self.expiries.get(key).is_some_and(|expiry| *expiry <= SystemTime::now()) {
+	    self.expiries.remove(key);
+	    self.items.remove(key);
+	}
+    }
+
+    /// Drops every key whose TTL has passed.
+    fn expire_all(&mut self) {
+	let now = SystemTime::now();
+	let items = &mut self.items;
+	self.expiries.retain(|key, expiry| {
+	    let live = *expiry > now;
+	    if !live {
+		items.remove(key);
+	    }
+	    live
+	});
+    }
+}
+
+impl Datastore for MemoryDatastore {
+    const NAME: &'static str = "memory";
+
+    async fn put_item(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<(), DatastoreError> {
+	self.expiries.remove(&key);
+	self.items.insert(key, value);
+	Ok(())
+    }
+
+    async fn put_item_with_ttl(&mut self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<(), DatastoreError> {
+	self.expiries.insert(key.clone(), SystemTime::now() + ttl);
+	self.items.insert(key, value);
+	Ok(())
+    }
+
+    async fn get_item(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>, DatastoreError> {
+	self.expire(key);
+	Ok(self.items.get(key).cloned())
+    }
+
+    async fn delete_item(&mut self, key: &[u8]) -> Result<(), DatastoreError> {
+	self.expiries.remove(key);
+	self.items.remove(key);
+	Ok(())
+    }
+
+    async fn exists(&mut self, key: &[u8]) -> Result<bool, DatastoreError> {
+	self.expire(key);
+	Ok(self.items.contains_key(key))
+    }
+
+    async fn compare_and_swap(&mut self, key: Vec<u8>, expected: Option<&[u8]>, new: Vec<u8>) -> Result<bool, DatastoreError> {
+	// `&mut self` already rules out concurrent writers, so the check and
+	// the write can't interleave with anyone else's.
+	self.expire(&key);
+	if self.items.get(&key).map(Vec::as_slice) != expected {
+	    return Ok(false);
+	}
+	self.expiries.remove(&key);
+	self.items.insert(key, new);
+	Ok(true)
+    }
+
+    async fn append(&mut self, key: Vec<u8>, suffix: Vec<u8>) -> Result<(), DatastoreError> {
+	self.expire(&key);
+	self.items.entry(key).or_default().extend(suffix);
+	Ok(())
+    }
+
+    async fn delete_if_equals(&mut self, key: &[u8], expected: &[u8]) -> Result<bool, DatastoreError> {
+	// As with `compare_and_swap`, `&mut self` makes this atomic.
+	self.expire(key);
+	if self.items.get(key).map(Vec::as_slice) != Some(expected) {
+	    return Ok(false);
+	}
+	self.expiries.remove(key);
+	self.items.remove(key);
+	Ok(true)
+    }
+
+    async fn swap(&mut self, key: Vec<u8>, new: Vec<u8>) -> Result<Option<Vec<u8>>, DatastoreError> {
+	self.expire(&key);
+	self.expiries.remove(&key);
+	Ok(self.items.insert(key, new))
+    }
+
+    async fn increment(&mut self, key: Vec<u8>, delta: i64) -> Result<Option<i64>, DatastoreError> {
+	self.expire(&key);
+	let value = self.items.entry(key).or_insert_with(|| 0i64.to_le_bytes().to_vec());
+	let Ok(current) = value.as_slice().try_into() else {
+	    return Ok(None);
+	};
+	let total = i64::from_le_bytes(current).wrapping_add(delta);
+	*value = total.to_le_bytes().to_vec();
+	Ok(Some(total))
+    }
+
+    async fn scan_prefix(&mut self, prefix: &[u8]) -> Result<Vec<(Vec<u8>, Vec<u8>)>, DatastoreError> {
+	self.expire_all();
+	let mut entries: Vec<_> = self.items.iter()
+	    .filter(|(k, _)| k.starts_with(prefix))
+	    .map(|(k, v)| (k.clone(), v.clone()))
+	    .collect();
+	entries.sort();
+	entries.truncate(MAX_SCAN_ENTRIES);
+	Ok(entries)
+    }
+
+    async fn count(&mut self) -> Result<u64, DatastoreError> {
+	self.expire_all();
+	Ok(self.items.len() as u64)
+    }
+}
+
+/// Stores each key as its own item: the key in the binary `pk` partition-key
+/// attribute and the value in a binary `value` attribute, counters included.
+/// Cloning is cheap: clones share the
+/// client, and with it its credentials and connection pool, so `main` builds
+/// one for the container and each request works on a clone.
+#[derive(Clone)]
 struct DynamoDBDatastore {
     client: aws_sdk_dynamodb::Client,
     table_name: String,
+    /// The numeric attribute the table's TTL is configured on.
+    ttl_attribute: String,
+}
+
+impl DynamoDBDatastore {
+    /// The table's partition-key attribute, which holds the datastore key.
+    const KEY_ATTRIBUTE: &'static str = "pk";
+    /// The attribute holding the stored value.
+    const VALUE_ATTRIBUTE: &'static str = "value";
+    /// How many calls a batch read or write gets before the part DynamoDB
+    /// keeps leaving unprocessed fails with `Throttled`; see `back_off`.
+    const BATCH_ATTEMPTS: u32 = 8;
+    /// How long to wait before the first retry of a batch's unprocessed part.
+    const BATCH_BASE_DELAY: Duration = Duration::from_millis(50);
+    /// The longest wait between retries of a batch's unprocessed part.
+    const MAX_BATCH_DELAY: Duration = Duration::from_secs(1);
+    /// How many times `increment` reads and swaps a counter that other
+    /// writers keep changing before it fails with `Throttled`.
+    const INCREMENT_ATTEMPTS: u32 = 10;
+
+    fn new(client: aws_sdk_dynamodb::Client, table_name: String) -> Self {
+	DynamoDBDatastore { client, table_name, ttl_attribute: "ttl".into() }
+    }
+
+    /// Uses the table named by `WASMTEST_DYNAMODB_TABLE`, with credentials
+    /// and region from the standard AWS environment.
+    ///
+    /// `WASMTEST_DYNAMODB_ENDPOINT` points the client somewhere other than
+    /// AWS, such as DynamoDB Local. For example, with
+    /// `docker run -p 8000:8000 amazon/dynamodb-local` running:
+    ///
+    /// ```text
+    /// aws dynamodb create-table --endpoint-url http://localhost:8000 \
+    ///     --table-name wasmtest --billing-mode PAY_PER_REQUEST \
+    ///     --attribute-definitions AttributeName=pk,AttributeType=B \
+    ///     --key-schema AttributeName=pk,KeyType=HASH
+    /// WASMTEST_DATASTORE=dynamodb WASMTEST_DYNAMODB_TABLE=wasmtest \
+    ///     WASMTEST_DYNAMODB_ENDPOINT=http://localhost:8000 \
+    ///     AWS_REGION=us-east-1 AWS_ACCESS_KEY_ID=local AWS_SECRET_ACCESS_KEY=local \
+    ///     WASMTEST_LOCAL_PORT=8080 cargo run
+    /// ```
+    ///
+    /// DynamoDB Local accepts any credentials, but the SDK still needs some.
+    /// `cargo test --features dynamodb-local` runs the guest against it the
+    /// same way, starting the container and creating a table itself.
+    async fn from_env() -> Result<Self, Error> {
+	let table_name = env_opt::<String>("WASMTEST_DYNAMODB_TABLE")?
+	    .ok_or("WASMTEST_DYNAMODB_TABLE must be set to use the DynamoDB datastore")?;
+	let config = aws_config::load_defaults(aws_config::BehaviorVersion::latest()).await;
+	let mut client_config = aws_sdk_dynamodb::config::Builder::from(&config);
+	if let Some(endpoint) = env_opt::<String>("WASMTEST_DYNAMODB_ENDPOINT")? {
+	    tracing::info!(%endpoint, "overriding DynamoDB endpoint");
+	    client_config = client_config.endpoint_url(endpoint);
+	}
+	Ok(Self::new(aws_sdk_dynamodb::Client::from_conf(client_config.build()), table_name))
+    }
+
+    /// The partition-key value `key` is stored under. Keys are arbitrary
+    /// bytes, so this is a binary attribute rather than a string, which
+    /// would mangle (and collide) keys that aren't valid UTF-8.
+    fn key(key: &[u8]) -> aws_sdk_dynamodb::types::AttributeValue {
+	aws_sdk_dynamodb::types::AttributeValue::B(aws_sdk_dynamodb::primitives::Blob::new(key))
+    }
+
+    /// Extracts the stored value from `item`. Counters that older versions of
+    /// `increment` wrote are number attributes, which we hand back in the
+    /// same little-endian form `increment` writes now.
+    fn value(item: &HashMap<String, aws_sdk_dynamodb::types::AttributeValue>) -> Option<Vec<u8>> {
+	use aws_sdk_dynamodb::types::AttributeValue;
+	match item.get(Self::VALUE_ATTRIBUTE)? {
+	    AttributeValue::N(n) => n.parse::<i64>().ok().map(|n| n.to_le_bytes().to_vec()),
+	    AttributeValue::B(b) => Some(b.clone().into_inner()),
+	    _ => None,
+	}
+    }
+
+    /// Waits before the batch call that follows `attempt`, when DynamoDB left
+    /// `remaining` of the requests in it unprocessed: `BATCH_BASE_DELAY`,
+    /// doubled once per earlier retry up to `MAX_BATCH_DELAY`. Once a batch
+    /// has had `BATCH_ATTEMPTS` calls this fails with `Throttled` instead,
+    /// rather than hammering a table that keeps turning us away.
+    async fn back_off(attempt: u32, remaining: usize) -> Result<(), DatastoreError> {
+	if attempt >= Self::BATCH_ATTEMPTS {
+	    tracing::warn!(attempts = attempt, remaining, "DynamoDB kept leaving part of a batch unprocessed");
+	    return Err(DatastoreError::Throttled);
+	}
+	let delay = Self::BATCH_BASE_DELAY.saturating_mul(1 << (attempt - 1)).min(Self::MAX_BATCH_DELAY);
+	tracing::debug!(attempt, remaining, ?delay, "resending the unprocessed part of a DynamoDB batch");
+	tokio::time::sleep(delay).await;
+	Ok(())
+    }
+
+    /// Whether `item` carries a TTL that has already passed.
+    ///
+    /// DynamoDB deletes expired items eventually rather than at the moment
+    /// they expire, so reads have to filter them out themselves.
+    fn is_expired(&self, item: &HashMap<String, aws_sdk_dynamodb::types::AttributeValue>) -> bool {
+	let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+	item.get(&self.ttl_attribute)
+	    .and_then(|ttl| ttl.as_n().ok())
+	    .and_then(|ttl| ttl.parse::<u64>().ok())
+	    .is_some_and(|ttl| ttl <= now)
+    }
 }
 
 impl Datastore for DynamoDBDatastore {
-    async fn put_item(&mut self, key: Vec<u8>, value: Vec<u8>) {
+    const NAME: &'static str = "dynamodb";
+
+    async fn put_item(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<(), DatastoreError> {
 	use aws_sdk_dynamodb::{types::AttributeValue, primitives::Blob};
-	let key = String::from_utf8_lossy(&key).to_string();
-	self.client.put_item().table_name(self.table_name.clone()).item(key, AttributeValue::B(Blob::new(value))).send().await.expect("put_item");
+	self.client.put_item().table_name(&self.table_name)
+	    .item(Self::KEY_ATTRIBUTE, Self::key(&key))
+	    .item(Self::VALUE_ATTRIBUTE, AttributeValue::B(Blob::new(value)))
+	    .send().await.map_err(DatastoreError::from_dynamodb)?;
+	Ok(())
     }
 
-    async fn get_item(&mut self, key: &Vec<u8>) -> Option<Vec<u8>> {
+    async fn put_item_with_ttl(&mut self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<(), DatastoreError> {
 	use aws_sdk_dynamodb::{types::AttributeValue, primitives::Blob};
-	let key = String::from_utf8_lossy(&key).to_string();
-	let result = self.client.get_item().table_name(self.table_name.clone())
-	    .key(key.clone(), AttributeValue::B(Blob::new(b""))).send().await.expect("get_item");
-	match result.item.and_then(|i| i.get(&key).map(Clone::clone)) {
-	    Some(r) => match r.as_b().ok() {
-		Some(b) => Some(b.clone().into_inner()),
-		None => None,
-	    },
-	    None => None,
+	let expiry = (SystemTime::now() + ttl).duration_since(UNIX_EPOCH).unwrap().as_secs();
+	self.client.put_item().table_name(&self.table_name)
+	    .item(Self::KEY_ATTRIBUTE, Self::key(&key))
+	    .item(Self::VALUE_ATTRIBUTE, AttributeValue::B(Blob::new(value)))
+	    .item(self.ttl_attribute.clone(), AttributeValue::N(expiry.to_string()))
+	    .send().await.map_err(DatastoreError::from_dynamodb)?;
+	Ok(())
+    }
+
+    async fn put_items(&mut self, pairs: Vec<(Vec<u8>, Vec<u8>)>) -> Result<(), DatastoreError> {
+	use aws_sdk_dynamodb::{types::{AttributeValue, PutRequest, WriteRequest}, primitives::Blob};
+	// `batch_write_item` takes at most 25 requests at a time.
+	// `batch_write_item` rejects a batch that writes the same key twice, so
+	// only the last write to each key is sent, which is what writing them
+	// in order would have left behind.
+	let mut seen = std::collections::HashSet::new();
+	let mut pairs: Vec<_> = pairs.into_iter().rev().filter(|(key, _)| seen.insert(key.clone())).collect();
+	pairs.reverse();
+	for chunk in pairs.chunks(25) {
+	    let mut requests: Vec<_> = chunk.iter().map(|(key, value)| {
+		let put = PutRequest::builder()
+		    .item(Self::KEY_ATTRIBUTE, Self::key(key))
+		    .item(Self::VALUE_ATTRIBUTE, AttributeValue::B(Blob::new(value.clone())))
+		    .build().map_err(|e| DatastoreError::Backend(e.to_string()))?;
+		Ok(WriteRequest::builder().put_request(put).build())
+	    }).collect::<Result<_, DatastoreError>>()?;
+	    // DynamoDB may hand back part of a batch unprocessed, usually because
+	    // it's throttling the table, so resubmit the remainder after a
+	    // growing delay until the whole chunk is written; see `back_off`.
+	    for attempt in 1.. {
+		let result = self.client.batch_write_item()
+		    .request_items(&self.table_name, requests).send().await
+		    .map_err(DatastoreError::from_dynamodb)?;
+		requests = result.unprocessed_items
+		    .and_then(|mut unprocessed| unprocessed.remove(&self.table_name))
+		    .unwrap_or_default();
+		if requests.is_empty() {
+		    break;
+		}
+		Self::back_off(attempt, requests.len()).await?;
+	    }
+	}
+	Ok(())
+    }
+
+    async fn get_item(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>, DatastoreError> {
+	let result = self.client.get_item().table_name(&self.table_name)
+	    .key(Self::KEY_ATTRIBUTE, Self::key(key))
+	    .send().await.map_err(DatastoreError::from_dynamodb)?;
+	Ok(result.item.filter(|i| !self.is_expired(i)).and_then(|i| Self::value(&i)))
+    }
+
+    async fn batch_get_items(&mut self, keys: &[Vec<u8>]) -> Result<Values, DatastoreError> {
+	use aws_sdk_dynamodb::types::KeysAndAttributes;
+	// `batch_get_item` takes at most 100 distinct keys at a time and hands
+	// items back in no particular order, so we look each key's value up
+	// afterwards.
+	let mut distinct: Vec<&Vec<u8>> = keys.iter().collect();
+	distinct.sort();
+	distinct.dedup();
+	let mut found = HashMap::new();
+	for chunk in distinct.chunks(100) {
+	    let mut request = Some(KeysAndAttributes::builder()
+		.set_keys(Some(chunk.iter().map(|key| HashMap::from([(Self::KEY_ATTRIBUTE.to_string(), Self::key(key))])).collect()))
+		.build().map_err(|e| DatastoreError::Backend(e.to_string()))?);
+	    // As with writes, DynamoDB may leave some keys unprocessed, so keep
+	    // asking for the remainder, backing off in between, until it's read
+	    // them all.
+	    for attempt in 1.. {
+		let Some(keys) = request else {
+		    break;
+		};
+		let mut result = self.client.batch_get_item()
+		    .request_items(&self.table_name, keys).send().await
+		    .map_err(DatastoreError::from_dynamodb)?;
+		let items = result.responses.as_mut().and_then(|responses| responses.remove(&self.table_name));
+		for item in items.unwrap_or_default() {
+		    if self.is_expired(&item) {
+			continue;
+		    }
+		    let key = item.get(Self::KEY_ATTRIBUTE).and_then(|k| k.as_b().ok());
+		    if let (Some(key), Some(value)) = (key, Self::value(&item)) {
+			found.insert(key.clone().into_inner(), value);
+		    }
+		}
+		request = result.unprocessed_keys
+		    .and_then(|mut unprocessed| unprocessed.remove(&self.table_name))
+		    .filter(|keys| !keys.keys.is_empty());
+		if let Some(keys) = &request {
+		    Self::back_off(attempt, keys.keys.len()).await?;
+		}
+	    }
+	}
+	Ok(keys.iter().map(|key| found.get(key).cloned()).collect())
+    }
+
+    async fn delete_item(&mut self, key: &[u8]) -> Result<(), DatastoreError> {
+	// DynamoDB treats deleting a missing item as a success, so this is
+	// safe to call for keys that were never written.
+	self.client.delete_item().table_name(&self.table_name)
+	    .key(Self::KEY_ATTRIBUTE, Self::key(key))
+	    .send().await.map_err(DatastoreError::from_dynamodb)?;
+	Ok(())
+    }
+
+    async fn exists(&mut self, key: &[u8]) -> Result<bool, DatastoreError> {
+	// Only project the key and TTL so large values aren't transferred.
+	let result = self.client.get_item().table_name(&self.table_name)
+	    .key(Self::KEY_ATTRIBUTE, Self::key(key))
+	    .projection_expression("#pk, #ttl")
+	    .expression_attribute_names("#pk", Self::KEY_ATTRIBUTE)
+	    .expression_attribute_names("#ttl", self.ttl_attribute.clone())
+	    .send().await.map_err(DatastoreError::from_dynamodb)?;
+	Ok(result.item.is_some_and(|i| !self.is_expired(&i)))
+    }
+
+    async fn compare_and_swap(&mut self, key: Vec<u8>, expected: Option<&[u8]>, new: Vec<u8>) -> Result<bool, DatastoreError> {
+	use aws_sdk_dynamodb::{types::AttributeValue, primitives::Blob};
+	let request = self.client.put_item().table_name(&self.table_name)
+	    .item(Self::KEY_ATTRIBUTE, Self::key(&key))
+	    .item(Self::VALUE_ATTRIBUTE, AttributeValue::B(Blob::new(new)));
+	let request = match expected {
+	    None => request.condition_expression("attribute_not_exists(#pk)")
+		.expression_attribute_names("#pk", Self::KEY_ATTRIBUTE),
+	    Some(expected) => request.condition_expression("#v = :expected")
+		.expression_attribute_names("#v", Self::VALUE_ATTRIBUTE)
+		.expression_attribute_values(":expected", AttributeValue::B(Blob::new(expected))),
+	};
+	match request.send().await {
+	    Ok(_) => Ok(true),
+	    Err(e) if e.as_service_error().is_some_and(|e| e.is_conditional_check_failed_exception()) => Ok(false),
+	    Err(e) => Err(DatastoreError::from_dynamodb(e)),
+	}
+    }
+
+    async fn delete_if_equals(&mut self, key: &[u8], expected: &[u8]) -> Result<bool, DatastoreError> {
+	use aws_sdk_dynamodb::{types::AttributeValue, primitives::Blob};
+	let result = self.client.delete_item().table_name(&self.table_name)
+	    .key(Self::KEY_ATTRIBUTE, Self::key(key))
+	    .condition_expression("#v = :expected")
+	    .expression_attribute_names("#v", Self::VALUE_ATTRIBUTE)
+	    .expression_attribute_values(":expected", AttributeValue::B(Blob::new(expected)))
+	    .send().await;
+	match result {
+	    Ok(_) => Ok(true),
+	    Err(e) if e.as_service_error().is_some_and(|e| e.is_conditional_check_failed_exception()) => Ok(false),
+	    Err(e) => Err(DatastoreError::from_dynamodb(e)),
+	}
+    }
+
+    async fn swap(&mut self, key: Vec<u8>, new: Vec<u8>) -> Result<Option<Vec<u8>>, DatastoreError> {
+	use aws_sdk_dynamodb::{types::{AttributeValue, ReturnValue}, primitives::Blob};
+	// A put replaces the whole item, TTL included, and can hand back the
+	// item it replaced.
+	let result = self.client.put_item().table_name(&self.table_name)
+	    .item(Self::KEY_ATTRIBUTE, Self::key(&key))
+	    .item(Self::VALUE_ATTRIBUTE, AttributeValue::B(Blob::new(new)))
+	    .return_values(ReturnValue::AllOld)
+	    .send().await.map_err(DatastoreError::from_dynamodb)?;
+	Ok(result.attributes.filter(|i| !self.is_expired(i)).and_then(|i| Self::value(&i)))
+    }
+
+    async fn increment(&mut self, key: Vec<u8>, delta: i64) -> Result<Option<i64>, DatastoreError> {
+	use aws_sdk_dynamodb::{types::AttributeValue, primitives::Blob};
+	// `ADD` would be one round trip, but only works on number attributes,
+	// and counters are 8-byte little-endian blobs here as everywhere else.
+	// So read the counter and swap in the new total on the condition that
+	// it hasn't changed, trying again if another writer got in first.
+	for _ in 0..Self::INCREMENT_ATTEMPTS {
+	    let result = self.client.get_item().table_name(&self.table_name)
+		.key(Self::KEY_ATTRIBUTE, Self::key(&key))
+		.consistent_read(true)
+		.send().await.map_err(DatastoreError::from_dynamodb)?;
+	    let item = result.item;
+	    // An expired item DynamoDB hasn't swept yet counts as missing.
+	    let live = item.as_ref().filter(|i| !self.is_expired(i));
+	    let current = match live {
+		Some(item) => match Self::value(item).and_then(|value| <[u8; 8]>::try_from(value).ok()) {
+		    Some(current) => i64::from_le_bytes(current),
+		    None => return Ok(None),
+		},
+		None => 0,
+	    };
+	    let total = current.wrapping_add(delta);
+	    let request = self.client.update_item().table_name(&self.table_name)
+		.key(Self::KEY_ATTRIBUTE, Self::key(&key))
+		.expression_attribute_names("#v", Self::VALUE_ATTRIBUTE)
+		.expression_attribute_values(":total", AttributeValue::B(Blob::new(total.to_le_bytes())));
+	    // A live counter keeps its TTL, and an expired one starts again
+	    // from 0 without one.
+	    let request = match live {
+		Some(_) => request.update_expression("SET #v = :total"),
+		None => request.update_expression("SET #v = :total REMOVE #ttl")
+		    .expression_attribute_names("#ttl", self.ttl_attribute.clone()),
+	    };
+	    // Only write over the value we read, whatever form it was in.
+	    let request = match item.as_ref().and_then(|i| i.get(Self::VALUE_ATTRIBUTE)) {
+		Some(stored) => request.condition_expression("#v = :stored")
+		    .expression_attribute_values(":stored", stored.clone()),
+		None => request.condition_expression("attribute_not_exists(#v)"),
+	    };
+	    match request.send().await {
+		Ok(_) => return Ok(Some(total)),
+		Err(e) if e.as_service_error().is_some_and(|e| e.is_conditional_check_failed_exception()) => continue,
+		Err(e) => return Err(DatastoreError::from_dynamodb(e)),
+	    }
+	}
+	tracing::warn!(attempts = Self::INCREMENT_ATTEMPTS, "a DynamoDB counter kept changing under increment");
+	Err(DatastoreError::Throttled)
+    }
+
+    async fn scan_prefix(&mut self, prefix: &[u8]) -> Result<Vec<(Vec<u8>, Vec<u8>)>, DatastoreError> {
+	// `begins_with` can only be a key condition on a sort key, so this is
+	// a filtered scan. The filter applies after DynamoDB reads each page,
+	// so pages can come back empty. Scans go in hash order rather than key
+	// order, so to return the first matches by key, like the other
+	// backends, we read to the end of the table, keeping only the lowest
+	// keys seen so far.
+	let mut entries = Vec::new();
+	let mut start_key = None;
+	loop {
+	    let result = self.client.scan().table_name(&self.table_name)
+		.filter_expression("begins_with(#pk, :prefix)")
+		.expression_attribute_names("#pk", Self::KEY_ATTRIBUTE)
+		.expression_attribute_values(":prefix", Self::key(prefix))
+		.set_exclusive_start_key(start_key).send().await
+		.map_err(DatastoreError::from_dynamodb)?;
+	    for item in result.items.unwrap_or_default() {
+		if self.is_expired(&item) {
+		    continue;
+		}
+		let key = item.get(Self::KEY_ATTRIBUTE).and_then(|k| k.as_b().ok());
+		if let (Some(key), Some(value)) = (key, Self::value(&item)) {
+		    entries.push((key.clone().into_inner(), value));
+		}
+	    }
+	    if entries.len() >= 2 * MAX_SCAN_ENTRIES {
+		entries.sort();
+		entries.truncate(MAX_SCAN_ENTRIES);
+	    }
+	    start_key = result.last_evaluated_key;
+	    if start_key.is_none() {
+		break;
+	    }
 	}
+	entries.sort();
+	entries.truncate(MAX_SCAN_ENTRIES);
+	Ok(entries)
+    }
+
+    /// Returns the table's `ItemCount`, which DynamoDB only updates about
+    /// every six hours, so it's approximate: it lags recent writes and
+    /// counts expired items until DynamoDB's TTL sweep deletes them.
+    /// Counting exactly would mean scanning, and paying for, every item.
+    async fn count(&mut self) -> Result<u64, DatastoreError> {
+	let result = self.client.describe_table().table_name(&self.table_name)
+	    .send().await.map_err(DatastoreError::from_dynamodb)?;
+	Ok(result.table.and_then(|table| table.item_count).unwrap_or(0) as u64)
     }
 }
 
-#[derive(Debug)]
+/// Encodes `bytes` as lowercase hex, for backends that need keys to be
+/// printable names. Hex sorts in the same order as the bytes it encodes.
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Decodes `to_hex`'s output, returning `None` if `hex` isn't valid hex.
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+	return None;
+    }
+    (0..hex.len()).step_by(2).map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok()).collect()
+}
+
+/// Checks that the `len` bytes at `base` lie entirely inside guest memory,
+/// so a buggy or malicious guest gets a descriptive trap rather than taking
+/// the host down with it.
+fn check_bounds(store: impl AsContext, memory: &Memory, base: GuestPtr, len: GuestPtr) -> Result<()> {
+    match base.checked_add(len) {
+	Some(end) if end as usize <= memory.data_size(&store) => Ok(()),
+	_ => Err(wasmtime::Error::msg(format!(
+	    "guest buffer at {:#x} of length {} is outside linear memory ({} bytes)",
+	    base, len, memory.data_size(&store)))),
+    }
+}
+
+/// Mismatched modules, such as ones built without exporting their memory,
+/// fail with this rather than a panic.
+const MISSING_MEMORY: &str = "guest doesn't export its linear memory as `memory`";
+
+/// The guest's linear memory, which host functions read arguments from and
+/// write results to.
+fn guest_memory<T>(caller: &mut Caller<'_, T>) -> Result<Memory> {
+    caller.get_export("memory").and_then(|m| m.into_memory())
+	.ok_or_else(|| wasmtime::Error::msg(MISSING_MEMORY))
+}
+
+/// Reads the `WasmBytes` base/len pair stored at `ptr` in guest memory and
+/// copies out the bytes it refers to.
+fn read_wasm_bytes<T>(caller: &mut Caller<'_, T>, memory: &Memory, ptr: GuestPtr) -> Result<Vec<u8>> {
+    let mut header = [0; WASM_BYTES_SIZE];
+    check_bounds(&*caller, memory, ptr, WASM_BYTES_SIZE as GuestPtr)?;
+    memory.read(caller.as_context_mut(), ptr as usize, &mut header)?;
+    let (base, len) = split_wasm_bytes(&header);
+
+    check_bounds(&*caller, memory, base, len)?;
+    let mut bytes = vec![0; len as usize];
+    memory.read(caller.as_context_mut(), base as usize, &mut bytes)?;
+    Ok(bytes)
+}
+
+/// Copies `bytes` into a buffer obtained from the guest's exported `alloc`
+/// and points the guest's `WasmBytes` at `result_base` to it.
+///
+/// Every buffer handed to the guest this way is its own allocation, so
+/// results from earlier host calls stay intact, and the guest owns it and
+/// frees it with `dealloc` when it's done. The guest's allocator grows
+/// linear memory as needed; if it can't, this returns `STATUS_OUT_OF_MEMORY`
+/// without writing anything.
+async fn write_wasm_bytes<T: Send>(caller: &mut Caller<'_, T>, memory: &Memory, result_base: GuestPtr, bytes: &[u8]) -> Result<u32> {
+    let alloc = caller.get_export("alloc").and_then(|e| e.into_func())
+	.ok_or_else(|| wasmtime::Error::msg("guest does not export `alloc`"))?
+	.typed::<GuestPtr, GuestPtr>(&caller)?;
+    let result_offset = alloc.call_async(&mut *caller, bytes.len() as GuestPtr).await?;
+    if result_offset == 0 {
+	tracing::warn!(len = bytes.len(), "guest couldn't allocate a result buffer");
+	return Ok(STATUS_OUT_OF_MEMORY);
+    }
+    check_bounds(&*caller, memory, result_offset, bytes.len() as GuestPtr)?;
+    check_bounds(&*caller, memory, result_base, WASM_BYTES_SIZE as GuestPtr)?;
+
+    memory.write(caller.as_context_mut(), result_offset as usize, bytes)?;
+    memory.write(caller.as_context_mut(), result_base as usize, &result_offset.to_le_bytes())?;
+    memory.write(caller.as_context_mut(), result_base as usize + PTR_SIZE, &((bytes.len() as GuestPtr).to_le_bytes()))?;
+    Ok(0)
+}
+
+/// Encodes key/value pairs the way the guest's `scan_prefix` and
+/// `write_batch` expect: a little-endian u32 count, followed by each key and
+/// value as a u32 length and its bytes.
+fn encode_pairs(pairs: &[(Vec<u8>, Vec<u8>)]) -> Vec<u8> {
+    let mut buf = Vec::new();
+    buf.extend_from_slice(&(pairs.len() as u32).to_le_bytes());
+    for (key, value) in pairs {
+	buf.extend_from_slice(&(key.len() as u32).to_le_bytes());
+	buf.extend_from_slice(key);
+	buf.extend_from_slice(&(value.len() as u32).to_le_bytes());
+	buf.extend_from_slice(value);
+    }
+    buf
+}
+
+/// Decodes an `encode_pairs` buffer, returning `None` if it is truncated.
+fn decode_pairs(mut buf: &[u8]) -> Option<Vec<(Vec<u8>, Vec<u8>)>> {
+    decode_pairs_from(&mut buf)
+}
+
+/// Decodes `encode_pairs` output from the front of `buf`, leaving `buf`
+/// pointing just past it.
+fn decode_pairs_from(buf: &mut &[u8]) -> Option<Vec<(Vec<u8>, Vec<u8>)>> {
+    let count = u32::from_le_bytes(take(buf, 4)?.try_into().ok()?);
+    let mut pairs = Vec::new();
+    for _ in 0..count {
+	let key = take_field(buf)?.to_vec();
+	let value = take_field(buf)?.to_vec();
+	pairs.push((key, value));
+    }
+    Some(pairs)
+}
+
+/// Decodes the guest's `read_many` keys: a little-endian u32 count followed
+/// by each key as a u32 length and its bytes. Returns `None` if the buffer is
+/// truncated.
+fn decode_keys(mut buf: &[u8]) -> Option<Vec<Vec<u8>>> {
+    let count = u32::from_le_bytes(take(&mut buf, 4)?.try_into().ok()?);
+    (0..count).map(|_| take_field(&mut buf).map(<[u8]>::to_vec)).collect()
+}
+
+/// Encodes `read_many` results: a u32 count followed by each value as a u32
+/// length and its bytes, with a length of `u32::MAX` marking a missing key.
+fn encode_values(values: &[Option<Vec<u8>>]) -> Vec<u8> {
+    let mut buf = Vec::new();
+    buf.extend_from_slice(&(values.len() as u32).to_le_bytes());
+    for value in values {
+	match value {
+	    Some(value) => {
+		buf.extend_from_slice(&(value.len() as u32).to_le_bytes());
+		buf.extend_from_slice(value);
+	    }
+	    None => buf.extend_from_slice(&u32::MAX.to_le_bytes()),
+	}
+    }
+    buf
+}
+
+/// Splits `len` bytes off the front of `buf`, or returns `None` if it's
+/// shorter than that.
+fn take<'a>(buf: &mut &'a [u8], len: usize) -> Option<&'a [u8]> {
+    let head = buf.get(..len)?;
+    *buf = &buf[len..];
+    Some(head)
+}
+
+/// Splits a field encoded as a little-endian u32 length and its bytes off
+/// the front of `buf`.
+fn take_field<'a>(buf: &mut &'a [u8]) -> Option<&'a [u8]> {
+    let len = u32::from_le_bytes(take(buf, 4)?.try_into().ok()?) as usize;
+    take(buf, len)
+}
+
 struct MyState<D: Datastore> {
     database: D,
+    /// Caps how far the guest can grow its memory and tables. Growing past
+    /// them fails the way running out of memory would, so the guest's `alloc`
+    /// returns null rather than the host running out.
+    limits: StoreLimits,
+    /// The time `current_time_millis` reports, if it's pinned rather than
+    /// following the host clock.
+    fixed_time_millis: Option<u64>,
+    /// Where `fill_random` draws from.
+    rng: StdRng,
+    /// Makes the requests guests send through `http_fetch`.
+    http: HttpFetcher,
+    /// Backs the WASI imports, for runtimes that provide them.
+    wasi: Option<WasiP1Ctx>,
+    /// The response status the guest asked for with `set_status`.
+    status: Option<u16>,
+    /// The response headers the guest has added with `set_header`.
+    headers: HeaderMap,
+    /// The request being handled, minus its body, which goes straight into
+    /// guest memory.
+    request: lambda_http::http::request::Parts,
+    /// The route the request matched, if the runtime routes requests.
+    route: Option<RouteMatch>,
+    /// The metrics the guest has emitted with `emit_metric`, flushed once its
+    /// entrypoint returns.
+    metrics: Metrics,
+    /// What the guest said when it panicked, through `report_panic`, to log
+    /// along with the trap that follows.
+    panic: Option<String>,
+    /// Prefixes every key the guest touches, so tenants sharing a datastore
+    /// can't see each other's keys; see `Runtime::namespace`.
+    namespace: Option<Vec<u8>>,
+    /// The invocation's ID, which the guest's log lines carry so they can be
+    /// matched to ours.
+    request_id: String,
+    /// How many datastore calls the guest has made, and how long they took
+    /// altogether, for the event logged once it's done.
+    datastore_calls: u32,
+    datastore_time: Duration,
 }
 
-async fn function_handler(event: Request) -> Result<Response<Body>, Error> {
-    let body = &event.body();
+/// Awaits the datastore call `$call`, adding how long it took to
+/// `$state`'s totals for the invocation.
+macro_rules! timed {
+    ($state:ident, $call:expr) => {{
+	let started = Instant::now();
+	let result = $call.await;
+	$state.datastore_time += started.elapsed();
+	$state.datastore_calls += 1;
+	result
+    }};
+}
 
-    // First the wasm module needs to be compiled. This is done with a global
-    // "compilation environment" within an `Engine`. Note that engines can be
-    // further configured through `Config` if desired instead of using the
-    // default like this is here.
-    let mut config = Config::new();
-    config.async_support(true);
-    let engine = Engine::new(&config)?;
-    let module = Module::from_file(&engine, "../wasmtest/target/wasm32-unknown-unknown/release/wasmtest.wasm")?;
+impl<D: Datastore> MyState<D> {
+    /// The current time as guests see it, in milliseconds since the Unix
+    /// epoch.
+    fn now_millis(&self) -> u64 {
+	self.fixed_time_millis.unwrap_or_else(|| {
+	    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64
+	})
+    }
 
-    // After a module is compiled we create a `Store` which will contain
-    // instantiated modules and other items like host functions. A Store
-    // contains an arbitrary piece of host information, and we use `MyState`
-    // here.
+    /// The key the guest's `key` is stored under.
+    fn stored_key(&self, key: &[u8]) -> Vec<u8> {
+	match &self.namespace {
+	    Some(namespace) => [namespace.as_slice(), key].concat(),
+	    None => key.to_vec(),
+	}
+    }
 
-    let mut state = MyState {
-	database: HashMap::new(),
-    };
+    /// `entries` from a scan of `stored_key`s, with the keys as the guest
+    /// knows them.
+    fn guest_entries(&self, entries: Entries) -> Entries {
+	let skip = self.namespace.as_ref().map_or(0, Vec::len);
+	entries.into_iter().map(|(key, value)| (key[skip..].to_vec(), value)).collect()
+    }
+}
 
-    state.database.insert(b"foo".into(), b"bar".into());
+/// Marks a guest result that's a whole response rather than just a body. It
+/// starts with `0xff`, which never appears in UTF-8 text, so text bodies
+/// can't be mistaken for one.
+const RESPONSE_MAGIC: &[u8] = b"\xffwtr";
 
-    let mut store = Store::new(
-        &engine,
-	state,
-    );
+/// Decodes the rest of a result that starts with `RESPONSE_MAGIC`: a response
+/// in `http::encode_response`'s encoding. Returns `None` if it's truncated or
+/// its status or any header isn't valid in HTTP, the same checks
+/// `set_status` and `set_header` make.
+fn decode_guest_response(buf: &[u8]) -> Option<(u16, HeaderMap, Vec<u8>)> {
+    let response = http::decode_response(buf).filter(|response| (100..=999).contains(&response.status))?;
+    let headers = response.headers.iter()
+	.map(|(name, value)| Some((HeaderName::from_bytes(name).ok()?, HeaderValue::from_bytes(value).ok()?)))
+	.collect::<Option<HeaderMap>>()?;
+    Some((response.status, headers, response.body))
+}
+
+/// The longest one `sleep_millis` call waits, however long the guest asks
+/// for, so a guest that backs off can't hold a request open by itself.
+const MAX_SLEEP: Duration = Duration::from_secs(5);
+
+/// The most bytes of a guest's panic message we keep.
+const MAX_PANIC_LEN: usize = 4096;
+
+/// Set once this container has handled an invocation, so the first one can
+/// be told apart as a cold start.
+static WARM: AtomicBool = AtomicBool::new(false);
+
+/// A plain-text error response with the given status.
+fn error_response(status: u16, message: String) -> Result<Response<Body>, Error> {
+    let resp = Response::builder()
+	.status(status)
+	.header("content-type", "text/plain")
+	.body(message.into())
+	.map_err(Box::new)?;
+    Ok(resp)
+}
+
+/// What every invocation shares: the compiled guest, the limits it runs
+/// under and the datastore it talks to.
+#[derive(Clone)]
+struct Runtime<D: Datastore> {
+    engine: Engine,
+    /// The compiled guest with its imports already resolved, so each
+    /// invocation only has to instantiate it.
+    instance_pre: InstancePre<MyState<D>>,
+    /// Cloned into each invocation's store. Backends that talk to a server
+    /// share their connection between clones.
+    datastore: D,
+    /// The fuel each invocation starts with. A guest that burns through it
+    /// traps with `Trap::OutOfFuel` rather than running forever.
+    fuel: u64,
+    /// How long each invocation may run before it traps with
+    /// `Trap::Interrupt`. Unlike fuel this counts wall clock time, including
+    /// time spent waiting on host calls.
+    timeout: Duration,
+    /// The most bytes each invocation's linear memory may grow to.
+    memory_limit: usize,
+    /// The most elements each invocation's tables may grow to.
+    table_limit: u32,
+    /// Pins the time guests see, so tests can be deterministic.
+    fixed_time_millis: Option<u64>,
+    /// Seeds each invocation's RNG, so tests can be deterministic. Without
+    /// it every invocation is seeded from the OS.
+    random_seed: Option<u64>,
+    /// Shared by every invocation, so connections to the same hosts get
+    /// reused.
+    http: HttpFetcher,
+    /// Whether guests are `wasm32-wasip1` modules, which get the WASI
+    /// imports on top of our own, rather than `wasm32-unknown-unknown` ones.
+    wasi: bool,
+    /// Picks the export that handles each request. Without one, every
+    /// request goes to `entry`.
+    router: Option<Arc<Router>>,
+    /// The CloudWatch namespace guests' metrics are reported under.
+    metrics_namespace: String,
+    /// The request header naming the tenant each request belongs to, if
+    /// tenants' keys are kept apart.
+    tenant_header: Option<HeaderName>,
+    /// The content type of responses whose guest didn't set one.
+    default_content_type: HeaderValue,
+}
+
+impl<D: Datastore + 'static> Runtime<D> {
+    /// Links the compiled guest against our host functions and wraps it with
+    /// the limits configured by the `WASMTEST_FUEL`, `WASMTEST_TIMEOUT_MS`,
+    /// `WASMTEST_MEMORY_LIMIT` and `WASMTEST_TABLE_LIMIT` environment
+    /// variables. `WASMTEST_FIXED_TIME_MS` pins the clock guests see, and
+    /// `WASMTEST_RANDOM_SEED` seeds their randomness. See
+    /// `HttpFetcher::from_env` for what configures outbound HTTP.
+    /// `WASMTEST_WASI=true` links the WASI imports for `wasm32-wasip1`
+    /// guests, and `WASMTEST_ROUTES` routes requests to exports other than
+    /// `entry` (see `router`). `WASMTEST_METRICS_NAMESPACE` names the
+    /// CloudWatch namespace guests' metrics go to (see `metrics`), and
+    /// `WASMTEST_TENANT_HEADER` the header that keeps tenants' keys apart
+    /// (see `Runtime::namespace`). `WASMTEST_DEFAULT_CONTENT_TYPE` replaces
+    /// `text/html` as the content type of responses that don't set one.
+    ///
+    /// The linker only hands the module the functions it actually imports, so
+    /// guests that don't use every datastore operation still link.
+    fn from_env(engine: Engine, module: Module, datastore: D) -> Result<Self, Error> {
+	let wasi = env_or("WASMTEST_WASI", false)?;
+	let instance_pre = linker(&engine, wasi)?.instantiate_pre(&module).map_err(|e| {
+	    if wasi {
+		e
+	    } else {
+		e.context("couldn't link the guest; set WASMTEST_WASI=true if it targets wasm32-wasip1")
+	    }
+	})?;
+	match module.get_export("memory") {
+	    Some(ExternType::Memory(memory)) if memory.is_64() != cfg!(feature = "memory64") => {
+		let (guest, runner) = if memory.is_64() { (64, 32) } else { (32, 64) };
+		let fix = if memory.is_64() { "with" } else { "without" };
+		return Err(format!(
+		    "guest uses a {}-bit memory, but this runner was built for {}-bit ones; rebuild it {} the `memory64` feature",
+		    guest, runner, fix).into());
+	    }
+	    Some(ExternType::Memory(_)) => {}
+	    _ => return Err(MISSING_MEMORY.into()),
+	}
+	let router = env_opt::<String>("WASMTEST_ROUTES")?.map(|routes| Router::parse(&routes)).transpose()?;
+	if let Some(router) = &router {
+	    for export in router.exports() {
+		if !matches!(module.get_export(export), Some(ExternType::Func(_))) {
+		    return Err(format!("routed export {:?} isn't a function the guest exports", export).into());
+		}
+	    }
+	}
+	Ok(Runtime {
+	    instance_pre,
+	    engine,
+	    datastore,
+	    fuel: env_or("WASMTEST_FUEL", DEFAULT_FUEL)?,
+	    timeout: Duration::from_millis(env_or("WASMTEST_TIMEOUT_MS", DEFAULT_TIMEOUT_MS)?),
+	    memory_limit: memory_limit()?,
+	    table_limit: table_limit()?,
+	    fixed_time_millis: env_opt("WASMTEST_FIXED_TIME_MS")?,
+	    random_seed: env_opt("WASMTEST_RANDOM_SEED")?,
+	    http: HttpFetcher::from_env()?,
+	    wasi,
+	    router: router.map(Arc::new),
+	    metrics_namespace: env_or("WASMTEST_METRICS_NAMESPACE", metrics::DEFAULT_NAMESPACE.to_string())?,
+	    tenant_header: env_opt::<String>("WASMTEST_TENANT_HEADER")?
+		.map(|name| HeaderName::from_bytes(name.as_bytes()).map_err(|e| format!("invalid WASMTEST_TENANT_HEADER {:?}: {}", name, e)))
+		.transpose()?,
+	    default_content_type: env_opt::<String>("WASMTEST_DEFAULT_CONTENT_TYPE")?
+		.map(|value| HeaderValue::from_str(&value).map_err(|e| format!("invalid WASMTEST_DEFAULT_CONTENT_TYPE {:?}: {}", value, e)))
+		.transpose()?
+		.unwrap_or(HeaderValue::from_static("text/html")),
+	})
+    }
+
+    /// The namespace `request`'s keys live in, or an error message if the
+    /// runtime keeps tenants apart and `request` doesn't say whose it is.
+    ///
+    /// With `WASMTEST_TENANT_HEADER` set, every key a guest touches is
+    /// stored behind the value of that header followed by a NUL byte, which
+    /// header values can't contain, so no tenant's keys overlap another's.
+    /// Guests see their keys as they wrote them, `scan_prefix_key` included.
+    /// `count_keys` can only count every tenant's keys, so it fails instead.
+    fn namespace(&self, request: &lambda_http::http::request::Parts) -> Result<Option<Vec<u8>>, String> {
+	let Some(header) = &self.tenant_header else {
+	    return Ok(None);
+	};
+	match request.headers.get(header).filter(|tenant| !tenant.is_empty()) {
+	    Some(tenant) => Ok(Some([tenant.as_bytes(), b"\0"].concat())),
+	    None => Err(format!("missing {} header", header)),
+	}
+    }
+
+    /// Builds the `Store` one request runs in, with `request` and `route`
+    /// for the request host functions to report and a clone of the
+    /// datastore, whose keys are kept in `namespace`. The guest's log lines
+    /// are tagged with `request_id`.
+    fn store(&self, request: lambda_http::http::request::Parts, route: Option<RouteMatch>, namespace: Option<Vec<u8>>, request_id: String) -> Result<Store<MyState<D>>> where D: Clone {
+	// Each request gets a fresh `Store`, which will contain instantiated
+	// modules and other items like host functions, so no guest state leaks
+	// from one invocation into the next. A Store contains an arbitrary piece
+	// of host information, and we use `MyState` here.
+	let state = MyState {
+	    database: self.datastore.clone(),
+	    limits: StoreLimitsBuilder::new()
+		.memory_size(self.memory_limit)
+		.table_elements(self.table_limit)
+		.build(),
+	    fixed_time_millis: self.fixed_time_millis,
+	    rng: match self.random_seed {
+		Some(seed) => StdRng::seed_from_u64(seed),
+		None => StdRng::from_entropy(),
+	    },
+	    http: self.http.clone(),
+	    // WASI guests' stdout and stderr go to ours, so they end up in the
+	    // function's logs.
+	    wasi: self.wasi.then(|| WasiCtxBuilder::new().inherit_stdout().inherit_stderr().build_p1()),
+	    status: None,
+	    headers: HeaderMap::new(),
+	    request,
+	    route,
+	    metrics: Metrics::default(),
+	    panic: None,
+	    namespace,
+	    request_id,
+	    datastore_calls: 0,
+	    datastore_time: Duration::ZERO,
+	};
 
-    // Our wasm module we'll be instantiating requires one imported function.
-    // the function takes no parameters and returns no results. We create a host
-    // implementation of that function here, and the `caller` parameter here is
-    // used to get access to our original `MyState` value.
-    let write_key_func = Func::wrap4_async(&mut store, |mut caller: Caller<'_, _>, key_base: u32, key_len: u32, value_base: u32, value_len: u32| {
+	let mut store = Store::new(&self.engine, state);
+	store.limiter(|state| &mut state.limits);
+	store.set_fuel(self.fuel)?;
+	store.set_epoch_deadline((self.timeout.as_millis() / EPOCH_TICK.as_millis()).max(1) as u64);
+	Ok(store)
+    }
+}
+
+/// Builds the `Linker` holding the host functions guests can import,
+/// including WASI's if `wasi` is set.
+fn linker<D: Datastore + 'static>(engine: &Engine, wasi: bool) -> Result<Linker<MyState<D>>> {
+    // Our wasm module imports one host function per datastore operation, which
+    // we register by name in a `Linker`. The guest passes each `WasmBytes`
+    // argument by reference (a pointer to its base/len pair in linear memory),
+    // and the `caller` parameter is used to get access to that memory and to
+    // our original `MyState` value. Operations with a status return hand a
+    // `DatastoreError::status` code back to the guest on failure; the rest
+    // trap with the error, which `function_handler` turns into a 500 response.
+    let mut linker: Linker<MyState<D>> = Linker::new(engine);
+    linker.func_wrap2_async("env", "write_key", |mut caller: Caller<'_, _>, key_ptr: GuestPtr, value_ptr: GuestPtr| {
 	Box::new(async move {
-	    let memory = caller.get_export("memory").and_then(|m| m.into_memory()).unwrap();
-	    let mut key = Vec::new();
-	    key.resize(key_len as usize, 0);
-	    memory.read(caller.as_context_mut(), key_base as usize, key.as_mut_slice()).unwrap();
+	    let memory = guest_memory(&mut caller)?;
+	    let key = read_wasm_bytes(&mut caller, &memory, key_ptr)?;
+	    let value = read_wasm_bytes(&mut caller, &memory, value_ptr)?;
+
+	    let state = caller.data_mut();
 
-	    let mut value = Vec::new();
-	    value.resize(value_len as usize, 0);
-	    memory.read(caller.as_context_mut(), value_base as usize, value.as_mut_slice()).unwrap();
+	    tracing::debug!(backend = state.database.name(), key_len = key.len(), value_len = value.len(), "write_key");
+	    tracing::trace!(key = %String::from_utf8_lossy(&key), value = %String::from_utf8_lossy(&value), "write_key");
+	    Ok(status(timed!(state, state.database.put_item(state.stored_key(&key), value))))
+	})
+    })?;
+    linker.func_wrap2_async("env", "append_key", |mut caller: Caller<'_, _>, key_ptr: GuestPtr, suffix_ptr: GuestPtr| {
+	Box::new(async move {
+	    let memory = guest_memory(&mut caller)?;
+	    let key = read_wasm_bytes(&mut caller, &memory, key_ptr)?;
+	    let suffix = read_wasm_bytes(&mut caller, &memory, suffix_ptr)?;
 
 	    let state = caller.data_mut();
 
-	    println!("writing {:?} {:?}", String::from_utf8(key.clone()), String::from_utf8(value.clone()));
-	    state.database.insert(key, value);
+	    tracing::debug!(backend = state.database.name(), key_len = key.len(), suffix_len = suffix.len(), "append_key");
+	    tracing::trace!(key = %String::from_utf8_lossy(&key), suffix = %String::from_utf8_lossy(&suffix), "append_key");
+	    Ok(status(timed!(state, state.database.append(state.stored_key(&key), suffix))))
 	})
-    });
-    let read_key_func = Func::wrap3_async(&mut store, |mut caller: Caller<'_, _>, result_base: u32, key_base: u32, key_len: u32| {
+    })?;
+    linker.func_wrap3_async("env", "write_key_with_ttl", |mut caller: Caller<'_, _>, key_ptr: GuestPtr, value_ptr: GuestPtr, ttl_secs: u64| {
 	Box::new(async move {
-	    let memory = caller.get_export("memory").and_then(|m| m.into_memory()).unwrap();
-	    let mut key = Vec::new();
-	    key.resize(key_len as usize, 0);
-	    memory.read(caller.as_context_mut(), key_base as usize, key.as_mut_slice()).unwrap();
+	    let memory = guest_memory(&mut caller)?;
+	    let key = read_wasm_bytes(&mut caller, &memory, key_ptr)?;
+	    let value = read_wasm_bytes(&mut caller, &memory, value_ptr)?;
 
-	    let state = caller.data();
-	    let result = state.database.get(&key).unwrap_or(&Vec::new()).clone();
+	    let state = caller.data_mut();
 
-	    let result_offset = memory.data_size(caller.as_context()) - result.len();
-	    memory.write(caller.as_context_mut(), result_offset, result.as_slice()).unwrap();
-	    memory.write(caller.as_context_mut(), result_base as usize, &((result_offset as u32).to_le_bytes())).unwrap();
-	    memory.write(caller.as_context_mut(), result_base as usize + 4, &((result.len() as u32).to_le_bytes())).unwrap();
+	    tracing::debug!(backend = state.database.name(), key_len = key.len(), value_len = value.len(), ttl_secs, "write_key_with_ttl");
+	    tracing::trace!(key = %String::from_utf8_lossy(&key), value = %String::from_utf8_lossy(&value), "write_key_with_ttl");
+	    Ok(status(timed!(state, state.database.put_item_with_ttl(state.stored_key(&key), value, Duration::from_secs(ttl_secs)))))
+	})
+    })?;
+    // `read_key` returns 0 and fills in the guest's result `WasmBytes` when the
+    // key exists, and returns 1 without touching it when the key is missing,
+    // so guests can tell an absent key from an empty value.
+    linker.func_wrap2_async("env", "read_key", |mut caller: Caller<'_, _>, key_ptr: GuestPtr, result_base: GuestPtr| {
+	Box::new(async move {
+	    let memory = guest_memory(&mut caller)?;
+	    let key = read_wasm_bytes(&mut caller, &memory, key_ptr)?;
 
-	    println!("reading {:?} {:?}", String::from_utf8(key.clone()), String::from_utf8(result));
+	    let state = caller.data_mut();
+	    let result = match timed!(state, state.database.get_item(&state.stored_key(&key))) {
+		Ok(Some(result)) => result,
+		Ok(None) => {
+		    tracing::debug!(backend = state.database.name(), key_len = key.len(), found = false, "read_key");
+		    tracing::trace!(key = %String::from_utf8_lossy(&key), "read_key");
+		    return Ok(1);
+		}
+		Err(e) => {
+		    tracing::warn!(backend = state.database.name(), key_len = key.len(), error = %e, "read_key failed");
+		    return Ok(e.status());
+		}
+	    };
+
+	    let status = write_wasm_bytes(&mut caller, &memory, result_base, &result).await?;
+
+	    tracing::debug!(backend = caller.data().database.name(), key_len = key.len(), value_len = result.len(), found = true, "read_key");
+	    tracing::trace!(key = %String::from_utf8_lossy(&key), value = %String::from_utf8_lossy(&result), "read_key");
+	    Ok(status)
 	})
-    });
+    })?;
+    linker.func_wrap1_async("env", "delete_key", |mut caller: Caller<'_, _>, key_ptr: GuestPtr| {
+	Box::new(async move {
+	    let memory = guest_memory(&mut caller)?;
+	    let key = read_wasm_bytes(&mut caller, &memory, key_ptr)?;
+
+	    let state = caller.data_mut();
+
+	    tracing::debug!(backend = state.database.name(), key_len = key.len(), "delete_key");
+	    tracing::trace!(key = %String::from_utf8_lossy(&key), "delete_key");
+	    Ok(status(timed!(state, state.database.delete_item(&state.stored_key(&key)))))
+	})
+    })?;
+
+    // `has_key`, `compare_and_swap_key` and `delete_if_equals_key` answer 1
+    // for yes and 0 for no, and return a `DatastoreError::status` code,
+    // which is never 0 or 1, if the backend fails.
+    linker.func_wrap1_async("env", "has_key", |mut caller: Caller<'_, _>, key_ptr: GuestPtr| {
+	Box::new(async move {
+	    let memory = guest_memory(&mut caller)?;
+	    let key = read_wasm_bytes(&mut caller, &memory, key_ptr)?;
+
+	    let state = caller.data_mut();
+	    let exists = match timed!(state, state.database.exists(&state.stored_key(&key))) {
+		Ok(exists) => exists,
+		Err(e) => {
+		    tracing::warn!(backend = state.database.name(), key_len = key.len(), error = %e, "has_key failed");
+		    return Ok(e.status());
+		}
+	    };
+
+	    tracing::debug!(backend = state.database.name(), key_len = key.len(), exists, "has_key");
+	    tracing::trace!(key = %String::from_utf8_lossy(&key), "has_key");
+	    Ok(exists as u32)
+	})
+    })?;
+
+    // `count_keys` writes the number of stored keys to `result_ptr` and
+    // returns 0, or returns a `DatastoreError::status` code. Backends can
+    // only count every key they hold, which would tell a tenant how many
+    // keys the others have, so with tenants kept apart it always fails.
+    linker.func_wrap1_async("env", "count_keys", |mut caller: Caller<'_, _>, result_ptr: GuestPtr| {
+	Box::new(async move {
+	    let memory = guest_memory(&mut caller)?;
+
+	    let state = caller.data_mut();
+	    if state.namespace.is_some() {
+		tracing::warn!(backend = state.database.name(), "count_keys refused: it would count other tenants' keys");
+		return Ok(DatastoreError::Backend("count_keys isn't available to tenants".into()).status());
+	    }
+	    let count = timed!(state, state.database.count());
+
+	    tracing::debug!(backend = state.database.name(), count = ?count, "count_keys");
+	    match count {
+		Ok(count) => {
+		    check_bounds(&caller, &memory, result_ptr, 8)?;
+		    memory.write(caller.as_context_mut(), result_ptr as usize, &count.to_le_bytes())?;
+		    Ok(0u32)
+		}
+		Err(e) => Ok(e.status()),
+	    }
+	})
+    })?;
+
+    // `scan_prefix_key` returns every match in one `encode_pairs` buffer.
+    linker.func_wrap2_async("env", "scan_prefix_key", |mut caller: Caller<'_, _>, prefix_ptr: GuestPtr, result_base: GuestPtr| {
+	Box::new(async move {
+	    let memory = guest_memory(&mut caller)?;
+	    let prefix = read_wasm_bytes(&mut caller, &memory, prefix_ptr)?;
+
+	    let state = caller.data_mut();
+	    let entries = match timed!(state, state.database.scan_prefix(&state.stored_key(&prefix))) {
+		Ok(entries) => state.guest_entries(entries),
+		Err(e) => {
+		    tracing::warn!(backend = state.database.name(), prefix_len = prefix.len(), error = %e, "scan_prefix_key failed");
+		    return Ok(e.status());
+		}
+	    };
+
+	    let status = write_wasm_bytes(&mut caller, &memory, result_base, &encode_pairs(&entries)).await?;
+
+	    tracing::debug!(backend = caller.data().database.name(), prefix_len = prefix.len(), entries = entries.len(), "scan_prefix_key");
+	    tracing::trace!(prefix = %String::from_utf8_lossy(&prefix), "scan_prefix_key");
+	    Ok(status)
+	})
+    })?;
+
+    // `read_many_key` reads a batch of keys with one host call, writing
+    // their values back in one `encode_values` buffer.
+    linker.func_wrap2_async("env", "read_many_key", |mut caller: Caller<'_, _>, keys_ptr: GuestPtr, result_base: GuestPtr| {
+	Box::new(async move {
+	    let memory = guest_memory(&mut caller)?;
+	    let keys = read_wasm_bytes(&mut caller, &memory, keys_ptr)?;
+	    let keys = decode_keys(&keys).ok_or_else(|| wasmtime::Error::msg("malformed read_many_key payload"))?;
+
+	    let state = caller.data_mut();
+	    let stored_keys: Vec<_> = keys.iter().map(|key| state.stored_key(key)).collect();
+	    let values = match timed!(state, state.database.batch_get_items(&stored_keys)) {
+		Ok(values) => values,
+		Err(e) => {
+		    tracing::warn!(backend = state.database.name(), keys = keys.len(), error = %e, "read_many_key failed");
+		    return Ok(e.status());
+		}
+	    };
+
+	    tracing::debug!(backend = state.database.name(), keys = keys.len(), found = values.iter().flatten().count(), "read_many_key");
+	    write_wasm_bytes(&mut caller, &memory, result_base, &encode_values(&values)).await
+	})
+    })?;
+
+    // `compare_and_swap_key` takes a null `expected` pointer to mean the key
+    // must be absent, and returns 1 if the swap happened.
+    linker.func_wrap3_async("env", "compare_and_swap_key", |mut caller: Caller<'_, _>, key_ptr: GuestPtr, expected_ptr: GuestPtr, new_ptr: GuestPtr| {
+	Box::new(async move {
+	    let memory = guest_memory(&mut caller)?;
+	    let key = read_wasm_bytes(&mut caller, &memory, key_ptr)?;
+	    let expected = match expected_ptr {
+		0 => None,
+		ptr => Some(read_wasm_bytes(&mut caller, &memory, ptr)?),
+	    };
+	    let new = read_wasm_bytes(&mut caller, &memory, new_ptr)?;
+
+	    let state = caller.data_mut();
+	    let swapped = match timed!(state, state.database.compare_and_swap(state.stored_key(&key), expected.as_deref(), new)) {
+		Ok(swapped) => swapped,
+		Err(e) => {
+		    tracing::warn!(backend = state.database.name(), key_len = key.len(), error = %e, "compare_and_swap_key failed");
+		    return Ok(e.status());
+		}
+	    };
+
+	    tracing::debug!(backend = state.database.name(), key_len = key.len(), swapped, "compare_and_swap_key");
+	    tracing::trace!(key = %String::from_utf8_lossy(&key), "compare_and_swap_key");
+	    Ok(swapped as u32)
+	})
+    })?;
+
+    // `delete_if_equals_key` returns 1 if it deleted the key, which it only
+    // does if the key holds the guest's expected value.
+    linker.func_wrap2_async("env", "delete_if_equals_key", |mut caller: Caller<'_, _>, key_ptr: GuestPtr, expected_ptr: GuestPtr| {
+	Box::new(async move {
+	    let memory = guest_memory(&mut caller)?;
+	    let key = read_wasm_bytes(&mut caller, &memory, key_ptr)?;
+	    let expected = read_wasm_bytes(&mut caller, &memory, expected_ptr)?;
+
+	    let state = caller.data_mut();
+	    let deleted = match timed!(state, state.database.delete_if_equals(&state.stored_key(&key), &expected)) {
+		Ok(deleted) => deleted,
+		Err(e) => {
+		    tracing::warn!(backend = state.database.name(), key_len = key.len(), error = %e, "delete_if_equals_key failed");
+		    return Ok(e.status());
+		}
+	    };
+
+	    tracing::debug!(backend = state.database.name(), key_len = key.len(), deleted, "delete_if_equals_key");
+	    tracing::trace!(key = %String::from_utf8_lossy(&key), "delete_if_equals_key");
+	    Ok(deleted as u32)
+	})
+    })?;
+
+    // `swap_key` writes the guest's new value and, like `read_key`, returns
+    // 0 and fills in its result `WasmBytes` with the old value if there was
+    // one, or returns 1 if the key was absent.
+    linker.func_wrap3_async("env", "swap_key", |mut caller: Caller<'_, _>, key_ptr: GuestPtr, new_ptr: GuestPtr, result_base: GuestPtr| {
+	Box::new(async move {
+	    let memory = guest_memory(&mut caller)?;
+	    let key = read_wasm_bytes(&mut caller, &memory, key_ptr)?;
+	    let new = read_wasm_bytes(&mut caller, &memory, new_ptr)?;
+
+	    let state = caller.data_mut();
+	    let old = match timed!(state, state.database.swap(state.stored_key(&key), new)) {
+		Ok(Some(old)) => old,
+		Ok(None) => {
+		    tracing::debug!(backend = state.database.name(), key_len = key.len(), found = false, "swap_key");
+		    tracing::trace!(key = %String::from_utf8_lossy(&key), "swap_key");
+		    return Ok(1);
+		}
+		Err(e) => {
+		    tracing::warn!(backend = state.database.name(), key_len = key.len(), error = %e, "swap_key failed");
+		    return Ok(e.status());
+		}
+	    };
+
+	    let status = write_wasm_bytes(&mut caller, &memory, result_base, &old).await?;
+
+	    tracing::debug!(backend = caller.data().database.name(), key_len = key.len(), old_len = old.len(), found = true, "swap_key");
+	    tracing::trace!(key = %String::from_utf8_lossy(&key), "swap_key");
+	    Ok(status)
+	})
+    })?;
+
+    // `increment_key` writes the new total to `result_ptr` and returns 0, or
+    // returns 1 if the stored value isn't an 8-byte counter.
+    linker.func_wrap3_async("env", "increment_key", |mut caller: Caller<'_, _>, key_ptr: GuestPtr, delta: i64, result_ptr: GuestPtr| {
+	Box::new(async move {
+	    let memory = guest_memory(&mut caller)?;
+	    let key = read_wasm_bytes(&mut caller, &memory, key_ptr)?;
+
+	    let state = caller.data_mut();
+	    let total = timed!(state, state.database.increment(state.stored_key(&key), delta));
+
+	    tracing::debug!(backend = state.database.name(), key_len = key.len(), delta, total = ?total, "increment_key");
+	    tracing::trace!(key = %String::from_utf8_lossy(&key), "increment_key");
+	    match total {
+		Ok(Some(total)) => {
+		    check_bounds(&caller, &memory, result_ptr, 8)?;
+		    memory.write(caller.as_context_mut(), result_ptr as usize, &total.to_le_bytes())?;
+		    Ok(0u32)
+		}
+		Ok(None) => Ok(1),
+		Err(e) => Ok(e.status()),
+	    }
+	})
+    })?;
+
+    // `write_batch_key` takes every pair in one `encode_pairs` buffer so a
+    // batch costs a single host call.
+    linker.func_wrap1_async("env", "write_batch_key", |mut caller: Caller<'_, _>, pairs_ptr: GuestPtr| {
+	Box::new(async move {
+	    let memory = guest_memory(&mut caller)?;
+	    let pairs = read_wasm_bytes(&mut caller, &memory, pairs_ptr)?;
+	    let pairs = decode_pairs(&pairs).ok_or_else(|| wasmtime::Error::msg("malformed write_batch_key payload"))?;
+
+	    let state = caller.data_mut();
+
+	    tracing::debug!(backend = state.database.name(), pairs = pairs.len(), "write_batch_key");
+	    let pairs = pairs.into_iter().map(|(key, value)| (state.stored_key(&key), value)).collect();
+	    Ok(status(timed!(state, state.database.put_items(pairs))))
+	})
+    })?;
+
+    // `log_message` forwards a guest's message to `tracing`. Levels count up
+    // from 1 for errors to 5 for traces, and anything else logs as info.
+    linker.func_wrap("env", "log_message", |mut caller: Caller<'_, MyState<D>>, level: u32, message_ptr: GuestPtr| {
+	let memory = guest_memory(&mut caller)?;
+	let message = read_wasm_bytes(&mut caller, &memory, message_ptr)?;
+	let message = String::from_utf8_lossy(&message);
+	let request_id = &caller.data().request_id;
+	match level {
+	    1 => tracing::error!(target: "guest", %request_id, "{}", message),
+	    2 => tracing::warn!(target: "guest", %request_id, "{}", message),
+	    4 => tracing::debug!(target: "guest", %request_id, "{}", message),
+	    5 => tracing::trace!(target: "guest", %request_id, "{}", message),
+	    _ => tracing::info!(target: "guest", %request_id, "{}", message),
+	}
+	Ok(())
+    })?;
+
+    // `current_time_millis` gives guests, which have no clock of their own,
+    // the time in milliseconds since the Unix epoch.
+    linker.func_wrap("env", "current_time_millis", |caller: Caller<'_, MyState<D>>| {
+	caller.data().now_millis()
+    })?;
+
+    // `sleep_millis` waits `ms` milliseconds, up to `MAX_SLEEP`, before
+    // returning to the guest. Other requests run in the meantime, and the
+    // time counts against the guest's timeout like any other.
+    linker.func_wrap1_async("env", "sleep_millis", |_caller: Caller<'_, MyState<D>>, ms: u32| {
+	Box::new(async move {
+	    tokio::time::sleep(Duration::from_millis(ms.into()).min(MAX_SLEEP)).await;
+	    Ok(())
+	})
+    })?;
+
+    // `report_panic` records the message of a panic the guest is about to
+    // trap on. It's only logged, so keep as much as the logs can use.
+    linker.func_wrap("env", "report_panic", |mut caller: Caller<'_, MyState<D>>, message_ptr: GuestPtr| {
+	let memory = guest_memory(&mut caller)?;
+	let mut message = read_wasm_bytes(&mut caller, &memory, message_ptr)?;
+	message.truncate(MAX_PANIC_LEN);
+	caller.data_mut().panic = Some(String::from_utf8_lossy(&message).into_owned());
+	Ok(())
+    })?;
+
+    // `emit_metric` adds `value` to the guest's metric called `name`,
+    // returning 0, or returns a `MetricError::status` code if the metric
+    // isn't allowed (see `metrics`).
+    linker.func_wrap("env", "emit_metric", |mut caller: Caller<'_, MyState<D>>, name_ptr: GuestPtr, value: f64| {
+	let memory = guest_memory(&mut caller)?;
+	let name = read_wasm_bytes(&mut caller, &memory, name_ptr)?;
+	match caller.data_mut().metrics.record(&name, value) {
+	    Ok(()) => Ok(0u32),
+	    Err(e) => {
+		tracing::debug!(name = %String::from_utf8_lossy(&name), value, error = ?e, "rejected metric");
+		Ok(e.status())
+	    }
+	}
+    })?;
+
+    // `fill_random` overwrites the guest's `len` bytes at `ptr` with random
+    // bytes.
+    linker.func_wrap("env", "fill_random", |mut caller: Caller<'_, MyState<D>>, ptr: GuestPtr, len: GuestPtr| {
+	let memory = guest_memory(&mut caller)?;
+	check_bounds(&caller, &memory, ptr, len)?;
+	let mut bytes = vec![0; len as usize];
+	caller.data_mut().rng.fill_bytes(&mut bytes);
+	memory.write(caller.as_context_mut(), ptr as usize, &bytes)?;
+	Ok(())
+    })?;
+
+    // `uuid_v4` writes a random version 4 UUID to the guest's 16 bytes at
+    // `out_ptr`. It draws from the same RNG as `fill_random`, so
+    // `WASMTEST_RANDOM_SEED` makes it deterministic too.
+    linker.func_wrap("env", "uuid_v4", |mut caller: Caller<'_, MyState<D>>, out_ptr: GuestPtr| {
+	let memory = guest_memory(&mut caller)?;
+	check_bounds(&caller, &memory, out_ptr, 16)?;
+	let mut uuid = [0; 16];
+	caller.data_mut().rng.fill_bytes(&mut uuid);
+	// Version 4 in the high nibble of byte 6, and the RFC 4122 variant in
+	// the top two bits of byte 8.
+	uuid[6] = (uuid[6] & 0x0f) | 0x40;
+	uuid[8] = (uuid[8] & 0x3f) | 0x80;
+	memory.write(caller.as_context_mut(), out_ptr as usize, &uuid)?;
+	Ok(())
+    })?;
+
+    // `sha256` writes the SHA-256 digest of the guest's `len` bytes at `ptr`
+    // to the 32 bytes at `out_ptr`.
+    linker.func_wrap("env", "sha256", |mut caller: Caller<'_, MyState<D>>, ptr: GuestPtr, len: GuestPtr, out_ptr: GuestPtr| {
+	let memory = guest_memory(&mut caller)?;
+	check_bounds(&caller, &memory, ptr, len)?;
+	check_bounds(&caller, &memory, out_ptr, 32)?;
+	let mut bytes = vec![0; len as usize];
+	memory.read(&caller, ptr as usize, &mut bytes)?;
+	memory.write(caller.as_context_mut(), out_ptr as usize, &Sha256::digest(&bytes))?;
+	Ok(())
+    })?;
+
+    // `base64_encode` and `base64_decode` fill in the guest's result
+    // `WasmBytes` with its input converted, like `read_key`. A nonzero
+    // `url_safe` picks the URL-safe alphabet without padding over the
+    // standard one with it. Decoding returns 1 if the input isn't valid in
+    // that alphabet.
+    linker.func_wrap3_async("env", "base64_encode", |mut caller: Caller<'_, MyState<D>>, input_ptr: GuestPtr, url_safe: u32, result_base: GuestPtr| {
+	Box::new(async move {
+	    let memory = guest_memory(&mut caller)?;
+	    let input = read_wasm_bytes(&mut caller, &memory, input_ptr)?;
+	    let encoded = match url_safe {
+		0 => BASE64_STANDARD.encode(&input),
+		_ => BASE64_URL_SAFE_NO_PAD.encode(&input),
+	    };
+	    write_wasm_bytes(&mut caller, &memory, result_base, encoded.as_bytes()).await
+	})
+    })?;
+    linker.func_wrap3_async("env", "base64_decode", |mut caller: Caller<'_, MyState<D>>, input_ptr: GuestPtr, url_safe: u32, result_base: GuestPtr| {
+	Box::new(async move {
+	    let memory = guest_memory(&mut caller)?;
+	    let input = read_wasm_bytes(&mut caller, &memory, input_ptr)?;
+	    let decoded = match url_safe {
+		0 => BASE64_STANDARD.decode(&input),
+		_ => BASE64_URL_SAFE_NO_PAD.decode(&input),
+	    };
+	    match decoded {
+		Ok(decoded) => write_wasm_bytes(&mut caller, &memory, result_base, &decoded).await,
+		Err(e) => {
+		    tracing::debug!(input_len = input.len(), error = %e, "base64_decode got invalid input");
+		    Ok(1)
+		}
+	    }
+	})
+    })?;
+
+    // `http_fetch` makes the request encoded in the guest's buffer and fills
+    // in its result `WasmBytes` with the encoded response. See `http` for
+    // both encodings. Failures, including requests to hosts that aren't on
+    // the allowlist, come back as a `FetchError::status` code.
+    linker.func_wrap2_async("env", "http_fetch", |mut caller: Caller<'_, _>, request_ptr: GuestPtr, result_base: GuestPtr| {
+	Box::new(async move {
+	    let memory = guest_memory(&mut caller)?;
+	    let request = read_wasm_bytes(&mut caller, &memory, request_ptr)?;
+	    let request = http::decode_request(&request).ok_or_else(|| wasmtime::Error::msg("malformed http_fetch request"))?;
+
+	    let (method, url) = (request.method.clone(), request.url.clone());
+	    let fetcher = caller.data().http.clone();
+	    let response = match fetcher.fetch(request).await {
+		Ok(response) => response,
+		Err(e) => {
+		    tracing::warn!(%method, %url, error = %e, "http_fetch failed");
+		    return Ok(e.status());
+		}
+	    };
+
+	    tracing::debug!(%method, %url, status = response.status, "http_fetch");
+	    write_wasm_bytes(&mut caller, &memory, result_base, &http::encode_response(&response)).await
+	})
+    })?;
+
+    // `set_status` picks the status code of the response to this request. The
+    // last call wins, and without one the response is a 200.
+    linker.func_wrap("env", "set_status", |mut caller: Caller<'_, MyState<D>>, code: u32| {
+	let code = u16::try_from(code).ok().filter(|code| (100..=999).contains(code))
+	    .ok_or_else(|| wasmtime::Error::msg(format!("invalid status code {}", code)))?;
+	caller.data_mut().status = Some(code);
+	Ok(())
+    })?;
+
+    // `set_header` adds a header to the response to this request, returning 1
+    // without adding it if the name or value isn't valid in HTTP. Adding a
+    // name more than once sends each value, as with `set-cookie`.
+    linker.func_wrap("env", "set_header", |mut caller: Caller<'_, MyState<D>>, name_ptr: GuestPtr, value_ptr: GuestPtr| {
+	let memory = guest_memory(&mut caller)?;
+	let name = read_wasm_bytes(&mut caller, &memory, name_ptr)?;
+	let value = read_wasm_bytes(&mut caller, &memory, value_ptr)?;
+	match (HeaderName::from_bytes(&name), HeaderValue::from_bytes(&value)) {
+	    (Ok(name), Ok(value)) => {
+		caller.data_mut().headers.append(name, value);
+		Ok(0u32)
+	    }
+	    _ => {
+		tracing::debug!(name = %String::from_utf8_lossy(&name), value_len = value.len(), "rejected invalid response header");
+		Ok(1)
+	    }
+	}
+    })?;
+
+    // `get_request_header` works like `read_key`, filling in the guest's
+    // result `WasmBytes` and returning 0 if the request has the header and
+    // returning 1 if it doesn't. Names are case-insensitive, and a header
+    // sent more than once gives its first value.
+    linker.func_wrap2_async("env", "get_request_header", |mut caller: Caller<'_, MyState<D>>, name_ptr: GuestPtr, result_base: GuestPtr| {
+	Box::new(async move {
+	    let memory = guest_memory(&mut caller)?;
+	    let name = read_wasm_bytes(&mut caller, &memory, name_ptr)?;
+
+	    let value = HeaderName::from_bytes(&name).ok()
+		.and_then(|name| caller.data().request.headers.get(name))
+		.map(|value| value.as_bytes().to_vec());
+	    match value {
+		Some(value) => write_wasm_bytes(&mut caller, &memory, result_base, &value).await,
+		None => Ok(1),
+	    }
+	})
+    })?;
+
+    // `get_request_method`, `get_request_path` and `get_request_query` fill in
+    // the guest's result `WasmBytes` with that part of the request line, like
+    // `read_key`. Only the query can be missing, in which case it returns 1.
+    linker.func_wrap1_async("env", "get_request_method", |mut caller: Caller<'_, MyState<D>>, result_base: GuestPtr| {
+	Box::new(async move {
+	    let memory = guest_memory(&mut caller)?;
+	    let method = caller.data().request.method.as_str().as_bytes().to_vec();
+	    write_wasm_bytes(&mut caller, &memory, result_base, &method).await
+	})
+    })?;
+    linker.func_wrap1_async("env", "get_request_path", |mut caller: Caller<'_, MyState<D>>, result_base: GuestPtr| {
+	Box::new(async move {
+	    let memory = guest_memory(&mut caller)?;
+	    let path = caller.data().request.uri.path().as_bytes().to_vec();
+	    write_wasm_bytes(&mut caller, &memory, result_base, &path).await
+	})
+    })?;
+    linker.func_wrap1_async("env", "get_request_query", |mut caller: Caller<'_, MyState<D>>, result_base: GuestPtr| {
+	Box::new(async move {
+	    let memory = guest_memory(&mut caller)?;
+	    match caller.data().request.uri.query().map(|q| q.as_bytes().to_vec()) {
+		Some(query) => write_wasm_bytes(&mut caller, &memory, result_base, &query).await,
+		None => Ok(1),
+	    }
+	})
+    })?;
+
+    // `get_request_route` fills in the pattern of the route the request
+    // matched, and `get_route_param` the path segment one of its `:name`
+    // segments captured. Both return 1 if there's no such value, including
+    // when the runtime doesn't route requests at all.
+    linker.func_wrap1_async("env", "get_request_route", |mut caller: Caller<'_, MyState<D>>, result_base: GuestPtr| {
+	Box::new(async move {
+	    let memory = guest_memory(&mut caller)?;
+	    match caller.data().route.as_ref().map(|route| route.pattern.clone().into_bytes()) {
+		Some(pattern) => write_wasm_bytes(&mut caller, &memory, result_base, &pattern).await,
+		None => Ok(1),
+	    }
+	})
+    })?;
+    linker.func_wrap2_async("env", "get_route_param", |mut caller: Caller<'_, MyState<D>>, name_ptr: GuestPtr, result_base: GuestPtr| {
+	Box::new(async move {
+	    let memory = guest_memory(&mut caller)?;
+	    let name = read_wasm_bytes(&mut caller, &memory, name_ptr)?;
+	    let value = caller.data().route.as_ref()
+		.and_then(|route| route.params.iter().find(|(param, _)| param.as_bytes() == name))
+		.map(|(_, value)| value.clone().into_bytes());
+	    match value {
+		Some(value) => write_wasm_bytes(&mut caller, &memory, result_base, &value).await,
+		None => Ok(1),
+	    }
+	})
+    })?;
+
+    // WASI guests get the standard WASI imports alongside ours. Stores for
+    // those guests always carry a WASI context.
+    if wasi {
+	wasmtime_wasi::preview1::add_to_linker_async(&mut linker, |state| state.wasi.as_mut().unwrap())?;
+    }
+    Ok(linker)
+}
+
+/// The response to a request whose guest trapped, or whose host function
+/// failed, while `export` was handling it. `panic` is the message of the
+/// panic the guest reported before trapping, if it did.
+fn failure_response<D: Datastore>(e: wasmtime::Error, panic: Option<String>, runtime: &Runtime<D>, request_id: &str, export: &str) -> Result<Response<Body>, Error> {
+    if let Some(e) = e.downcast_ref::<DatastoreError>() {
+	tracing::error!(%request_id, %export, backend = runtime.datastore.name(), error = %e, "host function failed: datastore error");
+	return error_response(500, format!("datastore error: {} (request {})", e, request_id));
+    }
+    match e.downcast_ref::<Trap>() {
+	Some(Trap::OutOfFuel) => {
+	    tracing::warn!(%request_id, %export, fuel = runtime.fuel, "guest ran out of fuel");
+	    error_response(500, "guest exceeded its CPU budget".into())
+	}
+	Some(Trap::Interrupt) => {
+	    tracing::warn!(%request_id, %export, timeout = ?runtime.timeout, "guest timed out");
+	    error_response(504, "guest timed out".into())
+	}
+	// Panic messages can hold anything the guest had in hand, so only the
+	// logs get them.
+	Some(trap) => match panic {
+	    Some(panic) => {
+		tracing::error!(%request_id, %export, %trap, %panic, "guest panicked");
+		error_response(500, format!("guest panicked (request {})", request_id))
+	    }
+	    None => {
+		tracing::error!(%request_id, %export, %trap, details = format!("{:?}", e), "guest trapped");
+		error_response(500, format!("guest trapped (request {})", request_id))
+	    }
+	},
+	None => {
+	    tracing::error!(%request_id, %export, error = format!("{:#}", e), "host function failed");
+	    error_response(500, format!("internal error (request {})", request_id))
+	}
+    }
+}
+
+async fn function_handler<D: Datastore + Clone + 'static>(runtime: Runtime<D>, event: Request) -> Result<Response<Body>, Error> {
+    let started = Instant::now();
+    let (request, body) = event.into_parts();
+    let body: &[u8] = &body;
+
+    // Failures are logged with this ID and the error response carries it, so
+    // a caller's report can be matched to the logs. Host functions log within
+    // the invocation span, which carries it, and the guest's `log_message`
+    // lines carry it as a field of their own. Lambda gives every invocation
+    // one; outside it we make one up.
+    let request_id = request.lambda_context_ref().map(|context| context.request_id.clone())
+	.unwrap_or_else(|| format!("{:016x}", rand::random::<u64>()));
+
+    // Find the export that handles this request before doing any work on
+    // it. Routed exports take the same arguments as `entry`.
+    let route = match &runtime.router {
+	Some(router) => match router.route(&request.method, request.uri.path()) {
+	    Some(route) => Some(route),
+	    None => return error_response(404, format!("no route for {} {}", request.method, request.uri.path())),
+	},
+	None => None,
+    };
+    let export = route.as_ref().map_or("entry", |route| route.export.as_str()).to_string();
+    let namespace = match runtime.namespace(&request) {
+	Ok(namespace) => namespace,
+	Err(message) => return error_response(400, message),
+    };
+
+    // The phases below get spans of their own under this one, and how long
+    // each took goes in the event we log at the end. Compilation isn't one of
+    // them: it happens once per container, when `main` logs "loaded module",
+    // so only the first invocation a container handles is `cold`.
+    let cold = !WARM.swap(true, Ordering::Relaxed);
+    let span = tracing::info_span!("invocation", %request_id, %export, body_len = body.len(), result_len = tracing::field::Empty, cold);
+
+    let mut store = match runtime.store(request, route, namespace, request_id.clone()) {
+	Ok(store) => store,
+	Err(e) => return failure_response(e, None, &runtime, &request_id, &export),
+    };
+
+    // Anything from here on can trap in the guest or fail in wasmtime, and
+    // whatever does gets the same response as a failure in the export.
+    macro_rules! or_respond {
+	($result:expr) => {
+	    match $result {
+		Ok(value) => value,
+		Err(e) => return failure_response(e.into(), store.data_mut().panic.take(), &runtime, &request_id, &export),
+	    }
+	};
+    }
 
     // Once we've got that all set up we can then move to the instantiation
-    // phase, pairing together a compiled module as well as a set of imports.
-    // Note that this is where the wasm `start` function, if any, would run.
-    let imports = [write_key_func.into(), read_key_func.into()];//, input_body.into(), response_body.into()];
-    let instance = Instance::new_async(&mut store, &module, &imports).await?;
+    // phase. The module's imports were resolved against our host functions
+    // when the runtime was built, so all that's left is to allocate the
+    // instance. Note that this is where the wasm `start` function, if any,
+    // would run.
+    let instance = or_respond!(runtime.instance_pre.instantiate_async(&mut store)
+	.instrument(tracing::info_span!(parent: &span, "instantiate"))
+	.await);
+
+    // WASI reactors export `_initialize` to set up their libc and must have
+    // it called before anything else.
+    if let Some(init) = instance.get_func(&mut store, "_initialize") {
+	let init = or_respond!(init.typed::<(), ()>(&store));
+	or_respond!(init.call_async(&mut store, ()).await);
+    }
 
-    // Next we poke around a bit to extract the `entry` function from the module.
-    let memory = instance.get_memory(&mut store, "memory").unwrap();
-    memory.write(&mut store, 8, body)?;
-    let run = instance.get_typed_func::<(i32, i32, i32), ()>(&mut store, "entry")?;
+    // Before handing the guest anything, make sure it speaks our version of
+    // the ABI (see `ABI_VERSION`). Guests from before it was versioned don't
+    // export a version at all.
+    let version = match instance.get_typed_func::<(), u32>(&mut store, "wasmtest_abi_version") {
+	Ok(version) => Some(or_respond!(version.call_async(&mut store, ()).await)),
+	Err(_) => None,
+    };
+    if version != Some(ABI_VERSION) {
+	let version = version.map_or("no ABI version".into(), |v| format!("ABI version {}", v));
+	tracing::error!(guest = %version, runner = ABI_VERSION, "guest ABI version mismatch");
+	return error_response(500, format!("guest speaks {}, but this runner speaks ABI version {}", version, ABI_VERSION));
+    }
+
+    // Next we poke around a bit to extract the functions we need from the
+    // module, and copy the request body into a buffer of the guest's own.
+    let Some(memory) = instance.get_memory(&mut store, "memory") else {
+	tracing::error!(%request_id, "{}", MISSING_MEMORY);
+	return error_response(500, MISSING_MEMORY.into());
+    };
+    let alloc = or_respond!(instance.get_typed_func::<GuestPtr, GuestPtr>(&mut store, "alloc"));
+    let dealloc = or_respond!(instance.get_typed_func::<(GuestPtr, GuestPtr), ()>(&mut store, "dealloc"));
+    let run = or_respond!(instance.get_typed_func::<(GuestPtr, GuestPtr, GuestPtr), ()>(&mut store, &export));
+
+    let body_len = body.len() as GuestPtr;
+    let body_base = or_respond!(alloc.call_async(&mut store, body_len).await);
+    let result_slot = or_respond!(alloc.call_async(&mut store, WASM_BYTES_SIZE as GuestPtr).await);
+    if body_base == 0 || result_slot == 0 {
+	tracing::warn!(len = body.len(), "guest couldn't allocate the request body");
+	return error_response(413, "request body too large".into());
+    }
+    or_respond!(check_bounds(&store, &memory, body_base, body_len));
+    or_respond!(check_bounds(&store, &memory, result_slot, WASM_BYTES_SIZE as GuestPtr));
+    or_respond!(memory.write(&mut store, body_base as usize, body));
+    or_respond!(memory.write(&mut store, result_slot as usize, &[0; WASM_BYTES_SIZE]));
+    // Everything up to here, down to copying the body in, counts as
+    // instantiating.
+    let instantiated = Instant::now();
 
     // And last but not least we can call it!
-    run.call_async(&mut store, (0, 8, body.len() as i32)).await?;
+    //
+    // Failures come in two kinds: traps raised by the guest itself, and
+    // errors from our host functions, such as the datastore failing, which
+    // surface the same way. Either way the caller gets a response, though
+    // only the logs say exactly what went wrong. Whatever metrics the guest
+    // emitted count either way.
+    let outcome = run.call_async(&mut store, (result_slot, body_base, body_len))
+	.instrument(tracing::info_span!(parent: &span, "execute"))
+	.await;
+    let executed = Instant::now();
+    store.data_mut().metrics.flush(&runtime.metrics_namespace, &export);
+    or_respond!(outcome);
+
+    let mut slot = [0; WASM_BYTES_SIZE];
+    or_respond!(memory.read(&store, result_slot as usize, &mut slot));
+    let (result_base, result_len) = split_wasm_bytes(&slot);
+    // The guest wrote these itself, so check them before copying anything:
+    // a length no bigger than its memory can still run off the end of it,
+    // and one bigger than that is rejected before we try to allocate it.
+    if let Err(e) = check_bounds(&store, &memory, result_base, result_len) {
+	tracing::error!(%request_id, %export, result_base, result_len, error = %e, "guest returned an invalid result");
+	return error_response(500, format!("guest returned an invalid result (request {})", request_id));
+    }
+    let result = memory.data(&store)[result_base as usize..][..result_len as usize].to_vec();
+
+    // The guest hands us ownership of the result buffer, so free it now that
+    // we have our own copy, along with the buffers we lent it.
+    or_respond!(dealloc.call_async(&mut store, (result_base, result_len)).await);
+    or_respond!(dealloc.call_async(&mut store, (result_slot, WASM_BYTES_SIZE as GuestPtr)).await);
+    or_respond!(dealloc.call_async(&mut store, (body_base, body_len)).await);
+    let extracted = Instant::now();
+
+    // A result that starts with `RESPONSE_MAGIC` is a whole response. Its
+    // status beats any `set_status`, and its headers replace any of the
+    // same name from `set_header`. Anything else is just the body.
+    let mut headers = std::mem::take(&mut store.data_mut().headers);
+    let mut status = store.data().status;
+    let result = match result.strip_prefix(RESPONSE_MAGIC) {
+	Some(encoded) => match decode_guest_response(encoded) {
+	    Some((code, extra, body)) => {
+		status = Some(code);
+		headers.extend(extra);
+		body
+	    }
+	    None => {
+		tracing::error!(%request_id, %export, result_len, "guest returned a malformed response");
+		return error_response(500, format!("guest returned an invalid result (request {})", request_id));
+	    }
+	},
+	None => result,
+    };
 
-    let mut result_base_bytes = [0; 4];
-    let mut result_len_bytes = [0; 4];
-    memory.read(&store, 0, &mut result_base_bytes)?;
-    memory.read(&store, 4, &mut result_len_bytes)?;
+    span.record("result_len", result.len());
+    tracing::info!(
+	parent: &span,
+	elapsed = ?started.elapsed(),
+	instantiate = ?instantiated.duration_since(started),
+	execute = ?executed.duration_since(instantiated),
+	extract = ?extracted.duration_since(executed),
+	datastore_calls = store.data().datastore_calls,
+	datastore = ?store.data().datastore_time,
+	response_len = result.len(),
+	"handled request",
+    );
 
-    let result_base = i32::from_le_bytes(result_base_bytes) as usize;
-    let result_len = i32::from_le_bytes(result_len_bytes) as usize;
-    let result_slice = &memory.data(&store)[result_base..][..result_len];
+    // The guest's headers go out as given, with ours as the default for a
+    // content type it didn't set.
+    headers.entry(CONTENT_TYPE).or_insert(runtime.default_content_type.clone());
 
     // Return something that implements IntoResponse.
     // It will be serialized to the right response event automatically by the runtime
-    let resp = Response::builder()
-        .status(200)
-        .header("content-type", "text/html")
-        .body(result_slice.into())
+    let mut resp = Response::builder()
+        .status(status.unwrap_or(200))
+        .body(result.into())
         .map_err(Box::new)?;
+    *resp.headers_mut() = headers;
     Ok(resp)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn memory(items: &[(&[u8], &[u8])]) -> MemoryDatastore {
+	let mut memory = MemoryDatastore::default();
+	memory.items.extend(items.iter().map(|(key, value)| (key.to_vec(), value.to_vec())));
+	memory
+    }
+
+    #[tokio::test]
+    async fn delete_item_removes_a_present_key() {
+	let mut memory = memory(&[(b"foo", b"bar"), (b"baz", b"qux")]);
+	memory.delete_item(b"foo").await.unwrap();
+	assert_eq!(memory.get_item(b"foo").await.unwrap(), None);
+	assert_eq!(memory.get_item(b"baz").await.unwrap(), Some(b"qux".to_vec()));
+    }
+
+    #[tokio::test]
+    async fn delete_item_ignores_an_absent_key() {
+	let mut memory = memory(&[(b"baz", b"qux")]);
+	memory.delete_item(b"foo").await.unwrap();
+	assert_eq!(memory.get_item(b"foo").await.unwrap(), None);
+	assert_eq!(memory.count().await.unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn scan_prefix_only_returns_matching_keys_in_order() {
+	let mut memory = memory(&[(b"user/b", b"2"), (b"user/a", b"1"), (b"users", b"x"), (b"other", b"y")]);
+	assert_eq!(memory.scan_prefix(b"user/").await.unwrap(), [
+	    (b"user/a".to_vec(), b"1".to_vec()),
+	    (b"user/b".to_vec(), b"2".to_vec()),
+	]);
+	assert_eq!(memory.scan_prefix(b"nothing").await.unwrap(), []);
+    }
+
+    #[tokio::test]
+    async fn scan_prefix_stops_at_max_scan_entries() {
+	let mut memory = MemoryDatastore::default();
+	for i in 0..MAX_SCAN_ENTRIES + 10 {
+	    memory.put_item(format!("key{:04}", i).into_bytes(), vec![]).await.unwrap();
+	}
+	let entries = memory.scan_prefix(b"key").await.unwrap();
+	assert_eq!(entries.len(), MAX_SCAN_ENTRIES);
+	assert_eq!(entries[0].0, b"key0000");
+	assert_eq!(entries[MAX_SCAN_ENTRIES - 1].0, format!("key{:04}", MAX_SCAN_ENTRIES - 1).into_bytes());
+    }
+
+    #[tokio::test]
+    async fn swap_returns_the_old_value_of_a_present_key() {
+	let mut memory = memory(&[(b"foo", b"old")]);
+	assert_eq!(memory.swap(b"foo".to_vec(), b"new".to_vec()).await.unwrap(), Some(b"old".to_vec()));
+	assert_eq!(memory.get_item(b"foo").await.unwrap(), Some(b"new".to_vec()));
+    }
+
+    #[tokio::test]
+    async fn swap_stores_an_absent_key() {
+	let mut memory = MemoryDatastore::default();
+	assert_eq!(memory.swap(b"foo".to_vec(), b"new".to_vec()).await.unwrap(), None);
+	assert_eq!(memory.get_item(b"foo").await.unwrap(), Some(b"new".to_vec()));
+    }
+
+    #[tokio::test]
+    async fn append_creates_an_absent_key() {
+	let mut memory = MemoryDatastore::default();
+	memory.append(b"log".to_vec(), b"first".to_vec()).await.unwrap();
+	assert_eq!(memory.get_item(b"log").await.unwrap(), Some(b"first".to_vec()));
+    }
+
+    #[tokio::test]
+    async fn append_extends_a_present_key() {
+	let mut memory = memory(&[(b"log", b"first")]);
+	memory.append(b"log".to_vec(), b",second".to_vec()).await.unwrap();
+	assert_eq!(memory.get_item(b"log").await.unwrap(), Some(b"first,second".to_vec()));
+    }
+
+    #[tokio::test]
+    async fn keeps_binary_keys_apart() {
+	let mut memory = MemoryDatastore::default();
+	// Both are `a\u{fffd}b` to a lossy UTF-8 decoding.
+	memory.put_item(b"a\xffb".to_vec(), b"ff".to_vec()).await.unwrap();
+	memory.put_item(b"a\xfeb".to_vec(), b"fe".to_vec()).await.unwrap();
+	memory.put_item(b"\x00\xff\x80".to_vec(), b"binary".to_vec()).await.unwrap();
+
+	assert_eq!(memory.get_item(b"a\xffb").await.unwrap(), Some(b"ff".to_vec()));
+	assert_eq!(memory.get_item(b"a\xfeb").await.unwrap(), Some(b"fe".to_vec()));
+	assert_eq!(memory.get_item(b"\x00\xff\x80").await.unwrap(), Some(b"binary".to_vec()));
+	assert_eq!(memory.count().await.unwrap(), 3);
+    }
+
+    /// A guest that answers with the value of the key its body names, or
+    /// with `missing`.
+    #[cfg(feature = "test-util")]
+    fn read_body_key_guest() -> String {
+	let items = r#"
+	    (import "env" "read_key" (func $read_key (param i32 i32) (result i32)))
+	    (data (i32.const 100) "missing")"#;
+	crate::test_runner::wat_guest(items, r#"
+	    (i32.store (i32.const 200) (local.get $body))
+	    (i32.store (i32.const 204) (local.get $len))
+	    (if (call $read_key (i32.const 200) (i32.const 300))
+	      (then
+		(i32.store (local.get $result) (i32.const 100))
+		(i32.store offset=4 (local.get $result) (i32.const 7)))
+	      (else
+		(i32.store (local.get $result) (i32.load (i32.const 300)))
+		(i32.store offset=4 (local.get $result) (i32.load (i32.const 304)))))"#)
+    }
+
+    #[cfg(feature = "test-util")]
+    #[tokio::test]
+    async fn memory_datastore_is_seeded_from_its_file() {
+	use crate::test_runner::TestRunner;
+
+	let path = std::env::temp_dir().join(format!("wasmtest-seed-{:016x}.json", rand::random::<u64>()));
+	let from_seed = |json: &str| {
+	    std::fs::write(&path, json).unwrap();
+	    std::env::set_var("WASMTEST_MEMORY_SEED", &path);
+	    let memory = MemoryDatastore::from_env();
+	    std::env::remove_var("WASMTEST_MEMORY_SEED");
+	    memory
+	};
+
+	// `foo` = `bar`, `\x00\xff` = `binary` and `empty` = ``.
+	let memory = from_seed(r#"{"Zm9v": "YmFy", "AP8=": "YmluYXJ5", "ZW1wdHk=": ""}"#).unwrap();
+	let runner = TestRunner::with_module(memory, read_body_key_guest()).unwrap();
+	assert_eq!(runner.call(b"foo").await.unwrap().body, b"bar");
+	assert_eq!(runner.call(b"\x00\xff").await.unwrap().body, b"binary");
+	assert_eq!(runner.call(b"empty").await.unwrap().body, b"");
+	assert_eq!(runner.call(b"other").await.unwrap().body, b"missing");
+
+	let e = from_seed(r#"{"Zm9v": "not base64!"}"#).unwrap_err().to_string();
+	assert!(e.contains("invalid base64"), "{}", e);
+	let e = from_seed("[]").unwrap_err().to_string();
+	assert!(e.contains("invalid seed file"), "{}", e);
+	std::fs::remove_file(&path).unwrap();
+    }
+
+    /// Runs the guest against DynamoDB Local: `cargo test --features
+    /// dynamodb-local`. The tests start it in Docker, or use the one at
+    /// `WASMTEST_DYNAMODB_ENDPOINT` if that's set, and create a table of
+    /// their own in it.
+    #[cfg(feature = "dynamodb-local")]
+    mod dynamodb_local {
+	use std::process::Command;
+
+	use aws_sdk_dynamodb::config::{Credentials, Region};
+	use aws_sdk_dynamodb::types::{AttributeDefinition, BillingMode, KeySchemaElement, KeyType, ScalarAttributeType};
+
+	use super::*;
+	use crate::test_runner::TestRunner;
+
+	/// A DynamoDB Local container, removed when this is dropped.
+	struct Container(String);
+
+	impl Container {
+	    fn start() -> Container {
+		let output = Command::new("docker")
+		    .args(["run", "--detach", "--rm", "--publish", "127.0.0.1::8000", "amazon/dynamodb-local"])
+		    .output()
+		    .expect("couldn't run docker; set WASMTEST_DYNAMODB_ENDPOINT to use a running DynamoDB Local");
+		assert!(output.status.success(), "docker run failed: {}", String::from_utf8_lossy(&output.stderr));
+		Container(String::from_utf8(output.stdout).unwrap().trim().to_string())
+	    }
+
+	    fn endpoint(&self) -> String {
+		let output = Command::new("docker").args(["port", &self.0, "8000/tcp"]).output().unwrap();
+		let address = String::from_utf8(output.stdout).unwrap();
+		format!("http://{}", address.lines().next().expect("DynamoDB Local's port isn't published"))
+	    }
+	}
+
+	impl Drop for Container {
+	    fn drop(&mut self) {
+		let _ = Command::new("docker").args(["rm", "--force", &self.0]).output();
+	    }
+	}
+
+	/// A client for DynamoDB Local at `endpoint`, which takes any
+	/// credentials, once it's answering.
+	async fn client(endpoint: String) -> aws_sdk_dynamodb::Client {
+	    let config = aws_sdk_dynamodb::Config::builder()
+		.behavior_version(aws_config::BehaviorVersion::latest())
+		.region(Region::new("us-east-1"))
+		.credentials_provider(Credentials::new("local", "local", None, None, "dynamodb-local"))
+		.endpoint_url(endpoint)
+		.build();
+	    let client = aws_sdk_dynamodb::Client::from_conf(config);
+	    for _ in 0..50 {
+		if client.list_tables().send().await.is_ok() {
+		    return client;
+		}
+		tokio::time::sleep(Duration::from_millis(200)).await;
+	    }
+	    panic!("DynamoDB Local didn't start");
+	}
+
+	/// Creates a table shaped the way `DynamoDBDatastore` expects.
+	async fn create_table(client: &aws_sdk_dynamodb::Client) -> String {
+	    let table_name = format!("wasmtest-{:016x}", rand::random::<u64>());
+	    client.create_table()
+		.table_name(&table_name)
+		.billing_mode(BillingMode::PayPerRequest)
+		.attribute_definitions(AttributeDefinition::builder()
+		    .attribute_name(DynamoDBDatastore::KEY_ATTRIBUTE)
+		    .attribute_type(ScalarAttributeType::B)
+		    .build()
+		    .unwrap())
+		.key_schema(KeySchemaElement::builder()
+		    .attribute_name(DynamoDBDatastore::KEY_ATTRIBUTE)
+		    .key_type(KeyType::Hash)
+		    .build()
+		    .unwrap())
+		.send()
+		.await
+		.unwrap();
+	    table_name
+	}
+
+	/// A datastore on a table of its own, and the container it's in if the
+	/// test started one.
+	async fn datastore() -> (Option<Container>, DynamoDBDatastore) {
+	    let (container, endpoint) = match env_opt::<String>("WASMTEST_DYNAMODB_ENDPOINT").unwrap() {
+		Some(endpoint) => (None, endpoint),
+		None => {
+		    let container = Container::start();
+		    let endpoint = container.endpoint();
+		    (Some(container), endpoint)
+		}
+	    };
+	    let client = client(endpoint).await;
+	    let table_name = create_table(&client).await;
+	    (container, DynamoDBDatastore::new(client, table_name))
+	}
+
+	#[tokio::test]
+	async fn guest_runs_against_dynamodb_local() {
+	    let (_container, mut datastore) = datastore().await;
+	    datastore.put_item(b"foo".to_vec(), b"bar".to_vec()).await.unwrap();
+	    let runner = TestRunner::new(datastore.clone()).unwrap();
+
+	    let response = runner.call(b"hello").await.unwrap();
+
+	    assert_eq!(response.status, 200);
+	    assert_eq!(response.body, b"bar");
+	    assert_eq!(datastore.get_item(b"hello").await.unwrap(), Some(b"world".to_vec()));
+	    assert_eq!(datastore.get_item(b"world").await.unwrap(), Some(b"bar".to_vec()));
+	    assert_eq!(runner.runtime().datastore.name(), "dynamodb");
+
+	    // Both are `a\u{fffd}b` to a lossy UTF-8 decoding.
+	    datastore.put_item(b"a\xffb".to_vec(), b"ff".to_vec()).await.unwrap();
+	    datastore.put_item(b"a\xfeb".to_vec(), b"fe".to_vec()).await.unwrap();
+	    assert_eq!(datastore.get_item(b"a\xffb").await.unwrap(), Some(b"ff".to_vec()));
+	    assert_eq!(datastore.get_item(b"a\xfeb").await.unwrap(), Some(b"fe".to_vec()));
+	}
+
+	#[tokio::test]
+	async fn increment_counts_like_the_other_backends() {
+	    let (_container, mut datastore) = datastore().await;
+
+	    assert_eq!(datastore.increment(b"new".to_vec(), 5).await.unwrap(), Some(5));
+	    assert_eq!(datastore.increment(b"new".to_vec(), -2).await.unwrap(), Some(3));
+	    assert_eq!(datastore.get_item(b"new").await.unwrap(), Some(3i64.to_le_bytes().to_vec()));
+
+	    // A counter the guest wrote itself, as 8 little-endian bytes.
+	    datastore.put_item(b"written".to_vec(), 40i64.to_le_bytes().to_vec()).await.unwrap();
+	    assert_eq!(datastore.increment(b"written".to_vec(), 2).await.unwrap(), Some(42));
+
+	    datastore.put_item(b"text".to_vec(), b"not a counter".to_vec()).await.unwrap();
+	    assert_eq!(datastore.increment(b"text".to_vec(), 1).await.unwrap(), None);
+	    assert_eq!(datastore.get_item(b"text").await.unwrap(), Some(b"not a counter".to_vec()));
+
+	    // Expired but not yet swept, so it starts again from 0.
+	    datastore.put_item_with_ttl(b"expired".to_vec(), 100i64.to_le_bytes().to_vec(), Duration::ZERO).await.unwrap();
+	    assert_eq!(datastore.increment(b"expired".to_vec(), 1).await.unwrap(), Some(1));
+	    assert_eq!(datastore.get_item(b"expired").await.unwrap(), Some(1i64.to_le_bytes().to_vec()));
+	}
+    }
+}
@@ -0,0 +1,100 @@
+//! A `Datastore` that counts the calls made to a backend, for the local
+//! server's `/metrics` page.
+//!
+//! Each call increments `wasmtest_datastore_calls_total`, labelled with the
+//! backend, the operation and whether it succeeded, and records how long it
+//! took in the `wasmtest_datastore_call_duration_seconds` histogram,
+//! labelled with the backend and the operation. Until something installs a
+//! metrics recorder, as `local_server` does, counting is a no-op.
+
+use std::time::{Duration, Instant};
+
+use crate::{Datastore, DatastoreError, Entries, Values};
+
+/// Counts the calls made to `backend`.
+#[derive(Clone, Debug)]
+pub struct MeteredDatastore<B> {
+    backend: B,
+}
+
+impl<B: Datastore> MeteredDatastore<B> {
+    pub fn new(backend: B) -> Self {
+	MeteredDatastore { backend }
+    }
+}
+
+/// Awaits `$call` and counts and times it as a `$op` of `$self`'s backend.
+macro_rules! metered {
+    ($self:ident, $op:literal, $call:expr) => {{
+	let started = Instant::now();
+	let result = $call.await;
+	let outcome = if result.is_ok() { "ok" } else { "error" };
+	::metrics::counter!("wasmtest_datastore_calls_total", "backend" => $self.backend.name(), "op" => $op, "outcome" => outcome).increment(1);
+	::metrics::histogram!("wasmtest_datastore_call_duration_seconds", "backend" => $self.backend.name(), "op" => $op).record(started.elapsed());
+	result
+    }};
+}
+
+impl<B: Datastore> Datastore for MeteredDatastore<B> {
+    const NAME: &'static str = B::NAME;
+
+    fn name(&self) -> &'static str {
+	self.backend.name()
+    }
+
+    async fn put_item(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<(), DatastoreError> {
+	metered!(self, "put_item", self.backend.put_item(key, value))
+    }
+
+    async fn put_item_with_ttl(&mut self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<(), DatastoreError> {
+	metered!(self, "put_item_with_ttl", self.backend.put_item_with_ttl(key, value, ttl))
+    }
+
+    async fn get_item(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>, DatastoreError> {
+	metered!(self, "get_item", self.backend.get_item(key))
+    }
+
+    async fn batch_get_items(&mut self, keys: &[Vec<u8>]) -> Result<Values, DatastoreError> {
+	metered!(self, "batch_get_items", self.backend.batch_get_items(keys))
+    }
+
+    async fn delete_item(&mut self, key: &[u8]) -> Result<(), DatastoreError> {
+	metered!(self, "delete_item", self.backend.delete_item(key))
+    }
+
+    async fn exists(&mut self, key: &[u8]) -> Result<bool, DatastoreError> {
+	metered!(self, "exists", self.backend.exists(key))
+    }
+
+    async fn compare_and_swap(&mut self, key: Vec<u8>, expected: Option<&[u8]>, new: Vec<u8>) -> Result<bool, DatastoreError> {
+	metered!(self, "compare_and_swap", self.backend.compare_and_swap(key, expected, new))
+    }
+
+    async fn append(&mut self, key: Vec<u8>, suffix: Vec<u8>) -> Result<(), DatastoreError> {
+	metered!(self, "append", self.backend.append(key, suffix))
+    }
+
+    async fn delete_if_equals(&mut self, key: &[u8], expected: &[u8]) -> Result<bool, DatastoreError> {
+	metered!(self, "delete_if_equals", self.backend.delete_if_equals(key, expected))
+    }
+
+    async fn swap(&mut self, key: Vec<u8>, new: Vec<u8>) -> Result<Option<Vec<u8>>, DatastoreError> {
+	metered!(self, "swap", self.backend.swap(key, new))
+    }
+
+    async fn increment(&mut self, key: Vec<u8>, delta: i64) -> Result<Option<i64>, DatastoreError> {
+	metered!(self, "increment", self.backend.increment(key, delta))
+    }
+
+    async fn put_items(&mut self, pairs: Vec<(Vec<u8>, Vec<u8>)>) -> Result<(), DatastoreError> {
+	metered!(self, "put_items", self.backend.put_items(pairs))
+    }
+
+    async fn scan_prefix(&mut self, prefix: &[u8]) -> Result<Entries, DatastoreError> {
+	metered!(self, "scan_prefix", self.backend.scan_prefix(prefix))
+    }
+
+    async fn count(&mut self) -> Result<u64, DatastoreError> {
+	metered!(self, "count", self.backend.count())
+    }
+}
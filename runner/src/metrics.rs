@@ -0,0 +1,161 @@
+//! Custom metrics guests emit through the `emit_metric` import.
+//!
+//! Each invocation adds up the values it emits under each name and, once the
+//! guest's entrypoint returns, prints them as a single line of CloudWatch's
+//! embedded metric format. Lambda sends stdout to CloudWatch Logs, which
+//! turns that line into metrics with no API calls on the request path. Each
+//! metric has an `export` dimension naming the entrypoint that emitted it.
+//!
+//! Metric names are 1 to 255 bytes of ASCII letters, digits, `.`, `_`, `-`
+//! and `/`, other than `export`, which is taken by the dimension, and `_aws`,
+//! which holds the format's metadata. Values must be finite. An invocation
+//! can emit at most `MAX_METRICS` different names.
+
+use std::collections::BTreeMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The namespace metrics are reported under if `WASMTEST_METRICS_NAMESPACE`
+/// doesn't say.
+pub const DEFAULT_NAMESPACE: &str = "wasmtest";
+
+/// The most different metrics one invocation can emit, which is as many as
+/// one embedded metric format document can hold.
+pub const MAX_METRICS: usize = 100;
+
+/// The longest metric name CloudWatch accepts.
+const MAX_NAME_LEN: usize = 255;
+
+/// Why `emit_metric` refused a metric. As with `DatastoreError`, each variant
+/// has a fixed status code that's handed back to the guest.
+#[derive(Debug, PartialEq, Eq)]
+pub enum MetricError {
+    /// The name breaks the rules above, or the value isn't finite.
+    Invalid,
+    /// The invocation has already emitted `MAX_METRICS` other names.
+    TooMany,
+}
+
+impl MetricError {
+    pub fn status(&self) -> u32 {
+	match self {
+	    MetricError::Invalid => 1,
+	    MetricError::TooMany => 2,
+	}
+    }
+}
+
+/// The metrics one invocation has emitted so far, totalled by name.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    totals: BTreeMap<String, f64>,
+}
+
+impl Metrics {
+    /// Adds `value` to the total for `name`.
+    pub fn record(&mut self, name: &[u8], value: f64) -> Result<(), MetricError> {
+	let name = std::str::from_utf8(name).ok().filter(|name| is_valid_name(name)).ok_or(MetricError::Invalid)?;
+	if !value.is_finite() {
+	    return Err(MetricError::Invalid);
+	}
+	if !self.totals.contains_key(name) && self.totals.len() >= MAX_METRICS {
+	    return Err(MetricError::TooMany);
+	}
+	*self.totals.entry(name.to_string()).or_insert(0.0) += value;
+	Ok(())
+    }
+
+    /// Prints everything recorded so far under `namespace`, for the
+    /// entrypoint `export`, and starts over. Prints nothing if nothing was
+    /// recorded.
+    pub fn flush(&mut self, namespace: &str, export: &str) {
+	if let Some(document) = self.take_document(namespace, export) {
+	    println!("{}", document);
+	}
+    }
+
+    /// The embedded metric format document `flush` prints, leaving nothing
+    /// recorded.
+    fn take_document(&mut self, namespace: &str, export: &str) -> Option<serde_json::Value> {
+	if self.totals.is_empty() {
+	    return None;
+	}
+	let totals = std::mem::take(&mut self.totals);
+	let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64;
+	let definitions: Vec<_> = totals.keys().map(|name| serde_json::json!({ "Name": name })).collect();
+	let mut document = serde_json::json!({
+	    "_aws": {
+		"Timestamp": timestamp,
+		"CloudWatchMetrics": [{
+		    "Namespace": namespace,
+		    "Dimensions": [["export"]],
+		    "Metrics": definitions,
+		}],
+	    },
+	    "export": export,
+	});
+	for (name, total) in totals {
+	    document[name] = total.into();
+	}
+	Some(document)
+    }
+}
+
+fn is_valid_name(name: &str) -> bool {
+    (1..=MAX_NAME_LEN).contains(&name.len())
+	&& name != "export"
+	&& name != "_aws"
+	&& name.bytes().all(|b| b.is_ascii_alphanumeric() || b"._-/".contains(&b))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn record_totals_each_name() {
+	let mut metrics = Metrics::default();
+	metrics.record(b"hits", 1.0).unwrap();
+	metrics.record(b"hits", 2.0).unwrap();
+	metrics.record(b"cache/misses", 1.0).unwrap();
+
+	let document = metrics.take_document("ns", "entry").unwrap();
+
+	assert_eq!(document["hits"], 3.0);
+	assert_eq!(document["cache/misses"], 1.0);
+	assert_eq!(document["export"], "entry");
+	let directive = &document["_aws"]["CloudWatchMetrics"][0];
+	assert_eq!(directive["Namespace"], "ns");
+	assert_eq!(directive["Dimensions"], serde_json::json!([["export"]]));
+	assert_eq!(directive["Metrics"], serde_json::json!([{ "Name": "cache/misses" }, { "Name": "hits" }]));
+	assert!(metrics.take_document("ns", "entry").is_none());
+    }
+
+    #[test]
+    fn record_refuses_names_the_document_needs() {
+	let mut metrics = Metrics::default();
+	assert_eq!(metrics.record(b"_aws", 1.0), Err(MetricError::Invalid));
+	assert_eq!(metrics.record(b"export", 1.0), Err(MetricError::Invalid));
+	assert!(metrics.take_document("ns", "entry").is_none());
+    }
+
+    #[test]
+    fn record_refuses_invalid_metrics() {
+	let mut metrics = Metrics::default();
+	assert_eq!(metrics.record(b"", 1.0), Err(MetricError::Invalid));
+	assert_eq!(metrics.record(b"spaces not allowed", 1.0), Err(MetricError::Invalid));
+	assert_eq!(metrics.record(&[b'a'; MAX_NAME_LEN + 1], 1.0), Err(MetricError::Invalid));
+	assert_eq!(metrics.record(b"nan", f64::NAN), Err(MetricError::Invalid));
+	assert_eq!(metrics.record(b"infinite", f64::INFINITY), Err(MetricError::Invalid));
+    }
+
+    #[test]
+    fn record_caps_distinct_names() {
+	let mut metrics = Metrics::default();
+	for i in 0..MAX_METRICS {
+	    metrics.record(format!("m{}", i).as_bytes(), 1.0).unwrap();
+	}
+	assert_eq!(metrics.record(b"one.more", 1.0), Err(MetricError::TooMany));
+	// Names already emitted can still be added to.
+	metrics.record(b"m0", 1.0).unwrap();
+    }
+}
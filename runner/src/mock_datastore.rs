@@ -0,0 +1,194 @@
+//! A `Datastore` for tests, which records every operation it's asked to do
+//! so a test can check exactly what a guest did to its storage.
+//!
+//! Only built for tests with the `test-util` feature, e.g.
+//! `cargo test --features test-util`.
+
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use lambda_http::http::request::Parts;
+use lambda_http::Request;
+use wasmtime::{Result, Store};
+
+use crate::{Datastore, DatastoreError, Entries, MemoryDatastore, MyState, Runtime, Values};
+
+/// One call a `MockDatastore` received, with its arguments.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Op {
+    Put { key: Vec<u8>, value: Vec<u8> },
+    PutWithTtl { key: Vec<u8>, value: Vec<u8>, ttl: Duration },
+    Get { key: Vec<u8> },
+    BatchGet { keys: Vec<Vec<u8>> },
+    Delete { key: Vec<u8> },
+    Exists { key: Vec<u8> },
+    CompareAndSwap { key: Vec<u8>, expected: Option<Vec<u8>>, new: Vec<u8> },
+    DeleteIfEquals { key: Vec<u8>, expected: Vec<u8> },
+    Append { key: Vec<u8>, suffix: Vec<u8> },
+    Swap { key: Vec<u8>, new: Vec<u8> },
+    Increment { key: Vec<u8>, delta: i64 },
+    PutItems { pairs: Vec<(Vec<u8>, Vec<u8>)> },
+    ScanPrefix { prefix: Vec<u8> },
+    Count,
+}
+
+/// Stores items in memory like `MemoryDatastore`, logging each operation
+/// first. Unlike `MemoryDatastore`, clones share both the items and the
+/// log, so what one request writes the next reads, and the test's own clone
+/// sees everything the runtime's clones did.
+#[derive(Clone, Debug, Default)]
+pub struct MockDatastore {
+    items: Arc<tokio::sync::Mutex<MemoryDatastore>>,
+    ops: Arc<Mutex<Vec<Op>>>,
+    /// How many of the next operations fail.
+    failures: Arc<AtomicU32>,
+}
+
+impl MockDatastore {
+    /// Starts out holding `items`. Seeding isn't logged.
+    pub fn with_items(items: impl IntoIterator<Item = (Vec<u8>, Vec<u8>)>) -> Self {
+	let mut memory = MemoryDatastore::default();
+	memory.items.extend(items);
+	MockDatastore { items: Arc::new(tokio::sync::Mutex::new(memory)), ..Default::default() }
+    }
+
+    /// The value stored at `key`, without logging a read.
+    pub async fn item(&self, key: &[u8]) -> Option<Vec<u8>> {
+	self.items.lock().await.get_item(key).await.unwrap()
+    }
+
+    /// Every operation so far, oldest first.
+    pub fn ops(&self) -> Vec<Op> {
+	self.ops.lock().unwrap().clone()
+    }
+
+    /// Forgets the operations so far, so a test can check only what comes
+    /// next.
+    pub fn clear_ops(&self) {
+	self.ops.lock().unwrap().clear();
+    }
+
+    /// Makes the next `n` operations fail with `DatastoreError::Throttled`,
+    /// as a struggling backend's would, without touching the items. They're
+    /// still logged.
+    pub fn fail_next(&self, n: u32) {
+	self.failures.store(n, Ordering::SeqCst);
+    }
+
+    /// Logs `op`, then fails it if `fail_next` said to.
+    fn record(&self, op: Op) -> Result<(), DatastoreError> {
+	self.ops.lock().unwrap().push(op);
+	match self.failures.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1)) {
+	    Ok(_) => Err(DatastoreError::Throttled),
+	    Err(_) => Ok(()),
+	}
+    }
+}
+
+impl Datastore for MockDatastore {
+    const NAME: &'static str = "mock";
+
+    async fn put_item(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<(), DatastoreError> {
+	self.record(Op::Put { key: key.clone(), value: value.clone() })?;
+	self.items.lock().await.put_item(key, value).await
+    }
+
+    async fn put_item_with_ttl(&mut self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<(), DatastoreError> {
+	self.record(Op::PutWithTtl { key: key.clone(), value: value.clone(), ttl })?;
+	self.items.lock().await.put_item_with_ttl(key, value, ttl).await
+    }
+
+    async fn get_item(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>, DatastoreError> {
+	self.record(Op::Get { key: key.to_vec() })?;
+	self.items.lock().await.get_item(key).await
+    }
+
+    async fn batch_get_items(&mut self, keys: &[Vec<u8>]) -> Result<Values, DatastoreError> {
+	self.record(Op::BatchGet { keys: keys.to_vec() })?;
+	self.items.lock().await.batch_get_items(keys).await
+    }
+
+    async fn delete_item(&mut self, key: &[u8]) -> Result<(), DatastoreError> {
+	self.record(Op::Delete { key: key.to_vec() })?;
+	self.items.lock().await.delete_item(key).await
+    }
+
+    async fn exists(&mut self, key: &[u8]) -> Result<bool, DatastoreError> {
+	self.record(Op::Exists { key: key.to_vec() })?;
+	self.items.lock().await.exists(key).await
+    }
+
+    async fn compare_and_swap(&mut self, key: Vec<u8>, expected: Option<&[u8]>, new: Vec<u8>) -> Result<bool, DatastoreError> {
+	self.record(Op::CompareAndSwap { key: key.clone(), expected: expected.map(<[u8]>::to_vec), new: new.clone() })?;
+	self.items.lock().await.compare_and_swap(key, expected, new).await
+    }
+
+    async fn append(&mut self, key: Vec<u8>, suffix: Vec<u8>) -> Result<(), DatastoreError> {
+	self.record(Op::Append { key: key.clone(), suffix: suffix.clone() })?;
+	self.items.lock().await.append(key, suffix).await
+    }
+
+    async fn delete_if_equals(&mut self, key: &[u8], expected: &[u8]) -> Result<bool, DatastoreError> {
+	self.record(Op::DeleteIfEquals { key: key.to_vec(), expected: expected.to_vec() })?;
+	self.items.lock().await.delete_if_equals(key, expected).await
+    }
+
+    async fn swap(&mut self, key: Vec<u8>, new: Vec<u8>) -> Result<Option<Vec<u8>>, DatastoreError> {
+	self.record(Op::Swap { key: key.clone(), new: new.clone() })?;
+	self.items.lock().await.swap(key, new).await
+    }
+
+    async fn increment(&mut self, key: Vec<u8>, delta: i64) -> Result<Option<i64>, DatastoreError> {
+	self.record(Op::Increment { key: key.clone(), delta })?;
+	self.items.lock().await.increment(key, delta).await
+    }
+
+    async fn put_items(&mut self, pairs: Vec<(Vec<u8>, Vec<u8>)>) -> Result<(), DatastoreError> {
+	self.record(Op::PutItems { pairs: pairs.clone() })?;
+	self.items.lock().await.put_items(pairs).await
+    }
+
+    async fn scan_prefix(&mut self, prefix: &[u8]) -> Result<Entries, DatastoreError> {
+	self.record(Op::ScanPrefix { prefix: prefix.to_vec() })?;
+	self.items.lock().await.scan_prefix(prefix).await
+    }
+
+    async fn count(&mut self) -> Result<u64, DatastoreError> {
+	self.record(Op::Count)?;
+	self.items.lock().await.count().await
+    }
+}
+
+/// A store for calling host functions or guest exports directly, shaped
+/// like the one `function_handler` would build for an empty `GET /`.
+pub fn mock_store(runtime: &Runtime<MockDatastore>) -> Result<Store<MyState<MockDatastore>>> {
+    let (parts, _): (Parts, _) = Request::default().into_parts();
+    runtime.store(parts, None, None, "mock".into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_runner::{TestInstance, TestRunner};
+
+    #[tokio::test]
+    async fn records_what_entry_does() {
+	let datastore = MockDatastore::with_items([(b"foo".to_vec(), b"bar".to_vec())]);
+	let runner = TestRunner::new(datastore.clone()).unwrap();
+	let mut store = mock_store(runner.runtime()).unwrap();
+	let instance = runner.runtime().instance_pre.instantiate_async(&mut store).await.unwrap();
+	let mut guest = TestInstance::new(store, instance).unwrap();
+
+	assert_eq!(guest.call(b"hello").await.unwrap(), b"bar");
+	assert_eq!(datastore.ops(), [
+	    Op::Put { key: b"hello".to_vec(), value: b"world".to_vec() },
+	    Op::Get { key: b"foo".to_vec() },
+	    Op::Put { key: b"world".to_vec(), value: b"bar".to_vec() },
+	]);
+
+	datastore.clear_ops();
+	guest.call(b"again").await.unwrap();
+	assert_eq!(datastore.ops()[0], Op::Put { key: b"again".to_vec(), value: b"world".to_vec() });
+    }
+}
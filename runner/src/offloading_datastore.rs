@@ -0,0 +1,269 @@
+//! A `Datastore` that keeps big values in a second backend, for metadata
+//! backends that cap how big a value can be, like DynamoDB's 400KB items.
+//!
+//! Values of at least `min_len` bytes are written to the blob backend (S3)
+//! under a fresh random ID, and the metadata backend stores `MAGIC` followed
+//! by that ID in their place. Reads follow the pointer, and everything else
+//! is stored and read back inline. A value that's shaped like a pointer is
+//! always offloaded, so it can't be mistaken for one.
+//!
+//! Writing a big value takes two writes, which aren't atomic together. The
+//! blob is written first, so a pointer never refers to a blob that hasn't
+//! been written yet, and the old blob is deleted once its pointer has been
+//! replaced. A read that races an overwrite can find its blob already gone,
+//! and reads the key again. Conditional operations compare the current
+//! value but swap on the pointer the metadata backend holds, so they're as
+//! atomic as that backend is. A runner that dies between the two writes, or
+//! writes that race each other, can leave blobs nothing points to; giving
+//! the blob store a lifecycle rule, or writing with TTLs, cleans those up.
+
+use std::time::Duration;
+
+use lambda_http::{tracing, Error};
+
+use crate::{env_or, Datastore, DatastoreError, Entries, Values};
+
+/// Marks a pointer to a blob. `0xff` never appears in UTF-8 text, so no JSON
+/// or other text value starts with it.
+const MAGIC: &[u8] = b"\xffwto";
+
+/// How many random bytes identify a blob.
+const ID_LEN: usize = 16;
+
+/// The smallest value to offload if `WASMTEST_OFFLOAD_MIN_BYTES` doesn't
+/// say. This leaves DynamoDB's 400KB limit room for the key, the item's
+/// other attributes and base64 overhead in its size accounting.
+pub const DEFAULT_MIN_LEN: usize = 300 * 1024;
+
+/// Stores small values in `meta` and big ones in `blob`.
+#[derive(Clone, Debug)]
+pub struct OffloadingDatastore<M, B> {
+    meta: M,
+    blob: B,
+    /// Values at least this long go to `blob`.
+    min_len: usize,
+}
+
+/// The ID of the blob `stored` points to, if it's a pointer.
+fn blob_id(stored: &[u8]) -> Option<&[u8]> {
+    stored.strip_prefix(MAGIC).filter(|id| id.len() == ID_LEN)
+}
+
+impl<M: Datastore, B: Datastore> OffloadingDatastore<M, B> {
+    pub fn new(meta: M, blob: B, min_len: usize) -> Self {
+	OffloadingDatastore { meta, blob, min_len }
+    }
+
+    /// Offloads values of at least `WASMTEST_OFFLOAD_MIN_BYTES` bytes.
+    pub fn from_env(meta: M, blob: B) -> Result<Self, Error> {
+	Ok(Self::new(meta, blob, env_or("WASMTEST_OFFLOAD_MIN_BYTES", DEFAULT_MIN_LEN)?))
+    }
+
+    /// The form `value` is stored in the metadata backend, writing it to
+    /// the blob backend first if it's big.
+    async fn store(&mut self, value: Vec<u8>, ttl: Option<Duration>) -> Result<Vec<u8>, DatastoreError> {
+	if value.len() < self.min_len && blob_id(&value).is_none() {
+	    return Ok(value);
+	}
+	let id = rand::random::<[u8; ID_LEN]>().to_vec();
+	match ttl {
+	    // The blob expires along with its pointer.
+	    Some(ttl) => self.blob.put_item_with_ttl(id.clone(), value, ttl).await?,
+	    None => self.blob.put_item(id.clone(), value).await?,
+	}
+	Ok([MAGIC, &id].concat())
+    }
+
+    /// The value `stored` holds, or `None` if it points to a blob that's
+    /// gone.
+    async fn load(&mut self, stored: Vec<u8>) -> Result<Option<Vec<u8>>, DatastoreError> {
+	match blob_id(&stored) {
+	    Some(id) => self.blob.get_item(id).await,
+	    None => Ok(Some(stored)),
+	}
+    }
+
+    /// Deletes the blob `stored` points to, if any, now that nothing should.
+    /// The write that replaced it already succeeded, so failing this only
+    /// leaves the blob behind.
+    async fn discard(&mut self, stored: Option<&[u8]>) {
+	let Some(id) = stored.and_then(blob_id) else {
+	    return;
+	};
+	if let Err(e) = self.blob.delete_item(id).await {
+	    tracing::warn!(error = %e, "couldn't delete offloaded value");
+	}
+    }
+
+    /// The value of `key` that was read from the metadata backend as
+    /// `stored`, reading the key again if its blob has been replaced.
+    async fn resolve(&mut self, key: &[u8], stored: Vec<u8>) -> Result<Option<Vec<u8>>, DatastoreError> {
+	if let Some(value) = self.load(stored).await? {
+	    return Ok(Some(value));
+	}
+	// The key was overwritten or deleted after we read it, so whatever
+	// it holds now is what a read should see.
+	match self.meta.get_item(key).await? {
+	    Some(stored) => self.load(stored).await,
+	    None => Ok(None),
+	}
+    }
+}
+
+impl<M: Datastore, B: Datastore> Datastore for OffloadingDatastore<M, B> {
+    const NAME: &'static str = M::NAME;
+
+    fn name(&self) -> &'static str {
+	self.meta.name()
+    }
+
+    async fn put_item(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<(), DatastoreError> {
+	// Swapping hands back whatever pointer this replaced in the same write.
+	let stored = self.store(value, None).await?;
+	match self.meta.swap(key, stored.clone()).await {
+	    Ok(old) => self.discard(old.as_deref()).await,
+	    Err(e) => {
+		self.discard(Some(&stored)).await;
+		return Err(e);
+	    }
+	}
+	Ok(())
+    }
+
+    async fn put_item_with_ttl(&mut self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<(), DatastoreError> {
+	// There's no swap that keeps a TTL, so look up the old pointer first.
+	// A write racing this one can slip in between and leave its blob
+	// behind.
+	let old = self.meta.get_item(&key).await?;
+	let stored = self.store(value, Some(ttl)).await?;
+	if let Err(e) = self.meta.put_item_with_ttl(key, stored.clone(), ttl).await {
+	    self.discard(Some(&stored)).await;
+	    return Err(e);
+	}
+	self.discard(old.as_deref()).await;
+	Ok(())
+    }
+
+    async fn get_item(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>, DatastoreError> {
+	match self.meta.get_item(key).await? {
+	    Some(stored) => self.resolve(key, stored).await,
+	    None => Ok(None),
+	}
+    }
+
+    async fn batch_get_items(&mut self, keys: &[Vec<u8>]) -> Result<Values, DatastoreError> {
+	let stored = self.meta.batch_get_items(keys).await?;
+	let mut values = Vec::with_capacity(keys.len());
+	for (key, stored) in keys.iter().zip(stored) {
+	    values.push(match stored {
+		Some(stored) => self.resolve(key, stored).await?,
+		None => None,
+	    });
+	}
+	Ok(values)
+    }
+
+    async fn delete_item(&mut self, key: &[u8]) -> Result<(), DatastoreError> {
+	let old = self.meta.get_item(key).await?;
+	self.meta.delete_item(key).await?;
+	self.discard(old.as_deref()).await;
+	Ok(())
+    }
+
+    async fn exists(&mut self, key: &[u8]) -> Result<bool, DatastoreError> {
+	self.meta.exists(key).await
+    }
+
+    async fn compare_and_swap(&mut self, key: Vec<u8>, expected: Option<&[u8]>, new: Vec<u8>) -> Result<bool, DatastoreError> {
+	// Compare the value the stored bytes hold, and have the metadata
+	// backend swap on those exact bytes. A blob that's gone means the key
+	// is being overwritten, so it doesn't hold `expected` any more.
+	let current = self.meta.get_item(&key).await?;
+	let value = match current.clone() {
+	    Some(stored) => match self.load(stored).await? {
+		Some(value) => Some(value),
+		None => return Ok(false),
+	    },
+	    None => None,
+	};
+	if value.as_deref() != expected {
+	    return Ok(false);
+	}
+	let stored = self.store(new, None).await?;
+	match self.meta.compare_and_swap(key, current.as_deref(), stored.clone()).await {
+	    Ok(true) => {
+		self.discard(current.as_deref()).await;
+		Ok(true)
+	    }
+	    result => {
+		self.discard(Some(&stored)).await;
+		result
+	    }
+	}
+    }
+
+    async fn delete_if_equals(&mut self, key: &[u8], expected: &[u8]) -> Result<bool, DatastoreError> {
+	let Some(current) = self.meta.get_item(key).await? else {
+	    return Ok(false);
+	};
+	if self.load(current.clone()).await?.as_deref() != Some(expected) {
+	    return Ok(false);
+	}
+	let deleted = self.meta.delete_if_equals(key, &current).await?;
+	if deleted {
+	    self.discard(Some(&current)).await;
+	}
+	Ok(deleted)
+    }
+
+    async fn swap(&mut self, key: Vec<u8>, new: Vec<u8>) -> Result<Option<Vec<u8>>, DatastoreError> {
+	let stored = self.store(new, None).await?;
+	let old = match self.meta.swap(key, stored.clone()).await {
+	    Ok(old) => old,
+	    Err(e) => {
+		self.discard(Some(&stored)).await;
+		return Err(e);
+	    }
+	};
+	// Read the old blob before deleting it.
+	let value = match old.clone() {
+	    Some(old) => self.load(old).await?,
+	    None => None,
+	};
+	self.discard(old.as_deref()).await;
+	Ok(value)
+    }
+
+    async fn increment(&mut self, key: Vec<u8>, delta: i64) -> Result<Option<i64>, DatastoreError> {
+	// Counters are always small enough to stay inline, and a pointer is
+	// never 8 bytes, so the metadata backend can count by itself.
+	self.meta.increment(key, delta).await
+    }
+
+    async fn scan_prefix(&mut self, prefix: &[u8]) -> Result<Entries, DatastoreError> {
+	let mut entries = Vec::new();
+	for (key, stored) in self.meta.scan_prefix(prefix).await? {
+	    if let Some(value) = self.resolve(&key, stored).await? {
+		entries.push((key, value));
+	    }
+	}
+	Ok(entries)
+    }
+
+    async fn count(&mut self) -> Result<u64, DatastoreError> {
+	self.meta.count().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::file_datastore::FileDatastore;
+    use crate::MemoryDatastore;
+
+    #[test]
+    fn is_named_for_its_metadata_backend() {
+	let datastore = OffloadingDatastore::new(MemoryDatastore::default(), FileDatastore::new("unused".into()), 1024);
+	assert_eq!(datastore.name(), "memory");
+    }
+}
@@ -0,0 +1,238 @@
+//! A `Datastore` that limits how often each key can be touched, so one
+//! guest hammering a key can't run up a shared backend's bill or get the
+//! whole table throttled.
+//!
+//! Every key has a token bucket that holds up to `burst` tokens and refills
+//! at `rate` tokens a second. Each operation on a key takes a token, and one
+//! that finds the bucket empty fails with `DatastoreError::Throttled`
+//! without reaching the backend, which guests see as the same status a
+//! throttling backend returns. `batch_get_items` and `put_items` take a token
+//! from every key they touch, and fail as a whole if any is empty.
+//! `scan_prefix` and `count` aren't about any one key, so they aren't
+//! limited.
+//!
+//! Buckets live in the runner's memory and clones share them, so the limit
+//! holds across every request a container serves, but each container
+//! counts separately.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use lambda_http::Error;
+
+use crate::{env_or, Datastore, DatastoreError, Entries, Values};
+
+/// How many buckets to keep before forgetting the ones that have refilled.
+/// A full bucket behaves the same as a new one, so forgetting it is free.
+const MAX_BUCKETS: usize = 10_000;
+
+/// Turns away operations on keys that have used up their rate.
+#[derive(Clone, Debug)]
+pub struct RateLimitedDatastore<B> {
+    backend: B,
+    /// How many tokens each bucket gains a second.
+    rate: f64,
+    /// How many tokens each bucket holds when full.
+    burst: f64,
+    buckets: Arc<Mutex<HashMap<Vec<u8>, Bucket>>>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    /// When `tokens` was last brought up to date.
+    updated: Instant,
+}
+
+impl<B: Datastore> RateLimitedDatastore<B> {
+    pub fn new(backend: B, rate: f64, burst: f64) -> Self {
+	RateLimitedDatastore { backend, rate, burst, buckets: Arc::default() }
+    }
+
+    /// Allows `rate` operations a second on each key, in bursts of up to
+    /// `WASMTEST_RATE_LIMIT_BURST`, which defaults to a second's worth.
+    pub fn from_env(backend: B, rate: f64) -> Result<Self, Error> {
+	if !rate.is_finite() || rate <= 0.0 {
+	    return Err(format!("WASMTEST_RATE_LIMIT must be positive, not {}", rate).into());
+	}
+	let burst = env_or("WASMTEST_RATE_LIMIT_BURST", rate.ceil())?;
+	if !burst.is_finite() || burst < 1.0 {
+	    return Err(format!("WASMTEST_RATE_LIMIT_BURST must be at least 1, not {}", burst).into());
+	}
+	Ok(Self::new(backend, rate, burst))
+    }
+
+    /// Takes a token from each of `keys`' buckets, or none of them if any is
+    /// empty.
+    fn acquire<'a>(&self, keys: impl IntoIterator<Item = &'a [u8]>) -> Result<(), DatastoreError> {
+	let now = Instant::now();
+	let mut buckets = self.buckets.lock().unwrap();
+	if buckets.len() >= MAX_BUCKETS {
+	    buckets.retain(|_, bucket| self.refill(bucket, now) < self.burst);
+	}
+	let mut taken: HashMap<&[u8], f64> = HashMap::new();
+	for key in keys {
+	    let bucket = buckets.entry(key.to_vec()).or_insert(Bucket { tokens: self.burst, updated: now });
+	    let tokens = self.refill(bucket, now);
+	    let wanted = taken.entry(key).or_default();
+	    *wanted += 1.0;
+	    if tokens < *wanted {
+		return Err(DatastoreError::Throttled);
+	    }
+	}
+	for (key, n) in taken {
+	    if let Some(bucket) = buckets.get_mut(key) {
+		bucket.tokens -= n;
+	    }
+	}
+	Ok(())
+    }
+
+    /// Brings `bucket` up to `now`, returning how many tokens it has.
+    fn refill(&self, bucket: &mut Bucket, now: Instant) -> f64 {
+	let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+	bucket.tokens = (bucket.tokens + elapsed * self.rate).min(self.burst);
+	bucket.updated = now;
+	bucket.tokens
+    }
+}
+
+impl<B: Datastore> Datastore for RateLimitedDatastore<B> {
+    const NAME: &'static str = B::NAME;
+
+    fn name(&self) -> &'static str {
+	self.backend.name()
+    }
+
+    async fn put_item(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<(), DatastoreError> {
+	self.acquire([&key[..]])?;
+	self.backend.put_item(key, value).await
+    }
+
+    async fn put_item_with_ttl(&mut self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<(), DatastoreError> {
+	self.acquire([&key[..]])?;
+	self.backend.put_item_with_ttl(key, value, ttl).await
+    }
+
+    async fn get_item(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>, DatastoreError> {
+	self.acquire([key])?;
+	self.backend.get_item(key).await
+    }
+
+    async fn batch_get_items(&mut self, keys: &[Vec<u8>]) -> Result<Values, DatastoreError> {
+	self.acquire(keys.iter().map(Vec::as_slice))?;
+	self.backend.batch_get_items(keys).await
+    }
+
+    async fn delete_item(&mut self, key: &[u8]) -> Result<(), DatastoreError> {
+	self.acquire([key])?;
+	self.backend.delete_item(key).await
+    }
+
+    async fn exists(&mut self, key: &[u8]) -> Result<bool, DatastoreError> {
+	self.acquire([key])?;
+	self.backend.exists(key).await
+    }
+
+    async fn compare_and_swap(&mut self, key: Vec<u8>, expected: Option<&[u8]>, new: Vec<u8>) -> Result<bool, DatastoreError> {
+	self.acquire([&key[..]])?;
+	self.backend.compare_and_swap(key, expected, new).await
+    }
+
+    async fn delete_if_equals(&mut self, key: &[u8], expected: &[u8]) -> Result<bool, DatastoreError> {
+	self.acquire([key])?;
+	self.backend.delete_if_equals(key, expected).await
+    }
+
+    async fn swap(&mut self, key: Vec<u8>, new: Vec<u8>) -> Result<Option<Vec<u8>>, DatastoreError> {
+	self.acquire([&key[..]])?;
+	self.backend.swap(key, new).await
+    }
+
+    async fn append(&mut self, key: Vec<u8>, suffix: Vec<u8>) -> Result<(), DatastoreError> {
+	self.acquire([&key[..]])?;
+	self.backend.append(key, suffix).await
+    }
+
+    async fn increment(&mut self, key: Vec<u8>, delta: i64) -> Result<Option<i64>, DatastoreError> {
+	self.acquire([&key[..]])?;
+	self.backend.increment(key, delta).await
+    }
+
+    async fn put_items(&mut self, pairs: Vec<(Vec<u8>, Vec<u8>)>) -> Result<(), DatastoreError> {
+	self.acquire(pairs.iter().map(|(key, _)| key.as_slice()))?;
+	self.backend.put_items(pairs).await
+    }
+
+    async fn scan_prefix(&mut self, prefix: &[u8]) -> Result<Entries, DatastoreError> {
+	self.backend.scan_prefix(prefix).await
+    }
+
+    async fn count(&mut self) -> Result<u64, DatastoreError> {
+	self.backend.count().await
+    }
+}
+
+#[cfg(all(test, feature = "test-util"))]
+mod tests {
+    use super::*;
+    use crate::mock_datastore::MockDatastore;
+    use crate::test_runner::{wat_guest, TestRunner};
+
+    /// Allows a burst of two, then barely refills during a test.
+    fn limited(backend: &MockDatastore) -> RateLimitedDatastore<MockDatastore> {
+	RateLimitedDatastore::new(backend.clone(), 0.001, 2.0)
+    }
+
+    #[tokio::test]
+    async fn throttles_a_key_past_its_burst() {
+	let backend = MockDatastore::default();
+	let mut datastore = limited(&backend);
+
+	datastore.get_item(b"foo").await.unwrap();
+	datastore.get_item(b"foo").await.unwrap();
+	assert!(matches!(datastore.get_item(b"foo").await, Err(DatastoreError::Throttled)));
+	assert_eq!(backend.ops().len(), 2);
+
+	datastore.get_item(b"bar").await.unwrap();
+	assert_eq!(backend.ops().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn batches_take_every_token_or_none() {
+	let backend = MockDatastore::default();
+	let mut datastore = limited(&backend);
+
+	datastore.get_item(b"foo").await.unwrap();
+	let keys = [b"bar".to_vec(), b"foo".to_vec(), b"foo".to_vec()];
+	assert!(matches!(datastore.batch_get_items(&keys).await, Err(DatastoreError::Throttled)));
+	assert_eq!(backend.ops().len(), 1);
+
+	datastore.get_item(b"bar").await.unwrap();
+	datastore.get_item(b"bar").await.unwrap();
+	assert!(matches!(datastore.get_item(b"bar").await, Err(DatastoreError::Throttled)));
+    }
+
+    #[tokio::test]
+    async fn guest_sees_a_throttled_status() {
+	let backend = MockDatastore::with_items([(b"foo".to_vec(), b"bar".to_vec())]);
+	// Reads `foo` three times and answers with the last `read_key` status.
+	let guest = wat_guest(r#"
+	    (import "env" "read_key" (func $read_key (param i32 i32) (result i32)))
+	    (data (i32.const 100) "foo")
+	    (data (i32.const 200) "\64\00\00\00\03\00\00\00")"#, r#"
+	    (drop (call $read_key (i32.const 200) (i32.const 300)))
+	    (drop (call $read_key (i32.const 200) (i32.const 300)))
+	    (i32.store8 (i32.const 400) (call $read_key (i32.const 200) (i32.const 300)))
+	    (i32.store (local.get $result) (i32.const 400))
+	    (i32.store offset=4 (local.get $result) (i32.const 1))"#);
+	let runner = TestRunner::with_module(limited(&backend), guest).unwrap();
+
+	let response = runner.call(b"").await.unwrap();
+
+	assert_eq!(response.status, 200);
+	assert_eq!(response.body, [2]);
+	assert_eq!(backend.ops().len(), 2);
+    }
+}
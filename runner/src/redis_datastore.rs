@@ -0,0 +1,225 @@
+//! A `Datastore` backed by Redis, for low-latency state shared between
+//! containers.
+//!
+//! Keys and values are stored as-is: Redis strings are binary-safe, so there's
+//! no need to encode them. Counters written by `increment` are the same
+//! little-endian i64s the guest reads and writes, rather than Redis' decimal
+//! integers, so a counter reads back through `get_item` the way it does on
+//! every other backend.
+
+use std::collections::HashSet;
+use std::time::Duration;
+
+use lambda_http::Error;
+use redis::{aio::ConnectionManager, AsyncCommands, Script};
+
+use crate::{env_opt, Datastore, DatastoreError, Values, MAX_SCAN_ENTRIES};
+
+/// Swaps in `ARGV[3]` if the key holds `ARGV[2]`, or if it's absent and
+/// `ARGV[1]` is `0`.
+const COMPARE_AND_SWAP: &str = r"
+local current = redis.call('GET', KEYS[1])
+if (ARGV[1] == '0' and not current) or (ARGV[1] == '1' and current == ARGV[2]) then
+    redis.call('SET', KEYS[1], ARGV[3])
+    return 1
+end
+return 0
+";
+
+/// Deletes the key if it holds `ARGV[1]`.
+const DELETE_IF_EQUALS: &str = r"
+if redis.call('GET', KEYS[1]) == ARGV[1] then
+    redis.call('DEL', KEYS[1])
+    return 1
+end
+return 0
+";
+
+/// Adds the little-endian i64 in `ARGV[1]` to the counter, a byte at a time
+/// since Lua's numbers are doubles and can't hold every i64. Returns nil if
+/// the key holds something that isn't a counter.
+const INCREMENT: &str = r"
+local current = redis.call('GET', KEYS[1]) or string.rep('\0', 8)
+if #current ~= 8 then
+    return false
+end
+local total, carry = {}, 0
+for i = 1, 8 do
+    local sum = current:byte(i) + ARGV[1]:byte(i) + carry
+    total[i] = string.char(sum % 256)
+    carry = math.floor(sum / 256)
+end
+total = table.concat(total)
+redis.call('SET', KEYS[1], total, 'KEEPTTL')
+return total
+";
+
+/// Stores each key as a Redis string. Cloning is cheap and shares the
+/// underlying connection, which reconnects by itself if it drops.
+#[derive(Clone)]
+pub struct RedisDatastore {
+    conn: ConnectionManager,
+    compare_and_swap: Script,
+    delete_if_equals: Script,
+    increment: Script,
+}
+
+impl RedisDatastore {
+    /// Connects to the server at `WASMTEST_REDIS_URL`, e.g.
+    /// `redis://localhost:6379/0`.
+    pub async fn from_env() -> Result<Self, Error> {
+	let url = env_opt::<String>("WASMTEST_REDIS_URL")?
+	    .ok_or("WASMTEST_REDIS_URL must be set to use the Redis datastore")?;
+	let client = redis::Client::open(url.as_str())
+	    .map_err(|e| format!("invalid WASMTEST_REDIS_URL {:?}: {}", url, e))?;
+	let conn = ConnectionManager::new(client).await
+	    .map_err(|e| format!("couldn't connect to Redis at {}: {}", url, e))?;
+	Ok(RedisDatastore {
+	    conn,
+	    compare_and_swap: Script::new(COMPARE_AND_SWAP),
+	    delete_if_equals: Script::new(DELETE_IF_EQUALS),
+	    increment: Script::new(INCREMENT),
+	})
+    }
+}
+
+impl DatastoreError {
+    fn from_redis(e: redis::RedisError) -> Self {
+	use redis::ErrorKind;
+	if e.is_timeout() {
+	    return DatastoreError::Timeout;
+	}
+	match e.kind() {
+	    ErrorKind::BusyLoadingError | ErrorKind::TryAgain => DatastoreError::Throttled,
+	    _ => DatastoreError::Backend(e.to_string()),
+	}
+    }
+}
+
+impl Datastore for RedisDatastore {
+    const NAME: &'static str = "redis";
+
+    async fn put_item(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<(), DatastoreError> {
+	self.conn.set(key, value).await.map_err(DatastoreError::from_redis)
+    }
+
+    async fn put_item_with_ttl(&mut self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<(), DatastoreError> {
+	// `PX` rejects a zero TTL, so round up to the smallest one it takes.
+	let millis = ttl.as_millis().clamp(1, u64::MAX as u128) as u64;
+	redis::cmd("SET").arg(key).arg(value).arg("PX").arg(millis)
+	    .query_async(&mut self.conn).await.map_err(DatastoreError::from_redis)
+    }
+
+    async fn get_item(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>, DatastoreError> {
+	self.conn.get(key).await.map_err(DatastoreError::from_redis)
+    }
+
+    async fn batch_get_items(&mut self, keys: &[Vec<u8>]) -> Result<Values, DatastoreError> {
+	if keys.is_empty() {
+	    return Ok(Vec::new());
+	}
+	redis::cmd("MGET").arg(keys)
+	    .query_async(&mut self.conn).await.map_err(DatastoreError::from_redis)
+    }
+
+    async fn delete_item(&mut self, key: &[u8]) -> Result<(), DatastoreError> {
+	self.conn.del(key).await.map_err(DatastoreError::from_redis)
+    }
+
+    async fn exists(&mut self, key: &[u8]) -> Result<bool, DatastoreError> {
+	self.conn.exists(key).await.map_err(DatastoreError::from_redis)
+    }
+
+    async fn compare_and_swap(&mut self, key: Vec<u8>, expected: Option<&[u8]>, new: Vec<u8>) -> Result<bool, DatastoreError> {
+	// Scripts run atomically, so nothing can write the key between the
+	// check and the swap.
+	self.compare_and_swap.key(key)
+	    .arg(if expected.is_some() { "1" } else { "0" })
+	    .arg(expected.unwrap_or_default())
+	    .arg(new)
+	    .invoke_async(&mut self.conn).await.map_err(DatastoreError::from_redis)
+    }
+
+    async fn append(&mut self, key: Vec<u8>, suffix: Vec<u8>) -> Result<(), DatastoreError> {
+	// `APPEND` is atomic and leaves the TTL alone.
+	let _: u64 = self.conn.append(key, suffix).await.map_err(DatastoreError::from_redis)?;
+	Ok(())
+    }
+
+    async fn delete_if_equals(&mut self, key: &[u8], expected: &[u8]) -> Result<bool, DatastoreError> {
+	self.delete_if_equals.key(key).arg(expected)
+	    .invoke_async(&mut self.conn).await.map_err(DatastoreError::from_redis)
+    }
+
+    async fn swap(&mut self, key: Vec<u8>, new: Vec<u8>) -> Result<Option<Vec<u8>>, DatastoreError> {
+	// `SET ... GET` (Redis 6.2 and up) replaces the value and its TTL and
+	// hands back the old value in one step.
+	redis::cmd("SET").arg(key).arg(new).arg("GET")
+	    .query_async(&mut self.conn).await.map_err(DatastoreError::from_redis)
+    }
+
+    async fn increment(&mut self, key: Vec<u8>, delta: i64) -> Result<Option<i64>, DatastoreError> {
+	let total: Option<Vec<u8>> = self.increment.key(key)
+	    .arg(&delta.to_le_bytes()[..])
+	    .invoke_async(&mut self.conn).await.map_err(DatastoreError::from_redis)?;
+	Ok(total.and_then(|total| Some(i64::from_le_bytes(total.try_into().ok()?))))
+    }
+
+    async fn put_items(&mut self, pairs: Vec<(Vec<u8>, Vec<u8>)>) -> Result<(), DatastoreError> {
+	if pairs.is_empty() {
+	    return Ok(());
+	}
+	let mut mset = redis::cmd("MSET");
+	for (key, value) in pairs {
+	    mset.arg(key).arg(value);
+	}
+	mset.query_async(&mut self.conn).await.map_err(DatastoreError::from_redis)
+    }
+
+    async fn scan_prefix(&mut self, prefix: &[u8]) -> Result<Vec<(Vec<u8>, Vec<u8>)>, DatastoreError> {
+	// `SCAN` walks the whole keyspace and filters as it goes, and can hand
+	// back the same key more than once, so collect every match before
+	// picking the first `MAX_SCAN_ENTRIES` and fetching only their values.
+	let mut pattern = Vec::with_capacity(prefix.len() + 1);
+	for &byte in prefix {
+	    if matches!(byte, b'*' | b'?' | b'[' | b']' | b'\\') {
+		pattern.push(b'\\');
+	    }
+	    pattern.push(byte);
+	}
+	pattern.push(b'*');
+
+	let mut keys = HashSet::new();
+	let mut cursor = 0u64;
+	loop {
+	    let (next, batch): (u64, Vec<Vec<u8>>) = redis::cmd("SCAN").arg(cursor)
+		.arg("MATCH").arg(&pattern).arg("COUNT").arg(1000)
+		.query_async(&mut self.conn).await.map_err(DatastoreError::from_redis)?;
+	    keys.extend(batch);
+	    if next == 0 {
+		break;
+	    }
+	    cursor = next;
+	}
+	let mut keys: Vec<_> = keys.into_iter().collect();
+	keys.sort();
+	keys.truncate(MAX_SCAN_ENTRIES);
+	if keys.is_empty() {
+	    return Ok(Vec::new());
+	}
+
+	// Keys can expire or be deleted between the scan and the `MGET`.
+	let values: Vec<Option<Vec<u8>>> = redis::cmd("MGET").arg(&keys)
+	    .query_async(&mut self.conn).await.map_err(DatastoreError::from_redis)?;
+	Ok(keys.into_iter().zip(values)
+	    .filter_map(|(key, value)| Some((key, value?)))
+	    .collect())
+    }
+
+    /// Returns `DBSIZE`, which counts every key in the database, including
+    /// any that weren't written through this store, such as another
+    /// runner's cache entries.
+    async fn count(&mut self) -> Result<u64, DatastoreError> {
+	redis::cmd("DBSIZE").query_async(&mut self.conn).await.map_err(DatastoreError::from_redis)
+    }
+}
@@ -0,0 +1,196 @@
+//! A `Datastore` that retries a backend's transient failures.
+//!
+//! Operations that fail because the backend throttled us or timed out are
+//! tried again after an exponentially growing, randomly jittered delay, up
+//! to a fixed number of attempts. Anything else fails straight away.
+//!
+//! A timed out write may still have happened, so `compare_and_swap`,
+//! `delete_if_equals`, `swap`, `append` and `increment`, which would do
+//! something different the second time, only retry when the backend
+//! throttled them, which means it turned the request away without changing
+//! anything.
+
+use std::time::Duration;
+
+use lambda_http::{tracing, Error};
+use rand::Rng;
+
+use crate::{env_or, Datastore, DatastoreError, Entries, Values};
+
+/// How many times an operation is tried if `WASMTEST_RETRY_ATTEMPTS` doesn't
+/// say.
+const DEFAULT_ATTEMPTS: u32 = 3;
+
+/// The delay before the first retry if `WASMTEST_RETRY_BASE_MS` doesn't say.
+/// Each retry after that waits up to twice as long as the one before.
+const DEFAULT_BASE_MS: u64 = 50;
+
+/// The longest any one retry waits.
+const MAX_DELAY: Duration = Duration::from_secs(2);
+
+/// Retries `backend`'s transient failures.
+#[derive(Clone, Debug)]
+pub struct RetryingDatastore<B> {
+    backend: B,
+    /// How many times to try each operation, including the first.
+    attempts: u32,
+    base_delay: Duration,
+}
+
+/// Whether an operation that failed with `e` can safely be tried again.
+fn is_transient(e: &DatastoreError) -> bool {
+    matches!(e, DatastoreError::Throttled | DatastoreError::Timeout)
+}
+
+/// Whether the backend turned the operation away without doing any of it.
+fn is_rejected(e: &DatastoreError) -> bool {
+    matches!(e, DatastoreError::Throttled)
+}
+
+/// Runs `$call` until it succeeds, fails with an error `$retryable` rejects,
+/// or runs out of attempts.
+macro_rules! retry {
+    ($self:ident, $retryable:path, $call:expr) => {{
+	let mut attempt = 1;
+	loop {
+	    match $call.await {
+		Err(e) if $retryable(&e) && attempt < $self.attempts => {
+		    tokio::time::sleep($self.delay(attempt, &e)).await;
+		    attempt += 1;
+		}
+		result => break result,
+	    }
+	}
+    }};
+}
+
+impl<B: Datastore> RetryingDatastore<B> {
+    pub fn new(backend: B, attempts: u32, base_delay: Duration) -> Self {
+	RetryingDatastore { backend, attempts, base_delay }
+    }
+
+    /// Tries each operation up to `WASMTEST_RETRY_ATTEMPTS` times, waiting
+    /// around `WASMTEST_RETRY_BASE_MS` before the first retry. One attempt
+    /// turns retrying off.
+    pub fn from_env(backend: B) -> Result<Self, Error> {
+	let attempts = env_or("WASMTEST_RETRY_ATTEMPTS", DEFAULT_ATTEMPTS)?;
+	if attempts == 0 {
+	    return Err("WASMTEST_RETRY_ATTEMPTS must be at least 1".into());
+	}
+	let base_delay = Duration::from_millis(env_or("WASMTEST_RETRY_BASE_MS", DEFAULT_BASE_MS)?);
+	Ok(Self::new(backend, attempts, base_delay))
+    }
+
+    /// How long to wait before the retry that follows `attempt`: a random
+    /// time up to `base_delay` doubled once per earlier retry. The randomness
+    /// keeps requests that were throttled together from all retrying
+    /// together.
+    fn delay(&self, attempt: u32, e: &DatastoreError) -> Duration {
+	let ceiling = self.base_delay.saturating_mul(1 << (attempt - 1).min(16)).min(MAX_DELAY);
+	let delay = ceiling.mul_f64(rand::thread_rng().gen());
+	tracing::warn!(backend = self.backend.name(), attempt, error = %e, ?delay, "retrying datastore operation");
+	delay
+    }
+}
+
+impl<B: Datastore> Datastore for RetryingDatastore<B> {
+    const NAME: &'static str = B::NAME;
+
+    fn name(&self) -> &'static str {
+	self.backend.name()
+    }
+
+    async fn put_item(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<(), DatastoreError> {
+	retry!(self, is_transient, self.backend.put_item(key.clone(), value.clone()))
+    }
+
+    async fn put_item_with_ttl(&mut self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<(), DatastoreError> {
+	retry!(self, is_transient, self.backend.put_item_with_ttl(key.clone(), value.clone(), ttl))
+    }
+
+    async fn get_item(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>, DatastoreError> {
+	retry!(self, is_transient, self.backend.get_item(key))
+    }
+
+    async fn batch_get_items(&mut self, keys: &[Vec<u8>]) -> Result<Values, DatastoreError> {
+	retry!(self, is_transient, self.backend.batch_get_items(keys))
+    }
+
+    async fn delete_item(&mut self, key: &[u8]) -> Result<(), DatastoreError> {
+	retry!(self, is_transient, self.backend.delete_item(key))
+    }
+
+    async fn exists(&mut self, key: &[u8]) -> Result<bool, DatastoreError> {
+	retry!(self, is_transient, self.backend.exists(key))
+    }
+
+    async fn compare_and_swap(&mut self, key: Vec<u8>, expected: Option<&[u8]>, new: Vec<u8>) -> Result<bool, DatastoreError> {
+	retry!(self, is_rejected, self.backend.compare_and_swap(key.clone(), expected, new.clone()))
+    }
+
+    async fn append(&mut self, key: Vec<u8>, suffix: Vec<u8>) -> Result<(), DatastoreError> {
+	retry!(self, is_rejected, self.backend.append(key.clone(), suffix.clone()))
+    }
+
+    async fn delete_if_equals(&mut self, key: &[u8], expected: &[u8]) -> Result<bool, DatastoreError> {
+	retry!(self, is_rejected, self.backend.delete_if_equals(key, expected))
+    }
+
+    async fn swap(&mut self, key: Vec<u8>, new: Vec<u8>) -> Result<Option<Vec<u8>>, DatastoreError> {
+	retry!(self, is_rejected, self.backend.swap(key.clone(), new.clone()))
+    }
+
+    async fn increment(&mut self, key: Vec<u8>, delta: i64) -> Result<Option<i64>, DatastoreError> {
+	retry!(self, is_rejected, self.backend.increment(key.clone(), delta))
+    }
+
+    async fn put_items(&mut self, pairs: Vec<(Vec<u8>, Vec<u8>)>) -> Result<(), DatastoreError> {
+	retry!(self, is_transient, self.backend.put_items(pairs.clone()))
+    }
+
+    async fn scan_prefix(&mut self, prefix: &[u8]) -> Result<Entries, DatastoreError> {
+	retry!(self, is_transient, self.backend.scan_prefix(prefix))
+    }
+
+    async fn count(&mut self) -> Result<u64, DatastoreError> {
+	retry!(self, is_transient, self.backend.count())
+    }
+}
+
+#[cfg(all(test, feature = "test-util"))]
+mod tests {
+    use super::*;
+    use crate::mock_datastore::{MockDatastore, Op};
+
+    fn retrying(backend: &MockDatastore) -> RetryingDatastore<MockDatastore> {
+	RetryingDatastore::new(backend.clone(), 3, Duration::from_millis(1))
+    }
+
+    #[tokio::test]
+    async fn succeeds_after_two_failures() {
+	let backend = MockDatastore::with_items([(b"foo".to_vec(), b"bar".to_vec())]);
+	backend.fail_next(2);
+
+	assert_eq!(retrying(&backend).get_item(b"foo").await.unwrap().as_deref(), Some(&b"bar"[..]));
+	assert_eq!(backend.ops(), vec![Op::Get { key: b"foo".to_vec() }; 3]);
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_the_last_attempt() {
+	let backend = MockDatastore::default();
+	backend.fail_next(3);
+
+	assert!(matches!(retrying(&backend).get_item(b"foo").await, Err(DatastoreError::Throttled)));
+	assert_eq!(backend.ops().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn only_retries_a_throttled_swap() {
+	let backend = MockDatastore::default();
+	backend.fail_next(1);
+
+	assert_eq!(retrying(&backend).swap(b"foo".to_vec(), b"bar".to_vec()).await.unwrap(), None);
+	assert_eq!(backend.ops().len(), 2);
+	assert_eq!(backend.item(b"foo").await.as_deref(), Some(&b"bar"[..]));
+    }
+}
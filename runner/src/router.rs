@@ -0,0 +1,97 @@
+//! Dispatches requests to the guest export that handles their path.
+//!
+//! Routes come from `WASMTEST_ROUTES`, a comma-separated list of
+//! `[METHOD ]PATTERN=EXPORT` entries such as `GET /users/:id=get_user`.
+//! Pattern segments starting with `:` match any single segment and capture it
+//! as a path parameter, and a route without a method matches every method.
+//! Routes are tried in order and the first match wins.
+
+use lambda_http::http::Method;
+
+/// The routes a runtime dispatches on.
+#[derive(Debug)]
+pub struct Router {
+    routes: Vec<Route>,
+}
+
+#[derive(Debug)]
+struct Route {
+    method: Option<Method>,
+    pattern: String,
+    segments: Vec<Segment>,
+    export: String,
+}
+
+#[derive(Debug)]
+enum Segment {
+    Literal(String),
+    Param(String),
+}
+
+/// The route a request matched.
+#[derive(Debug, Clone)]
+pub struct RouteMatch {
+    /// The pattern of the route, as configured.
+    pub pattern: String,
+    /// The export that handles it.
+    pub export: String,
+    /// The path segments captured by the pattern's `:name` segments.
+    pub params: Vec<(String, String)>,
+}
+
+impl Router {
+    /// Parses a `WASMTEST_ROUTES` value.
+    pub fn parse(spec: &str) -> Result<Self, String> {
+	let routes = spec.split(',').map(str::trim).filter(|r| !r.is_empty()).map(|route| {
+	    let (target, export) = route.rsplit_once('=')
+		.ok_or_else(|| format!("route {:?} doesn't name an export", route))?;
+	    let (method, pattern) = match target.trim().split_once(' ') {
+		Some((method, pattern)) => {
+		    let method = Method::from_bytes(method.as_bytes())
+			.map_err(|_| format!("route {:?} has an invalid method", route))?;
+		    (Some(method), pattern.trim())
+		}
+		None => (None, target.trim()),
+	    };
+	    if !pattern.starts_with('/') {
+		return Err(format!("route {:?} doesn't start with /", route));
+	    }
+	    let segments = split(pattern).map(|segment| match segment.strip_prefix(':') {
+		Some(name) => Segment::Param(name.into()),
+		None => Segment::Literal(segment.into()),
+	    }).collect();
+	    Ok(Route { method, pattern: pattern.into(), segments, export: export.trim().into() })
+	}).collect::<Result<_, _>>()?;
+	Ok(Router { routes })
+    }
+
+    /// The names of the exports the routes dispatch to.
+    pub fn exports(&self) -> impl Iterator<Item = &str> {
+	self.routes.iter().map(|route| route.export.as_str())
+    }
+
+    /// Finds the first route matching a request for `path` with `method`.
+    pub fn route(&self, method: &Method, path: &str) -> Option<RouteMatch> {
+	let path: Vec<_> = split(path).collect();
+	self.routes.iter().find_map(|route| {
+	    if route.method.as_ref().is_some_and(|m| m != method) || route.segments.len() != path.len() {
+		return None;
+	    }
+	    let mut params = Vec::new();
+	    for (segment, part) in route.segments.iter().zip(&path) {
+		match segment {
+		    Segment::Literal(literal) if literal == part => {}
+		    Segment::Literal(_) => return None,
+		    Segment::Param(name) => params.push((name.clone(), part.to_string())),
+		}
+	    }
+	    Some(RouteMatch { pattern: route.pattern.clone(), export: route.export.clone(), params })
+	})
+    }
+}
+
+/// The non-empty segments of `path`, so trailing and doubled slashes don't
+/// matter.
+fn split(path: &str) -> impl Iterator<Item = &str> {
+    path.split('/').filter(|segment| !segment.is_empty())
+}
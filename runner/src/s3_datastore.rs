@@ -0,0 +1,277 @@
+//! A `Datastore` backed by S3, for values too big for a DynamoDB item.
+//!
+//! Each key is an object whose name is the key in lowercase hex, which keeps
+//! binary keys intact and sorts the same way the keys do, so `scan_prefix`
+//! can list objects in order. S3 has nothing like a per-object TTL, so
+//! `put_item_with_ttl` records the expiry in the object's metadata and reads
+//! treat the object as absent once it's passed. A lifecycle rule on the bucket
+//! can sweep up expired objects eventually.
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use aws_sdk_s3::primitives::ByteStream;
+use lambda_http::Error;
+
+use crate::{env_opt, from_hex, to_hex, Datastore, DatastoreError, MAX_SCAN_ENTRIES};
+
+/// How many times `increment` retries when another writer keeps beating it
+/// to the object.
+const MAX_INCREMENT_ATTEMPTS: usize = 10;
+
+/// Stores each key as an object in `bucket`.
+#[derive(Clone)]
+pub struct S3Datastore {
+    client: aws_sdk_s3::Client,
+    bucket: String,
+}
+
+/// An object as it was when we read it.
+struct Object {
+    value: Vec<u8>,
+    e_tag: Option<String>,
+    /// When the object expires, in milliseconds since the epoch.
+    expires_at: Option<u64>,
+}
+
+impl Object {
+    fn is_live(&self) -> bool {
+	self.expires_at.is_none_or(|expires_at| expires_at > now_millis())
+    }
+
+    /// The condition that only lets a write through if the object hasn't
+    /// changed since we read it.
+    fn unchanged(&self) -> Precondition {
+	self.e_tag.clone().map_or(Precondition::Absent, Precondition::Matches)
+    }
+}
+
+/// What must be true of the object for a write to go through.
+enum Precondition {
+    None,
+    /// The object doesn't exist.
+    Absent,
+    /// The object has this ETag.
+    Matches(String),
+}
+
+impl S3Datastore {
+    /// User metadata holding an object's expiry, if it has one.
+    const EXPIRES_AT: &'static str = "wasmtest-expires-at";
+
+    pub fn new(client: aws_sdk_s3::Client, bucket: String) -> Self {
+	S3Datastore { client, bucket }
+    }
+
+    /// Uses the bucket named by `WASMTEST_S3_BUCKET`, with credentials and
+    /// region from the standard AWS environment.
+    pub async fn from_env() -> Result<Self, Error> {
+	let bucket = env_opt::<String>("WASMTEST_S3_BUCKET")?
+	    .ok_or("WASMTEST_S3_BUCKET must be set to use the S3 datastore")?;
+	let config = aws_config::load_defaults(aws_config::BehaviorVersion::latest()).await;
+	Ok(Self::new(aws_sdk_s3::Client::new(&config), bucket))
+    }
+
+    /// The name of the object `key` is stored in.
+    fn object_key(key: &[u8]) -> String {
+	to_hex(key)
+    }
+
+    async fn fetch(&self, key: &[u8]) -> Result<Option<Object>, DatastoreError> {
+	let output = match self.client.get_object().bucket(&self.bucket).key(Self::object_key(key)).send().await {
+	    Ok(output) => output,
+	    Err(e) if e.as_service_error().is_some_and(|e| e.is_no_such_key()) => return Ok(None),
+	    Err(e) => return Err(DatastoreError::from_s3(e)),
+	};
+	let e_tag = output.e_tag().map(String::from);
+	let expires_at = output.metadata()
+	    .and_then(|metadata| metadata.get(Self::EXPIRES_AT))
+	    .and_then(|expires_at| expires_at.parse().ok());
+	let value = output.body.collect().await
+	    .map_err(|e| DatastoreError::Backend(e.to_string()))?
+	    .into_bytes().to_vec();
+	Ok(Some(Object { value, e_tag, expires_at }))
+    }
+
+    /// Writes `value`, returning whether `precondition` held.
+    async fn put(&self, key: &[u8], value: Vec<u8>, expires_at: Option<u64>, precondition: Precondition) -> Result<bool, DatastoreError> {
+	let mut request = self.client.put_object().bucket(&self.bucket)
+	    .key(Self::object_key(key))
+	    .body(ByteStream::from(value));
+	if let Some(expires_at) = expires_at {
+	    request = request.metadata(Self::EXPIRES_AT, expires_at.to_string());
+	}
+	request = match precondition {
+	    Precondition::None => request,
+	    Precondition::Absent => request.if_none_match("*"),
+	    Precondition::Matches(e_tag) => request.if_match(e_tag),
+	};
+	match request.send().await {
+	    Ok(_) => Ok(true),
+	    // A conflict means another conditional write raced us for the
+	    // object, which comes to the same thing.
+	    Err(e) if matches!(aws_sdk_s3::error::ProvideErrorMetadata::code(&e), Some("PreconditionFailed" | "ConditionalRequestConflict")) => Ok(false),
+	    Err(e) => Err(DatastoreError::from_s3(e)),
+	}
+    }
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64
+}
+
+impl DatastoreError {
+    fn from_s3<E>(e: aws_sdk_s3::error::SdkError<E>) -> Self
+    where
+	E: aws_sdk_s3::error::ProvideErrorMetadata + std::error::Error + 'static,
+    {
+	use aws_sdk_s3::error::{DisplayErrorContext, ProvideErrorMetadata, SdkError};
+	match &e {
+	    SdkError::TimeoutError(_) => return DatastoreError::Timeout,
+	    SdkError::DispatchFailure(failure) if failure.is_timeout() => return DatastoreError::Timeout,
+	    _ => {}
+	}
+	match e.code() {
+	    Some("SlowDown" | "ThrottlingException") => DatastoreError::Throttled,
+	    Some("NoSuchBucket") => DatastoreError::ResourceNotFound(DisplayErrorContext(&e).to_string()),
+	    _ => DatastoreError::Backend(DisplayErrorContext(&e).to_string()),
+	}
+    }
+}
+
+impl Datastore for S3Datastore {
+    const NAME: &'static str = "s3";
+
+    async fn put_item(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<(), DatastoreError> {
+	self.put(&key, value, None, Precondition::None).await?;
+	Ok(())
+    }
+
+    async fn put_item_with_ttl(&mut self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<(), DatastoreError> {
+	let expires_at = now_millis() + ttl.as_millis() as u64;
+	self.put(&key, value, Some(expires_at), Precondition::None).await?;
+	Ok(())
+    }
+
+    async fn get_item(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>, DatastoreError> {
+	Ok(self.fetch(key).await?.filter(Object::is_live).map(|object| object.value))
+    }
+
+    async fn delete_item(&mut self, key: &[u8]) -> Result<(), DatastoreError> {
+	// Deleting an object that isn't there succeeds.
+	self.client.delete_object().bucket(&self.bucket).key(Self::object_key(key))
+	    .send().await.map_err(DatastoreError::from_s3)?;
+	Ok(())
+    }
+
+    async fn exists(&mut self, key: &[u8]) -> Result<bool, DatastoreError> {
+	let output = match self.client.head_object().bucket(&self.bucket).key(Self::object_key(key)).send().await {
+	    Ok(output) => output,
+	    Err(e) if e.as_service_error().is_some_and(|e| e.is_not_found()) => return Ok(false),
+	    Err(e) => return Err(DatastoreError::from_s3(e)),
+	};
+	let expires_at = output.metadata()
+	    .and_then(|metadata| metadata.get(Self::EXPIRES_AT))
+	    .and_then(|expires_at| expires_at.parse::<u64>().ok());
+	Ok(expires_at.is_none_or(|expires_at| expires_at > now_millis()))
+    }
+
+    async fn compare_and_swap(&mut self, key: Vec<u8>, expected: Option<&[u8]>, new: Vec<u8>) -> Result<bool, DatastoreError> {
+	// S3's conditional writes make the swap fail if anyone else wrote the
+	// object after we read it.
+	let current = self.fetch(&key).await?;
+	let value = current.as_ref().filter(|object| object.is_live()).map(|object| object.value.as_slice());
+	if value != expected {
+	    return Ok(false);
+	}
+	let precondition = current.as_ref().map_or(Precondition::Absent, Object::unchanged);
+	self.put(&key, new, None, precondition).await
+    }
+
+    async fn delete_if_equals(&mut self, key: &[u8], expected: &[u8]) -> Result<bool, DatastoreError> {
+	// As with `compare_and_swap`, the ETag makes the delete fail if anyone
+	// wrote the object after we read it.
+	let Some(object) = self.fetch(key).await?.filter(|object| object.is_live() && object.value == expected) else {
+	    return Ok(false);
+	};
+	let Some(e_tag) = object.e_tag else {
+	    return Err(DatastoreError::Backend("S3 didn't return an ETag".into()));
+	};
+	let result = self.client.delete_object().bucket(&self.bucket).key(Self::object_key(key))
+	    .if_match(e_tag)
+	    .send().await;
+	match result {
+	    Ok(_) => Ok(true),
+	    Err(e) if matches!(aws_sdk_s3::error::ProvideErrorMetadata::code(&e), Some("PreconditionFailed" | "ConditionalRequestConflict")) => Ok(false),
+	    Err(e) => Err(DatastoreError::from_s3(e)),
+	}
+    }
+
+    async fn increment(&mut self, key: Vec<u8>, delta: i64) -> Result<Option<i64>, DatastoreError> {
+	for _ in 0..MAX_INCREMENT_ATTEMPTS {
+	    let (value, expires_at, precondition) = match self.fetch(&key).await? {
+		Some(object) if object.is_live() => {
+		    let precondition = object.unchanged();
+		    (object.value, object.expires_at, precondition)
+		}
+		Some(object) => (0i64.to_le_bytes().to_vec(), None, object.unchanged()),
+		None => (0i64.to_le_bytes().to_vec(), None, Precondition::Absent),
+	    };
+	    let Ok(current) = value.as_slice().try_into() else {
+		return Ok(None);
+	    };
+	    let total = i64::from_le_bytes(current).wrapping_add(delta);
+	    if self.put(&key, total.to_le_bytes().to_vec(), expires_at, precondition).await? {
+		return Ok(Some(total));
+	    }
+	}
+	Err(DatastoreError::Throttled)
+    }
+
+    async fn scan_prefix(&mut self, prefix: &[u8]) -> Result<Vec<(Vec<u8>, Vec<u8>)>, DatastoreError> {
+	// Listings come back in name order, which is key order, so we can stop
+	// as soon as we have `MAX_SCAN_ENTRIES` live entries.
+	let mut entries = Vec::new();
+	let mut continuation_token = None;
+	loop {
+	    let output = self.client.list_objects_v2().bucket(&self.bucket)
+		.prefix(Self::object_key(prefix))
+		.set_continuation_token(continuation_token)
+		.send().await.map_err(DatastoreError::from_s3)?;
+	    for name in output.contents().iter().filter_map(|object| object.key()) {
+		let Some(key) = from_hex(name) else {
+		    continue;
+		};
+		// The object may have been deleted since it was listed.
+		if let Some(object) = self.fetch(&key).await?.filter(Object::is_live) {
+		    entries.push((key, object.value));
+		    if entries.len() == MAX_SCAN_ENTRIES {
+			return Ok(entries);
+		    }
+		}
+	    }
+	    continuation_token = output.next_continuation_token().map(String::from);
+	    if continuation_token.is_none() {
+		return Ok(entries);
+	    }
+	}
+    }
+
+    /// Counts the bucket's objects without fetching them, so objects that
+    /// have expired but haven't been swept up yet are counted too.
+    async fn count(&mut self) -> Result<u64, DatastoreError> {
+	let mut count = 0;
+	let mut continuation_token = None;
+	loop {
+	    let output = self.client.list_objects_v2().bucket(&self.bucket)
+		.set_continuation_token(continuation_token)
+		.send().await.map_err(DatastoreError::from_s3)?;
+	    count += output.contents().iter()
+		.filter(|object| object.key().and_then(from_hex).is_some())
+		.count() as u64;
+	    continuation_token = output.next_continuation_token().map(String::from);
+	    if continuation_token.is_none() {
+		return Ok(count);
+	    }
+	}
+    }
+}
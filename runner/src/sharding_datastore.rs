@@ -0,0 +1,124 @@
+//! A `Datastore` that spreads keys over shards, for backends that partition
+//! by key and slow down when traffic piles onto a few partitions, like
+//! DynamoDB.
+//!
+//! Each key is stored with a one-byte prefix naming its shard, which is an
+//! FNV-1a hash of the key modulo the shard count. The hash only depends on
+//! the key, so every runner finds a key in the same shard, but changing the
+//! shard count, or turning sharding on or off, moves most keys: data written
+//! under one setting can't be read under another.
+//!
+//! Related keys land in different shards, so `scan_prefix` scans every
+//! shard and merges what they return. That costs a backend call per shard.
+//! Everything else touches just the one shard its key is in.
+
+use std::num::NonZeroU8;
+use std::time::Duration;
+
+use crate::{Datastore, DatastoreError, Entries, Values, MAX_SCAN_ENTRIES};
+
+/// Prefixes the keys `backend` stores with their shard.
+#[derive(Clone, Debug)]
+pub struct ShardingDatastore<B> {
+    backend: B,
+    shards: NonZeroU8,
+}
+
+impl<B: Datastore> ShardingDatastore<B> {
+    pub fn new(backend: B, shards: NonZeroU8) -> Self {
+	ShardingDatastore { backend, shards }
+    }
+
+    /// The key `key` is stored under.
+    fn shard_key(&self, key: &[u8]) -> Vec<u8> {
+	let shard = (fnv1a(key) % u64::from(self.shards.get())) as u8;
+	[&[shard][..], key].concat()
+    }
+}
+
+/// The 64-bit FNV-1a hash of `bytes`. Unlike `std`'s hashers it's
+/// guaranteed not to change between Rust versions, which would move keys.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, &b| (hash ^ u64::from(b)).wrapping_mul(0x100000001b3))
+}
+
+/// The key that was stored as `stored`, without its shard.
+fn unshard_key(mut stored: Vec<u8>) -> Vec<u8> {
+    stored.remove(0);
+    stored
+}
+
+impl<B: Datastore> Datastore for ShardingDatastore<B> {
+    const NAME: &'static str = B::NAME;
+
+    fn name(&self) -> &'static str {
+	self.backend.name()
+    }
+
+    async fn put_item(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<(), DatastoreError> {
+	self.backend.put_item(self.shard_key(&key), value).await
+    }
+
+    async fn put_item_with_ttl(&mut self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<(), DatastoreError> {
+	self.backend.put_item_with_ttl(self.shard_key(&key), value, ttl).await
+    }
+
+    async fn get_item(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>, DatastoreError> {
+	self.backend.get_item(&self.shard_key(key)).await
+    }
+
+    async fn batch_get_items(&mut self, keys: &[Vec<u8>]) -> Result<Values, DatastoreError> {
+	let keys: Vec<_> = keys.iter().map(|key| self.shard_key(key)).collect();
+	self.backend.batch_get_items(&keys).await
+    }
+
+    async fn delete_item(&mut self, key: &[u8]) -> Result<(), DatastoreError> {
+	self.backend.delete_item(&self.shard_key(key)).await
+    }
+
+    async fn exists(&mut self, key: &[u8]) -> Result<bool, DatastoreError> {
+	self.backend.exists(&self.shard_key(key)).await
+    }
+
+    async fn compare_and_swap(&mut self, key: Vec<u8>, expected: Option<&[u8]>, new: Vec<u8>) -> Result<bool, DatastoreError> {
+	self.backend.compare_and_swap(self.shard_key(&key), expected, new).await
+    }
+
+    async fn delete_if_equals(&mut self, key: &[u8], expected: &[u8]) -> Result<bool, DatastoreError> {
+	self.backend.delete_if_equals(&self.shard_key(key), expected).await
+    }
+
+    async fn swap(&mut self, key: Vec<u8>, new: Vec<u8>) -> Result<Option<Vec<u8>>, DatastoreError> {
+	self.backend.swap(self.shard_key(&key), new).await
+    }
+
+    async fn append(&mut self, key: Vec<u8>, suffix: Vec<u8>) -> Result<(), DatastoreError> {
+	self.backend.append(self.shard_key(&key), suffix).await
+    }
+
+    async fn increment(&mut self, key: Vec<u8>, delta: i64) -> Result<Option<i64>, DatastoreError> {
+	self.backend.increment(self.shard_key(&key), delta).await
+    }
+
+    async fn put_items(&mut self, pairs: Vec<(Vec<u8>, Vec<u8>)>) -> Result<(), DatastoreError> {
+	let pairs = pairs.into_iter().map(|(key, value)| (self.shard_key(&key), value)).collect();
+	self.backend.put_items(pairs).await
+    }
+
+    async fn scan_prefix(&mut self, prefix: &[u8]) -> Result<Entries, DatastoreError> {
+	// Each shard returns its first matches in key order, so the first
+	// matches overall are among them.
+	let mut entries = Vec::new();
+	for shard in 0..self.shards.get() {
+	    let found = self.backend.scan_prefix(&[&[shard][..], prefix].concat()).await?;
+	    entries.extend(found.into_iter().map(|(key, value)| (unshard_key(key), value)));
+	}
+	entries.sort();
+	entries.truncate(MAX_SCAN_ENTRIES);
+	Ok(entries)
+    }
+
+    async fn count(&mut self) -> Result<u64, DatastoreError> {
+	self.backend.count().await
+    }
+}
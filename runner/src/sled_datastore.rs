@@ -0,0 +1,176 @@
+//! A `Datastore` backed by sled, an embedded database, for durable
+//! single-node storage that's faster than the file backend.
+//!
+//! Keys are stored as-is in one tree, which sorts them bytewise, so
+//! `scan_prefix` walks only the matching range. Each value is prefixed with
+//! the time it expires, as little-endian milliseconds since the epoch (or
+//! zero if it never does), the same layout the file backend uses. Expired
+//! values read as absent and stay on disk until they're next written.
+//!
+//! Every operation that reads and then writes a key does so with sled's
+//! `fetch_and_update`, which retries until nothing else wrote the key in
+//! between, so they're all atomic. Writes reach disk within sled's flush
+//! interval, half a second by default, rather than before they return.
+//! Only one process can open a database at a time.
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use lambda_http::Error;
+
+use crate::{env_or, Datastore, DatastoreError, Entries, MAX_SCAN_ENTRIES};
+
+/// The database to use if `WASMTEST_SLED_PATH` doesn't say.
+const DEFAULT_PATH: &str = "wasmtest.sled";
+
+/// Stores every key in one tree. Clones share the open database.
+#[derive(Clone)]
+pub struct SledDatastore {
+    tree: sled::Tree,
+}
+
+impl SledDatastore {
+    /// Opens (or creates) the database in the directory at `path`.
+    pub fn open(path: &str) -> Result<Self, Error> {
+	let db = sled::open(path).map_err(|e| format!("couldn't open {}: {}", path, e))?;
+	Ok(SledDatastore { tree: db.open_tree("items")? })
+    }
+
+    /// Opens the database at `WASMTEST_SLED_PATH`.
+    pub fn from_env() -> Result<Self, Error> {
+	Self::open(&env_or("WASMTEST_SLED_PATH", DEFAULT_PATH.to_string())?)
+    }
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64
+}
+
+/// The bytes `value` is stored as, expiring at `expires_at` milliseconds
+/// since the epoch, or never if that's zero.
+fn encode(value: &[u8], expires_at: u64) -> Vec<u8> {
+    [&expires_at.to_le_bytes(), value].concat()
+}
+
+/// The value in `stored` and when it expires, or `None` if it has.
+fn decode(stored: &[u8]) -> Option<(&[u8], u64)> {
+    let (expires_at, value) = stored.split_first_chunk::<8>()?;
+    let expires_at = u64::from_le_bytes(*expires_at);
+    (expires_at == 0 || expires_at > now_millis()).then_some((value, expires_at))
+}
+
+/// The live value in `stored`, if any.
+fn live(stored: Option<&[u8]>) -> Option<&[u8]> {
+    Some(decode(stored?)?.0)
+}
+
+impl DatastoreError {
+    fn from_sled(e: sled::Error) -> Self {
+	DatastoreError::Backend(e.to_string())
+    }
+}
+
+impl Datastore for SledDatastore {
+    const NAME: &'static str = "sled";
+
+    async fn put_item(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<(), DatastoreError> {
+	self.tree.insert(key, encode(&value, 0)).map_err(DatastoreError::from_sled)?;
+	Ok(())
+    }
+
+    async fn put_item_with_ttl(&mut self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<(), DatastoreError> {
+	let expires_at = now_millis() + ttl.as_millis() as u64;
+	self.tree.insert(key, encode(&value, expires_at)).map_err(DatastoreError::from_sled)?;
+	Ok(())
+    }
+
+    async fn get_item(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>, DatastoreError> {
+	let stored = self.tree.get(key).map_err(DatastoreError::from_sled)?;
+	Ok(live(stored.as_deref()).map(<[u8]>::to_vec))
+    }
+
+    async fn delete_item(&mut self, key: &[u8]) -> Result<(), DatastoreError> {
+	self.tree.remove(key).map_err(DatastoreError::from_sled)?;
+	Ok(())
+    }
+
+    async fn compare_and_swap(&mut self, key: Vec<u8>, expected: Option<&[u8]>, new: Vec<u8>) -> Result<bool, DatastoreError> {
+	// `fetch_and_update` may call this more than once, so only the last
+	// call's verdict counts. Leaving the key alone means writing back
+	// exactly what was there.
+	let mut swapped = false;
+	self.tree.fetch_and_update(&key, |stored| {
+	    swapped = live(stored) == expected;
+	    if swapped { Some(encode(&new, 0)) } else { stored.map(<[u8]>::to_vec) }
+	}).map_err(DatastoreError::from_sled)?;
+	Ok(swapped)
+    }
+
+    async fn delete_if_equals(&mut self, key: &[u8], expected: &[u8]) -> Result<bool, DatastoreError> {
+	let mut deleted = false;
+	self.tree.fetch_and_update(key, |stored| {
+	    deleted = live(stored) == Some(expected);
+	    if deleted { None } else { stored.map(<[u8]>::to_vec) }
+	}).map_err(DatastoreError::from_sled)?;
+	Ok(deleted)
+    }
+
+    async fn swap(&mut self, key: Vec<u8>, new: Vec<u8>) -> Result<Option<Vec<u8>>, DatastoreError> {
+	let stored = self.tree.insert(key, encode(&new, 0)).map_err(DatastoreError::from_sled)?;
+	Ok(live(stored.as_deref()).map(<[u8]>::to_vec))
+    }
+
+    async fn append(&mut self, key: Vec<u8>, suffix: Vec<u8>) -> Result<(), DatastoreError> {
+	// The key keeps its expiry.
+	self.tree.fetch_and_update(&key, |stored| {
+	    let (value, expires_at) = stored.and_then(decode).unwrap_or_default();
+	    Some(encode(&[value, &suffix].concat(), expires_at))
+	}).map_err(DatastoreError::from_sled)?;
+	Ok(())
+    }
+
+    async fn increment(&mut self, key: Vec<u8>, delta: i64) -> Result<Option<i64>, DatastoreError> {
+	let mut total = None;
+	self.tree.fetch_and_update(&key, |stored| {
+	    let (value, expires_at) = stored.and_then(decode).unwrap_or((&[0; 8], 0));
+	    total = value.try_into().ok().map(|value| i64::from_le_bytes(value).wrapping_add(delta));
+	    match total {
+		Some(total) => Some(encode(&total.to_le_bytes(), expires_at)),
+		None => stored.map(<[u8]>::to_vec),
+	    }
+	}).map_err(DatastoreError::from_sled)?;
+	Ok(total)
+    }
+
+    async fn put_items(&mut self, pairs: Vec<(Vec<u8>, Vec<u8>)>) -> Result<(), DatastoreError> {
+	let mut batch = sled::Batch::default();
+	for (key, value) in pairs {
+	    batch.insert(key, encode(&value, 0));
+	}
+	self.tree.apply_batch(batch).map_err(DatastoreError::from_sled)
+    }
+
+    async fn scan_prefix(&mut self, prefix: &[u8]) -> Result<Entries, DatastoreError> {
+	let mut entries = Vec::new();
+	for entry in self.tree.scan_prefix(prefix) {
+	    let (key, stored) = entry.map_err(DatastoreError::from_sled)?;
+	    if let Some(value) = live(Some(&stored)) {
+		entries.push((key.to_vec(), value.to_vec()));
+		if entries.len() == MAX_SCAN_ENTRIES {
+		    break;
+		}
+	    }
+	}
+	Ok(entries)
+    }
+
+    /// Walks the whole tree, since sled doesn't keep a count.
+    async fn count(&mut self) -> Result<u64, DatastoreError> {
+	let mut count = 0;
+	for stored in self.tree.iter().values() {
+	    if live(Some(&stored.map_err(DatastoreError::from_sled)?)).is_some() {
+		count += 1;
+	    }
+	}
+	Ok(count)
+    }
+}
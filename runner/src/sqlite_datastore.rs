@@ -0,0 +1,231 @@
+//! A `Datastore` backed by SQLite, for durable single-node storage without a
+//! server.
+//!
+//! Everything lives in one `items` table keyed by a `BLOB`, which SQLite
+//! compares bytewise, so binary keys work and `scan_prefix` is a range query
+//! on the primary key. `rusqlite` is synchronous, so queries run on tokio's
+//! blocking thread pool.
+
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use lambda_http::Error;
+use rusqlite::{params, Connection, OptionalExtension, TransactionBehavior};
+
+use crate::{env_or, Datastore, DatastoreError, MAX_SCAN_ENTRIES};
+
+/// The database to use if `WASMTEST_SQLITE_PATH` doesn't say.
+const DEFAULT_PATH: &str = "wasmtest.db";
+
+/// How long to wait for another process to release the database.
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+const SCHEMA: &str = "CREATE TABLE IF NOT EXISTS items (
+    key BLOB PRIMARY KEY,
+    value BLOB NOT NULL,
+    -- Milliseconds since the epoch, or NULL if the item never expires.
+    expires_at INTEGER
+) WITHOUT ROWID";
+
+const UPSERT: &str = "INSERT INTO items (key, value, expires_at) VALUES (?1, ?2, ?3)
+    ON CONFLICT (key) DO UPDATE SET value = excluded.value, expires_at = excluded.expires_at";
+
+/// Selects the live value of `?1`, given the current time in `?2`.
+const SELECT: &str = "SELECT value, expires_at FROM items WHERE key = ?1 AND (expires_at IS NULL OR expires_at > ?2)";
+
+/// Stores every key as a row in one table. Clones share the connection.
+#[derive(Clone)]
+pub struct SqliteDatastore {
+    conn: Arc<Mutex<Connection>>,
+}
+
+impl SqliteDatastore {
+    /// Opens (or creates) the database at `path`, creating the table if it
+    /// isn't there yet.
+    pub fn open(path: &str) -> Result<Self, Error> {
+	let conn = Connection::open(path).map_err(|e| format!("couldn't open {}: {}", path, e))?;
+	conn.busy_timeout(BUSY_TIMEOUT)?;
+	conn.execute(SCHEMA, [])?;
+	Ok(SqliteDatastore { conn: Arc::new(Mutex::new(conn)) })
+    }
+
+    /// Opens the database at `WASMTEST_SQLITE_PATH`.
+    pub fn from_env() -> Result<Self, Error> {
+	Self::open(&env_or("WASMTEST_SQLITE_PATH", DEFAULT_PATH.to_string())?)
+    }
+
+    /// Runs `query` against the connection on the blocking thread pool.
+    async fn with<T, F>(&self, query: F) -> Result<T, DatastoreError>
+    where
+	T: Send + 'static,
+	F: FnOnce(&mut Connection) -> rusqlite::Result<T> + Send + 'static,
+    {
+	let conn = self.conn.clone();
+	tokio::task::spawn_blocking(move || query(&mut conn.lock().unwrap())).await
+	    .map_err(|e| DatastoreError::Backend(e.to_string()))?
+	    .map_err(DatastoreError::from_sqlite)
+    }
+}
+
+fn now_millis() -> i64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as i64
+}
+
+/// The smallest key greater than every key starting with `prefix`, or `None`
+/// if there isn't one (the prefix is empty or all `0xff`s).
+fn prefix_end(prefix: &[u8]) -> Option<Vec<u8>> {
+    let mut end = prefix.to_vec();
+    while let Some(last) = end.pop() {
+	if last < u8::MAX {
+	    end.push(last + 1);
+	    return Some(end);
+	}
+    }
+    None
+}
+
+impl DatastoreError {
+    fn from_sqlite(e: rusqlite::Error) -> Self {
+	use rusqlite::ErrorCode;
+	match e.sqlite_error_code() {
+	    Some(ErrorCode::DatabaseBusy | ErrorCode::DatabaseLocked) => DatastoreError::Throttled,
+	    _ => DatastoreError::Backend(e.to_string()),
+	}
+    }
+}
+
+impl Datastore for SqliteDatastore {
+    const NAME: &'static str = "sqlite";
+
+    async fn put_item(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<(), DatastoreError> {
+	self.with(move |conn| conn.execute(UPSERT, params![key, value, None::<i64>])).await?;
+	Ok(())
+    }
+
+    async fn put_item_with_ttl(&mut self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<(), DatastoreError> {
+	let expires_at = now_millis() + ttl.as_millis() as i64;
+	self.with(move |conn| conn.execute(UPSERT, params![key, value, expires_at])).await?;
+	Ok(())
+    }
+
+    async fn get_item(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>, DatastoreError> {
+	let key = key.to_vec();
+	self.with(move |conn| {
+	    conn.query_row(SELECT, params![key, now_millis()], |row| row.get(0)).optional()
+	}).await
+    }
+
+    async fn delete_item(&mut self, key: &[u8]) -> Result<(), DatastoreError> {
+	let key = key.to_vec();
+	self.with(move |conn| conn.execute("DELETE FROM items WHERE key = ?1", params![key])).await?;
+	Ok(())
+    }
+
+    async fn compare_and_swap(&mut self, key: Vec<u8>, expected: Option<&[u8]>, new: Vec<u8>) -> Result<bool, DatastoreError> {
+	// An immediate transaction takes the write lock up front, so no other
+	// process can write the key between the check and the swap.
+	let expected = expected.map(<[u8]>::to_vec);
+	self.with(move |conn| {
+	    let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+	    let current: Option<Vec<u8>> = tx.query_row(SELECT, params![key, now_millis()], |row| row.get(0)).optional()?;
+	    if current != expected {
+		return Ok(false);
+	    }
+	    tx.execute(UPSERT, params![key, new, None::<i64>])?;
+	    tx.commit()?;
+	    Ok(true)
+	}).await
+    }
+
+    async fn append(&mut self, key: Vec<u8>, suffix: Vec<u8>) -> Result<(), DatastoreError> {
+	// As with `increment`, the transaction makes this atomic, and the key
+	// keeps its expiry.
+	self.with(move |conn| {
+	    let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+	    let current: Option<(Vec<u8>, Option<i64>)> = tx.query_row(SELECT, params![key, now_millis()], |row| {
+		Ok((row.get(0)?, row.get(1)?))
+	    }).optional()?;
+	    let (mut value, expires_at) = current.unwrap_or_default();
+	    value.extend(suffix);
+	    tx.execute(UPSERT, params![key, value, expires_at])?;
+	    tx.commit()
+	}).await
+    }
+
+    async fn delete_if_equals(&mut self, key: &[u8], expected: &[u8]) -> Result<bool, DatastoreError> {
+	// A single statement is atomic by itself. Expired rows don't count as
+	// holding anything.
+	let key = key.to_vec();
+	let expected = expected.to_vec();
+	let deleted = self.with(move |conn| conn.execute(
+	    "DELETE FROM items WHERE key = ?1 AND value = ?2 AND (expires_at IS NULL OR expires_at > ?3)",
+	    params![key, expected, now_millis()],
+	)).await?;
+	Ok(deleted > 0)
+    }
+
+    async fn swap(&mut self, key: Vec<u8>, new: Vec<u8>) -> Result<Option<Vec<u8>>, DatastoreError> {
+	self.with(move |conn| {
+	    let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+	    let current: Option<Vec<u8>> = tx.query_row(SELECT, params![key, now_millis()], |row| row.get(0)).optional()?;
+	    tx.execute(UPSERT, params![key, new, None::<i64>])?;
+	    tx.commit()?;
+	    Ok(current)
+	}).await
+    }
+
+    async fn increment(&mut self, key: Vec<u8>, delta: i64) -> Result<Option<i64>, DatastoreError> {
+	self.with(move |conn| {
+	    let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+	    let current: Option<(Vec<u8>, Option<i64>)> = tx.query_row(SELECT, params![key, now_millis()], |row| {
+		Ok((row.get(0)?, row.get(1)?))
+	    }).optional()?;
+	    let (value, expires_at) = current.unwrap_or_else(|| (0i64.to_le_bytes().to_vec(), None));
+	    let Ok(value) = value.as_slice().try_into() else {
+		return Ok(None);
+	    };
+	    let total = i64::from_le_bytes(value).wrapping_add(delta);
+	    tx.execute(UPSERT, params![key, total.to_le_bytes(), expires_at])?;
+	    tx.commit()?;
+	    Ok(Some(total))
+	}).await
+    }
+
+    async fn put_items(&mut self, pairs: Vec<(Vec<u8>, Vec<u8>)>) -> Result<(), DatastoreError> {
+	self.with(move |conn| {
+	    let tx = conn.transaction()?;
+	    {
+		let mut upsert = tx.prepare_cached(UPSERT)?;
+		for (key, value) in pairs {
+		    upsert.execute(params![key, value, None::<i64>])?;
+		}
+	    }
+	    tx.commit()
+	}).await
+    }
+
+    async fn scan_prefix(&mut self, prefix: &[u8]) -> Result<Vec<(Vec<u8>, Vec<u8>)>, DatastoreError> {
+	// Every key starting with `prefix` sorts at or after it and before
+	// `prefix_end`, which keeps this a range scan of the primary key.
+	let start = prefix.to_vec();
+	let end = prefix_end(prefix);
+	self.with(move |conn| {
+	    let mut select = conn.prepare_cached(
+		"SELECT key, value FROM items
+		 WHERE key >= ?1 AND (?2 IS NULL OR key < ?2) AND (expires_at IS NULL OR expires_at > ?3)
+		 ORDER BY key LIMIT ?4",
+	    )?;
+	    let rows = select.query_map(params![start, end, now_millis(), MAX_SCAN_ENTRIES as i64], |row| {
+		Ok((row.get(0)?, row.get(1)?))
+	    })?;
+	    rows.collect()
+	}).await
+    }
+
+    async fn count(&mut self) -> Result<u64, DatastoreError> {
+	let count: i64 = self.with(|conn| {
+	    conn.query_row("SELECT COUNT(*) FROM items WHERE expires_at IS NULL OR expires_at > ?1", params![now_millis()], |row| row.get(0))
+	}).await?;
+	Ok(count as u64)
+    }
+}
@@ -0,0 +1,804 @@
+//! Runs requests through the guest in-process, for tests that want to drive
+//! it the way Lambda would without the Lambda runtime.
+//!
+//! Only built for tests with the `test-util` feature, e.g.
+//! `cargo test --features test-util`. Requests take the same path as in
+//! production: `function_handler` never talks to the Lambda runtime itself,
+//! so calling it directly exercises the same module loading, host functions
+//! and result protocol. `TestRunner::new` loads the real guest, so build it
+//! first with `cargo build --release` in `wasmtest`; fixtures written in WAT
+//! with `wat_guest` need nothing built.
+//!
+//! To seed the datastore and check what the guest stored, use a
+//! `MockDatastore`, whose clones share their items. `MemoryDatastore` hands
+//! each request its own copy, so nothing a request writes is visible
+//! afterwards.
+
+use lambda_http::http::request::Parts;
+use lambda_http::{Body, Error, Request, Response};
+use wasmtime::{Instance, Memory, Module, Store, TypedFunc};
+
+use crate::{engine, function_handler, load_module, split_wasm_bytes, tick_epochs, Datastore, GuestPtr, MyState, Runtime, ABI_VERSION, WASM_BYTES_SIZE};
+
+/// Calls the guest against `datastore`.
+pub struct TestRunner<D: Datastore> {
+    runtime: Runtime<D>,
+}
+
+/// What the guest answered a request with.
+#[derive(Debug)]
+pub struct TestResponse {
+    pub status: u16,
+    pub headers: lambda_http::http::HeaderMap,
+    pub body: Vec<u8>,
+}
+
+impl<D: Datastore + Clone + 'static> TestRunner<D> {
+    /// Loads the guest the way `main` does, from `WASMTEST_CWASM_PATH` or
+    /// `WASMTEST_MODULE_PATH`, and configures its runtime from the same
+    /// environment variables.
+    pub fn new(datastore: D) -> Result<Self, Error> {
+	let engine = engine()?;
+	let module = load_module(&engine)?;
+	tick_epochs(&engine);
+	Ok(TestRunner { runtime: Runtime::from_env(engine, module, datastore)? })
+    }
+
+    /// Runs `module`, as wasm or WAT text, instead of the real guest.
+    pub fn with_module(datastore: D, module: impl AsRef<[u8]>) -> Result<Self, Error> {
+	let engine = engine()?;
+	let module = Module::new(&engine, module)?;
+	tick_epochs(&engine);
+	Ok(TestRunner { runtime: Runtime::from_env(engine, module, datastore)? })
+    }
+
+    pub fn runtime(&self) -> &Runtime<D> {
+	&self.runtime
+    }
+
+    /// Instantiates the guest once, for tests that call `entry` on the same
+    /// instance again and again, which the runner itself never does.
+    pub async fn instance(&self) -> Result<TestInstance<D>, Error> {
+	let (parts, _): (Parts, _) = Request::default().into_parts();
+	let mut store = self.runtime.store(parts, None, None, "test".into())?;
+	let instance = self.runtime.instance_pre.instantiate_async(&mut store).await?;
+	TestInstance::new(store, instance)
+    }
+
+    /// For tests that change the limits or settings the environment gave the
+    /// runtime.
+    pub fn runtime_mut(&mut self) -> &mut Runtime<D> {
+	&mut self.runtime
+    }
+
+    /// Sends `body` to `entry` as a `POST /`.
+    pub async fn call(&self, body: &[u8]) -> Result<TestResponse, Error> {
+	let request = lambda_http::http::Request::builder().method("POST").uri("/").body(Body::from(body.to_vec()))?;
+	self.request(request).await
+    }
+
+    /// Handles `request` as the runner would, including routing.
+    pub async fn request(&self, request: Request) -> Result<TestResponse, Error> {
+	let response: Response<Body> = function_handler(self.runtime.clone(), request).await?;
+	let (parts, body) = response.into_parts();
+	Ok(TestResponse { status: parts.status.as_u16(), headers: parts.headers, body: body.to_vec() })
+    }
+}
+
+/// One instance of the guest, called directly rather than through
+/// `function_handler`.
+pub struct TestInstance<D: Datastore> {
+    store: Store<MyState<D>>,
+    memory: Memory,
+    alloc: TypedFunc<GuestPtr, GuestPtr>,
+    dealloc: TypedFunc<(GuestPtr, GuestPtr), ()>,
+    entry: TypedFunc<(GuestPtr, GuestPtr, GuestPtr), ()>,
+}
+
+impl<D: Datastore + 'static> TestInstance<D> {
+    /// Wraps `instance`, which lives in `store`.
+    pub fn new(mut store: Store<MyState<D>>, instance: Instance) -> Result<Self, Error> {
+	let memory = instance.get_memory(&mut store, "memory").ok_or("guest doesn't export `memory`")?;
+	let alloc = instance.get_typed_func(&mut store, "alloc")?;
+	let dealloc = instance.get_typed_func(&mut store, "dealloc")?;
+	let entry = instance.get_typed_func(&mut store, "entry")?;
+	Ok(TestInstance { store, memory, alloc, dealloc, entry })
+    }
+
+    /// Calls `entry` with `body` the way `function_handler` does, freeing
+    /// every buffer afterwards, and returns its result.
+    pub async fn call(&mut self, body: &[u8]) -> Result<Vec<u8>, Error> {
+	let store = &mut self.store;
+	let body_len = body.len() as GuestPtr;
+	let body_base = self.alloc.call_async(&mut *store, body_len).await?;
+	let result_slot = self.alloc.call_async(&mut *store, WASM_BYTES_SIZE as GuestPtr).await?;
+	self.memory.write(&mut *store, body_base as usize, body)?;
+	self.memory.write(&mut *store, result_slot as usize, &[0; WASM_BYTES_SIZE])?;
+	self.entry.call_async(&mut *store, (result_slot, body_base, body_len)).await?;
+
+	let mut slot = [0; WASM_BYTES_SIZE];
+	self.memory.read(&*store, result_slot as usize, &mut slot)?;
+	let (result_base, result_len) = split_wasm_bytes(&slot);
+	let mut result = vec![0; result_len as usize];
+	self.memory.read(&*store, result_base as usize, &mut result)?;
+
+	self.dealloc.call_async(&mut *store, (result_base, result_len)).await?;
+	self.dealloc.call_async(&mut *store, (result_slot, WASM_BYTES_SIZE as GuestPtr)).await?;
+	self.dealloc.call_async(&mut *store, (body_base, body_len)).await?;
+	Ok(result)
+    }
+
+    /// How many bytes the guest's linear memory has grown to.
+    pub fn memory_size(&self) -> usize {
+	self.memory.data_size(&self.store)
+    }
+}
+
+/// A minimal guest in WAT, speaking this runner's ABI version, whose `entry`
+/// runs `entry_body`. `items` go at the top of the module, so they can
+/// import host functions, add data segments and define helpers.
+///
+/// `entry` takes `$result`, `$body` and `$len` like the real guest's, and
+/// may declare more locals at the start of `entry_body`. Its `alloc` bumps a
+/// pointer, growing memory as needed, and `dealloc` frees nothing.
+///
+/// The module has a 32-bit memory, so a runner built with the `memory64`
+/// feature refuses it.
+pub fn wat_guest(items: &str, entry_body: &str) -> String {
+    wat_guest_speaking(ABI_VERSION, items, entry_body)
+}
+
+/// `wat_guest`, but reporting ABI version `version`.
+pub fn wat_guest_speaking(version: u32, items: &str, entry_body: &str) -> String {
+    format!(r#"(module
+  {items}
+  (memory (export "memory") 1)
+  (global $heap (mut i32) (i32.const 1024))
+  (func (export "wasmtest_abi_version") (result i32) (i32.const {version}))
+  (func $alloc (export "alloc") (param $len i32) (result i32)
+    (local $base i32)
+    (local.set $base (global.get $heap))
+    (global.set $heap (i32.add (local.get $base) (local.get $len)))
+    (if (i32.gt_u (global.get $heap) (i32.mul (memory.size) (i32.const 65536)))
+      (then
+        (if (i32.eq (memory.grow (i32.sub (i32.add (i32.shr_u (global.get $heap) (i32.const 16)) (i32.const 1)) (memory.size))) (i32.const -1))
+          (then
+            (global.set $heap (local.get $base))
+            (return (i32.const 0))))))
+    (local.get $base))
+  (func (export "dealloc") (param i32 i32))
+  (func (export "entry") (param $result i32) (param $body i32) (param $len i32)
+    {entry_body}))
+"#)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    use lambda_http::http::HeaderName;
+
+    use crate::encode_values;
+    use crate::mock_datastore::MockDatastore;
+
+    /// A data segment putting `bytes` at `offset`.
+    fn data(offset: u32, bytes: &[u8]) -> String {
+	let escaped: String = bytes.iter().map(|byte| format!("\\{:02x}", byte)).collect();
+	format!(r#"(data (i32.const {}) "{}")"#, offset, escaped)
+    }
+
+    /// A data segment putting a `WasmBytes` that points at `len` bytes at
+    /// `base` at `offset`.
+    fn wasm_bytes(offset: u32, base: u32, len: u32) -> String {
+	data(offset, &[base.to_le_bytes(), len.to_le_bytes()].concat())
+    }
+
+    #[tokio::test]
+    async fn guest_stores_the_body_and_returns_foo() {
+	let datastore = MockDatastore::with_items([(b"foo".to_vec(), b"bar".to_vec())]);
+	let runner = TestRunner::new(datastore.clone()).unwrap();
+	let request = lambda_http::http::Request::builder().method("POST").uri("/").body(Body::from("hello")).unwrap();
+
+	let response = runner.request(request).await.unwrap();
+
+	assert_eq!(response.status, 200);
+	assert_eq!(response.headers["content-type"], "text/html");
+	assert_eq!(response.body, b"bar");
+	assert_eq!(datastore.item(b"hello").await.as_deref(), Some(&b"world"[..]));
+	assert_eq!(datastore.item(b"world").await.as_deref(), Some(&b"bar"[..]));
+    }
+
+    #[tokio::test]
+    async fn wat_guest_echoes_its_body() {
+	let guest = wat_guest("", r#"
+	    (i32.store (local.get $result) (local.get $body))
+	    (i32.store offset=4 (local.get $result) (local.get $len))"#);
+	let runner = TestRunner::with_module(MockDatastore::default(), guest).unwrap();
+
+	let response = runner.call(b"echo").await.unwrap();
+
+	assert_eq!(response.status, 200);
+	assert_eq!(response.body, b"echo");
+    }
+
+    #[tokio::test]
+    async fn two_reads_get_separate_buffers() {
+	let datastore = MockDatastore::with_items([(b"a".to_vec(), b"first".to_vec()), (b"b".to_vec(), b"second".to_vec())]);
+	// Reads both keys, then returns everything from the start of the first
+	// value to the end of the second, which the bump allocator puts right
+	// after it.
+	let items = [
+	    r#"(import "env" "read_key" (func $read_key (param i32 i32) (result i32)))"#.to_string(),
+	    data(100, b"ab"),
+	    wasm_bytes(200, 100, 1),
+	    wasm_bytes(208, 101, 1),
+	].join("\n");
+	let guest = wat_guest(&items, r#"
+	    (drop (call $read_key (i32.const 200) (i32.const 300)))
+	    (drop (call $read_key (i32.const 208) (i32.const 308)))
+	    (i32.store (local.get $result) (i32.load (i32.const 300)))
+	    (i32.store offset=4 (local.get $result) (i32.add (i32.load (i32.const 304)) (i32.load (i32.const 312))))"#);
+	let runner = TestRunner::with_module(datastore, guest).unwrap();
+
+	let response = runner.call(b"").await.unwrap();
+
+	assert_eq!(response.status, 200);
+	assert_eq!(response.body, b"firstsecond");
+    }
+
+    /// Sends the real guest a `len`-byte body and checks it stored all of it.
+    async fn guest_reads_a_body_of(len: usize) {
+	let datastore = MockDatastore::with_items([(b"foo".to_vec(), b"bar".to_vec())]);
+	let runner = TestRunner::new(datastore.clone()).unwrap();
+	let body: Vec<u8> = (0..len).map(|i| b'a' + (i % 26) as u8).collect();
+
+	let response = runner.call(&body).await.unwrap();
+
+	assert_eq!(response.status, 200);
+	assert_eq!(response.body, b"bar");
+	assert_eq!(datastore.item(&body).await.as_deref(), Some(&b"world"[..]));
+    }
+
+    #[tokio::test]
+    async fn guest_reads_a_body_bigger_than_a_page() {
+	guest_reads_a_body_of(70 << 10).await;
+    }
+
+    #[tokio::test]
+    async fn guest_reads_a_multi_megabyte_body() {
+	guest_reads_a_body_of(4 << 20).await;
+    }
+
+    #[tokio::test]
+    async fn guest_frees_what_each_call_allocates() {
+	let value = vec![b'x'; 64 << 10];
+	let datastore = MockDatastore::with_items([(b"foo".to_vec(), value.clone())]);
+	let runner = TestRunner::new(datastore).unwrap();
+	let mut guest = runner.instance().await.unwrap();
+
+	// Let the allocator settle into the memory one call needs.
+	assert_eq!(guest.call(b"hello").await.unwrap(), value);
+	let settled = guest.memory_size();
+	for _ in 0..200 {
+	    assert_eq!(guest.call(b"hello").await.unwrap(), value);
+	}
+	assert_eq!(guest.memory_size(), settled);
+    }
+
+    #[tokio::test]
+    async fn requests_dont_see_each_others_memory() {
+	// Answers "seen" if the marker at 2000 is set and "clean" if not, and
+	// sets it either way.
+	let items = data(100, b"seenclean");
+	let guest = wat_guest(&items, r#"
+	    (if (i32.load8_u (i32.const 2000))
+	      (then
+		(i32.store (local.get $result) (i32.const 100))
+		(i32.store offset=4 (local.get $result) (i32.const 4)))
+	      (else
+		(i32.store (local.get $result) (i32.const 104))
+		(i32.store offset=4 (local.get $result) (i32.const 5))))
+	    (i32.store8 (i32.const 2000) (i32.const 1))"#);
+	let runner = TestRunner::with_module(MockDatastore::default(), guest).unwrap();
+
+	assert_eq!(runner.call(b"a").await.unwrap().body, b"clean");
+	assert_eq!(runner.call(b"b").await.unwrap().body, b"clean");
+
+	// The same instance does see its own marker, so the requests above
+	// were really isolated rather than the marker never sticking.
+	let mut instance = runner.instance().await.unwrap();
+	assert_eq!(instance.call(b"a").await.unwrap(), b"clean");
+	assert_eq!(instance.call(b"b").await.unwrap(), b"seen");
+    }
+
+    /// A guest that never returns.
+    fn spinning_guest() -> String {
+	wat_guest("", "(loop $spin (br $spin))")
+    }
+
+    #[tokio::test]
+    async fn guest_that_spins_runs_out_of_fuel() {
+	let mut runner = TestRunner::with_module(MockDatastore::default(), spinning_guest()).unwrap();
+	runner.runtime_mut().fuel = 100_000;
+
+	let response = runner.call(b"").await.unwrap();
+
+	assert_eq!(response.status, 500);
+	assert_eq!(response.body, b"guest exceeded its CPU budget");
+    }
+
+    #[tokio::test]
+    async fn guest_that_spins_times_out() {
+	let mut runner = TestRunner::with_module(MockDatastore::default(), spinning_guest()).unwrap();
+	runner.runtime_mut().fuel = u64::MAX;
+	runner.runtime_mut().timeout = Duration::from_millis(50);
+
+	let response = runner.call(b"").await.unwrap();
+
+	assert_eq!(response.status, 504);
+	assert_eq!(response.body, b"guest timed out");
+    }
+
+    #[tokio::test]
+    async fn guest_cant_grow_memory_past_the_limit() {
+	// Traps if it can't grow its memory by 2 MiB.
+	let guest = wat_guest("", "(if (i32.eq (memory.grow (i32.const 32)) (i32.const -1)) (then unreachable))");
+	let mut runner = TestRunner::with_module(MockDatastore::default(), guest).unwrap();
+	runner.runtime_mut().memory_limit = 1 << 20;
+
+	let response = runner.call(b"").await.unwrap();
+
+	assert_eq!(response.status, 500);
+	assert!(response.body.starts_with(b"guest trapped"), "{}", String::from_utf8_lossy(&response.body));
+    }
+
+    #[tokio::test]
+    async fn guest_speaking_another_abi_version_is_refused() {
+	let guest = wat_guest_speaking(ABI_VERSION + 1, "", "unreachable");
+	let runner = TestRunner::with_module(MockDatastore::default(), guest).unwrap();
+
+	let response = runner.call(b"").await.unwrap();
+
+	assert_eq!(response.status, 500);
+	let expected = format!("guest speaks ABI version {}, but this runner speaks ABI version {}", ABI_VERSION + 1, ABI_VERSION);
+	assert_eq!(response.body, expected.as_bytes());
+    }
+
+    #[tokio::test]
+    async fn read_many_answers_in_key_order() {
+	let datastore = MockDatastore::with_items([(b"a".to_vec(), b"1".to_vec()), (b"b".to_vec(), b"2".to_vec())]);
+	let mut keys = 3u32.to_le_bytes().to_vec();
+	for key in [&b"b"[..], b"missing", b"a"] {
+	    keys.extend_from_slice(&(key.len() as u32).to_le_bytes());
+	    keys.extend_from_slice(key);
+	}
+	let items = [
+	    r#"(import "env" "read_many_key" (func $read_many_key (param i32 i32) (result i32)))"#.to_string(),
+	    data(100, &keys),
+	    wasm_bytes(200, 100, keys.len() as u32),
+	].join("\n");
+	let guest = wat_guest(&items, r#"
+	    (drop (call $read_many_key (i32.const 200) (i32.const 300)))
+	    (i32.store (local.get $result) (i32.load (i32.const 300)))
+	    (i32.store offset=4 (local.get $result) (i32.load (i32.const 304)))"#);
+	let runner = TestRunner::with_module(datastore, guest).unwrap();
+
+	let response = runner.call(b"").await.unwrap();
+
+	assert_eq!(response.status, 200);
+	assert_eq!(response.body, encode_values(&[Some(b"2".to_vec()), None, Some(b"1".to_vec())]));
+    }
+
+    /// A guest that passes its body through the `base64_encode` or
+    /// `base64_decode` import and returns what it gets back, or `invalid` if
+    /// the import refused.
+    fn base64_guest(import: &str, url_safe: bool) -> String {
+	let items = [
+	    format!(r#"(import "env" "{}" (func $convert (param i32 i32 i32) (result i32)))"#, import),
+	    data(100, b"invalid"),
+	].join("\n");
+	wat_guest(&items, &format!(r#"
+	    (i32.store (i32.const 200) (local.get $body))
+	    (i32.store (i32.const 204) (local.get $len))
+	    (if (call $convert (i32.const 200) (i32.const {}) (i32.const 300))
+	      (then
+		(i32.store (local.get $result) (i32.const 100))
+		(i32.store offset=4 (local.get $result) (i32.const 7)))
+	      (else
+		(i32.store (local.get $result) (i32.load (i32.const 300)))
+		(i32.store offset=4 (local.get $result) (i32.load (i32.const 304)))))"#, url_safe as u32))
+    }
+
+    /// What `base64_guest` answers `body` with.
+    async fn base64(import: &str, url_safe: bool, body: &[u8]) -> Vec<u8> {
+	let runner = TestRunner::with_module(MockDatastore::default(), base64_guest(import, url_safe)).unwrap();
+	let response = runner.call(body).await.unwrap();
+	assert_eq!(response.status, 200);
+	response.body
+    }
+
+    #[tokio::test]
+    async fn base64_encodes_known_answers() {
+	assert_eq!(base64("base64_encode", false, b"hello").await, b"aGVsbG8=");
+	assert_eq!(base64("base64_encode", false, b"\xfb\xff").await, b"+/8=");
+	assert_eq!(base64("base64_encode", true, b"\xfb\xff").await, b"-_8");
+	assert_eq!(base64("base64_encode", false, b"").await, b"");
+    }
+
+    #[tokio::test]
+    async fn base64_decodes_what_it_encodes() {
+	assert_eq!(base64("base64_decode", false, b"aGVsbG8=").await, b"hello");
+	assert_eq!(base64("base64_decode", false, b"+/8=").await, b"\xfb\xff");
+	assert_eq!(base64("base64_decode", true, b"-_8").await, b"\xfb\xff");
+    }
+
+    #[tokio::test]
+    async fn base64_refuses_invalid_input() {
+	assert_eq!(base64("base64_decode", false, b"not base64!").await, b"invalid");
+	// Each alphabet refuses the other's characters.
+	assert_eq!(base64("base64_decode", false, b"-_8").await, b"invalid");
+	assert_eq!(base64("base64_decode", true, b"+/8=").await, b"invalid");
+    }
+
+    #[tokio::test]
+    async fn sha256_matches_known_answers() {
+	let items = r#"(import "env" "sha256" (func $sha256 (param i32 i32 i32)))"#;
+	let guest = wat_guest(items, r#"
+	    (call $sha256 (local.get $body) (local.get $len) (i32.const 300))
+	    (i32.store (local.get $result) (i32.const 300))
+	    (i32.store offset=4 (local.get $result) (i32.const 32))"#);
+	let runner = TestRunner::with_module(MockDatastore::default(), guest).unwrap();
+	let hex = |bytes: Vec<u8>| bytes.iter().map(|byte| format!("{:02x}", byte)).collect::<String>();
+
+	assert_eq!(hex(runner.call(b"abc").await.unwrap().body), "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+	assert_eq!(hex(runner.call(b"").await.unwrap().body), "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855");
+    }
+
+    #[tokio::test]
+    async fn uuid_v4_sets_the_version_and_variant_bits() {
+	let items = r#"(import "env" "uuid_v4" (func $uuid_v4 (param i32)))"#;
+	let guest = wat_guest(items, r#"
+	    (call $uuid_v4 (i32.const 300))
+	    (i32.store (local.get $result) (i32.const 300))
+	    (i32.store offset=4 (local.get $result) (i32.const 16))"#);
+	let runner = TestRunner::with_module(MockDatastore::default(), guest).unwrap();
+
+	let mut uuids = Vec::new();
+	for _ in 0..32 {
+	    let uuid = runner.call(b"").await.unwrap().body;
+	    assert_eq!(uuid.len(), 16);
+	    assert_eq!(uuid[6] >> 4, 4, "version in {:02x?}", uuid);
+	    assert_eq!(uuid[8] >> 6, 0b10, "variant in {:02x?}", uuid);
+	    uuids.push(uuid);
+	}
+	uuids.sort();
+	uuids.dedup();
+	assert_eq!(uuids.len(), 32);
+    }
+
+    /// A request from `tenant`, as named by the `x-tenant` header.
+    fn tenant_request(tenant: &str, body: &str) -> Request {
+	lambda_http::http::Request::builder().method("POST").uri("/")
+	    .header("x-tenant", tenant)
+	    .body(Body::from(body.to_string()))
+	    .unwrap()
+    }
+
+    #[tokio::test]
+    async fn tenants_keep_the_same_key_apart() {
+	let datastore = MockDatastore::with_items([
+	    (b"a\0foo".to_vec(), b"apple".to_vec()),
+	    (b"b\0foo".to_vec(), b"banana".to_vec()),
+	]);
+	let mut runner = TestRunner::new(datastore.clone()).unwrap();
+	runner.runtime_mut().tenant_header = Some(HeaderName::from_static("x-tenant"));
+
+	assert_eq!(runner.request(tenant_request("a", "hello")).await.unwrap().body, b"apple");
+	assert_eq!(runner.request(tenant_request("b", "hello")).await.unwrap().body, b"banana");
+
+	assert_eq!(datastore.item(b"a\0world").await.as_deref(), Some(&b"apple"[..]));
+	assert_eq!(datastore.item(b"b\0world").await.as_deref(), Some(&b"banana"[..]));
+	assert_eq!(datastore.item(b"world").await, None);
+	assert_eq!(datastore.item(b"hello").await, None);
+    }
+
+    #[tokio::test]
+    async fn tenants_cant_count_keys() {
+	// Returns the status `count_keys` answered with, as one byte.
+	let items = r#"(import "env" "count_keys" (func $count_keys (param i32) (result i32)))"#;
+	let guest = wat_guest(items, r#"
+	    (i32.store8 (i32.const 400) (call $count_keys (i32.const 300)))
+	    (i32.store (local.get $result) (i32.const 400))
+	    (i32.store offset=4 (local.get $result) (i32.const 1))"#);
+	let datastore = MockDatastore::with_items([(b"b\0foo".to_vec(), b"banana".to_vec())]);
+	let mut runner = TestRunner::with_module(datastore, guest).unwrap();
+
+	assert_eq!(runner.call(b"").await.unwrap().body, [0]);
+	runner.runtime_mut().tenant_header = Some(HeaderName::from_static("x-tenant"));
+	assert_eq!(runner.request(tenant_request("a", "")).await.unwrap().body, [3]);
+    }
+
+    #[tokio::test]
+    async fn guest_that_traps_in_initialize_gets_a_500() {
+	let guest = wat_guest(r#"(func (export "_initialize") unreachable)"#, "");
+	let runner = TestRunner::with_module(MockDatastore::default(), guest).unwrap();
+
+	let response = runner.call(b"").await.unwrap();
+
+	assert_eq!(response.status, 500);
+	assert!(response.body.starts_with(b"guest trapped (request "), "{:?}", String::from_utf8_lossy(&response.body));
+    }
+
+    #[tokio::test]
+    async fn guest_that_traps_in_alloc_gets_a_500() {
+	let guest = r#"(module
+	  (memory (export "memory") 1)
+	  (func (export "wasmtest_abi_version") (result i32) (i32.const 1))
+	  (func (export "alloc") (param i32) (result i32) unreachable)
+	  (func (export "dealloc") (param i32 i32))
+	  (func (export "entry") (param i32 i32 i32)))"#;
+	let runner = TestRunner::with_module(MockDatastore::default(), guest).unwrap();
+
+	let response = runner.call(b"hello").await.unwrap();
+
+	assert_eq!(response.status, 500);
+	assert!(response.body.starts_with(b"guest trapped (request "), "{:?}", String::from_utf8_lossy(&response.body));
+    }
+
+    #[tokio::test]
+    async fn guest_without_dealloc_gets_a_500() {
+	let guest = r#"(module
+	  (memory (export "memory") 1)
+	  (func (export "wasmtest_abi_version") (result i32) (i32.const 1))
+	  (func (export "alloc") (param i32) (result i32) (i32.const 1024))
+	  (func (export "entry") (param i32 i32 i32)))"#;
+	let runner = TestRunner::with_module(MockDatastore::default(), guest).unwrap();
+
+	let response = runner.call(b"").await.unwrap();
+
+	assert_eq!(response.status, 500);
+	assert!(response.body.starts_with(b"internal error (request "), "{:?}", String::from_utf8_lossy(&response.body));
+    }
+
+    #[tokio::test]
+    async fn failing_yes_or_no_imports_return_a_status_instead_of_trapping() {
+	let datastore = MockDatastore::with_items([(b"k".to_vec(), b"v".to_vec())]);
+	datastore.fail_next(4);
+	// Calls each import once and answers with the four statuses, a byte
+	// each.
+	let items = [
+	    r#"(import "env" "has_key" (func $has_key (param i32) (result i32)))"#.to_string(),
+	    r#"(import "env" "delete_key" (func $delete_key (param i32) (result i32)))"#.to_string(),
+	    r#"(import "env" "compare_and_swap_key" (func $compare_and_swap_key (param i32 i32 i32) (result i32)))"#.to_string(),
+	    r#"(import "env" "delete_if_equals_key" (func $delete_if_equals_key (param i32 i32) (result i32)))"#.to_string(),
+	    data(100, b"kv"),
+	    wasm_bytes(200, 100, 1),
+	    wasm_bytes(208, 101, 1),
+	].join("\n");
+	let guest = wat_guest(&items, r#"
+	    (i32.store8 (i32.const 300) (call $has_key (i32.const 200)))
+	    (i32.store8 (i32.const 301) (call $delete_key (i32.const 200)))
+	    (i32.store8 (i32.const 302) (call $compare_and_swap_key (i32.const 200) (i32.const 208) (i32.const 208)))
+	    (i32.store8 (i32.const 303) (call $delete_if_equals_key (i32.const 200) (i32.const 208)))
+	    (i32.store (local.get $result) (i32.const 300))
+	    (i32.store offset=4 (local.get $result) (i32.const 4))"#);
+	let runner = TestRunner::with_module(datastore.clone(), guest).unwrap();
+
+	let response = runner.call(b"").await.unwrap();
+
+	assert_eq!(response.status, 200);
+	assert_eq!(response.body, [2, 2, 2, 2]);
+	assert_eq!(datastore.item(b"k").await.as_deref(), Some(&b"v"[..]));
+    }
+
+    /// Collects what's logged, for tests that check what the runner keeps
+    /// out of its responses still makes it to the logs.
+    #[derive(Clone, Default)]
+    struct Logs(std::sync::Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl Logs {
+	/// Logs to `self` until the guard is dropped.
+	fn capture(&self) -> lambda_http::tracing::dispatcher::DefaultGuard {
+	    use lambda_http::tracing::subscriber::util::SubscriberInitExt;
+	    let logs = self.clone();
+	    lambda_http::tracing::subscriber::fmt().with_writer(move || logs.clone()).finish().set_default()
+	}
+
+	fn contents(&self) -> String {
+	    String::from_utf8_lossy(&self.0.lock().unwrap()).into_owned()
+	}
+    }
+
+    impl std::io::Write for Logs {
+	fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+	    self.0.lock().unwrap().extend_from_slice(buf);
+	    Ok(buf.len())
+	}
+
+	fn flush(&mut self) -> std::io::Result<()> {
+	    Ok(())
+	}
+    }
+
+    #[tokio::test]
+    async fn guest_panic_is_logged_but_kept_out_of_the_response() {
+	// Echoes its body, unless it starts with `!`, which it panics on.
+	let items = [
+	    r#"(import "env" "report_panic" (func $report_panic (param i32)))"#.to_string(),
+	    data(100, b"panicked at src/lib.rs: the secret is hunter2"),
+	    wasm_bytes(200, 100, 45),
+	].join("\n");
+	let guest = wat_guest(&items, r#"
+	    (if (i32.and (i32.ne (local.get $len) (i32.const 0)) (i32.eq (i32.load8_u (local.get $body)) (i32.const 33)))
+	      (then
+		(call $report_panic (i32.const 200))
+		(unreachable)))
+	    (i32.store (local.get $result) (local.get $body))
+	    (i32.store offset=4 (local.get $result) (local.get $len))"#);
+	let runner = TestRunner::with_module(MockDatastore::default(), guest).unwrap();
+	let logs = Logs::default();
+	let _guard = logs.capture();
+
+	assert_eq!(runner.call(b"fine").await.unwrap().body, b"fine");
+	let response = runner.call(b"!boom").await.unwrap();
+
+	assert_eq!(response.status, 500);
+	let body = String::from_utf8(response.body).unwrap();
+	assert!(body.starts_with("guest panicked (request "), "{}", body);
+	assert!(!body.contains("hunter2"), "{}", body);
+	let logs = logs.contents();
+	assert!(logs.contains("guest panicked"), "{}", logs);
+	assert!(logs.contains("the secret is hunter2"), "{}", logs);
+    }
+
+    #[tokio::test]
+    async fn guest_pointing_its_result_out_of_bounds_gets_a_500() {
+	for (base, len) in [(0xffff_fff0u32, 64u32), (100, 0xffff_0000), (65_530, 16)] {
+	    let guest = wat_guest("", &format!(r#"
+		(i32.store (local.get $result) (i32.const {}))
+		(i32.store offset=4 (local.get $result) (i32.const {}))"#, base as i32, len as i32));
+	    let runner = TestRunner::with_module(MockDatastore::default(), guest).unwrap();
+
+	    let response = runner.call(b"").await.unwrap();
+
+	    assert_eq!(response.status, 500, "base {} len {}", base, len);
+	    let body = String::from_utf8(response.body).unwrap();
+	    assert!(body.starts_with("guest returned an invalid result (request "), "{}", body);
+	}
+    }
+
+    #[tokio::test]
+    async fn guest_sets_its_status() {
+	let items = [
+	    r#"(import "env" "set_status" (func $set_status (param i32)))"#.to_string(),
+	    data(100, b"no such page"),
+	].join("\n");
+	let guest = wat_guest(&items, r#"
+	    (call $set_status (i32.const 404))
+	    (i32.store (local.get $result) (i32.const 100))
+	    (i32.store offset=4 (local.get $result) (i32.const 12))"#);
+	let runner = TestRunner::with_module(MockDatastore::default(), guest).unwrap();
+	let request = lambda_http::http::Request::builder().method("GET").uri("/missing").body(Body::Empty).unwrap();
+
+	let response = runner.request(request).await.unwrap();
+
+	assert_eq!(response.status, 404);
+	assert_eq!(response.body, b"no such page");
+    }
+
+    #[tokio::test]
+    async fn guest_content_type_overrides_the_default() {
+	let items = [
+	    r#"(import "env" "set_header" (func $set_header (param i32 i32) (result i32)))"#.to_string(),
+	    data(100, b"content-type"),
+	    data(120, b"application/json"),
+	    data(140, b"{}"),
+	    wasm_bytes(200, 100, 12),
+	    wasm_bytes(208, 120, 16),
+	].join("\n");
+	let guest = wat_guest(&items, r#"
+	    (drop (call $set_header (i32.const 200) (i32.const 208)))
+	    (i32.store (local.get $result) (i32.const 140))
+	    (i32.store offset=4 (local.get $result) (i32.const 2))"#);
+	let runner = TestRunner::with_module(MockDatastore::default(), guest).unwrap();
+	let request = lambda_http::http::Request::builder().method("GET").uri("/").body(Body::Empty).unwrap();
+
+	let response = runner.request(request).await.unwrap();
+
+	assert_eq!(response.status, 200);
+	assert_eq!(response.headers.get_all("content-type").iter().collect::<Vec<_>>(), ["application/json"]);
+	assert_eq!(response.body, b"{}");
+    }
+
+    #[tokio::test]
+    async fn guest_sees_the_pinned_time() {
+	let guest = wat_guest(r#"(import "env" "current_time_millis" (func $current_time_millis (result i64)))"#, r#"
+	    (i64.store (i32.const 400) (call $current_time_millis))
+	    (i32.store (local.get $result) (i32.const 400))
+	    (i32.store offset=4 (local.get $result) (i32.const 8))"#);
+	std::env::set_var("WASMTEST_FIXED_TIME_MS", "1700000000123");
+	let runner = TestRunner::with_module(MockDatastore::default(), guest);
+	std::env::remove_var("WASMTEST_FIXED_TIME_MS");
+	let runner = runner.unwrap();
+
+	let response = runner.call(b"").await.unwrap();
+
+	assert_eq!(response.body, 1_700_000_000_123u64.to_le_bytes());
+    }
+
+    #[tokio::test]
+    async fn guest_reads_a_value_bigger_than_its_memory() {
+	// Three pages' worth, where the guest starts with one.
+	let value: Vec<u8> = (0..3 * 65_536 + 100).map(|i| (i % 251) as u8).collect();
+	let datastore = MockDatastore::with_items([(b"big".to_vec(), value.clone())]);
+	let items = [
+	    r#"(import "env" "read_key" (func $read_key (param i32 i32) (result i32)))"#.to_string(),
+	    data(100, b"big"),
+	    wasm_bytes(200, 100, 3),
+	].join("\n");
+	let guest = wat_guest(&items, r#"
+	    (drop (call $read_key (i32.const 200) (local.get $result)))"#);
+	let runner = TestRunner::with_module(datastore, guest).unwrap();
+	let mut guest = runner.instance().await.unwrap();
+	assert_eq!(guest.memory_size(), 65_536);
+
+	assert_eq!(guest.call(b"").await.unwrap(), value);
+	assert!(guest.memory_size() > value.len());
+
+	// And through the real guest, whose memory starts out bigger.
+	let value = vec![b'x'; 8 << 20];
+	let runner = TestRunner::new(MockDatastore::with_items([(b"foo".to_vec(), value.clone())])).unwrap();
+	let response = runner.call(b"hello").await.unwrap();
+	assert_eq!(response.status, 200);
+	assert_eq!(response.body, value);
+    }
+
+    /// A guest with a 64-bit memory that stores its body under `key` and
+    /// returns what it reads back from there, so every `WasmBytes` it
+    /// exchanges with the host has 64-bit fields.
+    #[cfg(feature = "memory64")]
+    const ECHO_GUEST_64: &str = r#"(module
+  (import "env" "write_key" (func $write_key (param i64 i64) (result i32)))
+  (import "env" "read_key" (func $read_key (param i64 i64) (result i32)))
+  (memory (export "memory") i64 1)
+  ;; A `WasmBytes` at 100 pointing at "key" at 200.
+  (data (i64.const 100) "\c8\00\00\00\00\00\00\00\03\00\00\00\00\00\00\00")
+  (data (i64.const 200) "key")
+  (global $heap (mut i64) (i64.const 1024))
+  (func (export "wasmtest_abi_version") (result i32) (i32.const 1))
+  (func (export "alloc") (param $len i64) (result i64)
+    (local $base i64)
+    (local.set $base (global.get $heap))
+    (global.set $heap (i64.add (local.get $base) (local.get $len)))
+    (local.get $base))
+  (func (export "dealloc") (param i64 i64))
+  (func (export "entry") (param $result i64) (param $body i64) (param $len i64)
+    (i64.store (i64.const 300) (local.get $body))
+    (i64.store (i64.const 308) (local.get $len))
+    (drop (call $write_key (i64.const 100) (i64.const 300)))
+    (drop (call $read_key (i64.const 100) (i64.const 400)))
+    (i64.store (local.get $result) (i64.load (i64.const 400)))
+    (i64.store offset=8 (local.get $result) (i64.load (i64.const 408)))))"#;
+
+    #[cfg(feature = "memory64")]
+    #[tokio::test]
+    async fn memory64_guest_round_trips_through_the_datastore() {
+	let datastore = MockDatastore::default();
+	let runner = TestRunner::with_module(datastore.clone(), ECHO_GUEST_64).unwrap();
+
+	let response = runner.call(b"hello").await.unwrap();
+
+	assert_eq!(response.status, 200);
+	assert_eq!(response.body, b"hello");
+	assert_eq!(datastore.item(b"key").await.as_deref(), Some(&b"hello"[..]));
+    }
+
+    #[cfg(feature = "memory64")]
+    #[test]
+    fn memory64_runner_refuses_32_bit_guests() {
+	let guest = wat_guest("", "");
+	let error = TestRunner::with_module(MockDatastore::default(), guest).err().unwrap();
+	assert!(error.to_string().contains("32-bit memory"), "{}", error);
+    }
+}
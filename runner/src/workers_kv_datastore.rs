@@ -0,0 +1,214 @@
+//! A `Datastore` backed by a Cloudflare Workers KV namespace, through its
+//! REST API, for running the same guest next to an edge deployment's data.
+//!
+//! Each key is stored under its lowercase hex, like S3's object names, which
+//! keeps binary keys intact and lists in key order for `scan_prefix`. TTLs
+//! map onto KV's own expiry, which can't be shorter than 60 seconds, so
+//! shorter ones are rounded up.
+//!
+//! KV is eventually consistent and has no conditional writes, so nothing
+//! here can be made atomic: `compare_and_swap`, `delete_if_equals` and
+//! `increment`, and with them `swap` and `append`, fail with a backend
+//! error rather than pretend. A write can also take up to a minute to be
+//! seen from other locations.
+
+use std::time::Duration;
+
+use lambda_http::Error;
+use reqwest::{Method, RequestBuilder, Response, StatusCode, Url};
+
+use crate::{env_opt, from_hex, to_hex, Datastore, DatastoreError, MAX_SCAN_ENTRIES};
+
+/// Where the API lives if `WASMTEST_KV_ENDPOINT` doesn't say.
+const DEFAULT_ENDPOINT: &str = "https://api.cloudflare.com/client/v4";
+
+/// How long a call may take before it fails with `DatastoreError::Timeout`.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The shortest expiry KV accepts.
+const MIN_TTL: Duration = Duration::from_secs(60);
+
+/// The most keys one listing returns.
+const LIST_LIMIT: usize = 1000;
+
+/// Stores each key as a value in one KV namespace. Cloning is cheap: clones
+/// share the HTTP client and its connections.
+#[derive(Clone, Debug)]
+pub struct WorkersKvDatastore {
+    client: reqwest::Client,
+    /// The namespace's URL, which the API's paths hang off.
+    namespace: Url,
+    token: String,
+}
+
+/// One page of a key listing.
+struct Listing {
+    keys: Vec<Vec<u8>>,
+    cursor: Option<String>,
+}
+
+impl WorkersKvDatastore {
+    pub fn new(endpoint: &str, account_id: &str, namespace_id: &str, token: String) -> Result<Self, Error> {
+	let namespace = Url::parse(&format!("{}/accounts/{}/storage/kv/namespaces/{}/", endpoint.trim_end_matches('/'), account_id, namespace_id))?;
+	let client = reqwest::Client::builder().timeout(TIMEOUT).build()?;
+	Ok(WorkersKvDatastore { client, namespace, token })
+    }
+
+    /// Uses the namespace `WASMTEST_KV_NAMESPACE_ID` in the account
+    /// `WASMTEST_KV_ACCOUNT_ID`, authenticating with the API token in
+    /// `WASMTEST_KV_API_TOKEN`. `WASMTEST_KV_ENDPOINT` points the client
+    /// somewhere other than Cloudflare's API, such as a local mock.
+    pub fn from_env() -> Result<Self, Error> {
+	let required = |name: &str| -> Result<String, Error> {
+	    env_opt::<String>(name)?.ok_or_else(|| format!("{} must be set to use the Workers KV datastore", name).into())
+	};
+	let account_id = required("WASMTEST_KV_ACCOUNT_ID")?;
+	let namespace_id = required("WASMTEST_KV_NAMESPACE_ID")?;
+	let token = required("WASMTEST_KV_API_TOKEN")?;
+	let endpoint = env_opt::<String>("WASMTEST_KV_ENDPOINT")?.unwrap_or_else(|| DEFAULT_ENDPOINT.into());
+	Self::new(&endpoint, &account_id, &namespace_id, token)
+    }
+
+    /// A request for `path`, under the namespace, with our credentials.
+    fn request(&self, method: Method, path: &str) -> RequestBuilder {
+	let url = self.namespace.join(path).expect("paths are hex or fixed");
+	self.client.request(method, url).bearer_auth(&self.token)
+    }
+
+    /// A request for `key`'s value.
+    fn value(&self, method: Method, key: &[u8]) -> RequestBuilder {
+	self.request(method, &format!("values/{}", to_hex(key)))
+    }
+
+    /// Sends `request`, returning its response if it succeeded or, when
+    /// `missing_ok`, wasn't found.
+    async fn send(request: RequestBuilder, missing_ok: bool) -> Result<Option<Response>, DatastoreError> {
+	let response = request.send().await.map_err(kv_error)?;
+	match response.status() {
+	    status if status.is_success() => Ok(Some(response)),
+	    StatusCode::NOT_FOUND if missing_ok => Ok(None),
+	    StatusCode::TOO_MANY_REQUESTS => Err(DatastoreError::Throttled),
+	    StatusCode::NOT_FOUND => Err(DatastoreError::ResourceNotFound("Workers KV namespace not found".into())),
+	    status => {
+		let body = response.text().await.unwrap_or_default();
+		Err(DatastoreError::Backend(format!("Workers KV returned {}: {}", status, body)))
+	    }
+	}
+    }
+
+    async fn put(&self, key: &[u8], value: Vec<u8>, ttl: Option<Duration>) -> Result<(), DatastoreError> {
+	let mut request = self.value(Method::PUT, key)
+	    .header(reqwest::header::CONTENT_TYPE, "application/octet-stream")
+	    .body(value);
+	if let Some(ttl) = ttl {
+	    request = request.query(&[("expiration_ttl", ttl.max(MIN_TTL).as_secs())]);
+	}
+	Self::send(request, false).await?;
+	Ok(())
+    }
+
+    /// Lists the keys that start with `prefix`, a page at a time, skipping
+    /// any that weren't written by us.
+    async fn list(&self, prefix: &[u8], cursor: Option<String>) -> Result<Listing, DatastoreError> {
+	let mut query = vec![("prefix", to_hex(prefix)), ("limit", LIST_LIMIT.to_string())];
+	query.extend(cursor.map(|cursor| ("cursor", cursor)));
+	let response = Self::send(self.request(Method::GET, "keys").query(&query), false).await?
+	    .expect("only missing_ok requests come back empty");
+	let body = response.bytes().await.map_err(kv_error)?;
+	let body: serde_json::Value = serde_json::from_slice(&body)
+	    .map_err(|e| DatastoreError::Backend(format!("malformed Workers KV listing: {}", e)))?;
+	let keys = body["result"].as_array().into_iter().flatten()
+	    .filter_map(|key| from_hex(key["name"].as_str()?))
+	    .collect();
+	// The last page has an empty cursor.
+	let cursor = body["result_info"]["cursor"].as_str().filter(|cursor| !cursor.is_empty()).map(String::from);
+	Ok(Listing { keys, cursor })
+    }
+}
+
+fn kv_error(e: reqwest::Error) -> DatastoreError {
+    if e.is_timeout() {
+	DatastoreError::Timeout
+    } else {
+	DatastoreError::Backend(e.to_string())
+    }
+}
+
+/// What the operations KV can't do atomically fail with.
+fn not_atomic() -> DatastoreError {
+    DatastoreError::Backend("Workers KV has no conditional writes, so it can't do this atomically".into())
+}
+
+impl Datastore for WorkersKvDatastore {
+    const NAME: &'static str = "workers-kv";
+
+    async fn put_item(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<(), DatastoreError> {
+	self.put(&key, value, None).await
+    }
+
+    async fn put_item_with_ttl(&mut self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<(), DatastoreError> {
+	self.put(&key, value, Some(ttl)).await
+    }
+
+    async fn get_item(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>, DatastoreError> {
+	// A missing key is a 404 like a missing namespace, so a namespace
+	// that doesn't exist reads as empty.
+	let Some(response) = Self::send(self.value(Method::GET, key), true).await? else {
+	    return Ok(None);
+	};
+	Ok(Some(response.bytes().await.map_err(kv_error)?.to_vec()))
+    }
+
+    async fn delete_item(&mut self, key: &[u8]) -> Result<(), DatastoreError> {
+	Self::send(self.value(Method::DELETE, key), true).await?;
+	Ok(())
+    }
+
+    async fn compare_and_swap(&mut self, _key: Vec<u8>, _expected: Option<&[u8]>, _new: Vec<u8>) -> Result<bool, DatastoreError> {
+	Err(not_atomic())
+    }
+
+    async fn delete_if_equals(&mut self, _key: &[u8], _expected: &[u8]) -> Result<bool, DatastoreError> {
+	Err(not_atomic())
+    }
+
+    async fn increment(&mut self, _key: Vec<u8>, _delta: i64) -> Result<Option<i64>, DatastoreError> {
+	Err(not_atomic())
+    }
+
+    async fn scan_prefix(&mut self, prefix: &[u8]) -> Result<Vec<(Vec<u8>, Vec<u8>)>, DatastoreError> {
+	// Listings come back in name order, which is key order, so we can stop
+	// as soon as we have `MAX_SCAN_ENTRIES` entries.
+	let mut entries = Vec::new();
+	let mut cursor = None;
+	loop {
+	    let listing = self.list(prefix, cursor).await?;
+	    for key in listing.keys {
+		// The key may have been deleted or expired since it was listed.
+		if let Some(value) = self.get_item(&key).await? {
+		    entries.push((key, value));
+		    if entries.len() == MAX_SCAN_ENTRIES {
+			return Ok(entries);
+		    }
+		}
+	    }
+	    cursor = listing.cursor;
+	    if cursor.is_none() {
+		return Ok(entries);
+	    }
+	}
+    }
+
+    async fn count(&mut self) -> Result<u64, DatastoreError> {
+	let mut count = 0;
+	let mut cursor = None;
+	loop {
+	    let listing = self.list(&[], cursor).await?;
+	    count += listing.keys.len() as u64;
+	    cursor = listing.cursor;
+	    if cursor.is_none() {
+		return Ok(count);
+	    }
+	}
+    }
+}
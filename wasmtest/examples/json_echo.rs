@@ -0,0 +1,31 @@
+//! A guest that answers a JSON object with the same object, plus an
+//! `echoed_at` field holding the time it was echoed.
+//!
+//! It exports `json_echo` rather than `entry`, which the library already
+//! exports, so route to it:
+//!
+//! ```text
+//! cargo build --release --example json_echo --features json
+//! WASMTEST_MODULE_PATH=../wasmtest/target/wasm32-unknown-unknown/release/examples/json_echo.wasm \
+//!     WASMTEST_ROUTES='POST /echo=json_echo' WASMTEST_LOCAL_PORT=8080 cargo run
+//! curl -d '{"hello":"world"}' localhost:8080/echo
+//! ```
+
+use serde_json::{Map, Value};
+use wasmtest::{json, response, time, WasmBytes};
+
+#[no_mangle]
+pub fn json_echo(result: &mut WasmBytes, body: WasmBytes) {
+    let out = match json::parse::<Map<String, Value>>(&body) {
+        Ok(mut object) => {
+            object.insert("echoed_at".into(), time::now_millis().into());
+            response::set_header("content-type", "application/json").unwrap();
+            json::to_bytes(&object)
+        }
+        Err(e) => {
+            response::set_status(400);
+            e.to_string().into_bytes()
+        }
+    };
+    *result = WasmBytes::from_vec(out);
+}
@@ -1,24 +1,1125 @@
 pub mod datastore {
     use super::WasmBytes;
-    extern {
-        fn write_key(key: WasmBytes, body: WasmBytes);
-        fn read_key(key: WasmBytes) -> WasmBytes;
+
+    /// Why a datastore call failed, decoded from the status code the host
+    /// returns.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum DatastoreError {
+        /// The key doesn't exist.
+        NotFound,
+        /// The backend is rate limiting requests; retrying later may succeed.
+        Throttled,
+        /// Any other backend failure, such as a network or serialization
+        /// error.
+        Backend,
+        /// Guest memory couldn't grow enough to hold the result.
+        OutOfMemory,
+        /// The backend didn't respond in time.
+        Timeout,
+        /// `increment` found a value at the key that isn't a counter.
+        NotACounter,
     }
 
-    pub fn write(key: &[u8], body: &[u8]) {
-        unsafe {
-            write_key(WasmBytes::from_slice(key), WasmBytes::from_slice(body))
+    impl DatastoreError {
+        fn check(status: u32) -> Result<(), DatastoreError> {
+            match status {
+                0 => Ok(()),
+                1 => Err(DatastoreError::NotFound),
+                2 => Err(DatastoreError::Throttled),
+                4 => Err(DatastoreError::OutOfMemory),
+                5 => Err(DatastoreError::Timeout),
+                _ => Err(DatastoreError::Backend),
+            }
+        }
+
+        /// Decodes the status of an import that answers yes or no, which
+        /// returns 0 for no and 1 for yes rather than 1 for a missing key.
+        fn check_bool(status: u32) -> Result<bool, DatastoreError> {
+            match status {
+                0 => Ok(false),
+                1 => Ok(true),
+                status => DatastoreError::check(status).map(|()| false),
+            }
         }
     }
 
+    // Host imports that return bytes fill in a `WasmBytes` pointing at a
+    // buffer the host obtained from our exported `alloc`. Each such buffer
+    // belongs to the caller, which must release it with `dealloc`.
+    extern "C" {
+        fn write_key(key: WasmBytes, body: WasmBytes) -> u32;
+        fn write_key_with_ttl(key: WasmBytes, body: WasmBytes, ttl_secs: u64) -> u32;
+        fn append_key(key: WasmBytes, suffix: WasmBytes) -> u32;
+        /// Fills in `result` and returns 0 if `key` is present, or returns 1
+        /// and leaves `result` untouched if it is not. Other codes are
+        /// `DatastoreError`s.
+        fn read_key(key: WasmBytes, result: &mut WasmBytes) -> u32;
+        fn read_many_key(keys: WasmBytes, result: &mut WasmBytes) -> u32;
+        fn delete_key(key: WasmBytes) -> u32;
+        /// Returns 1 if `key` is present and 0 if not. Other codes are
+        /// `DatastoreError`s, as they are for the other imports that answer
+        /// yes or no.
+        fn has_key(key: WasmBytes) -> u32;
+        fn scan_prefix_key(prefix: WasmBytes, result: &mut WasmBytes) -> u32;
+        fn write_batch_key(pairs: WasmBytes) -> u32;
+        fn increment_key(key: WasmBytes, delta: i64, result: &mut i64) -> u32;
+        fn compare_and_swap_key(key: WasmBytes, expected: Option<&WasmBytes>, new: WasmBytes) -> u32;
+        fn delete_if_equals_key(key: WasmBytes, expected: WasmBytes) -> u32;
+        /// Writes `new` and then reports the old value like `read_key`.
+        fn swap_key(key: WasmBytes, new: WasmBytes, result: &mut WasmBytes) -> u32;
+        fn count_keys(result: &mut u64) -> u32;
+    }
+
+    pub fn write(key: &[u8], body: &[u8]) -> Result<(), DatastoreError> {
+        let status = unsafe {
+            write_key(WasmBytes::from_slice(key), WasmBytes::from_slice(body))
+        };
+        DatastoreError::check(status)
+    }
+
+    /// Writes `key` so that it expires `ttl_secs` seconds from now, after
+    /// which reads treat it as absent.
+    pub fn write_with_ttl(key: &[u8], body: &[u8], ttl_secs: u64) -> Result<(), DatastoreError> {
+        let status = unsafe {
+            write_key_with_ttl(WasmBytes::from_slice(key), WasmBytes::from_slice(body), ttl_secs)
+        };
+        DatastoreError::check(status)
+    }
+
+    /// Reads `key`, treating a missing key the same as an empty value. Use
+    /// `read_opt` to tell the two apart.
     pub fn read<F, R>(key: &[u8], mut f: F) -> R where F: (FnMut(&[u8]) -> R) {
+        match read_opt(key, &mut f) {
+            Some(r) => r,
+            None => f(&[]),
+        }
+    }
+
+    /// Reads `key`, calling `f` with its value if it is present. Returns
+    /// `None` without calling `f` if the key does not exist.
+    ///
+    /// Panics if the read itself fails; use `try_read` to handle that.
+    pub fn read_opt<F, R>(key: &[u8], f: F) -> Option<R> where F: (FnMut(&[u8]) -> R) {
+        try_read(key, f).expect("datastore read failed")
+    }
+
+    /// Reads `key` like `read_opt`, but reports failures such as the host
+    /// being unable to fit the value in guest memory.
+    pub fn try_read<F, R>(key: &[u8], mut f: F) -> Result<Option<R>, DatastoreError> where F: (FnMut(&[u8]) -> R) {
         unsafe {
-            let result = read_key(WasmBytes::from_slice(key));
-	    f(result.as_slice())
-	}
+            let mut result = WasmBytes::from_slice(&[]);
+            match read_key(WasmBytes::from_slice(key), &mut result) {
+                0 => {
+                    let r = f(result.as_slice());
+                    super::dealloc(result.base as *mut u8, result.len);
+                    Ok(Some(r))
+                }
+                1 => Ok(None),
+                status => DatastoreError::check(status).map(|()| None),
+            }
+        }
+    }
+
+    /// Reads `key` into an owned buffer, or returns `None` if it is missing.
+    ///
+    /// The value is copied out of the buffer the host wrote it into, so the
+    /// `Vec` is independent of guest memory the host hands out later.
+    pub fn read_vec(key: &[u8]) -> Option<Vec<u8>> {
+        read_opt(key, |value| value.to_vec())
+    }
+
+    /// Atomically appends `suffix` to the value at `key`, creating it if it's
+    /// missing, e.g. to accumulate a log one record at a time.
+    ///
+    /// How this is done depends on the host's backend. Memory, Redis, SQLite
+    /// and file backends append in place and keep the key's TTL. DynamoDB
+    /// and S3, and any backend with encryption or compression turned on,
+    /// read the value and write it back only if nobody changed it in
+    /// between. They try up to 10 times before failing with `Throttled`, and
+    /// clear the key's TTL. Either way no append is lost or applied twice.
+    pub fn append(key: &[u8], suffix: &[u8]) -> Result<(), DatastoreError> {
+        let status = unsafe {
+            append_key(WasmBytes::from_slice(key), WasmBytes::from_slice(suffix))
+        };
+        DatastoreError::check(status)
+    }
+
+    /// Writes a string value under a string key, like `write`.
+    pub fn write_str(key: &str, body: &str) -> Result<(), DatastoreError> {
+        write(key.as_bytes(), body.as_bytes())
+    }
+
+    /// Reads `key` as a string, or returns `None` if it is missing or its
+    /// value isn't valid UTF-8. Like `read_opt`, panics if the read itself
+    /// fails.
+    pub fn read_str(key: &str) -> Option<String> {
+        read_opt(key.as_bytes(), |value| std::str::from_utf8(value).ok().map(String::from)).flatten()
+    }
+
+    /// Reads every key in `keys` with a single host call, returning their
+    /// values in the same order, with `None` for keys that don't exist.
+    ///
+    /// The keys are sent as a little-endian u32 count followed by each key
+    /// as a u32 length and its bytes. The values come back the same way,
+    /// with a length of `u32::MAX` marking a missing key.
+    pub fn read_many(keys: &[&[u8]]) -> Result<Vec<Option<Vec<u8>>>, DatastoreError> {
+        let mut buf = Vec::new();
+        buf.extend_from_slice(&(keys.len() as u32).to_le_bytes());
+        for key in keys {
+            buf.extend_from_slice(&(key.len() as u32).to_le_bytes());
+            buf.extend_from_slice(key);
+        }
+        let mut result = WasmBytes::from_slice(&[]);
+        let status = unsafe {
+            read_many_key(WasmBytes::from_slice(&buf), &mut result)
+        };
+        DatastoreError::check(status)?;
+
+        let mut buf = result.as_slice();
+        let mut take = |len: usize| {
+            let (head, rest) = buf.split_at(len);
+            buf = rest;
+            head
+        };
+        let count = u32::from_le_bytes(take(4).try_into().unwrap());
+        let values = (0..count).map(|_| {
+            match u32::from_le_bytes(take(4).try_into().unwrap()) {
+                u32::MAX => None,
+                len => Some(take(len as usize).to_vec()),
+            }
+        }).collect();
+        unsafe {
+            super::dealloc(result.base as *mut u8, result.len);
+        }
+        Ok(values)
+    }
+
+    /// Returns whether `key` is present, without transferring its value.
+    pub fn exists(key: &[u8]) -> Result<bool, DatastoreError> {
+        let status = unsafe {
+            has_key(WasmBytes::from_slice(key))
+        };
+        DatastoreError::check_bool(status)
+    }
+
+    /// Atomically sets `key` to `new` if its current value is `expected`,
+    /// where `None` means the key must not exist yet. Returns whether the
+    /// swap happened.
+    pub fn compare_and_swap(key: &[u8], expected: Option<&[u8]>, new: &[u8]) -> Result<bool, DatastoreError> {
+        let expected = expected.map(WasmBytes::from_slice);
+        let status = unsafe {
+            compare_and_swap_key(WasmBytes::from_slice(key), expected.as_ref(), WasmBytes::from_slice(new))
+        };
+        DatastoreError::check_bool(status)
+    }
+
+    /// How many times `update_with` tries before giving up.
+    const MAX_UPDATE_ATTEMPTS: usize = 10;
+
+    /// Sets `key` to what `f` makes of its current value, which is `None` if
+    /// the key is missing, with a `compare_and_swap` so that no other write
+    /// is lost. If another writer changes the key between our read and our
+    /// write, `f` is called again on the new value, up to 10 times in all.
+    /// Returns whether the write went through, or an error if a read or the
+    /// swap failed.
+    pub fn update_with(key: &[u8], mut f: impl FnMut(Option<&[u8]>) -> Vec<u8>) -> Result<bool, DatastoreError> {
+        for _ in 0..MAX_UPDATE_ATTEMPTS {
+            let old = try_read(key, |value| value.to_vec())?;
+            let new = f(old.as_deref());
+            if compare_and_swap(key, old.as_deref(), &new)? {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /// Atomically deletes `key` if its current value is `expected`, e.g. to
+    /// release a lock only if we still hold it. Returns whether the delete
+    /// happened.
+    pub fn delete_if_equals(key: &[u8], expected: &[u8]) -> Result<bool, DatastoreError> {
+        let status = unsafe {
+            delete_if_equals_key(WasmBytes::from_slice(key), WasmBytes::from_slice(expected))
+        };
+        DatastoreError::check_bool(status)
+    }
+
+    /// Atomically sets `key` to `new`, clearing any TTL, and returns the value
+    /// it replaced, or `None` if the key didn't exist.
+    ///
+    /// Panics if the swap fails; use `try_swap` to handle that.
+    pub fn swap(key: &[u8], new: &[u8]) -> Option<Vec<u8>> {
+        try_swap(key, new).expect("datastore swap failed")
+    }
+
+    /// Swaps like `swap`, but reports failures. An `OutOfMemory` error means
+    /// the new value was written but the old one couldn't be handed back.
+    pub fn try_swap(key: &[u8], new: &[u8]) -> Result<Option<Vec<u8>>, DatastoreError> {
+        unsafe {
+            let mut result = WasmBytes::from_slice(&[]);
+            match swap_key(WasmBytes::from_slice(key), WasmBytes::from_slice(new), &mut result) {
+                0 => Ok(Some(result.into_vec())),
+                1 => Ok(None),
+                status => DatastoreError::check(status).map(|()| None),
+            }
+        }
+    }
+
+    /// Atomically adds `delta` to the counter at `key` and returns the new
+    /// total. Counters are stored as little-endian i64s and missing keys
+    /// start from 0. Fails with `NotACounter` if the existing value isn't 8
+    /// bytes long.
+    pub fn increment(key: &[u8], delta: i64) -> Result<i64, DatastoreError> {
+        let mut total = 0;
+        unsafe {
+            match increment_key(WasmBytes::from_slice(key), delta, &mut total) {
+                0 => Ok(total),
+                1 => Err(DatastoreError::NotACounter),
+                status => DatastoreError::check(status).map(|()| total),
+            }
+        }
+    }
+
+    /// Returns how many keys the datastore holds. On some backends this is
+    /// only an estimate: DynamoDB's count can lag recent writes by hours,
+    /// and S3's and Redis' include keys that have expired or that weren't
+    /// written by a guest. Hosts that keep tenants' keys apart can't count
+    /// one tenant's keys alone, so there this fails with `Backend`.
+    pub fn count() -> Result<u64, DatastoreError> {
+        let mut count = 0;
+        let status = unsafe {
+            count_keys(&mut count)
+        };
+        DatastoreError::check(status)?;
+        Ok(count)
+    }
+
+    /// Writes every pair with a single host call.
+    ///
+    /// The pairs are sent as one buffer: a little-endian u32 count, followed
+    /// by each key and value as a u32 length and its bytes.
+    pub fn write_batch(pairs: &[(&[u8], &[u8])]) -> Result<(), DatastoreError> {
+        let mut buf = Vec::new();
+        buf.extend_from_slice(&(pairs.len() as u32).to_le_bytes());
+        for (key, value) in pairs {
+            buf.extend_from_slice(&(key.len() as u32).to_le_bytes());
+            buf.extend_from_slice(key);
+            buf.extend_from_slice(&(value.len() as u32).to_le_bytes());
+            buf.extend_from_slice(value);
+        }
+        let status = unsafe {
+            write_batch_key(WasmBytes::from_slice(&buf))
+        };
+        DatastoreError::check(status)
+    }
+
+    /// Calls `f` with each key/value pair whose key starts with `prefix`.
+    ///
+    /// The host returns at most 100 entries per scan, so callers with more
+    /// matching keys than that should scan again with narrower prefixes.
+    pub fn scan_prefix(prefix: &[u8], mut f: impl FnMut(&[u8], &[u8])) -> Result<(), DatastoreError> {
+        let mut result = WasmBytes::from_slice(&[]);
+        let status = unsafe {
+            scan_prefix_key(WasmBytes::from_slice(prefix), &mut result)
+        };
+        DatastoreError::check(status)?;
+
+        // The result uses the same encoding as `write_batch`.
+        let mut buf = result.as_slice();
+        let mut take = |len: usize| {
+            let (head, rest) = buf.split_at(len);
+            buf = rest;
+            head
+        };
+        let count = u32::from_le_bytes(take(4).try_into().unwrap());
+        for _ in 0..count {
+            let key_len = u32::from_le_bytes(take(4).try_into().unwrap()) as usize;
+            let key = take(key_len);
+            let value_len = u32::from_le_bytes(take(4).try_into().unwrap()) as usize;
+            let value = take(value_len);
+            f(key, value);
+        }
+        unsafe {
+            super::dealloc(result.base as *mut u8, result.len);
+        }
+        Ok(())
+    }
+
+    /// Removes `key` from the datastore. Deleting a key that was never
+    /// written is not an error.
+    pub fn delete(key: &[u8]) -> Result<(), DatastoreError> {
+        let status = unsafe {
+            delete_key(WasmBytes::from_slice(key))
+        };
+        DatastoreError::check(status)
+    }
+
+    /// A separate keyspace called `name`, like a table of its own, for
+    /// keeping unrelated data apart. Panics if `name` is longer than 255
+    /// bytes.
+    pub fn namespace(name: &str) -> Namespace {
+        assert!(name.len() <= u8::MAX as usize, "namespace name longer than 255 bytes");
+        let mut prefix = vec![0xff, name.len() as u8];
+        prefix.extend_from_slice(name.as_bytes());
+        Namespace { prefix }
+    }
+
+    /// A keyspace from `namespace`. Its keys are stored as `0xff`, the
+    /// name's length as one byte, the name, and then the key. Names are
+    /// length-prefixed, so no two namespaces share a key, and `0xff` never
+    /// appears in UTF-8, so they don't collide with text keys written
+    /// outside any namespace either.
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub struct Namespace {
+        prefix: Vec<u8>,
+    }
+
+    impl Namespace {
+        /// The key `key` in this namespace is stored under, for the
+        /// operations this doesn't wrap.
+        pub fn key(&self, key: &[u8]) -> Vec<u8> {
+            [self.prefix.as_slice(), key].concat()
+        }
+
+        pub fn write(&self, key: &[u8], body: &[u8]) -> Result<(), DatastoreError> {
+            write(&self.key(key), body)
+        }
+
+        /// Reads `key` into an owned buffer, or returns `Ok(None)` if it is
+        /// missing. Failures are reported like `try_read`'s.
+        pub fn read(&self, key: &[u8]) -> Result<Option<Vec<u8>>, DatastoreError> {
+            try_read(&self.key(key), |value| value.to_vec())
+        }
+
+        pub fn exists(&self, key: &[u8]) -> Result<bool, DatastoreError> {
+            exists(&self.key(key))
+        }
+
+        pub fn delete(&self, key: &[u8]) -> Result<(), DatastoreError> {
+            delete(&self.key(key))
+        }
+
+        /// Calls `f` with each pair in this namespace whose key starts with
+        /// `prefix`, with the keys as they were written here. Like
+        /// `scan_prefix`, returns at most 100 entries.
+        pub fn scan_prefix(&self, prefix: &[u8], mut f: impl FnMut(&[u8], &[u8])) -> Result<(), DatastoreError> {
+            scan_prefix(&self.key(prefix), |key, value| f(&key[self.prefix.len()..], value))
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn namespaces_store_the_same_key_apart() {
+            let (users, orders) = (namespace("users"), namespace("orders"));
+            assert_ne!(users.key(b"42"), orders.key(b"42"));
+            // Neither is a prefix of the other, so scans in one namespace
+            // never reach into the other.
+            assert!(!users.key(b"42").starts_with(&orders.key(b"")));
+            assert!(!orders.key(b"42").starts_with(&users.key(b"")));
+            assert_ne!(users.key(b"42"), b"42");
+        }
+
+        #[test]
+        fn namespace_names_dont_run_into_keys() {
+            assert_ne!(namespace("ab").key(b"c"), namespace("a").key(b"bc"));
+        }
+
+        #[cfg(not(target_family = "wasm"))]
+        #[test]
+        fn update_with_retries_on_what_another_writer_left() {
+            host::ITEMS.with_borrow_mut(|items| items.insert(b"count".to_vec(), b"1".to_vec()));
+            // Someone else bumps the count between our read and our swap.
+            host::BEFORE_SWAP.set(Some(Box::new(|| {
+                host::ITEMS.with_borrow_mut(|items| items.insert(b"count".to_vec(), b"5".to_vec()));
+            })));
+
+            let mut seen = Vec::new();
+            let updated = update_with(b"count", |old| {
+                seen.push(old.map(<[u8]>::to_vec));
+                let count: u32 = std::str::from_utf8(old.unwrap()).unwrap().parse().unwrap();
+                (count + 1).to_string().into_bytes()
+            });
+
+            assert_eq!(updated, Ok(true));
+            assert_eq!(seen, [Some(b"1".to_vec()), Some(b"5".to_vec())]);
+            assert_eq!(host::ITEMS.with_borrow(|items| items[&b"count"[..]].clone()), b"6");
+        }
+
+        /// Stand-ins for the host's imports, so native tests can call the
+        /// wrappers that use them. Each test's thread has its own items.
+        #[cfg(not(target_family = "wasm"))]
+        mod host {
+            use std::cell::RefCell;
+            use std::collections::HashMap;
+
+            use crate::WasmBytes;
+
+            thread_local! {
+                pub static ITEMS: RefCell<HashMap<Vec<u8>, Vec<u8>>> = RefCell::default();
+                /// Runs at the start of the next `compare_and_swap_key`, to
+                /// play a writer that gets in first.
+                pub static BEFORE_SWAP: RefCell<Option<Box<dyn FnOnce()>>> = RefCell::default();
+            }
+
+            #[no_mangle]
+            extern "C" fn read_key(key: WasmBytes, result: &mut WasmBytes) -> u32 {
+                match ITEMS.with_borrow(|items| items.get(key.as_slice()).cloned()) {
+                    Some(value) => {
+                        *result = WasmBytes::from_vec(value);
+                        0
+                    }
+                    None => 1,
+                }
+            }
+
+            #[no_mangle]
+            extern "C" fn compare_and_swap_key(key: WasmBytes, expected: Option<&WasmBytes>, new: WasmBytes) -> u32 {
+                if let Some(writer) = BEFORE_SWAP.take() {
+                    writer();
+                }
+                ITEMS.with_borrow_mut(|items| {
+                    if items.get(key.as_slice()).map(Vec::as_slice) != expected.map(WasmBytes::as_slice) {
+                        return 0;
+                    }
+                    items.insert(key.to_vec(), new.to_vec());
+                    1
+                })
+            }
+        }
+    }
+}
+
+pub mod log {
+    use super::WasmBytes;
+
+    /// How severe a log message is, from most to least. These map onto the
+    /// host's `tracing` levels.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum Level {
+        Error = 1,
+        Warn = 2,
+        Info = 3,
+        Debug = 4,
+        Trace = 5,
+    }
+
+    extern "C" {
+        fn log_message(level: u32, message: WasmBytes);
+    }
+
+    /// Logs `msg` through the host at `level`.
+    pub fn log(level: Level, msg: &str) {
+        unsafe { log_message(level as u32, WasmBytes::from_slice(msg.as_bytes())) }
+    }
+}
+
+pub mod time {
+    extern "C" {
+        fn current_time_millis() -> u64;
+        fn sleep_millis(ms: u32);
+    }
+
+    /// The current time in milliseconds since the Unix epoch, according to
+    /// the host.
+    pub fn now_millis() -> u64 {
+        unsafe { current_time_millis() }
+    }
+
+    /// Waits `ms` milliseconds, e.g. to back off between polls. The host
+    /// caps each wait at 5 seconds, and the time counts against the
+    /// request's timeout.
+    pub fn delay(ms: u32) {
+        unsafe { sleep_millis(ms) }
+    }
+}
+
+pub mod metrics {
+    use super::WasmBytes;
+
+    /// Why the host refused a metric.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum MetricError {
+        /// The name isn't 1 to 255 bytes of ASCII letters, digits, `.`, `_`,
+        /// `-` and `/`, or is `export` or `_aws`, or the value isn't finite.
+        Invalid,
+        /// This request has already emitted 100 other metrics.
+        TooMany,
+    }
+
+    extern "C" {
+        fn emit_metric(name: WasmBytes, value: f64) -> u32;
+    }
+
+    /// Adds `value` to this request's metric called `name`. The host adds up
+    /// everything a request emits under each name and reports the totals to
+    /// CloudWatch once `entry` returns, so counting a cache hit is
+    /// `count("cache_hits", 1.0)`.
+    pub fn count(name: &str, value: f64) -> Result<(), MetricError> {
+        match unsafe { emit_metric(WasmBytes::from_slice(name.as_bytes()), value) } {
+            0 => Ok(()),
+            1 => Err(MetricError::Invalid),
+            _ => Err(MetricError::TooMany),
+        }
+    }
+}
+
+pub mod random {
+    extern "C" {
+        fn fill_random(buf: *mut u8, len: usize);
+        #[link_name = "uuid_v4"]
+        fn host_uuid_v4(out: *mut u8);
+    }
+
+    /// Fills `buf` with random bytes from the host. Guests have no entropy
+    /// source of their own, so this is the one to use for anything that
+    /// needs to be unpredictable, like tokens or IDs.
+    pub fn random_bytes(buf: &mut [u8]) {
+        unsafe { fill_random(buf.as_mut_ptr(), buf.len()) }
+    }
+
+    /// A random version 4 UUID from the host.
+    pub fn uuid_v4() -> [u8; 16] {
+        let mut uuid = [0; 16];
+        unsafe { host_uuid_v4(uuid.as_mut_ptr()) }
+        uuid
+    }
+
+    /// A random version 4 UUID in its usual lowercase hyphenated form, like
+    /// `0b5f2a52-9c1e-4d3a-8f6b-2e7c1a9d4f30`.
+    pub fn uuid_v4_string() -> String {
+        format_uuid(&uuid_v4())
+    }
+
+    /// `uuid` in lowercase hyphenated form.
+    fn format_uuid(uuid: &[u8; 16]) -> String {
+        let mut text = String::with_capacity(36);
+        for (i, byte) in uuid.iter().enumerate() {
+            if matches!(i, 4 | 6 | 8 | 10) {
+                text.push('-');
+            }
+            text.push_str(&format!("{:02x}", byte));
+        }
+        text
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn formats_uuids_hyphenated() {
+            let uuid = [0x0b, 0x5f, 0x2a, 0x52, 0x9c, 0x1e, 0x4d, 0x3a, 0x8f, 0x6b, 0x2e, 0x7c, 0x1a, 0x9d, 0x4f, 0x30];
+            assert_eq!(format_uuid(&uuid), "0b5f2a52-9c1e-4d3a-8f6b-2e7c1a9d4f30");
+            assert_eq!(format_uuid(&[0; 16]), "00000000-0000-0000-0000-000000000000");
+        }
+    }
+}
+
+pub mod hash {
+    extern "C" {
+        #[link_name = "sha256"]
+        fn host_sha256(ptr: *const u8, len: usize, out: *mut u8);
+    }
+
+    /// The SHA-256 digest of `bytes`, computed by the host, for checksums
+    /// and content-addressed keys.
+    pub fn sha256(bytes: &[u8]) -> [u8; 32] {
+        let mut digest = [0; 32];
+        unsafe { host_sha256(bytes.as_ptr(), bytes.len(), digest.as_mut_ptr()) }
+        digest
+    }
+}
+
+pub mod base64 {
+    use super::WasmBytes;
+
+    /// Input `decode` refused because it isn't valid base64 in the alphabet
+    /// it was asked for.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct InvalidBase64;
+
+    // Both fill in `result` with a buffer we own, like `read_key`. `url_safe`
+    // picks the URL-safe alphabet without padding, as JWTs use, over the
+    // standard one with padding. Decoding returns 1 if the input isn't valid.
+    extern "C" {
+        fn base64_encode(input: WasmBytes, url_safe: u32, result: &mut WasmBytes) -> u32;
+        fn base64_decode(input: WasmBytes, url_safe: u32, result: &mut WasmBytes) -> u32;
+    }
+
+    /// `bytes` in standard base64, with padding.
+    pub fn encode(bytes: &[u8]) -> String {
+        let value = fetch(|result| unsafe { base64_encode(WasmBytes::from_slice(bytes), 0, result) });
+        String::from_utf8(value.expect("encoding can't fail")).unwrap()
+    }
+
+    /// `bytes` in URL-safe base64, without padding.
+    pub fn encode_url(bytes: &[u8]) -> String {
+        let value = fetch(|result| unsafe { base64_encode(WasmBytes::from_slice(bytes), 1, result) });
+        String::from_utf8(value.expect("encoding can't fail")).unwrap()
+    }
+
+    /// The bytes standard, padded base64 `text` holds.
+    pub fn decode(text: &str) -> Result<Vec<u8>, InvalidBase64> {
+        fetch(|result| unsafe { base64_decode(WasmBytes::from_slice(text.as_bytes()), 0, result) }).ok_or(InvalidBase64)
+    }
+
+    /// The bytes URL-safe, unpadded base64 `text` holds, such as a JWT's
+    /// segments.
+    pub fn decode_url(text: &str) -> Result<Vec<u8>, InvalidBase64> {
+        fetch(|result| unsafe { base64_decode(WasmBytes::from_slice(text.as_bytes()), 1, result) }).ok_or(InvalidBase64)
+    }
+
+    /// Copies out the value one of our imports hands back, freeing the
+    /// host's buffer, or returns `None` if the input was invalid.
+    fn fetch(import: impl FnOnce(&mut WasmBytes) -> u32) -> Option<Vec<u8>> {
+        let mut result = WasmBytes::from_slice(&[]);
+        match import(&mut result) {
+            0 => {}
+            1 => return None,
+            _ => panic!("base64 result too large for guest memory"),
+        }
+        let value = result.as_slice().to_vec();
+        unsafe {
+            super::dealloc(result.base as *mut u8, result.len);
+        }
+        Some(value)
+    }
+}
+
+pub mod http {
+    use super::WasmBytes;
+
+    /// An outbound request for `fetch` to make.
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub struct Request {
+        pub method: String,
+        pub url: String,
+        pub headers: Vec<(String, String)>,
+        pub body: Vec<u8>,
+    }
+
+    impl Request {
+        /// A `GET` of `url` with no headers.
+        pub fn get(url: &str) -> Self {
+            Request { method: "GET".into(), url: url.into(), headers: Vec::new(), body: Vec::new() }
+        }
+    }
+
+    /// What the server sent back.
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub struct Response {
+        pub status: u16,
+        pub headers: Vec<(String, String)>,
+        pub body: Vec<u8>,
+    }
+
+    /// Why a fetch failed, decoded from the status code the host returns.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum HttpError {
+        /// The host doesn't allow requests to the URL's domain.
+        Forbidden,
+        /// The server didn't respond in time.
+        Timeout,
+        /// Guest memory couldn't grow enough to hold the response.
+        OutOfMemory,
+        /// The request was malformed, or the connection failed.
+        Failed,
+    }
+
+    impl HttpError {
+        fn check(status: u32) -> Result<(), HttpError> {
+            match status {
+                0 => Ok(()),
+                4 => Err(HttpError::OutOfMemory),
+                5 => Err(HttpError::Timeout),
+                6 => Err(HttpError::Forbidden),
+                _ => Err(HttpError::Failed),
+            }
+        }
+    }
+
+    extern "C" {
+        /// Like `read_key`, fills in `result` with a buffer we own on
+        /// success.
+        fn http_fetch(request: WasmBytes, result: &mut WasmBytes) -> u32;
+    }
+
+    /// Makes `req` from the host. Only domains on the host's allowlist can be
+    /// reached.
+    pub fn fetch(req: Request) -> Result<Response, HttpError> {
+        // The host expects the method, URL and body each as a u32 length and
+        // its bytes, with the headers in between encoded like
+        // `datastore::write_batch` pairs.
+        let mut buf = Vec::new();
+        let put = |buf: &mut Vec<u8>, bytes: &[u8]| {
+            buf.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+            buf.extend_from_slice(bytes);
+        };
+        put(&mut buf, req.method.as_bytes());
+        put(&mut buf, req.url.as_bytes());
+        buf.extend_from_slice(&(req.headers.len() as u32).to_le_bytes());
+        for (name, value) in &req.headers {
+            put(&mut buf, name.as_bytes());
+            put(&mut buf, value.as_bytes());
+        }
+        put(&mut buf, &req.body);
+
+        let mut result = WasmBytes::from_slice(&[]);
+        let status = unsafe {
+            http_fetch(WasmBytes::from_slice(&buf), &mut result)
+        };
+        HttpError::check(status)?;
+
+        // The response is a u32 status followed by headers and a body in the
+        // same encoding.
+        let mut buf = result.as_slice();
+        let status = u32::from_le_bytes(take(&mut buf, 4).try_into().unwrap()) as u16;
+        let count = u32::from_le_bytes(take(&mut buf, 4).try_into().unwrap());
+        let headers = (0..count).map(|_| {
+            let name = String::from_utf8_lossy(take_field(&mut buf)).into_owned();
+            let value = String::from_utf8_lossy(take_field(&mut buf)).into_owned();
+            (name, value)
+        }).collect();
+        let body = take_field(&mut buf).to_vec();
+        unsafe {
+            super::dealloc(result.base as *mut u8, result.len);
+        }
+        Ok(Response { status, headers, body })
+    }
+
+    fn take<'a>(buf: &mut &'a [u8], len: usize) -> &'a [u8] {
+        let (head, rest) = buf.split_at(len);
+        *buf = rest;
+        head
+    }
+
+    fn take_field<'a>(buf: &mut &'a [u8]) -> &'a [u8] {
+        let len = u32::from_le_bytes(take(buf, 4).try_into().unwrap()) as usize;
+        take(buf, len)
+    }
+}
+
+pub mod request {
+    use super::WasmBytes;
+
+    // Each of these works like `read_key`: it fills in `result` and returns
+    // 0 if the request has the value, or returns 1 if it doesn't.
+    extern "C" {
+        fn get_request_header(name: WasmBytes, result: &mut WasmBytes) -> u32;
+        fn get_request_method(result: &mut WasmBytes) -> u32;
+        fn get_request_path(result: &mut WasmBytes) -> u32;
+        fn get_request_query(result: &mut WasmBytes) -> u32;
+        fn get_request_route(result: &mut WasmBytes) -> u32;
+        fn get_route_param(name: WasmBytes, result: &mut WasmBytes) -> u32;
+    }
+
+    /// The HTTP method of the request, such as `GET`.
+    pub fn method() -> String {
+        let value = fetch(|result| unsafe { get_request_method(result) });
+        String::from_utf8(value.expect("the request has no method")).unwrap()
+    }
+
+    /// The path of the request URL, without its query string.
+    pub fn path() -> String {
+        let value = fetch(|result| unsafe { get_request_path(result) });
+        String::from_utf8(value.expect("the request has no path")).unwrap()
+    }
+
+    /// The query string of the request URL, without the leading `?`, or
+    /// `None` if it doesn't have one.
+    pub fn query() -> Option<String> {
+        let value = fetch(|result| unsafe { get_request_query(result) });
+        value.map(|value| String::from_utf8(value).unwrap())
+    }
+
+    /// The pattern of the route that sent the request to this export, such as
+    /// `/users/:id`, or `None` if the host doesn't route requests.
+    pub fn route() -> Option<String> {
+        let value = fetch(|result| unsafe { get_request_route(result) });
+        value.map(|value| String::from_utf8(value).unwrap())
+    }
+
+    /// The path segment the route's `:name` segment matched, or `None` if it
+    /// has no such segment.
+    pub fn param(name: &str) -> Option<String> {
+        let value = fetch(|result| unsafe { get_route_param(WasmBytes::from_slice(name.as_bytes()), result) });
+        value.map(|value| String::from_utf8(value).unwrap())
+    }
+
+    /// The value of the request header `name`, which is case-insensitive, or
+    /// `None` if the request doesn't have one. A header sent more than once
+    /// gives its first value.
+    pub fn header(name: &str) -> Option<Vec<u8>> {
+        fetch(|result| unsafe { get_request_header(WasmBytes::from_slice(name.as_bytes()), result) })
+    }
+
+    /// Copies out the value one of our imports hands back, freeing the
+    /// host's buffer.
+    fn fetch(import: impl FnOnce(&mut WasmBytes) -> u32) -> Option<Vec<u8>> {
+        let mut result = WasmBytes::from_slice(&[]);
+        match import(&mut result) {
+            0 => {}
+            1 => return None,
+            _ => panic!("reading the request failed"),
+        }
+        let value = result.as_slice().to_vec();
+        unsafe {
+            super::dealloc(result.base as *mut u8, result.len);
+        }
+        Some(value)
     }
 }
 
+pub mod response {
+    use super::WasmBytes;
+
+    /// A header `set_header` refused because its name or value isn't valid in
+    /// HTTP.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct InvalidHeader;
+
+    extern "C" {
+        #[link_name = "set_status"]
+        fn host_set_status(code: u32);
+        #[link_name = "set_header"]
+        fn host_set_header(name: WasmBytes, value: WasmBytes) -> u32;
+    }
+
+    /// Sets the HTTP status of the response `entry` returns, which is 200 if
+    /// this is never called. The code must be between 100 and 999.
+    pub fn set_status(code: u16) {
+        unsafe { host_set_status(code as u32) }
+    }
+
+    /// Adds a header to the response `entry` returns. Setting `content-type`
+    /// replaces the host's default, which is `text/html` unless it's
+    /// configured otherwise; setting any name more than once sends every
+    /// value.
+    pub fn set_header(name: &str, value: &str) -> Result<(), InvalidHeader> {
+        let status = unsafe {
+            host_set_header(WasmBytes::from_slice(name.as_bytes()), WasmBytes::from_slice(value.as_bytes()))
+        };
+        match status {
+            0 => Ok(()),
+            _ => Err(InvalidHeader),
+        }
+    }
+
+    /// Marks a result that's a whole `Response` rather than just a body.
+    const MAGIC: &[u8] = b"\xffwtr";
+
+    /// A whole response, for `entry` to return instead of a bare body, so the
+    /// status and headers travel with it rather than through `set_status`
+    /// and `set_header`.
+    ///
+    /// Its status replaces any from `set_status`, and its headers replace any
+    /// of the same name from `set_header`. An invalid status or header fails
+    /// the whole request with a 500 rather than being skipped.
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub struct Response {
+        pub status: u16,
+        pub headers: Vec<(String, String)>,
+        pub body: Vec<u8>,
+    }
+
+    impl Response {
+        /// A response with `status` and `body` and no headers.
+        pub fn new(status: u16, body: impl Into<Vec<u8>>) -> Self {
+            Response { status, headers: Vec::new(), body: body.into() }
+        }
+
+        /// Adds a header, keeping any earlier ones of the same name.
+        pub fn header(mut self, name: &str, value: &str) -> Self {
+            self.headers.push((name.into(), value.into()));
+            self
+        }
+
+        /// Encodes the response for `entry` to return, e.g. with
+        /// `WasmBytes::from_vec`.
+        ///
+        /// The host tells this apart from a bare body by its first bytes,
+        /// which start with `0xff` and so never begin UTF-8 text. A binary
+        /// body that could start the same way should be sent in a `Response`.
+        pub fn into_bytes(self) -> Vec<u8> {
+            // `MAGIC`, then a u32 status and the headers and body in the
+            // encoding `http::fetch` requests use.
+            let mut buf = MAGIC.to_vec();
+            let put = |buf: &mut Vec<u8>, bytes: &[u8]| {
+                buf.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+                buf.extend_from_slice(bytes);
+            };
+            buf.extend_from_slice(&(self.status as u32).to_le_bytes());
+            buf.extend_from_slice(&(self.headers.len() as u32).to_le_bytes());
+            for (name, value) in &self.headers {
+                put(&mut buf, name.as_bytes());
+                put(&mut buf, value.as_bytes());
+            }
+            put(&mut buf, &self.body);
+            buf
+        }
+    }
+}
+
+/// Parsing request bodies and building responses as JSON, with `serde`.
+/// Only built with the `json` feature.
+#[cfg(feature = "json")]
+pub mod json {
+    use serde::{Deserialize, Serialize};
+
+    /// Why a body couldn't be parsed as the type asked for.
+    #[derive(Debug)]
+    pub struct JsonError(serde_json::Error);
+
+    impl std::fmt::Display for JsonError {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(f, "invalid JSON: {}", self.0)
+        }
+    }
+
+    impl std::error::Error for JsonError {}
+
+    /// Parses `bytes`, such as the body `entry` is called with, as a `T`.
+    pub fn parse<'a, T: Deserialize<'a>>(bytes: &'a [u8]) -> Result<T, JsonError> {
+        serde_json::from_slice(bytes).map_err(JsonError)
+    }
+
+    /// Serializes `value` for `entry` to return. Set a `content-type` of
+    /// `application/json` with `response::set_header` to go with it.
+    ///
+    /// Panics if `value` can't be represented as JSON, such as a map whose
+    /// keys aren't strings.
+    pub fn to_bytes<T: Serialize + ?Sized>(value: &T) -> Vec<u8> {
+        serde_json::to_vec(value).expect("value can't be serialized as JSON")
+    }
+}
+
+/// Encoding request bodies, responses and datastore values as MessagePack,
+/// with `serde`. Only built with the `msgpack` feature.
+///
+/// Structs are encoded as maps keyed by field name, so values stay readable
+/// after fields are added or reordered, and by programs in other languages.
+#[cfg(feature = "msgpack")]
+pub mod msgpack {
+    use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+    use crate::datastore::{self, DatastoreError};
+
+    /// Why bytes couldn't be decoded as the type asked for.
+    #[derive(Debug)]
+    pub struct MsgpackError(rmp_serde::decode::Error);
+
+    impl std::fmt::Display for MsgpackError {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(f, "invalid MessagePack: {}", self.0)
+        }
+    }
+
+    impl std::error::Error for MsgpackError {}
+
+    /// Decodes `bytes`, such as the body `entry` is called with, as a `T`.
+    pub fn decode<'a, T: Deserialize<'a>>(bytes: &'a [u8]) -> Result<T, MsgpackError> {
+        rmp_serde::from_slice(bytes).map_err(MsgpackError)
+    }
+
+    /// Encodes `value`, for `entry` to return or to store. A response should
+    /// come with a `content-type` of `application/msgpack`.
+    ///
+    /// Panics if `value`'s `Serialize` impl fails.
+    pub fn encode<T: Serialize + ?Sized>(value: &T) -> Vec<u8> {
+        rmp_serde::to_vec_named(value).expect("value can't be serialized as MessagePack")
+    }
+
+    /// Stores `value` under `key`, encoded with `encode`.
+    pub fn write<T: Serialize + ?Sized>(key: &[u8], value: &T) -> Result<(), DatastoreError> {
+        datastore::write(key, &encode(value))
+    }
+
+    /// Reads and decodes the value under `key`, or returns `None` if it is
+    /// missing. Like `datastore::read_opt`, panics if the read itself fails.
+    pub fn read<T: DeserializeOwned>(key: &[u8]) -> Result<Option<T>, MsgpackError> {
+        datastore::read_opt(key, |value| decode(value)).transpose()
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use std::collections::BTreeMap;
+
+        use super::*;
+
+        #[test]
+        fn values_round_trip() {
+            let value = (String::from("order"), 42u64, Some(-1.5f64), vec![1u8, 2, 3], BTreeMap::from([("a".to_string(), true)]));
+            let decoded: (String, u64, Option<f64>, Vec<u8>, BTreeMap<String, bool>) = decode(&encode(&value)).unwrap();
+            assert_eq!(decoded, value);
+        }
+
+        #[test]
+        fn decoding_the_wrong_type_fails() {
+            let error = decode::<u32>(&encode("not a number")).unwrap_err();
+            assert!(error.to_string().starts_with("invalid MessagePack: "), "{}", error);
+        }
+    }
+}
+
+/// The version of the guest ABI this crate implements, which the host checks
+/// through `wasmtest_abi_version` before it calls anything else.
+///
+/// Version 1 works like this:
+///
+/// * Byte buffers cross the boundary as `WasmBytes`: a little-endian base
+///   address followed by a length, each as wide as a pointer.
+/// * The host passes every buffer it creates for the guest, including each
+///   request body, in memory it got from our exported `alloc`, so it never
+///   writes anywhere the guest hasn't handed it.
+/// * An entrypoint such as `entry` is called with a pointer to a `WasmBytes`
+///   the host allocated for its result and the request body, flattened to
+///   its base and length. Both belong to the host, which frees them once the
+///   call returns. The entrypoint points the result at a buffer of its own,
+///   which the host takes ownership of and frees with `dealloc`. The buffer
+///   is either the response body or a `response::Response`.
+///
+/// Addresses and lengths, in `WasmBytes` and as arguments, are 32 bits wide
+/// by default, for a 32-bit linear memory. With the `memory64` feature they
+/// are 64 bits wide, for guests built for a 64-bit memory, which only a
+/// runner built with its own `memory64` feature will load. Lengths inside
+/// encoded buffers, such as `read_many`'s, are u32 either way.
+pub const ABI_VERSION: u32 = 1;
+
+// `WasmBytes` is `repr(C)` over a pointer and a `usize`, so it only matches
+// the width the host expects if the feature agrees with the target. Native
+// builds, such as for `cargo test`, never cross the boundary, so only wasm
+// targets are checked.
+#[cfg(all(target_family = "wasm", not(feature = "memory64")))]
+const _: () = assert!(std::mem::size_of::<usize>() == 4, "64-bit linear memory needs the `memory64` feature");
+#[cfg(all(target_family = "wasm", feature = "memory64"))]
+const _: () = assert!(std::mem::size_of::<usize>() == 8, "the `memory64` feature needs a 64-bit linear memory");
+
+/// Reports `ABI_VERSION` to the host.
+///
+/// The host calls this first on every instance, so it's also where we set
+/// up `report_panics`.
+#[no_mangle]
+pub extern "C" fn wasmtest_abi_version() -> u32 {
+    report_panics();
+    ABI_VERSION
+}
+
+extern "C" {
+    fn report_panic(message: WasmBytes);
+}
+
+/// Has every panic hand its message and location to the host before the
+/// guest traps, so the host can log what went wrong rather than just that
+/// the guest hit an `unreachable`.
+fn report_panics() {
+    std::panic::set_hook(Box::new(|info| {
+        let message = info.to_string();
+        unsafe { report_panic(WasmBytes::from_slice(message.as_bytes())) }
+    }));
+}
+
 #[repr(C)]
 pub struct WasmBytes {
     base: *const u8,
@@ -33,22 +1134,142 @@ impl WasmBytes {
         }
     }
 
+    /// The bytes `base` points to. An empty `WasmBytes` reads as an empty
+    /// slice whatever its `base`, since hosts may hand back a null one.
     pub fn as_slice(&self) -> &[u8] {
+        if self.len == 0 {
+            return &[];
+        }
 	unsafe {
 	    std::slice::from_raw_parts(self.base, self.len)
 	}
     }
+
+    /// Leaks `bytes` into a buffer whose ownership goes with the returned
+    /// `WasmBytes`, for results handed to the host. Whoever ends up with it
+    /// must free it exactly once: the host by calling `dealloc` with its base
+    /// and length, as it does for `entry`'s result, or the guest with
+    /// `into_vec`. The buffer is shrunk to fit first, so base and length
+    /// describe the whole allocation, which is what `dealloc` expects.
+    pub fn from_vec(bytes: Vec<u8>) -> Self {
+        WasmBytes::from_slice(Box::leak(bytes.into_boxed_slice()))
+    }
+
+    /// Takes back a buffer leaked by `from_vec`, so dropping the `Vec` frees
+    /// it.
+    ///
+    /// # Safety
+    ///
+    /// `self` must have come from `from_vec` (or describe exactly one buffer
+    /// from `alloc`), and its buffer must not have been freed already or be
+    /// used again afterwards.
+    pub unsafe fn into_vec(self) -> Vec<u8> {
+        if self.len == 0 {
+            return Vec::new();
+        }
+        Box::from_raw(std::ptr::slice_from_raw_parts_mut(self.base as *mut u8, self.len)).into_vec()
+    }
+
+    /// The number of bytes, read straight from the header without touching
+    /// the buffer.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+/// Shows the base and length, and a hex preview of the first few bytes. A
+/// null base is shown without the preview rather than read; beyond that,
+/// `Debug` trusts the pointer as much as `as_slice` does.
+impl std::fmt::Debug for WasmBytes {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        const PREVIEW_LEN: usize = 16;
+        write!(f, "WasmBytes {{ base: {:p}, len: {}", self.base, self.len)?;
+        if !self.base.is_null() {
+            let bytes = self.as_slice();
+            f.write_str(", bytes: ")?;
+            for byte in bytes.iter().take(PREVIEW_LEN) {
+                write!(f, "{:02x}", byte)?;
+            }
+            if bytes.len() > PREVIEW_LEN {
+                f.write_str("...")?;
+            }
+        }
+        f.write_str(" }")
+    }
+}
+
+impl std::ops::Deref for WasmBytes {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        self.as_slice()
+    }
 }
 
+/// Allocates a `len`-byte buffer in guest memory for the host to fill or to
+/// take ownership of. The buffer must be released with `dealloc`.
+///
+/// Returns null rather than aborting if linear memory can't grow enough, so
+/// the host can report the failure instead of writing out of bounds.
+#[no_mangle]
+pub extern "C" fn alloc(len: usize) -> *mut u8 {
+    match std::alloc::Layout::array::<u8>(len) {
+        Ok(layout) if len > 0 => unsafe { std::alloc::alloc(layout) },
+        Ok(_) => std::ptr::NonNull::dangling().as_ptr(),
+        Err(_) => std::ptr::null_mut(),
+    }
+}
+
+/// Frees a buffer returned by `alloc`, or handed to the host as an owned
+/// result such as `entry`'s.
+///
+/// # Safety
+///
+/// `ptr` and `len` must describe exactly one such buffer, which must not be
+/// used again afterwards. Empty buffers own no memory, so their `ptr` may be
+/// anything, including the null of a result `entry` never set.
+#[no_mangle]
+pub unsafe extern "C" fn dealloc(ptr: *mut u8, len: usize) {
+    if len == 0 {
+        return;
+    }
+    drop(Box::from_raw(std::ptr::slice_from_raw_parts_mut(ptr, len)));
+}
+
+fn handle(body: &[u8]) -> Result<Vec<u8>, datastore::DatastoreError> {
+    datastore::write(body, b"world")?;
+    datastore::read(b"foo", |value| {
+	datastore::write(b"world", value)?;
+	Ok(value.into())
+    })
+}
 
 #[no_mangle]
 pub fn entry(result: &mut WasmBytes, body: WasmBytes) {
     let body = body.as_slice();
-    datastore::write(body, b"world");
-    let res: Vec<u8> = datastore::read(b"foo", |value| {
-	datastore::write(b"world", value);
-	value.into()
-    });
-    *result = WasmBytes::from_slice(&res);
-    std::mem::forget(res);
+    let res: Vec<u8> = match handle(body) {
+	Ok(res) => res,
+	Err(e) => format!("datastore error: {:?}", e).into_bytes(),
+    };
+    // Ownership of the buffer passes to the host, which frees it with
+    // `dealloc` once it has copied the response out.
+    *result = WasmBytes::from_vec(res);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn empty_wasm_bytes_with_a_null_base_reads_as_empty() {
+        let bytes = WasmBytes { base: std::ptr::null(), len: 0 };
+        assert_eq!(bytes.as_slice(), b"");
+        assert!(bytes.is_empty());
+        assert_eq!(format!("{:?}", bytes), "WasmBytes { base: 0x0, len: 0 }");
+        assert_eq!(unsafe { bytes.into_vec() }, b"");
+    }
 }